@@ -0,0 +1,37 @@
+//! a server which sends arbitrary bytes, starting with the response to the prelogin
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate tiberius;
+
+use std::io::{self, Cursor, Read, Write};
+use tiberius::{AuthenticationMethod, Connection, ConnectionOptBuilder};
+
+#[derive(Debug)]
+struct FuzzStream(Cursor<Vec<u8>>);
+
+impl Read for FuzzStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for FuzzStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let opts = ConnectionOptBuilder::new(FuzzStream(Cursor::new(data.to_vec())))
+        .auth(AuthenticationMethod::internal("fuzz", "fuzz"))
+        .db("fuzz")
+        .build();
+    if let Ok(conn) = Connection::connect(opts) {
+        let _ = conn.query("SELECT 1");
+    }
+});
@@ -0,0 +1,54 @@
+//! a server which completes the login and answers the query with arbitrary tokens
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate tiberius;
+
+use std::io::{self, Cursor, Read, Write};
+use tiberius::{AuthenticationMethod, Connection, ConnectionOptBuilder};
+
+#[derive(Debug)]
+struct FuzzStream(Cursor<Vec<u8>>);
+
+impl Read for FuzzStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for FuzzStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// split the data into tabular result packets, the last one ends the message
+fn packets(data: &[u8], stream: &mut Vec<u8>) {
+    let chunks: Vec<&[u8]> = if data.is_empty() { vec![data] } else { data.chunks(4088).collect() };
+    for (i, chunk) in chunks.iter().enumerate() {
+        let len = chunk.len() + 8;
+        let status = if i + 1 == chunks.len() { 1 } else { 0 };
+        stream.extend_from_slice(&[4, status, (len >> 8) as u8, len as u8, 0, 0, 1, 0]);
+        stream.extend_from_slice(chunk);
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut stream = vec![];
+    // the prelogin response (only the terminator) and a DONE token completing the login
+    packets(&[0xFF], &mut stream);
+    packets(&[0xFD, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], &mut stream);
+    packets(data, &mut stream);
+    let opts = ConnectionOptBuilder::new(FuzzStream(Cursor::new(stream)))
+        .auth(AuthenticationMethod::internal("fuzz", "fuzz"))
+        .db("fuzz")
+        .build();
+    let conn = Connection::connect(opts).unwrap();
+    // the whole response as well as the incremental decoding
+    let _ = conn.query("SELECT 1");
+    let _ = conn.query_each("SELECT 1", |_| Ok(()));
+});
@@ -1,14 +1,22 @@
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cmp;
 use std::fmt;
-use std::rc::Rc;
-use std::io::prelude::*;
+use std::io::{self, prelude::*};
+use std::mem;
 use std::net::{TcpStream, ToSocketAddrs};
 use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, UTC};
 
 use protocol::*;
-use stmt::{StatementInternal, QueryResult, PreparedStatement};
-use ::{TdsResult, TdsError};
+use stmt::{ColumnInfo, DoneStatus, FromRow, StatementInfo, StatementInternal, QueryResult, PreparedStatement, ParameterizedStatement, Param, ProcResult, Row};
+use types::{ColumnType, FromColumnValue, ToColumnType};
+use transaction::Transaction;
+use ::{TdsResult, TdsError, ServerMessage};
 
 #[derive(Debug, PartialEq)]
 pub enum ClientState {
@@ -17,28 +25,253 @@ pub enum ClientState {
     Ready
 }
 
-/// A connection to a MSSQL server
+/// A stream a connection can use, it has to be `Send` so the connection can be moved to another thread
+pub trait TargetStream: Read + Write + fmt::Debug + Send {}
+impl<T: Read + Write + fmt::Debug + Send> TargetStream for T {}
+
+/// A token to cancel a query (see `Connection::query_cancelable`) from another thread
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// signal the cancellation, a query using this token is cancelled as soon as possible
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
 
-pub trait TargetStream: Read + Write + fmt::Debug {}
-impl<T: Read + Write + fmt::Debug> TargetStream for T {}
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// The SET options of a session as reported by `sys.dm_exec_sessions`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionOptions {
+    pub quoted_identifier: bool,
+    pub arithabort: bool,
+    pub ansi_nulls: bool,
+    pub ansi_null_dflt_on: bool,
+    pub ansi_padding: bool,
+    pub ansi_warnings: bool,
+    pub concat_null_yields_null: bool,
+    /// 0 = unspecified, 1 = read uncommitted, 2 = read committed, 3 = repeatable read, 4 = serializable, 5 = snapshot
+    pub transaction_isolation_level: i32,
+    /// in milliseconds, -1 waits forever
+    pub lock_timeout: i32,
+}
 
-pub struct Connection<'a, S: 'a + TargetStream>(Rc<RefCell<InternalConnection<'a, S>>>);
+/// A connection to a MSSQL server
+///
+/// The connection can be moved to (and cloned into) other threads, but there is only one
+/// underlying connection: each request locks it until its whole response is read,
+/// so requests from multiple owners are executed one after another
+pub struct Connection<'a, S: 'a + TargetStream>(Arc<Mutex<InternalConnection<'a, S>>>);
 
 #[derive(Debug)]
 pub enum AuthenticationMethod<'a> {
     /// username, password
-    InternalSqlServerAuth(Cow<'a, str>, Cow<'a, str>)
+    InternalSqlServerAuth(Cow<'a, str>, Password<'a>)
 }
 
 impl<'a> AuthenticationMethod<'a> {
-    pub fn internal<U: Into<Cow<'a, str>>, P: Into<Cow<'a, str>>>(username: U, password: P) -> AuthenticationMethod<'a> {
+    pub fn internal<U: Into<Cow<'a, str>>, P: Into<Password<'a>>>(username: U, password: P) -> AuthenticationMethod<'a> {
         AuthenticationMethod::InternalSqlServerAuth(username.into(), password.into())
     }
 }
 
+/// A password which is overwritten with zeroes when it is dropped
+///
+/// Only an owned password (e.g. a `String`) is wiped, a borrowed one belongs to the caller
+#[derive(Clone, PartialEq)]
+pub struct Password<'a>(PasswordData<'a>);
+
+// the wiping lives in a type without a lifetime, so a connection borrowing
+// its options does not have to outlive them (as it would with `Drop` on `Password`)
+#[derive(Clone, PartialEq)]
+enum PasswordData<'a> {
+    Borrowed(&'a str),
+    Owned(OwnedPassword),
+}
+
+#[derive(Clone, PartialEq)]
+struct OwnedPassword(String);
+
+impl Drop for OwnedPassword {
+    fn drop(&mut self) {
+        // zeroes are valid UTF-8
+        unsafe { wipe(self.0.as_bytes_mut()) };
+    }
+}
+
+impl<'a> Password<'a> {
+    pub fn as_str(&self) -> &str {
+        match self.0 {
+            PasswordData::Borrowed(password) => password,
+            PasswordData::Owned(ref password) => &password.0,
+        }
+    }
+}
+
+impl<'a> Deref for Password<'a> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<'a> fmt::Debug for Password<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Password(***)")
+    }
+}
+
+impl<'a> From<&'a str> for Password<'a> {
+    fn from(password: &'a str) -> Password<'a> {
+        Password(PasswordData::Borrowed(password))
+    }
+}
+
+impl<'a> From<String> for Password<'a> {
+    fn from(password: String) -> Password<'a> {
+        Password(PasswordData::Owned(OwnedPassword(password)))
+    }
+}
+
+impl<'a> From<Cow<'a, str>> for Password<'a> {
+    fn from(password: Cow<'a, str>) -> Password<'a> {
+        match password {
+            Cow::Borrowed(password) => Password::from(password),
+            Cow::Owned(password) => Password::from(password),
+        }
+    }
+}
+
+/// Option bits of the login packet (2.2.6.4) which can be set on a connection
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LoginFlag {
+    /// fReadOnlyIntent: the application only reads, which allows routing to a readable secondary
+    ReadOnlyIntent,
+    /// fCacheConnect: the server may cache the connection
+    CacheConnect,
+    /// fUserInstance: request a connection to a user instance
+    UserInstance,
+    /// fUnknownCollationHandling: the client can handle collations it does not know
+    UnknownCollationHandling,
+}
+
+impl LoginFlag {
+    fn apply(&self, login: &mut Login7) {
+        match *self {
+            LoginFlag::ReadOnlyIntent => login.type_flags |= 0x20,
+            LoginFlag::CacheConnect => login.flags2 |= 0x08,
+            LoginFlag::UserInstance => login.flags3 |= 0x04,
+            LoginFlag::UnknownCollationHandling => login.flags3 |= 0x08,
+        }
+    }
+}
+
+/// Features which can be requested in the FeatureExt block of the login packet (2.2.6.4),
+/// requesting any of them requests TDS 7.4
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LoginFeature {
+    /// UTF8_SUPPORT: the server may use UTF-8 collations, whose non-unicode data is UTF-8
+    Utf8Support,
+}
+
+impl LoginFeature {
+    /// the FeatureId
+    pub fn id(&self) -> u8 {
+        match *self {
+            LoginFeature::Utf8Support => 0x0A,
+        }
+    }
+
+    fn apply(&self, login: &mut Login7) {
+        match *self {
+            LoginFeature::Utf8Support => login.features.push((self.id(), vec![])),
+        }
+    }
+}
+
+/// a callback receiving the informational messages of the server
+struct MessageHandler(Box<Fn(&ServerMessage) + Send>);
+
+impl fmt::Debug for MessageHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MessageHandler")
+    }
+}
+
+/// a callback receiving the number of bytes sent of a request and its total size
+struct ProgressHandler(Box<Fn(usize, usize) + Send>);
+
+impl fmt::Debug for ProgressHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProgressHandler")
+    }
+}
+
+/// a callback opening a new stream (and socket) to the server the login is redirected to
+struct Redirect<S>(Box<Fn(&str, u16) -> TdsResult<(S, Option<TcpStream>)> + Send>);
+
+impl<S> fmt::Debug for Redirect<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Redirect")
+    }
+}
+
+/// a callback opening a new stream (and socket) to the server for another attempt to connect
+struct Reconnect<S>(Box<Fn() -> TdsResult<(S, Option<TcpStream>)> + Send>);
+
+impl<S> fmt::Debug for Reconnect<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Reconnect")
+    }
+}
+
+/// reconnect to the address of `stream` using TCP
+fn tcp_reconnect<S: TargetStream, F: Fn(TcpStream) -> S + Send + 'static>(stream: &TcpStream, wrap: F) -> TdsResult<Reconnect<S>> {
+    let addr = try!(stream.peer_addr());
+    Ok(Reconnect(Box::new(move || {
+        let stream = try!(TcpStream::connect(addr));
+        let socket = try!(stream.try_clone());
+        Ok((wrap(stream), Some(socket)))
+    })))
+}
+
+/// reconnect using TCP, `wrap` converts the stream to the stream type of the connection
+fn tcp_redirect<S: TargetStream, F: Fn(TcpStream) -> S + Send + 'static>(wrap: F) -> Redirect<S> {
+    Redirect(Box::new(move |server, port| {
+        let stream = try!(TcpStream::connect((server, port)));
+        let socket = try!(stream.try_clone());
+        Ok((wrap(stream), Some(socket)))
+    }))
+}
+
 pub struct ConnectionOptBuilder<'a, S: 'a + TargetStream> {
     auth: Option<AuthenticationMethod<'a>>,
     database: Option<Cow<'a, str>>,
+    language: Option<Cow<'a, str>>,
+    login_flags: Vec<LoginFlag>,
+    login_features: Vec<LoginFeature>,
+    packet_size: u16,
+    strict_binding: bool,
+    empty_strings_as_null: bool,
+    connect_timeout: Option<Duration>,
+    statement_cache_size: usize,
+    on_message: Option<MessageHandler>,
+    on_upload_progress: Option<ProgressHandler>,
+    redirect: Option<Redirect<S>>,
+    connect_retries: u32,
+    connect_backoff: Duration,
+    reconnect: Option<Reconnect<S>>,
+    /// a handle to the underlying socket (if any) to apply timeouts to
+    socket: Option<TcpStream>,
     stream: S,
 }
 
@@ -47,6 +280,21 @@ impl<'a, S: 'a + TargetStream> ConnectionOptBuilder<'a, S> {
         ConnectionOptBuilder {
             auth: None,
             database: None,
+            language: None,
+            login_flags: vec![],
+            login_features: vec![],
+            packet_size: 0x1000,
+            strict_binding: false,
+            empty_strings_as_null: false,
+            connect_timeout: None,
+            statement_cache_size: 0,
+            on_message: None,
+            on_upload_progress: None,
+            redirect: None,
+            connect_retries: 0,
+            connect_backoff: Duration::from_millis(500),
+            reconnect: None,
+            socket: None,
             stream: stream,
         }
     }
@@ -55,15 +303,142 @@ impl<'a, S: 'a + TargetStream> ConnectionOptBuilder<'a, S> {
         self
     }
 
+    /// the database to use, without it the server uses the default database of the login
     pub fn db<D: Into<Cow<'a, str>>>(mut self, db: D) -> ConnectionOptBuilder<'a, S> {
         self.database = Some(db.into());
         self
     }
 
+    /// the language of the session (e.g. `Deutsch`), which determines the names of months and days,
+    /// the date format and the language of messages, without it the default language of the login is used
+    pub fn language<L: Into<Cow<'a, str>>>(mut self, language: L) -> ConnectionOptBuilder<'a, S> {
+        self.language = Some(language.into());
+        self
+    }
+
+    /// set an option bit in the login packet
+    pub fn login_flag(mut self, flag: LoginFlag) -> ConnectionOptBuilder<'a, S> {
+        if !self.login_flags.contains(&flag) {
+            self.login_flags.push(flag);
+        }
+        self
+    }
+
+    /// request a feature in the FeatureExt block of the login packet,
+    /// whether the server acknowledged it is available through `Connection::feature_ack`
+    pub fn login_feature(mut self, feature: LoginFeature) -> ConnectionOptBuilder<'a, S> {
+        if !self.login_features.contains(&feature) {
+            self.login_features.push(feature);
+        }
+        self
+    }
+
+    /// the packet size to request in the login, which is clamped to the valid range of 512 to 32767
+    /// the server may still decide to use another (e.g. smaller) size
+    pub fn packet_size(mut self, size: u16) -> ConnectionOptBuilder<'a, S> {
+        self.packet_size = cmp::min(cmp::max(size, 512), 32767);
+        self
+    }
+
+    /// check the types of bound parameters against the types the server infers for them
+    /// (`sp_describe_undeclared_parameters`) when preparing a statement, and fail if binding
+    /// them causes an implicit conversion which prevents using an index (nvarchar compared to varchar)
+    pub fn strict_binding(mut self, strict: bool) -> ConnectionOptBuilder<'a, S> {
+        self.strict_binding = strict;
+        self
+    }
+
+    /// bind empty string parameters as NULL instead of `''` (for schemas treating both the same)
+    pub fn empty_strings_as_null(mut self, empty_as_null: bool) -> ConnectionOptBuilder<'a, S> {
+        self.empty_strings_as_null = empty_as_null;
+        self
+    }
+
+    /// bound the whole handshake (prelogin and login), exceeding it fails with `TdsError::Timeout`
+    /// for streams other than a `TcpStream` this is only checked in between the round trips
+    pub fn connect_timeout(mut self, timeout: Duration) -> ConnectionOptBuilder<'a, S> {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// the name of the connection string keyword `Login Timeout` for `connect_timeout`:
+    /// it only bounds the handshake, later requests are not limited by it
+    pub fn login_timeout(self, timeout: Duration) -> ConnectionOptBuilder<'a, S> {
+        self.connect_timeout(timeout)
+    }
+
+    /// keep the handles of up to `size` prepared statements, so preparing the same sql again does not
+    /// require a `sp_prepare`, the least recently used statement is unprepared when the cache is full
+    ///
+    /// the cache belongs to the physical connection, so it is shared by all clones of a `Connection`
+    /// (e.g. when a pool hands out the same connection again)
+    pub fn statement_cache_size(mut self, size: usize) -> ConnectionOptBuilder<'a, S> {
+        self.statement_cache_size = size;
+        self
+    }
+
+    /// call `handler` for each informational message (e.g. `PRINT` or a warning) as it is received
+    pub fn on_message<F: Fn(&ServerMessage) + Send + 'static>(mut self, handler: F) -> ConnectionOptBuilder<'a, S> {
+        self.on_message = Some(MessageHandler(Box::new(handler)));
+        self
+    }
+
+    /// call `handler` with the bytes sent so far and the total size after each packet of a request
+    /// with parameters (e.g. to show the progress of uploading a large `varbinary(max)` value)
+    pub fn on_upload_progress<F: Fn(usize, usize) + Send + 'static>(mut self, handler: F) -> ConnectionOptBuilder<'a, S> {
+        self.on_upload_progress = Some(ProgressHandler(Box::new(handler)));
+        self
+    }
+
+    /// open a stream to `(server, port)` when the server redirects the login (ROUTING), as Azure SQL Database does
+    /// connections created by `TcpConnectionBuilder` or from a connection string reconnect using TCP
+    pub fn on_redirect<F: Fn(&str, u16) -> TdsResult<S> + Send + 'static>(mut self, connect: F) -> ConnectionOptBuilder<'a, S> {
+        self.redirect = Some(Redirect(Box::new(move |server, port| Ok((try!(connect(server, port)), None)))));
+        self
+    }
+
+    /// retry connecting up to `count` times if it fails with a transient error (e.g. the connection
+    /// is refused while the server is starting), but not on errors of the login (e.g. a wrong password)
+    ///
+    /// each retry opens a new stream using the callback of `on_reconnect`,
+    /// connections created by `TcpConnectionBuilder` or from a connection string reconnect using TCP
+    /// (opening the first stream is not retried, e.g. `TcpConnectionBuilder::new_connect` fails if it is refused)
+    pub fn connect_retries(mut self, count: u32) -> ConnectionOptBuilder<'a, S> {
+        self.connect_retries = count;
+        self
+    }
+
+    /// the time to wait before the first retry of connecting, which doubles for every further retry (500ms by default)
+    pub fn connect_backoff(mut self, backoff: Duration) -> ConnectionOptBuilder<'a, S> {
+        self.connect_backoff = backoff;
+        self
+    }
+
+    /// open a new stream to the server when retrying to connect (see `connect_retries`)
+    pub fn on_reconnect<F: Fn() -> TdsResult<S> + Send + 'static>(mut self, connect: F) -> ConnectionOptBuilder<'a, S> {
+        self.reconnect = Some(Reconnect(Box::new(move || Ok((try!(connect()), None)))));
+        self
+    }
+
     pub fn build(self) -> ConnectionOptions<'a, S> {
         ConnectionOptions {
             auth: self.auth.unwrap(),
-            database: self.database.unwrap(),
+            database: self.database,
+            language: self.language,
+            login_flags: self.login_flags,
+            login_features: self.login_features,
+            packet_size: self.packet_size,
+            strict_binding: self.strict_binding,
+            empty_strings_as_null: self.empty_strings_as_null,
+            connect_timeout: self.connect_timeout,
+            statement_cache_size: self.statement_cache_size,
+            on_message: self.on_message,
+            on_upload_progress: self.on_upload_progress,
+            redirect: self.redirect,
+            connect_retries: self.connect_retries,
+            connect_backoff: self.connect_backoff,
+            reconnect: self.reconnect,
+            socket: self.socket,
             stream: self.stream,
         }
     }
@@ -72,7 +447,27 @@ impl<'a, S: 'a + TargetStream> ConnectionOptBuilder<'a, S> {
 #[derive(Debug)]
 pub struct ConnectionOptions<'a, S: 'a + TargetStream> {
     pub auth: AuthenticationMethod<'a>,
-    pub database: Cow<'a, str>,
+    /// the database to use after the login, `None` uses the default database of the login
+    pub database: Option<Cow<'a, str>>,
+    /// the language of the session, `None` uses the default language of the login
+    pub language: Option<Cow<'a, str>>,
+    pub login_flags: Vec<LoginFlag>,
+    pub login_features: Vec<LoginFeature>,
+    /// the requested packet size
+    pub packet_size: u16,
+    pub strict_binding: bool,
+    pub empty_strings_as_null: bool,
+    pub connect_timeout: Option<Duration>,
+    /// the number of prepared statements kept per connection (0 disables the cache)
+    pub statement_cache_size: usize,
+    on_message: Option<MessageHandler>,
+    on_upload_progress: Option<ProgressHandler>,
+    redirect: Option<Redirect<S>>,
+    /// the number of retries of connecting on a transient error
+    pub connect_retries: u32,
+    pub connect_backoff: Duration,
+    reconnect: Option<Reconnect<S>>,
+    socket: Option<TcpStream>,
     pub stream: S,
 }
 
@@ -90,7 +485,11 @@ impl<'a, S: 'a + TargetStream> IntoConnectOpts<'a, S> for ConnectionOptions<'a,
 /// as specified in "ODBC Driver Connection String Keywords"
 /// https://msdn.microsoft.com/de-de/library/ms130822(v=sql.120).aspx
 ///
-/// supported options: Server, Database, UID, PWD
+/// supported options: Server, Database, UID, PWD, MultipleActiveResultSets (only `false`),
+/// Login Timeout (or Connect Timeout, in seconds, 0 waits forever) bounding the TCP connect and the handshake,
+/// Failover Partner which is connected to if connecting to the server fails
+///
+/// servers are given as `host:port` or as `tcp:host,port` (e.g. by Azure)
 ///
 /// a sample connection string could be something like:
 /// `Server=localhost;Database=testdb;UID=test;PWD=1234`
@@ -115,7 +514,9 @@ impl <'a> IntoConnectOpts<'a, Box<TargetStream>> for &'a str {
             auth_method: None,
             db: None
         };
-        let mut builder = None;
+        let mut server = None;
+        let mut failover_partner = None;
+        let mut login_timeout = None;
 
         for opt in self.split(";") {
             let parts: Vec<&str> = opt.splitn(2, "=").collect();
@@ -134,47 +535,418 @@ impl <'a> IntoConnectOpts<'a, Box<TargetStream>> for &'a str {
                     }
                 },
                 "database" => ctxt.db = Some(Cow::Borrowed(parts[1])),
-                "server" => {
-                    let stream = try!(TcpStream::connect(parts[1]));
-                    builder = Some(ConnectionOptBuilder::new(Box::new(stream) as Box<TargetStream>));
+                // MARS requires framing every request in SMP, which is not implemented
+                "multipleactiveresultsets" => match &parts[1].to_lowercase()[..] {
+                    "false" | "no" => (),
+                    "true" | "yes" => return Err(TdsError::Other("MultipleActiveResultSets is not supported".to_owned())),
+                    x => return Err(TdsError::Other(format!("invalid value {} for MultipleActiveResultSets", x)))
+                },
+                "login timeout" | "connect timeout" => match parts[1].trim().parse::<u64>() {
+                    Ok(0) => login_timeout = None,
+                    Ok(secs) => login_timeout = Some(Duration::from_secs(secs)),
+                    Err(_) => return Err(TdsError::Other(format!("invalid value {} for {}", parts[1], parts[0])))
                 },
+                // connected to once all options are known, the login timeout may follow the server
+                "server" => server = Some(parts[1]),
+                "failover partner" => failover_partner = Some(parts[1]),
                 _ => panic!("TODO! unknown parameter {}", parts[0])
             }
         }
-        if let Some(x) = builder {
-            return Ok(apply_opts(Box::new(ctxt), x))
+        let server = match server {
+            Some(server) => server,
+            None => return Err(TdsError::Other("server not specified".to_owned()))
+        };
+        let stream = match connect_server(server, login_timeout) {
+            Ok(stream) => stream,
+            Err(err) => match failover_partner {
+                Some(partner) => try!(connect_server(partner, login_timeout)),
+                None => return Err(err)
+            }
+        };
+        let socket = try!(stream.try_clone());
+        let reconnect = try!(tcp_reconnect(&stream, |stream| Box::new(stream) as Box<TargetStream>));
+        let mut opts_builder = ConnectionOptBuilder::new(Box::new(stream) as Box<TargetStream>);
+        opts_builder.socket = Some(socket);
+        opts_builder.redirect = Some(tcp_redirect(|stream| Box::new(stream) as Box<TargetStream>));
+        opts_builder.reconnect = Some(reconnect);
+        if let Some(timeout) = login_timeout {
+            opts_builder = opts_builder.login_timeout(timeout);
         }
-        Err(TdsError::Other("server not specified".to_owned()))
+        Ok(apply_opts(Box::new(ctxt), opts_builder))
+    }
+}
+
+/// connect to a server of a connection string, given as `host:port` or `tcp:host,port`
+fn connect_server(server: &str, timeout: Option<Duration>) -> TdsResult<TcpStream> {
+    let server = server.trim();
+    let server = match server.get(..4) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("tcp:") => &server[4..],
+        _ => server
+    };
+    let addr = server.replace(",", ":");
+    match timeout {
+        Some(timeout) => tcp_connect_timeout(&addr[..], timeout),
+        None => Ok(try!(TcpStream::connect(&addr[..])))
+    }
+}
+
+/// bracket-quote an identifier such as a database name, doubling internal `]`
+/// e.g. `My DB` becomes `[My DB]`
+pub fn quote_identifier(name: &str) -> String {
+    format!("[{}]", name.replace("]", "]]"))
+}
+
+/// the login packet expects the plain name, so strip the quotes off an already quoted identifier
+fn unquote_identifier<'a>(name: &Cow<'a, str>) -> Cow<'a, str> {
+    if name.len() >= 2 && name.starts_with("[") && name.ends_with("]") {
+        Cow::Owned(name[1..name.len() - 1].replace("]]", "]"))
+    } else {
+        name.clone()
     }
 }
 
 // manual impl since autoderef seemed to mess up when cloning
 impl<'a, S: 'a + TargetStream> Connection<'a, S> {
-    pub fn clone(&'a self) -> Connection<'a, S> {
+    pub fn clone(&self) -> Connection<'a, S> {
         Connection(self.0.clone())
     }
 }
 
 impl<'c, S: 'c + TargetStream> Connection<'c, S> {
+    /// The TDS version negotiated with the server, e.g. 0x730B0003 for TDS 7.3B
+    pub fn tds_version(&self) -> u32 {
+        self.lock().unwrap().tds_version
+    }
+
+    /// The product name of the server, e.g. "Microsoft SQL Server"
+    pub fn server_name(&self) -> String {
+        self.lock().unwrap().server_name.clone()
+    }
+
+    /// The version of the server as (major, minor, build), e.g. (13, 0, 4001) for SQL Server 2016 SP1
+    pub fn server_version(&self) -> (u8, u8, u16) {
+        self.lock().unwrap().server_version
+    }
+
+    /// The data the server acknowledged a feature of the login with, `None` if it was not acknowledged
+    pub fn feature_ack(&self, feature: LoginFeature) -> Option<Vec<u8>> {
+        self.lock().unwrap().features.iter().find(|&&(id, _)| id == feature.id()).map(|&(_, ref data)| data.clone())
+    }
+
+    /// Whether the server supports UTF-8 collations, requires `LoginFeature::Utf8Support`
+    pub fn utf8_support(&self) -> bool {
+        self.feature_ack(LoginFeature::Utf8Support).map_or(false, |data| data.first().map_or(false, |x| x & 0x01 != 0))
+    }
+
+    /// The DTC token (the payload of the promote transaction ENVCHANGE) of the last transaction
+    /// the server promoted to a distributed transaction, which is required to enlist in it
+    pub fn promoted_transaction(&self) -> Option<Vec<u8>> {
+        self.lock().unwrap().promoted_transaction.clone()
+    }
+
+    /// The name of the current database (e.g. the default database of the login or set by `use_database`)
+    pub fn current_database(&self) -> Option<String> {
+        self.lock().unwrap().database.clone()
+    }
+
+    /// Send a packet as is, bypassing the state of the connection (e.g. to prototype a request which is not supported yet)
+    ///
+    /// the caller has to read the whole response with `recv_raw` before issuing any other request,
+    /// and a packet the server does not expect in the current state may abort the connection
+    #[cfg(feature = "raw")]
+    pub fn send_raw(&self, packet: Packet) -> TdsResult<()> {
+        let mut conn = self.lock().unwrap();
+        conn.raw_prelogin = match packet {
+            Packet::PreLogin(_) => true,
+            _ => false
+        };
+        conn.send_packet(&packet)
+    }
+
+    /// Read the response to a packet sent with `send_raw`: a prelogin response for a prelogin packet,
+    /// else the tokens of the response, which are applied to the connection (e.g. an ENVCHANGE)
+    /// like those of any other request
+    #[cfg(feature = "raw")]
+    pub fn recv_raw<'a>(&self) -> TdsResult<Packet<'a>> {
+        let mut conn = self.lock().unwrap();
+        let message = try!(conn.opts.stream.read_message());
+        if conn.raw_prelogin {
+            return message.into_prelogin()
+        }
+        let packet = try!(message.into_general_token_stream());
+        conn.process_tokens(&packet);
+        Ok(packet)
+    }
+
+    /// The descriptor the server assigned to the current local transaction (sent in the
+    /// ALL_HEADERS of each request), `None` outside of a transaction
+    pub fn current_transaction_descriptor(&self) -> Option<u64> {
+        match self.lock().unwrap().transaction_descriptor {
+            0 => None,
+            descriptor => Some(descriptor),
+        }
+    }
+
     /// Execute the given query and return the resulting rows
-    pub fn query<L>(&'c self, sql: L) -> TdsResult<QueryResult> where L: Into<Cow<'c, str>> {
+    pub fn query<L>(&self, sql: L) -> TdsResult<QueryResult<'c>> where L: Into<Cow<'c, str>> {
         let stmt = StatementInternal::new(self.clone(), sql.into());
         Ok(try!(stmt.execute_into_query()))
     }
 
-    /// Execute a sql statement and return the number of affected rows
-    pub fn exec<L>(&'c self, sql: L) -> TdsResult<usize> where L: Into<Cow<'c, str>> {
+    /// like `query`, but the result owns its values and does not borrow from the connection
+    pub fn query_owned<L>(&self, sql: L) -> TdsResult<QueryResult<'static>> where L: Into<Cow<'c, str>> {
+        Ok(try!(self.query(sql)).into_owned())
+    }
+
+    /// Execute the given query and return the first resulting row, if any
+    pub fn query_row<L>(&self, sql: L) -> TdsResult<Option<Row<'c>>> where L: Into<Cow<'c, str>> {
+        match try!(self.query(sql)).try_iter().next() {
+            Some(row) => row.map(Some),
+            None => Ok(None)
+        }
+    }
+
+    /// Execute the given query and convert the first column of the first row, if any
+    /// e.g. for `SELECT COUNT(*)`, use `Option<T>` as `T` if the value may be NULL
+    pub fn query_scalar<T, L>(&self, sql: L) -> TdsResult<Option<T>> where L: Into<Cow<'c, str>>, T: for<'r> FromColumnValue<'r> {
+        match try!(self.query_row(sql)) {
+            None => Ok(None),
+            Some(row) => {
+                let values = row.into_values();
+                match values.first() {
+                    Some(val) => Ok(Some(try!(T::from_column_value(val)))),
+                    None => Err(TdsError::Other("query_scalar: the row does not contain any column".to_owned()))
+                }
+            }
+        }
+    }
+
+    /// Like `query` but `cancel` is polled while waiting for the response
+    /// once it returns true the query is cancelled and `TdsError::Cancelled` is returned
+    pub fn query_cancellable<L, F>(&self, sql: L, cancel: F) -> TdsResult<QueryResult<'c>> where L: Into<Cow<'c, str>>, F: Fn() -> bool {
+        let stmt = StatementInternal::new(self.clone(), sql.into());
+        Ok(try!(stmt.execute_into_query_cancellable(&cancel)))
+    }
+
+    /// Like `query_cancellable`, cancelling the query once `token` is signalled,
+    /// the connection can be used again after `TdsError::Cancelled` was returned
+    pub fn query_cancelable<L>(&self, sql: L, token: &CancellationToken) -> TdsResult<QueryResult<'c>> where L: Into<Cow<'c, str>> {
+        self.query_cancellable(sql, || token.is_cancelled())
+    }
+
+    /// Execute the given query and call `f` for each row as soon as the packet containing it is read,
+    /// so only the rows of one packet are buffered at a time, if `f` returns an error the query is cancelled
+    /// and the error is returned
+    ///
+    /// `f` runs without the connection locked, but the connection cannot send another request until the
+    /// rows are read (it fails instead), a panic in `f` cancels the query before it is resumed
+    pub fn query_each<L, F>(&self, sql: L, f: F) -> TdsResult<()> where L: Into<Cow<'c, str>>, F: FnMut(&Row) -> TdsResult<()> {
+        let stmt = StatementInternal::new(self.clone(), sql.into());
+        stmt.execute_each(f)
+    }
+
+    /// Execute a sql statement and return the number of affected rows,
+    /// this is 0 for statements which do not report a count (e.g. `TRUNCATE TABLE` or DDL)
+    pub fn exec<L>(&self, sql: L) -> TdsResult<usize> where L: Into<Cow<'c, str>> {
         let mut stmt = StatementInternal::new(self.clone(), sql.into());
         Ok(try!(stmt.execute()))
     }
 
-    pub fn prepare<L>(&'c self, sql: L) -> TdsResult<PreparedStatement<'c, S>> where L: Into<Cow<'c, str>> {
+    /// Execute a sql batch and return the status (e.g. the affected rows) of each of its statements
+    pub fn exec_statuses<L>(&self, sql: L) -> TdsResult<Vec<DoneStatus>> where L: Into<Cow<'c, str>> {
+        let mut stmt = StatementInternal::new(self.clone(), sql.into());
+        Ok(try!(stmt.execute_statuses()))
+    }
+
+    /// Execute multiple statements in a single batch and return the number of affected rows of each,
+    /// statements which do not report a count yield 0 and a procedure call yields the rows of its statements
+    ///
+    /// a failing statement aborts the batch with `TdsError::BatchError`, the index of the statement
+    /// and the counts of the statements before it
+    pub fn exec_batch(&self, statements: &[&str]) -> TdsResult<Vec<usize>> {
+        let statements: Vec<&str> = statements.iter().map(|x| x.trim().trim_matches(';')).collect();
+        let mut stmt = StatementInternal::new(self.clone(), Cow::Owned(statements.join(";\n")));
+        match try!(stmt.execute_batch()) {
+            (counts, None) => Ok(counts),
+            (counts, Some(err)) => {
+                // find the statement by the line of the error within the batch, an error raised
+                // within a procedure carries the line within the procedure instead
+                let mut next_line = 1;
+                let idx = if err.proc_name.is_empty() {
+                    statements.iter().position(|x| {
+                        next_line += x.matches('\n').count() + 1;
+                        next_line > err.line_number as usize
+                    })
+                } else {
+                    None
+                };
+                // otherwise the statement after the last completed one
+                Err(TdsError::BatchError(idx.unwrap_or(counts.len()), counts, err))
+            }
+        }
+    }
+
+    /// Execute a sql statement and return the number of affected rows,
+    /// or `None` if no statement of the batch reported a count (e.g. `TRUNCATE TABLE` or DDL)
+    pub fn exec_count<L>(&self, sql: L) -> TdsResult<Option<usize>> where L: Into<Cow<'c, str>> {
+        let statuses = try!(self.exec_statuses(sql));
+        Ok(statuses.iter().fold(None, |sum, x| match x.row_count {
+            Some(count) => Some(sum.unwrap_or(0) + count as usize),
+            None => sum,
+        }))
+    }
+
+    /// Execute a sql statement with an `OUTPUT` clause (e.g. `MERGE ... OUTPUT $action, inserted.*`)
+    /// and return the number of affected rows together with the output rows
+    pub fn exec_returning<L>(&self, sql: L) -> TdsResult<(usize, QueryResult<'c>)> where L: Into<Cow<'c, str>> {
+        let stmt = StatementInternal::new(self.clone(), sql.into());
+        Ok(try!(stmt.execute_returning()))
+    }
+
+    /// Call a stored procedure and return its return status and `OUTPUT` parameters
+    pub fn call_proc<L>(&self, name: L, params: &[Param]) -> TdsResult<ProcResult<'c>> where L: Into<Cow<'c, str>> {
+        let stmt = StatementInternal::new(self.clone(), name.into());
+        Ok(try!(stmt.execute_proc(params)))
+    }
+
+    /// Check that the connection is still alive by running a trivial query,
+    /// e.g. to validate an idle connection of a pool before handing it out
+    pub fn ping(&self) -> TdsResult<()> {
+        match try!(self.query_scalar::<i32, _>("SELECT 1;")) {
+            Some(1) => Ok(()),
+            x => Err(TdsError::Other(format!("ping: unexpected result {:?}", x)))
+        }
+    }
+
+    /// The current time of the server in UTC (`SYSUTCDATETIME()`), e.g. to detect a clock skew
+    pub fn server_utc_now(&self) -> TdsResult<DateTime<UTC>> {
+        match try!(self.query_scalar("SELECT SYSUTCDATETIME();")) {
+            Some(x) => Ok(x),
+            None => Err(TdsError::Other("server_utc_now: no result".to_owned()))
+        }
+    }
+
+    /// The SET options of this session (e.g. to diagnose "incorrect SET options" errors)
+    pub fn session_options(&self) -> TdsResult<SessionOptions> {
+        let row = match try!(self.query_row("SELECT quoted_identifier, arithabort, ansi_nulls, ansi_null_dflt_on, ansi_padding, ansi_warnings, \
+            concat_null_yields_null, transaction_isolation_level, lock_timeout FROM sys.dm_exec_sessions WHERE session_id = @@SPID;")) {
+            Some(row) => row,
+            None => return Err(TdsError::Other("session_options: the session was not found".to_owned()))
+        };
+        Ok(SessionOptions {
+            quoted_identifier: try!(row.try_get("quoted_identifier")),
+            arithabort: try!(row.try_get("arithabort")),
+            ansi_nulls: try!(row.try_get("ansi_nulls")),
+            ansi_null_dflt_on: try!(row.try_get("ansi_null_dflt_on")),
+            ansi_padding: try!(row.try_get("ansi_padding")),
+            ansi_warnings: try!(row.try_get("ansi_warnings")),
+            concat_null_yields_null: try!(row.try_get("concat_null_yields_null")),
+            transaction_isolation_level: try!(row.try_get("transaction_isolation_level")),
+            lock_timeout: try!(row.try_get("lock_timeout")),
+        })
+    }
+
+    /// The columns the query would return, without executing it (`SET FMTONLY ON`)
+    /// e.g. to learn the shape of a query with side effects
+    pub fn describe(&self, sql: &str) -> TdsResult<Vec<ColumnInfo>> {
+        let result = self.query(format!("SET FMTONLY ON; {}; SET FMTONLY OFF;", sql));
+        if result.is_err() {
+            // the batch may have been aborted before FMTONLY was reset
+            let _ = self.exec("SET FMTONLY OFF;");
+        }
+        let result = try!(result);
+        let columns = result.columns();
+        Ok(columns.iter().map(ColumnInfo::from).collect())
+    }
+
+    /// Switch the database of the connection, the name is quoted as required
+    /// (a name which is not a single quoted identifier, e.g. `[a]; DROP ...`, is quoted as a whole)
+    pub fn use_database(&self, db: &str) -> TdsResult<()> {
+        let quoted = db.starts_with("[") && quote_identifier(&unquote_identifier(&Cow::Borrowed(db))) == db;
+        let name = if quoted { Cow::Borrowed(db) } else { Cow::Owned(quote_identifier(db)) };
+        try!(self.exec(format!("USE {};", name)));
+        Ok(())
+    }
+
+    /// Fail statements waiting longer than `ms` milliseconds for a lock with `TdsError::LockTimeout`
+    /// (`SET LOCK_TIMEOUT`), -1 waits forever and 0 does not wait at all
+    pub fn set_lock_timeout(&self, ms: i32) -> TdsResult<()> {
+        try!(self.exec(format!("SET LOCK_TIMEOUT {};", ms)));
+        Ok(())
+    }
+
+    /// Change a session setting using `SET <option> <value>`, e.g. `set_option("DATEFORMAT", "dmy")`
+    /// a value which is not a single word or number (e.g. a language containing a space) is sent as a string literal
+    pub fn set_option(&self, option: &str, value: &str) -> TdsResult<()> {
+        let is_word = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_alphanumeric() || c == '_');
+        if !is_word(option) {
+            return Err(TdsError::Other(format!("set_option: invalid option {:?}", option)))
+        }
+        let value = if is_word(value.trim_start_matches('-')) {
+            Cow::Borrowed(value)
+        } else {
+            Cow::Owned(format!("N'{}'", value.replace("'", "''")))
+        };
+        try!(self.exec(format!("SET {} {};", option, value)));
+        Ok(())
+    }
+
+    /// Create a statement executed with `sp_executesql`, declaring the parameters `@P1, @P2, ...`
+    /// with the types of the values bound to it
+    pub fn parameterized_auto<L>(&self, sql: L) -> TdsResult<ParameterizedStatement<'c, S>> where L: Into<Cow<'c, str>> {
+        Ok(try!(ParameterizedStatement::new(self.clone(), sql.into())))
+    }
+
+    /// Execute the given query and map each row to a `T`
+    pub fn query_as<T: FromRow, L>(&self, sql: L) -> TdsResult<Vec<T>> where L: Into<Cow<'c, str>> {
+        let rows = try!(self.query(sql));
+        rows.try_iter().map(|row| row.and_then(|row| T::from_row(&row))).collect()
+    }
+
+    /// Like `query_as`, for a query with the parameters `@P1`, `@P2`, ... (see `parameterized_auto`)
+    pub fn query_as_params<T: FromRow, L>(&self, sql: L, params: &[&ToColumnType]) -> TdsResult<Vec<T>> where L: Into<Cow<'c, str>> {
+        let stmt = try!(self.parameterized_auto(sql));
+        let rows = try!(stmt.query(params));
+        rows.try_iter().map(|row| row.and_then(|row| T::from_row(&row))).collect()
+    }
+
+    /// Begin a transaction, which is rolled back unless it is committed
+    pub fn begin_transaction(&self) -> TdsResult<Transaction<'c, S>> {
+        Transaction::new(self.clone())
+    }
+
+    /// Run `f` in a transaction, which is committed if `f` returns `Ok`
+    /// and rolled back if it returns `Err` or panics (the panic is resumed after the rollback)
+    pub fn transaction<T, F>(&self, f: F) -> TdsResult<T> where F: FnOnce(&Transaction<'c, S>) -> TdsResult<T> {
+        let tx = try!(self.begin_transaction());
+        match panic::catch_unwind(AssertUnwindSafe(|| f(&tx))) {
+            Ok(Ok(x)) => {
+                try!(tx.commit());
+                Ok(x)
+            },
+            Ok(Err(err)) => {
+                try!(tx.rollback());
+                Err(err)
+            },
+            Err(cause) => {
+                let _ = tx.rollback();
+                panic::resume_unwind(cause)
+            }
+        }
+    }
+
+    /// whether the login was redirected (ROUTING) to another server, which the connection is connected to
+    pub fn was_redirected(&self) -> bool {
+        self.lock().unwrap().redirected
+    }
+
+    pub fn prepare<L>(&self, sql: L) -> TdsResult<PreparedStatement<'c, S>> where L: Into<Cow<'c, str>> {
         Ok(try!(PreparedStatement::new(self.clone(), sql.into())))
     }
 }
 
 impl<'a, S: 'a + TargetStream> Deref for Connection<'a, S> {
-    type Target = Rc<RefCell<InternalConnection<'a, S>>>;
+    type Target = Arc<Mutex<InternalConnection<'a, S>>>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -185,19 +957,97 @@ impl<'a, S: 'a + TargetStream> Connection<'a, S> {
     pub fn connect<T: IntoConnectOpts<'a, S>>(opts: T) -> TdsResult<Connection<'a, S>> {
         let opts = try!(opts.into_connect_opts());
         let mut conn = InternalConnection::new(opts);
-        try!(conn.initialize());
-        Ok(Connection(Rc::new(RefCell::new(conn))))
+        let mut attempt = 0;
+        loop {
+            let ret = if attempt == 0 {
+                conn.initialize()
+            } else {
+                match conn.reconnect() {
+                    Ok(()) => conn.initialize(),
+                    Err(err) => Err(err)
+                }
+            };
+            match ret {
+                Ok(()) => return Ok(Connection(Arc::new(Mutex::new(conn)))),
+                Err(ref err) if attempt < conn.opts.connect_retries && conn.opts.reconnect.is_some() && is_transient(err) => (),
+                Err(err) => return Err(err)
+            }
+            thread::sleep(conn.opts.connect_backoff * (1 << cmp::min(attempt, 16)));
+            attempt += 1;
+        }
     }
 }
 
+/// whether connecting might succeed when retrying, e.g. while the server is starting
+fn is_transient(err: &TdsError) -> bool {
+    match *err {
+        TdsError::IoError(ref err) => match err.kind() {
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::UnexpectedEof => true,
+            _ => false
+        },
+        TdsError::Timeout | TdsError::UnexpectedEOF => true,
+        _ => false
+    }
+}
+
+/// connect to the first of the addresses which accepts the connection within `timeout`
+fn tcp_connect_timeout<A: ToSocketAddrs>(addrs: A, timeout: Duration) -> TdsResult<TcpStream> {
+    let mut last_err = None;
+    for addr in try!(addrs.to_socket_addrs()) {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err)
+        }
+    }
+    Err(match last_err {
+        Some(err) => TdsError::from(err),
+        None => TdsError::Other("could not resolve any address".to_owned())
+    })
+}
+
 pub struct TcpConnectionBuilder;
 impl TcpConnectionBuilder {
     /// connects to the SQL server using the TCP protocol and returns get a config builder for the connection
     pub fn new_connect<'a, A: ToSocketAddrs>(addrs: A) -> TdsResult<ConnectionOptBuilder<'a, TcpStream>> {
-        Ok(ConnectionOptBuilder::new(try!(TcpStream::connect(addrs))))
+        TcpConnectionBuilder::from_stream(try!(TcpStream::connect(addrs)))
+    }
+
+    /// like `new_connect` but gives up connecting after `timeout`, which also bounds the handshake
+    pub fn new_connect_timeout<'a, A: ToSocketAddrs>(addrs: A, timeout: Duration) -> TdsResult<ConnectionOptBuilder<'a, TcpStream>> {
+        let stream = try!(tcp_connect_timeout(addrs, timeout));
+        Ok(try!(TcpConnectionBuilder::from_stream(stream)).connect_timeout(timeout))
+    }
+
+    fn from_stream<'a>(stream: TcpStream) -> TdsResult<ConnectionOptBuilder<'a, TcpStream>> {
+        let socket = try!(stream.try_clone());
+        let reconnect = try!(tcp_reconnect(&stream, |stream| stream));
+        let mut builder = ConnectionOptBuilder::new(stream);
+        builder.socket = Some(socket);
+        builder.redirect = Some(tcp_redirect(|stream| stream));
+        builder.reconnect = Some(reconnect);
+        Ok(builder)
     }
 }
 
+/// The TDS version requested in the login: TDS 7.3A (SQL Server 2008)
+pub const TDS_VERSION_REQUESTED: u32 = 0x730A0003;
+
+/// The TDS version requested in the login with a FeatureExt block: TDS 7.4 (SQL Server 2012)
+pub const TDS_VERSION_FEATURE_EXT: u32 = 0x74000004;
+
+/// whether the TDS version supports the date and time types introduced with TDS 7.3 (e.g. datetime2)
+#[inline]
+fn supports_tds73(tds_version: u32) -> bool {
+    tds_version >> 24 >= 0x73
+}
+
+/// a handle in the statement cache
+struct CachedStatement {
+    key: String,
+    handle: u32,
+    column_infos: Vec<ColumnData>,
+}
+
 /// Internal representation of a Internal Connection
 #[doc(hidden)]
 pub struct InternalConnection<'a, S: 'a + TargetStream> {
@@ -205,6 +1055,28 @@ pub struct InternalConnection<'a, S: 'a + TargetStream> {
     last_packet_id: u8,
     pub opts: ConnectionOptions<'a, S>,
     packet_size: u16,
+    /// the TDS version the server acknowledged in the login
+    tds_version: u32,
+    /// the product name and version (major, minor, build) of the server
+    server_name: String,
+    server_version: (u8, u8, u16),
+    /// the features the server acknowledged in the login as (FeatureId, FeatureAckData)
+    features: Vec<(u8, Vec<u8>)>,
+    /// the DTC token of the last transaction promoted to a distributed transaction
+    promoted_transaction: Option<Vec<u8>>,
+    /// the descriptor of the current local transaction (0 outside of a transaction)
+    transaction_descriptor: u64,
+    /// the current database, as announced by the server after the login and `USE`
+    database: Option<String>,
+    /// the prepared statements, the least recently used first
+    statement_cache: Vec<CachedStatement>,
+    /// whether the login was redirected to another server
+    redirected: bool,
+    /// whether the rows of `query_each` are still being read, the connection is not locked while its callback runs
+    pub reading_rows: bool,
+    /// whether the response to the last raw packet is a prelogin response
+    #[cfg(feature = "raw")]
+    raw_prelogin: bool,
 }
 
 impl<'c, S: 'c + TargetStream> InternalConnection<'c, S> {
@@ -212,8 +1084,20 @@ impl<'c, S: 'c + TargetStream> InternalConnection<'c, S> {
         InternalConnection {
             state: ClientState::Initial,
             last_packet_id: 0,
+            packet_size: opts.packet_size,
+            tds_version: TDS_VERSION_REQUESTED,
+            server_name: String::new(),
+            server_version: (0, 0, 0),
+            features: vec![],
+            promoted_transaction: None,
+            database: None,
+            transaction_descriptor: 0,
+            statement_cache: vec![],
+            redirected: false,
+            reading_rows: false,
+            #[cfg(feature = "raw")]
+            raw_prelogin: false,
             opts: opts,
-            packet_size: 0x1000,
         }
     }
 
@@ -224,8 +1108,38 @@ impl<'c, S: 'c + TargetStream> InternalConnection<'c, S> {
         id
     }
 
-    /// Send a prelogin packet with version number 9.0.0000 (>=TDS 7.3 ?), and US_SUBBUILD=0 (for MSSQL always 0)
+    /// perform the handshake, bounded by the connect timeout if one is configured
     fn initialize(&mut self) -> TdsResult<()> {
+        let deadline = self.opts.connect_timeout.map(|timeout| Instant::now() + timeout);
+        let ret = self.handshake(deadline);
+        // only the handshake is bounded, reset the socket to blocking without a timeout
+        if let Some(ref socket) = self.opts.socket {
+            if deadline.is_some() {
+                try!(socket.set_read_timeout(None));
+                try!(socket.set_write_timeout(None));
+            }
+        }
+        ret
+    }
+
+    /// apply the time left until `deadline` to the socket, or fail if there is none left
+    fn check_deadline(&self, deadline: Option<Instant>) -> TdsResult<()> {
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(TdsError::Timeout)
+            }
+            if let Some(ref socket) = self.opts.socket {
+                try!(socket.set_read_timeout(Some(deadline - now)));
+                try!(socket.set_write_timeout(Some(deadline - now)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a prelogin packet with version number 9.0.0000 (>=TDS 7.3 ?), and US_SUBBUILD=0 (for MSSQL always 0)
+    fn handshake(&mut self, deadline: Option<Instant>) -> TdsResult<()> {
+        try!(self.check_deadline(deadline));
         try!(self.send_packet(&Packet::PreLogin(vec![
             OptionTokenPair::Version(0x09000000, 0),
             OptionTokenPair::Encryption(EncryptionSetting::NotSupported),
@@ -234,29 +1148,60 @@ impl<'c, S: 'c + TargetStream> InternalConnection<'c, S> {
             OptionTokenPair::Mars(0)
         ])));
         {
+            try!(self.check_deadline(deadline));
             let response_packet = try!(self.read_packet());
             // TODO: move catch_error and tokenstream env change handling into one general "generic handle" func?
             try!(response_packet.catch_error());
         }
         self.state = ClientState::PreloginPerformed;
-        let mut login_packet = Login7::new(0x03000A73);
+        let tds_version = if self.opts.login_features.is_empty() { TDS_VERSION_REQUESTED } else { TDS_VERSION_FEATURE_EXT };
+        let mut login_packet = Login7::new(tds_version.swap_bytes());
+        self.features.clear();
         {
             login_packet.set_auth(&self.opts.auth);
-            login_packet.set_db(self.opts.database.clone());
+            if let Some(ref db) = self.opts.database {
+                login_packet.set_db(unquote_identifier(db));
+            }
+            if let Some(ref language) = self.opts.language {
+                login_packet.language = language.clone();
+            }
             login_packet.packet_size = self.packet_size as u32;
+            for flag in &self.opts.login_flags {
+                flag.apply(&mut login_packet);
+            }
+            for feature in &self.opts.login_features {
+                feature.apply(&mut login_packet);
+            }
         }
         let packet = Packet::Login(login_packet);
+        try!(self.check_deadline(deadline));
         try!(self.send_packet(&packet));
+        let mut routing = None;
         {
+            try!(self.check_deadline(deadline));
             let response_packet = try!(self.read_packet());
             try!(response_packet.catch_error());
             match response_packet {
                 Packet::TokenStream(tokens) => {
                     for token in tokens {
                         match token {
+                            TokenStream::EnvChange(TokenStreamEnvChange::Routing(server, port)) => {
+                                routing = Some((server, port));
+                            },
+                            TokenStream::EnvChange(TokenStreamEnvChange::Database(db, _)) => {
+                                self.database = Some(db);
+                            },
                             TokenStream::EnvChange(TokenStreamEnvChange::PacketSize(x, _)) => {
                                 self.packet_size = try!(x.parse::<u16>().map_err(|e| TdsError::Other(format!("cannot convert packet size: {:?}", e))));
                             },
+                            TokenStream::LoginAck(ack) => {
+                                self.tds_version = ack.tds_version;
+                                self.server_version = (ack.major_version, ack.minor_version, (ack.build_num_high as u16) << 8 | ack.build_num_low as u16);
+                                self.server_name = ack.prog_name;
+                            },
+                            TokenStream::FeatureExtAck(ack) => {
+                                self.features = ack.features;
+                            },
                             _ => ()
                         }
                     }
@@ -264,11 +1209,48 @@ impl<'c, S: 'c + TargetStream> InternalConnection<'c, S> {
                 _ => return Err(TdsError::Other("expected a envchange setting a packet size after the login".to_owned()))
             }
         }
+        if let Some((server, port)) = routing {
+            return self.redirect(&server, port, deadline)
+        }
         // TODO verify and use response data
         self.state = ClientState::Ready;
         Ok(())
     }
 
+    /// reconnect to the server the login was routed to and perform the handshake there
+    fn redirect(&mut self, server: &str, port: u16, deadline: Option<Instant>) -> TdsResult<()> {
+        if self.redirected {
+            return Err(TdsError::Other(format!("login: redirected to {}:{} again", server, port)))
+        }
+        let (stream, socket) = match self.opts.redirect {
+            Some(ref redirect) => try!((redirect.0)(server, port)),
+            None => return Err(TdsError::Other(format!("login: the server redirects to {}:{}, but the connection cannot reconnect", server, port)))
+        };
+        self.reset_stream(stream, socket);
+        self.redirected = true;
+        self.handshake(deadline)
+    }
+
+    /// open a new stream for another attempt to connect
+    fn reconnect(&mut self) -> TdsResult<()> {
+        let (stream, socket) = match self.opts.reconnect {
+            Some(ref reconnect) => try!((reconnect.0)()),
+            None => return Err(TdsError::Other("connect: the connection cannot reconnect".to_owned()))
+        };
+        self.reset_stream(stream, socket);
+        self.redirected = false;
+        Ok(())
+    }
+
+    /// replace the stream, the handshake has to be performed again
+    fn reset_stream(&mut self, stream: S, socket: Option<TcpStream>) {
+        self.opts.stream = stream;
+        self.opts.socket = socket;
+        self.state = ClientState::Initial;
+        self.last_packet_id = 0;
+        self.packet_size = self.opts.packet_size;
+    }
+
     #[inline]
     pub fn internal_exec(&mut self, sql: &str) -> TdsResult<()> {
         assert_eq!(self.state, ClientState::Ready);
@@ -276,6 +1258,186 @@ impl<'c, S: 'c + TargetStream> InternalConnection<'c, S> {
         Ok(())
     }
 
+    /// like `read_message` on the stream, but check `cancel` before each packet and while waiting for one
+    /// if it returns true, the request is cancelled using an attention and `TdsError::Cancelled` is returned
+    pub fn read_message_cancellable(&mut self, cancel: &Fn() -> bool) -> TdsResult<RawPacket> {
+        let mut header = PacketHeader::new();
+        let mut data = vec![];
+        loop {
+            if try!(self.wait_readable(cancel)) {
+                // the message read so far starts with its metadata
+                try!(self.cancel_request(&data, &mut StatementInfo::new()));
+                return Err(TdsError::Cancelled)
+            }
+            let packet = try!(self.opts.stream.read_packet());
+            data.extend(packet.data);
+            if packet.header.status != PacketStatus::NormalMessage {
+                header.ptype = packet.header.ptype;
+                header.status = packet.header.status;
+                break
+            }
+        }
+        Ok(RawPacket { header: header, data: data })
+    }
+
+    /// wait until the next packet can be read, returns true if `cancel` signalled in the meantime
+    /// without access to the socket `cancel` can only be checked once before reading
+    fn wait_readable(&self, cancel: &Fn() -> bool) -> TdsResult<bool> {
+        let socket = match self.opts.socket {
+            Some(ref socket) => socket,
+            None => return Ok(cancel())
+        };
+        // peeking with a timeout does not consume anything, so we can check `cancel` in between
+        try!(socket.set_read_timeout(Some(Duration::from_millis(100))));
+        let mut buf = [0u8; 1];
+        let ret = loop {
+            if cancel() {
+                break Ok(true)
+            }
+            match socket.peek(&mut buf) {
+                Ok(_) => break Ok(false),
+                Err(err) => match TdsError::from(err) {
+                    TdsError::Timeout => continue,
+                    err => break Err(err)
+                }
+            }
+        };
+        try!(socket.set_read_timeout(None));
+        ret
+    }
+
+    /// send an attention and skip the response until the server acknowledged it (2.2.1.7),
+    /// bounded by the connect timeout if one is configured
+    ///
+    /// `pending` is the part of the current message which was read but not decoded yet (starting at a token),
+    /// `stmt` holds the metadata of the rows it belongs to
+    pub fn cancel_request(&mut self, pending: &[u8], stmt: &mut StatementInfo) -> TdsResult<()> {
+        try!(self.send_packet(&Packet::Attention));
+        let deadline = self.opts.connect_timeout.map(|timeout| Instant::now() + timeout);
+        let ret = self.read_attention_ack(pending, stmt, deadline);
+        if let Some(ref socket) = self.opts.socket {
+            if deadline.is_some() {
+                try!(socket.set_read_timeout(None));
+                try!(socket.set_write_timeout(None));
+            }
+        }
+        ret
+    }
+
+    fn read_attention_ack(&mut self, pending: &[u8], stmt: &mut StatementInfo, deadline: Option<Instant>) -> TdsResult<()> {
+        let mut pending = pending.to_vec();
+        loop {
+            try!(self.check_deadline(deadline));
+            let mut message = try!(self.opts.stream.read_message());
+            if !pending.is_empty() {
+                pending.extend(message.data);
+                message.data = mem::replace(&mut pending, vec![]);
+            }
+            let packet = try!(message.into_stmt_token_stream(stmt));
+            self.process_tokens(&packet);
+            // the acknowledgement is a DONE token with the attention bit set
+            if let Packet::TokenStream(ref tokens) = packet {
+                let acknowledged = tokens.iter().any(|token| match *token {
+                    TokenStream::Done(ref done) => done.status & TokenStreamDoneStatus::Attn as u16 != 0,
+                    _ => false
+                });
+                if acknowledged {
+                    return Ok(())
+                }
+            }
+        }
+    }
+
+    /// read the response to a statement, passing its informational messages to the `on_message` handler
+    pub fn read_stmt_message<'a>(&mut self, stmt: &mut StatementInfo) -> TdsResult<Packet<'a>> {
+        let packet = try!(try!(self.opts.stream.read_message()).into_stmt_token_stream(stmt));
+        self.process_tokens(&packet);
+        Ok(packet)
+    }
+
+    /// read the response to a query, where a malformed row ends the rows instead of failing the response
+    pub fn read_query_message<'a>(&mut self, stmt: &mut StatementInfo) -> TdsResult<(Packet<'a>, Option<TdsError>)> {
+        let (packet, row_error) = try!(try!(self.opts.stream.read_message()).into_query_token_stream(stmt));
+        self.process_tokens(&packet);
+        Ok((packet, row_error))
+    }
+
+    /// pass informational messages to the `on_message` handler and track the environment changes of a response
+    pub fn process_tokens(&mut self, packet: &Packet) {
+        if let Packet::TokenStream(ref tokens) = *packet {
+            for token in tokens {
+                self.process_token(token);
+            }
+        }
+    }
+
+    pub fn process_token(&mut self, token: &TokenStream) {
+        match *token {
+            TokenStream::Info(ref msg) => {
+                if let Some(ref handler) = self.opts.on_message {
+                    (handler.0)(msg);
+                }
+            },
+            TokenStream::EnvChange(TokenStreamEnvChange::PromoteTransaction(ref dtc_token)) => {
+                self.promoted_transaction = Some(dtc_token.clone());
+            },
+            TokenStream::EnvChange(TokenStreamEnvChange::BeginTransaction(descriptor)) => {
+                self.transaction_descriptor = descriptor;
+            },
+            TokenStream::EnvChange(TokenStreamEnvChange::EndTransaction) => {
+                self.transaction_descriptor = 0;
+            },
+            TokenStream::EnvChange(TokenStreamEnvChange::Database(ref db, _)) => {
+                self.database = Some(db.clone());
+            },
+            _ => ()
+        }
+    }
+
+    /// look up the handle (and column metadata) of a cached prepared statement, marking it as most recently used
+    pub fn cached_statement(&mut self, key: &str) -> Option<(u32, Vec<ColumnData>)> {
+        match self.statement_cache.iter().position(|x| x.key == key) {
+            Some(pos) => {
+                let entry = self.statement_cache.remove(pos);
+                let cached = (entry.handle, entry.column_infos.clone());
+                self.statement_cache.push(entry);
+                Some(cached)
+            },
+            None => None
+        }
+    }
+
+    /// add a prepared statement to the cache, unpreparing the least recently used ones exceeding its size
+    pub fn cache_statement(&mut self, key: String, handle: u32, column_infos: Vec<ColumnData>) -> TdsResult<()> {
+        self.statement_cache.push(CachedStatement {
+            key: key,
+            handle: handle,
+            column_infos: column_infos,
+        });
+        while self.statement_cache.len() > self.opts.statement_cache_size {
+            let evicted = self.statement_cache.remove(0);
+            try!(self.unprepare(evicted.handle));
+        }
+        Ok(())
+    }
+
+    /// release a prepared statement (sp_unprepare)
+    fn unprepare(&mut self, handle: u32) -> TdsResult<()> {
+        let rpc_req = RpcRequestData {
+            proc_id: RpcProcIdValue::Id(RpcProcId::SpUnprepare),
+            flags: 0,
+            params: vec![RpcParamData {
+                name: Cow::Borrowed("handle"),
+                status_flags: 0,
+                value: ColumnType::I32(handle as i32),
+                is_null: false,
+            }],
+        };
+        try!(self.send_packet(&Packet::RpcRequest(&rpc_req)));
+        let packet = try!(self.read_stmt_message(&mut StatementInfo::new()));
+        packet.catch_error()
+    }
+
     /// read and parse "simple" packets
     pub fn read_packet<'a>(&mut self) -> TdsResult<Packet<'a>> {
         let packet = try!(self.opts.stream.read_message());
@@ -284,7 +1446,9 @@ impl<'c, S: 'c + TargetStream> InternalConnection<'c, S> {
                 try!(packet.into_prelogin())
             },
             ClientState::PreloginPerformed => {
-                try!(packet.into_general_token_stream())
+                let packet = try!(packet.into_general_token_stream());
+                self.process_tokens(&packet);
+                packet
             },
             ClientState::Ready => {
                 panic!("read_packet: cannot be used in ready state");
@@ -292,36 +1456,87 @@ impl<'c, S: 'c + TargetStream> InternalConnection<'c, S> {
         })
     }
 
+    /// ensure that the server's TDS version supports the types of the parameters
+    fn check_rpc_params(&self, params: &[RpcParamData]) -> TdsResult<()> {
+        if supports_tds73(self.tds_version) {
+            return Ok(())
+        }
+        for param in params {
+            match param.value {
+                ColumnType::Date(_) | ColumnType::Time(_) | ColumnType::Datetime(_) | ColumnType::DatetimeOffset(_, _) | ColumnType::Tvp(_) => {
+                    return Err(TdsError::Other(format!("rpc: parameter {} requires TDS 7.3, the server uses 0x{:x}", param.name, self.tds_version)))
+                },
+                _ => ()
+            }
+        }
+        Ok(())
+    }
+
     /// Convert a message-packet into a protocol-packet
     /// ensure that packets are sent properly, respecting the
     /// configured `max packet size` and allocate
     /// a packet-id for each sent packet
     pub fn send_packet(&mut self, packet: &Packet) -> TdsResult<()> {
-        let mut header = PacketHeader::new();
-        let mut packet = try!(self.opts.stream.build_packet(header, packet));
+        if self.reading_rows {
+            if let Packet::Attention = *packet {} else {
+                return Err(TdsError::Other("request: the connection is still reading the rows of query_each".to_owned()))
+            }
+        }
+        let mut report_progress = false;
+        if let Packet::RpcRequest(req) = *packet {
+            try!(self.check_rpc_params(&req.params));
+            report_progress = true;
+        }
+        // the login carries the (obfuscated) password
+        let sensitive = match *packet { Packet::Login(_) => true, _ => false };
+        let header = PacketHeader::new();
+        // requests within a transaction have to carry its descriptor, else they run outside of it
+        let mut packet = try!(self.opts.stream.build_packet(header, packet, self.transaction_descriptor));
+        let total = packet.data.len();
+        let mut sent = 0;
         // if we don't have to split the packet due to max packet size, sent it
-        if packet.header.length < self.packet_size {
-            header.id = self.alloc_id();
-            try!(self.opts.stream.write_packet(&mut packet));
+        // (the length of the header overflows for a request larger than 64 KB)
+        if total + packets::HEADER_SIZE as usize <= self.packet_size as usize {
+            packet.header.id = self.alloc_id();
+            let result = self.opts.stream.write_packet(&mut packet);
+            if sensitive {
+                wipe_vec(&mut packet.data);
+            }
+            try!(result);
+            if report_progress {
+                self.report_upload_progress(total, total);
+            }
             return Ok(())
         }
-        packet.header.status = PacketStatus::NormalMessage;
-        while !packet.data.is_empty() {
-            let next_data = if self.packet_size as usize > packet.data.len() + packets::HEADER_SIZE as usize {
-                    packet.header.status = PacketStatus::EndOfMessage;
-                    vec![]
-            } else {
-                let idx = (self.packet_size - packets::HEADER_SIZE) as usize;
-                let mut current = packet.data;
-                let next = current.split_off(idx);
-                packet.data = current;
-                next
-            };
+        // every packet is copied from the message into the same buffer, so both can be wiped as a whole
+        let chunk_size = (self.packet_size - packets::HEADER_SIZE) as usize;
+        let mut data = mem::replace(&mut packet.data, Vec::with_capacity(chunk_size));
+        let mut result = Ok(());
+        for (i, chunk) in data.chunks(chunk_size).enumerate() {
+            packet.header.status = if (i + 1) * chunk_size >= total { PacketStatus::EndOfMessage } else { PacketStatus::NormalMessage };
+            packet.data.clear();
+            packet.data.extend_from_slice(chunk);
             packet.header.id = self.alloc_id();
             packet.update_len();
-            try!(self.opts.stream.write_packet(&mut packet));
-            packet.data = next_data;
+            result = self.opts.stream.write_packet(&mut packet);
+            if result.is_err() {
+                break
+            }
+            sent += chunk.len();
+            if report_progress {
+                self.report_upload_progress(sent, total);
+            }
+        }
+        if sensitive {
+            wipe_vec(&mut packet.data);
+            wipe_vec(&mut data);
+        }
+        result
+    }
+
+    fn report_upload_progress(&self, sent: usize, total: usize) {
+        if let Some(ref handler) = self.opts.on_upload_progress {
+            (handler.0)(sent, total);
         }
-        Ok(())
     }
 }
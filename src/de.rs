@@ -0,0 +1,108 @@
+//! map the columns of a row to the fields of a type implementing `Deserialize` (feature `row`)
+use std::fmt;
+use std::vec;
+use chrono::TimeZone;
+use serde::de::{self, Deserializer, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use types::{ColumnType, ColumnValue};
+use ::TdsError;
+
+impl de::Error for TdsError {
+    fn custom<T: fmt::Display>(msg: T) -> TdsError {
+        TdsError::Other(format!("deserialize: {}", msg))
+    }
+}
+
+/// deserializes a row as a map of the column names to the column values
+pub struct RowDeserializer<'a> {
+    columns: vec::IntoIter<(String, &'a ColumnValue<'a>)>,
+    value: Option<&'a ColumnValue<'a>>,
+}
+
+impl<'a> RowDeserializer<'a> {
+    pub fn new(columns: Vec<(String, &'a ColumnValue<'a>)>) -> RowDeserializer<'a> {
+        RowDeserializer {
+            columns: columns.into_iter(),
+            value: None,
+        }
+    }
+}
+
+impl<'de, 'a: 'de> Deserializer<'de> for RowDeserializer<'a> {
+    type Error = TdsError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TdsError> {
+        visitor.visit_map(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf option unit
+        unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de, 'a: 'de> MapAccess<'de> for RowDeserializer<'a> {
+    type Error = TdsError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, TdsError> {
+        match self.columns.next() {
+            Some((name, value)) => {
+                self.value = Some(value);
+                seed.deserialize(name.into_deserializer()).map(Some)
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, TdsError> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(ValueDeserializer(value)),
+            None => Err(de::Error::custom("value requested before its column"))
+        }
+    }
+}
+
+/// deserializes a single column value, NULL is only accepted for `Option`
+struct ValueDeserializer<'a>(&'a ColumnValue<'a>);
+
+impl<'de, 'a: 'de> Deserializer<'de> for ValueDeserializer<'a> {
+    type Error = TdsError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TdsError> {
+        match *self.0 {
+            ColumnValue::None => visitor.visit_none(),
+            ColumnValue::Some(ref x) => match *x.base_value() {
+                ColumnType::Bool(x) => visitor.visit_bool(x),
+                // tinyint is unsigned
+                ColumnType::I8(x) => visitor.visit_u8(x as u8),
+                ColumnType::I16(x) => visitor.visit_i16(x),
+                ColumnType::I32(x) => visitor.visit_i32(x),
+                ColumnType::I64(x) => visitor.visit_i64(x),
+                ColumnType::F32(x) => visitor.visit_f32(x),
+                ColumnType::F64(x) => visitor.visit_f64(x),
+                ColumnType::String(ref x) => visitor.visit_borrowed_str(x),
+                ColumnType::Guid(ref x) => visitor.visit_string(x.as_str()),
+                ColumnType::Datetime(ref x) => visitor.visit_string(x.to_string()),
+                ColumnType::DatetimeOffset(ref x, ref offset) => visitor.visit_string(offset.from_utc_datetime(x).to_rfc3339()),
+                ColumnType::Date(ref x) => visitor.visit_string(x.to_string()),
+                ColumnType::Time(ref x) => visitor.visit_string(x.to_string()),
+                ColumnType::Binary(ref x) => visitor.visit_borrowed_bytes(x),
+                ColumnType::Money(x) => visitor.visit_f64(x as f64 / 10000.0),
+                ColumnType::Tvp(_) => Err(de::Error::custom("a table-valued parameter is not a column value")),
+                // the base value of a sql_variant is not a variant itself
+                ColumnType::Variant(_) => unreachable!(),
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TdsError> {
+        match *self.0 {
+            ColumnValue::None => visitor.visit_none(),
+            ColumnValue::Some(_) => visitor.visit_some(self),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf unit
+        unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
@@ -2,24 +2,37 @@ extern crate byteorder;
 extern crate chrono;
 extern crate encoding;
 extern crate net2;
+#[cfg(feature = "row")]
+#[macro_use]
+extern crate serde;
 
 use std::borrow::Cow;
 use std::convert::From;
 use std::error;
+use std::fmt;
 use std::io;
 
 mod protocol;
 mod conn;
 mod stmt;
 mod types;
+mod transaction;
+#[cfg(feature = "row")]
+mod de;
 pub use conn::*;
 pub use stmt::*;
 pub use types::*;
+pub use transaction::*;
+pub use protocol::{ColumnData, TypeInfo, FixedLenType, VarLenType, Collation};
+#[cfg(feature = "raw")]
+pub use protocol::{Packet, OptionTokenPair, EncryptionSetting, TokenStream};
 
 pub static LIB_NAME: &'static str = "tiberius";
 
 /// An error returned by the SQL-server
 pub type ServerError = protocol::TokenStreamError;
+/// An informational message (e.g. of `PRINT` or a warning) returned by the SQL-server
+pub type ServerMessage = protocol::TokenStreamError;
 
 #[derive(Debug)]
 pub enum TdsProtocolError {
@@ -31,18 +44,91 @@ pub enum TdsProtocolError {
 pub enum TdsError {
     ProtocolError(TdsProtocolError),
     UnexpectedEOF,
+    /// An error of the underlying stream, e.g. to check for `io::ErrorKind::ConnectionReset`
+    /// (a timeout of the socket is reported as `Timeout` instead)
     IoError(io::Error),
     /// An error returned by the SQL-server
     ServerError(ServerError),
     Other(String),
-    Conversion(Box<error::Error + Sync + Send>)
+    Conversion(Box<error::Error + Sync + Send>),
+    /// The operation did not complete in the configured time
+    Timeout,
+    /// The request was cancelled by the client
+    Cancelled,
+    /// A statement of `Connection::exec_batch` failed, with the index of the statement
+    /// and the affected rows of the statements executed before it
+    BatchError(usize, Vec<usize>, ServerError),
+    /// Waiting for a lock exceeded the lock timeout (server error 1222), see `Connection::set_lock_timeout`
+    LockTimeout(ServerError)
 }
 
 pub type TdsResult<T> = std::result::Result<T, TdsError>;
 
+impl fmt::Display for TdsProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TdsProtocolError::InvalidValue(ref msg, pos) => write!(f, "invalid value at position {}: {}", pos, msg),
+            TdsProtocolError::InvalidLength(ref msg) => write!(f, "invalid length: {}", msg),
+        }
+    }
+}
+
+impl error::Error for TdsProtocolError {
+    fn description(&self) -> &str {
+        "tds protocol error"
+    }
+}
+
+impl fmt::Display for TdsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TdsError::ProtocolError(ref err) => write!(f, "protocol error: {}", err),
+            TdsError::UnexpectedEOF => write!(f, "unexpected end of the stream"),
+            TdsError::ServerError(ref err) => write!(f, "server error {}: {}", err.code, err.message),
+            TdsError::Other(ref msg) => write!(f, "{}", msg),
+            TdsError::IoError(ref err) => write!(f, "{}", err),
+            TdsError::Conversion(ref err) => write!(f, "conversion: {}", err),
+            TdsError::Timeout => write!(f, "the operation timed out"),
+            TdsError::Cancelled => write!(f, "the request was cancelled"),
+            TdsError::BatchError(idx, _, ref err) => write!(f, "statement {} of the batch failed: server error {}: {}", idx, err.code, err.message),
+            TdsError::LockTimeout(ref err) => write!(f, "lock timeout: {}", err.message),
+        }
+    }
+}
+
+impl error::Error for TdsError {
+    fn description(&self) -> &str {
+        "tds error"
+    }
+
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            TdsError::ProtocolError(ref err) => Some(err),
+            TdsError::IoError(ref err) => Some(err),
+            TdsError::ServerError(ref err) | TdsError::BatchError(_, _, ref err) | TdsError::LockTimeout(ref err) => Some(err),
+            TdsError::Conversion(ref err) => Some(&**err),
+            _ => None
+        }
+    }
+}
+
 impl From<io::Error> for TdsError {
     fn from(err: io::Error) -> TdsError {
-        TdsError::IoError(err)
+        match err.kind() {
+            // depending on the platform a socket timeout is reported as either of those
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => TdsError::Timeout,
+            _ => TdsError::IoError(err)
+        }
+    }
+}
+
+impl From<ServerError> for TdsError {
+    fn from(err: ServerError) -> TdsError {
+        match err.code {
+            // lock request time out period exceeded
+            1222 => TdsError::LockTimeout(err),
+            _ => TdsError::ServerError(err)
+        }
     }
 }
 
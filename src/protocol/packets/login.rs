@@ -4,11 +4,9 @@ use std::io::prelude::*;
 use std::io::Cursor;
 use byteorder::{LittleEndian, BigEndian, WriteBytesExt};
 use chrono::{Offset, Local};
-use encoding::{Encoding, EncoderTrap};
-use encoding::all::UTF_16LE;
 use protocol::token_stream::WriteTokenStream;
-use protocol::util::WriteUtf16;
-use ::{LIB_NAME, TdsResult, AuthenticationMethod};
+use protocol::util::{WriteUtf16, wipe_vec};
+use ::{LIB_NAME, TdsResult, AuthenticationMethod, Password};
 
 macro_rules! write_login_offset {
     ($cursor:expr, $pos:expr, $len:expr) => (write_login_offset!($cursor, $pos, $len, $len));
@@ -43,7 +41,7 @@ pub struct Login7<'a>
     //OffsetLength
     pub hostname: Cow<'a, str>,
     pub username: Cow<'a, str>,
-    pub password: Cow<'a, str>,
+    pub password: Password<'a>,
     pub app_name: Cow<'a, str>,
     pub server_name: Cow<'a, str>,
     pub library_name: Cow<'a, str>,
@@ -52,7 +50,9 @@ pub struct Login7<'a>
     /// initial db
     pub default_db: Cow<'a, str>,
     /// unique client identifier created by using the NIC-Address/MAC
-    pub client_id: [u8; 6]
+    pub client_id: [u8; 6],
+    /// the FeatureExt block (>= TDS 7.4) as (FeatureId, FeatureData)
+    pub features: Vec<(u8, Vec<u8>)>,
 }
 
 impl<'a> Login7<'a> {
@@ -72,7 +72,7 @@ impl<'a> Login7<'a> {
             lcid: 0x00000409,
             hostname: Cow::Borrowed(""),
             username: Cow::Borrowed(""),
-            password: Cow::Borrowed(""),
+            password: Password::from(""),
             app_name: Cow::Borrowed(LIB_NAME),
             server_name: Cow::Borrowed(""),
             library_name: Cow::Borrowed(LIB_NAME),
@@ -80,6 +80,7 @@ impl<'a> Login7<'a> {
             default_db: Cow::Borrowed(""),
             // todo make this unique?
             client_id: [1, 2, 3, 4, 5, 6],
+            features: vec![],
         }
     }
 
@@ -101,7 +102,16 @@ impl<'a> Login7<'a> {
 
 impl<'a, W: Write> WriteTokenStream<&'a Login7<'a>> for W {
     fn write_token_stream(&mut self, login7: &'a Login7) -> TdsResult<()> {
-        let buf = vec![];
+        let data_start: u16 = 4 + 4 * 5 + 4 + 4 + 4 + (13 * 4) + 6;
+        let fields = [&*login7.hostname, &*login7.username, &*login7.password, &*login7.app_name, &*login7.server_name,
+            &*login7.library_name, &*login7.language, &*login7.default_db];
+        // allocate the whole packet upfront, growing it would leave copies of the password behind
+        let mut data_len: usize = fields.iter().map(|val| val.encode_utf16().count() * 2).sum();
+        if !login7.features.is_empty() {
+            // the offset of the block, each feature (id, length, data) and the terminator
+            data_len += 4 + login7.features.iter().map(|&(_, ref data)| 5 + data.len()).sum::<usize>() + 1;
+        }
+        let buf = Vec::with_capacity(data_start as usize + data_len);
         let mut cursor = Cursor::new(buf);
         let pos = cursor.position();
         // write the length at the end, skip 4 bytes for it (u32)
@@ -114,28 +124,29 @@ impl<'a, W: Write> WriteTokenStream<&'a Login7<'a>> for W {
         try!(cursor.write_u8(login7.flags1));
         try!(cursor.write_u8(login7.flags2));
         try!(cursor.write_u8(login7.type_flags));
-        try!(cursor.write_u8(login7.flags3));
+        // fExtension
+        try!(cursor.write_u8(if login7.features.is_empty() { login7.flags3 } else { login7.flags3 | 0x10 }));
         try!(cursor.write_i32::<LittleEndian>(login7.timezone));
         try!(cursor.write_u32::<LittleEndian>(login7.lcid)); //LE? unused anyways
-        let data_start: u16 = cursor.position() as u16 + (13 * 4) + 6;
+        assert_eq!(cursor.position() as u16 + (13 * 4) + 6, data_start);
         let mut data_pos = data_start;
+        let mut extension_pos = None;
 
-        for (i, val) in [&login7.hostname, &login7.username, &login7.password, &login7.app_name, &login7.server_name,
-            &login7.library_name, &login7.language, &login7.default_db].iter().enumerate() {
+        for (i, val) in fields.iter().enumerate() {
             let old_pos = cursor.position();
             cursor.set_position(data_pos as u64);
             //try!(cursor.write_cstr(val));
             let mut data_len = 0;
             if val.len() > 0 {
                 // encode password
+                // (directly into the packet, so the plain password is never copied)
                 if i == 2 {
-                    let mut bytes = try!(UTF_16LE.encode(val, EncoderTrap::Strict));
-                    for byte in &mut bytes {
-                        *byte = (*byte >> 4) | ((*byte & 0x0f) << 4);
-                        *byte ^= 0xa5;
+                    for unit in val.encode_utf16() {
+                        for &byte in &[unit as u8, (unit >> 8) as u8] {
+                            try!(cursor.write_u8(((byte >> 4) | ((byte & 0x0f) << 4)) ^ 0xa5));
+                            data_len += 1;
+                        }
                     }
-                    try!(cursor.write_all(&bytes));
-                    data_len = bytes.len() as u16;
                 } else {
                     data_len = try!(cursor.write_as_utf16(val)) as u16;
                 }
@@ -144,7 +155,13 @@ impl<'a, W: Write> WriteTokenStream<&'a Login7<'a>> for W {
             write_login_offset!(cursor, data_pos, val.len() as u16, data_len);      //1,2,3,4,6,7,8,9
 
             if i == 4 {
-                write_login_offset!(cursor, data_pos, 0);                           //5 [unused in TDSV7.3]
+                if login7.features.is_empty() {
+                    write_login_offset!(cursor, data_pos, 0);                       //5 [unused in TDSV7.3]
+                } else {
+                    // ibExtension points to the offset of the FeatureExt block, which follows all other data
+                    extension_pos = Some(data_pos);
+                    write_login_offset!(cursor, data_pos, 4);                       //5 [ibExtension & cbExtension]
+                }
             }
         }
         try!(cursor.write(&login7.client_id));                                      //client unique ID
@@ -155,10 +172,25 @@ impl<'a, W: Write> WriteTokenStream<&'a Login7<'a>> for W {
 
         // write remaining data
         assert_eq!(cursor.position() as u16, data_start);
+        if let Some(extension_pos) = extension_pos {
+            cursor.set_position(extension_pos as u64);
+            try!(cursor.write_u32::<LittleEndian>(data_pos as u32));
+            cursor.set_position(data_pos as u64);
+            for &(id, ref data) in &login7.features {
+                try!(cursor.write_u8(id));
+                try!(cursor.write_u32::<LittleEndian>(data.len() as u32));
+                try!(cursor.write_all(data));
+            }
+            try!(cursor.write_u8(0xFF));
+            data_pos = cursor.position() as u16;
+        }
         // write length
         cursor.set_position(0);
         try!(cursor.write_u32::<LittleEndian>(data_pos as u32));
-        try!(self.write_all(&cursor.into_inner()));
+        let mut buf = cursor.into_inner();
+        let result = self.write_all(&buf);
+        wipe_vec(&mut buf);
+        try!(result);
         Ok(())
     }
 }
\ No newline at end of file
@@ -12,6 +12,7 @@ pub use self::login::Login7;
 
 use protocol::util::{WriteUtf16, WriteCharStream};
 use protocol::token_stream::*;
+use protocol::types::write_rpc_value;
 use stmt::StatementInfo;
 use ::{TdsResult, TdsError, TdsProtocolError};
 
@@ -24,7 +25,7 @@ pub trait ReadPacket {
 }
 
 pub trait WritePacket {
-    fn build_packet(&self, header: PacketHeader, data: &Packet) -> TdsResult<RawPacket>;
+    fn build_packet(&self, header: PacketHeader, data: &Packet, transaction_descriptor: u64) -> TdsResult<RawPacket>;
     fn write_packet(&mut self, packet: &mut RawPacket) -> TdsResult<()>;
 }
 
@@ -41,9 +42,15 @@ fn handle_token_stream<'a, C: AsRef<[u8]>>(token_type: MessageTypeToken, cursor:
         MessageTypeToken::Error => {
             Ok(TokenStream::Error(try!(TokenStreamError::decode(cursor))))
         },
+        MessageTypeToken::Info => {
+            Ok(TokenStream::Info(try!(TokenStreamError::decode(cursor))))
+        },
         MessageTypeToken::LoginAck => {
             Ok(TokenStream::LoginAck(try!(TokenStreamLoginAck::decode(cursor))))
         },
+        MessageTypeToken::FeatureExtAck => {
+            Ok(TokenStream::FeatureExtAck(try!(TokenStreamFeatureExtAck::decode(cursor))))
+        },
         MessageTypeToken::EnvChange => {
             Ok(TokenStream::EnvChange(try!(TokenStreamEnvChange::decode(cursor))))
         },
@@ -71,6 +78,25 @@ fn handle_token_stream<'a, C: AsRef<[u8]>>(token_type: MessageTypeToken, cursor:
     }
 }
 
+/// decode the next token of the response to a statement
+/// an incomplete token (e.g. split across packets) fails to decode as well
+pub fn decode_stmt_token<'a, C: AsRef<[u8]>>(cursor: &mut Cursor<C>, stmt: &mut StatementInfo) -> TdsResult<TokenStream<'a>> {
+    let token_type = read_packet_data!(None, cursor, read_u8, from_u8, "unknown message token '0x{:x}'", cursor.position());
+    Ok(match token_type {
+        MessageTypeToken::Colmetadata => TokenStream::Colmetadata(try!(TokenStreamColmetadata::decode_stmt(cursor, stmt))),
+        MessageTypeToken::Row => TokenStream::Row(try!(TokenStreamRow::decode_stmt(cursor, stmt))),
+        _ => try!(handle_token_stream(token_type, cursor))
+    })
+}
+
+/// a token whose length exceeds the message leaves the cursor behind its end
+fn check_token_stream_end<C: AsRef<[u8]>>(cursor: &Cursor<C>, packet_len: usize) -> TdsResult<()> {
+    if cursor.position() != packet_len as u64 {
+        return Err(TdsError::ProtocolError(TdsProtocolError::InvalidLength(format!("token stream: the last token exceeds the message of {} bytes", packet_len))))
+    }
+    Ok(())
+}
+
 impl RawPacket {
     #[inline]
     pub fn update_len(&mut self) {
@@ -79,8 +105,9 @@ impl RawPacket {
     }
 
     pub fn into_prelogin<'a>(self) -> TdsResult<Packet<'a>> {
-        assert_eq!(self.header.ptype, PacketType::TabularResult);
-        assert_eq!(self.header.status, PacketStatus::EndOfMessage);
+        if self.header.ptype != PacketType::TabularResult || self.header.status != PacketStatus::EndOfMessage {
+            return Err(TdsError::ProtocolError(TdsProtocolError::InvalidValue(format!("prelogin: unexpected packet {:?} with status {:?}", self.header.ptype, self.header.status), 0)))
+        }
         let mut token_pairs: Vec<OptionTokenPair> = Vec::new();
         {
             let terminator = OptionTokenPair::Terminator.token();
@@ -114,7 +141,7 @@ impl RawPacket {
                 let stream = try!(handle_token_stream(token_type, &mut cursor));
                 streams.push(stream);
             }
-            assert_eq!(cursor.position(), packet_len as u64);
+            try!(check_token_stream_end(&cursor, packet_len));
         }
         Ok(Packet::TokenStream(streams))
     }
@@ -126,17 +153,36 @@ impl RawPacket {
             let mut cursor = Cursor::new(self.data);
 
             while cursor.position() < packet_len as u64 {
-                let token_type = read_packet_data!(None, cursor, read_u8, from_u8, "unknown message token '0x{:x}'", cursor.position());
-                streams.push(match token_type {
-                    MessageTypeToken::Colmetadata => TokenStream::Colmetadata(try!(TokenStreamColmetadata::decode_stmt(&mut cursor, stmt))),
-                    MessageTypeToken::Row => TokenStream::Row(try!(TokenStreamRow::decode_stmt(&mut cursor, stmt))),
-                    _ => try!(handle_token_stream(token_type, &mut cursor))
-                })
+                streams.push(try!(decode_stmt_token(&mut cursor, stmt)));
             }
-            assert_eq!(cursor.position(), packet_len as u64);
+            try!(check_token_stream_end(&cursor, packet_len));
         }
         Ok(Packet::TokenStream(streams))
     }
+
+    /// like `into_stmt_token_stream`, but a row which fails to decode ends the token stream
+    /// instead of failing the response, its error is returned along with the tokens decoded before
+    pub fn into_query_token_stream<'a>(self, stmt: &mut StatementInfo) -> TdsResult<(Packet<'a>, Option<TdsError>)> {
+        let mut streams: Vec<TokenStream> = vec![];
+        {
+            let packet_len = self.data.len();
+            let mut cursor = Cursor::new(self.data);
+
+            while cursor.position() < packet_len as u64 {
+                let is_row = cursor.get_ref()[cursor.position() as usize] == MessageTypeToken::Row as u8;
+                match decode_stmt_token(&mut cursor, stmt) {
+                    Ok(token) => streams.push(token),
+                    Err(err) => if is_row {
+                        return Ok((Packet::TokenStream(streams), Some(err)))
+                    } else {
+                        return Err(err)
+                    }
+                }
+            }
+            try!(check_token_stream_end(&cursor, packet_len));
+        }
+        Ok((Packet::TokenStream(streams), None))
+    }
 }
 
 /// 8-byte packet headers as described in 2.2.3.
@@ -202,6 +248,8 @@ pub enum Packet<'a>
     /// as specified in 2.2.6.7
     RpcRequest(&'a RpcRequestData<'a>),
     SqlBatch(&'a str),
+    /// as specified in 2.2.1.7, cancels the current request
+    Attention,
     TokenStream(Vec<TokenStream<'a>>)
 }
 
@@ -213,7 +261,7 @@ impl<'a> Packet<'a> {
                 for token in tokens {
                     match *token {
                         TokenStream::Error(ref err) => {
-                            return Err(TdsError::ServerError(err.clone()))
+                            return Err(TdsError::from(err.clone()))
                         },
                         _ => ()
                     }
@@ -251,9 +299,12 @@ impl<R: Read> ReadPacket for R
         header.id = read_packet_data!(None, self, read_u8, from_u8, "header: invalid id {}");
         header.window = read_packet_data!(None, self, read_u8, from_u8, "header: invalid window {}");
 
-        let mut buf = vec![0 as u8; header.length as usize - 8];
-        let read_bytes = try!(self.read(&mut buf[..]));
-        assert_eq!(read_bytes, buf.len());
+        if header.length < HEADER_SIZE {
+            return Err(TdsError::ProtocolError(TdsProtocolError::InvalidLength(format!("header: invalid packet length {}", header.length))))
+        }
+        // a single read might return less than the whole packet, e.g. if it was split into multiple tcp segments
+        let mut buf = vec![0 as u8; (header.length - HEADER_SIZE) as usize];
+        try!(self.read_exact(&mut buf[..]));
         Ok(RawPacket { header: header, data: buf })
     }
 
@@ -283,17 +334,16 @@ impl<R: Read> ReadPacket for R
 
 impl<W: Write> WritePacket for W
 {
-    fn build_packet(&self, mut header: PacketHeader, packet: &Packet) -> TdsResult<RawPacket> {
+    fn build_packet(&self, mut header: PacketHeader, packet: &Packet, transaction_descriptor: u64) -> TdsResult<RawPacket> {
         let mut buf = vec![];
         match *packet {
             Packet::SqlBatch(ref sql_) => {
                 header.status = PacketStatus::EndOfMessage;
                 header.ptype = PacketType::SqlBatch;
 
-                //TODO: transaction support, move this out
                 try!(buf.write_data_header(&PacketDataHeader::Transaction(PacketDataHeaderTransaction {
                     outstanding_requests: 1,
-                    transaction_descriptor: 0
+                    transaction_descriptor: transaction_descriptor
                 })));
                 try!(buf.write_as_utf16(sql_));
             },
@@ -301,10 +351,9 @@ impl<W: Write> WritePacket for W
                 header.status = PacketStatus::EndOfMessage;
                 header.ptype = PacketType::Rpc;
 
-                //TODO: transaction support, move this out
                 try!(buf.write_data_header(&PacketDataHeader::Transaction(PacketDataHeaderTransaction {
                     outstanding_requests: 1,
-                    transaction_descriptor: 0
+                    transaction_descriptor: transaction_descriptor
                 })));
 
                 try!(buf.write_rpc_procid(&req.proc_id));
@@ -314,9 +363,13 @@ impl<W: Write> WritePacket for W
                     try!(buf.write_b_varchar(&meta.name));
                     try!(buf.write_u8(meta.status_flags));
                     //write TYPE_INFo
-                    try!(buf.write_token_stream(&meta.value));
+                    try!(write_rpc_value(&mut buf, &meta.value, meta.is_null));
                 }
             },
+            Packet::Attention => {
+                header.status = PacketStatus::EndOfMessage;
+                header.ptype = PacketType::Attention;
+            },
             Packet::PreLogin(ref token_vec) => {
                 header.status = PacketStatus::EndOfMessage;
                 header.ptype = PacketType::PreLogin;
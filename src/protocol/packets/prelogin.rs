@@ -91,8 +91,12 @@ impl<R: BufRead> ReadOptionToken for R {
                 OptionTokenPair::Encryption(try!(FromPrimitive::from(read_data).ok_or(TdsProtocolError::InvalidValue(format!("prelogin: could not parse encryption: {}", read_data), 0))))
             },
             2 => {
+                // the instance name is terminated by a 0 byte
+                if max_len == 0 {
+                    return Err(TdsError::from(TdsProtocolError::InvalidLength("prelogin: empty instance name".to_owned())))
+                }
                 let mut buf = vec![0 as u8; max_len as usize - 1];
-                try!(self.read(&mut buf));
+                try!(self.read_exact(&mut buf));
                 OptionTokenPair::Instance(try!(String::from_utf8(buf).map_err(|_| TdsProtocolError::InvalidValue("prelogin: invalid string for instance name".to_owned(), 0))))
             },
             3 =>  OptionTokenPair::ThreadId(if max_len > 0 { try!(self.read_u32::<BigEndian>()) } else { 0 }),
@@ -100,14 +104,14 @@ impl<R: BufRead> ReadOptionToken for R {
             5 => {
                 let mut guid_connid = [0 as u8; 16];
                 let mut activity_id = [0 as u8; 20];
-                try!(self.read(&mut guid_connid));
-                try!(self.read(&mut activity_id));
+                try!(self.read_exact(&mut guid_connid));
+                try!(self.read_exact(&mut activity_id));
                 OptionTokenPair::TraceId(guid_connid, activity_id)
             }
             6 => OptionTokenPair::FedAuthRequired(try!(self.read_u8())),
             7 => {
                 let mut nonce = [0 as u8; 32];
-                try!(self.read(&mut nonce));
+                try!(self.read_exact(&mut nonce));
                 OptionTokenPair::Nonce(nonce)
             },
             255 => OptionTokenPair::Terminator,
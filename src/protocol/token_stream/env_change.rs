@@ -1,15 +1,29 @@
 use std::io::Cursor;
 use byteorder::{LittleEndian, ReadBytesExt};
 use super::DecodeTokenStream;
-use protocol::util::ReadCharStream;
-use ::{TdsResult, TdsProtocolError};
+use protocol::util::{ReadCharStream, FromPrimitive, read_bytes};
+use ::{TdsResult, TdsError, TdsProtocolError};
 
 /// The environment change token stream "ENVCHANGE" as described by 2.2.7.8
 #[derive(Debug)]
 pub enum TokenStreamEnvChange {
     /// Change of database from old_value to new_value
     Database(String, Option<String>),
-    PacketSize(String, Option<String>)
+    PacketSize(String, Option<String>),
+    /// The partner of a database mirroring session (formerly "real time log shipping")
+    Rtls(String),
+    /// The descriptor of a started local transaction, which requests within the transaction have to send
+    BeginTransaction(u64),
+    /// The local transaction was committed or rolled back
+    EndTransaction,
+    /// The DTC token of a local transaction which was promoted to a distributed transaction
+    PromoteTransaction(Vec<u8>),
+    /// The address of the transaction manager to enlist a distributed transaction in
+    TransactionManagerAddr(Vec<u8>),
+    /// The server (and port) the client has to reconnect to, e.g. sent by Azure SQL Database after the login
+    Routing(String, u16),
+    /// A change which is not interpreted (yet), its data is skipped
+    Other(u8)
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -47,12 +61,56 @@ impl_from_primitive!(EnvChangeType, Database, Language, CharacterSet, PacketSize
 
 impl DecodeTokenStream for TokenStreamEnvChange {
     fn decode<T: AsRef<[u8]>>(cursor: &mut Cursor<T>) -> TdsResult<TokenStreamEnvChange> {
-        let start_pos = cursor.position();
-        let end_pos = start_pos + try!(cursor.read_u16::<LittleEndian>()) as u64;
-        let token_type: EnvChangeType = read_packet_data!(None, cursor, read_u8, from_u8, "unknown envchange token type '0x{:x}'");
-        Ok(match token_type {
-            EnvChangeType::PacketSize => TokenStreamEnvChange::PacketSize(try!(cursor.read_b_varchar()), if cursor.position() < end_pos { Some(try!(cursor.read_b_varchar())) } else { None }),
-            _ => panic!("unsupported envchange token: 0x{:x}", token_type as u8)
-        })
+        let len = try!(cursor.read_u16::<LittleEndian>()) as u64;
+        let end_pos = cursor.position() + len;
+        // the token is skipped by its length, which thus has to be within the message
+        if end_pos > cursor.get_ref().as_ref().len() as u64 {
+            return Err(TdsError::ProtocolError(TdsProtocolError::InvalidLength(format!("envchange: length of {} exceeds the message", len))))
+        }
+        let token_type = try!(cursor.read_u8());
+        let change = match FromPrimitive::from(token_type) {
+            Some(EnvChangeType::Database) => TokenStreamEnvChange::Database(try!(cursor.read_b_varchar()), if cursor.position() < end_pos { Some(try!(cursor.read_b_varchar())) } else { None }),
+            Some(EnvChangeType::PacketSize) => TokenStreamEnvChange::PacketSize(try!(cursor.read_b_varchar()), if cursor.position() < end_pos { Some(try!(cursor.read_b_varchar())) } else { None }),
+            Some(EnvChangeType::Rtls) => TokenStreamEnvChange::Rtls(try!(cursor.read_b_varchar())),
+            Some(EnvChangeType::BeginTransaction) => {
+                // the new value is a B_VARBYTE containing the descriptor
+                let len = try!(cursor.read_u8());
+                if len != 8 {
+                    return Err(TdsError::ProtocolError(TdsProtocolError::InvalidLength(format!("envchange: transaction descriptor of length {}", len))))
+                }
+                TokenStreamEnvChange::BeginTransaction(try!(cursor.read_u64::<LittleEndian>()))
+            },
+            Some(EnvChangeType::CommitTransaction) | Some(EnvChangeType::RollbackTransaction) |
+            Some(EnvChangeType::DefectTransaction) | Some(EnvChangeType::TransactionEnded) => TokenStreamEnvChange::EndTransaction,
+            // a L_VARBYTE may not fit the length of the token, so the length is not used to skip it
+            Some(EnvChangeType::PromoteTransaction) => {
+                let len = try!(cursor.read_u32::<LittleEndian>()) as usize;
+                let token = try!(read_bytes(cursor, len));
+                // the old value is always empty (0x00)
+                try!(cursor.read_u8());
+                return Ok(TokenStreamEnvChange::PromoteTransaction(token))
+            },
+            Some(EnvChangeType::TransactionManagerAddr) => {
+                let len = try!(cursor.read_u8()) as usize;
+                let addr = try!(read_bytes(cursor, len));
+                TokenStreamEnvChange::TransactionManagerAddr(addr)
+            },
+            Some(EnvChangeType::RoutingInformation) => {
+                // the length of the routing data, the protocol (0 = TCP) and the port
+                try!(cursor.read_u16::<LittleEndian>());
+                let protocol = try!(cursor.read_u8());
+                if protocol != 0 {
+                    return Err(TdsError::ProtocolError(TdsProtocolError::InvalidValue(format!("envchange: routing protocol {} is not supported", protocol), cursor.position())))
+                }
+                let port = try!(cursor.read_u16::<LittleEndian>());
+                TokenStreamEnvChange::Routing(try!(cursor.read_us_varchar()), port)
+            },
+            _ => TokenStreamEnvChange::Other(token_type)
+        };
+        if cursor.position() > end_pos {
+            return Err(TdsError::ProtocolError(TdsProtocolError::InvalidLength(format!("envchange: 0x{:x} exceeds its length {}", token_type, len))))
+        }
+        cursor.set_position(end_pos);
+        Ok(change)
     }
 }
@@ -1,3 +1,5 @@
+use std::error;
+use std::fmt;
 use std::io::Cursor;
 use byteorder::{LittleEndian, ReadBytesExt};
 use super::DecodeTokenStream;
@@ -20,6 +22,18 @@ pub struct TokenStreamError {
     pub line_number: u32
 }
 
+impl fmt::Display for TokenStreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (error {}, severity {}, state {}, line {})", self.message, self.code, self.class, self.state, self.line_number)
+    }
+}
+
+impl error::Error for TokenStreamError {
+    fn description(&self) -> &str {
+        "server error"
+    }
+}
+
 impl DecodeTokenStream for TokenStreamError {
     fn decode<T: AsRef<[u8]>>(cursor: &mut Cursor<T>) -> TdsResult<TokenStreamError> {
         try!(cursor.read_u16::<LittleEndian>()); //length
@@ -0,0 +1,28 @@
+use std::io::Cursor;
+use byteorder::{LittleEndian, ReadBytesExt};
+use super::DecodeTokenStream;
+use protocol::util::read_bytes;
+use ::{TdsResult};
+
+/// The acknowledgement of the FeatureExt block of the login "FEATUREEXTACK" as described by 2.2.7.11
+#[derive(Debug)]
+pub struct TokenStreamFeatureExtAck {
+    /// the acknowledged features as (FeatureId, FeatureAckData)
+    pub features: Vec<(u8, Vec<u8>)>,
+}
+
+impl DecodeTokenStream for TokenStreamFeatureExtAck {
+    fn decode<T: AsRef<[u8]>>(cursor: &mut Cursor<T>) -> TdsResult<TokenStreamFeatureExtAck> {
+        let mut features = vec![];
+        loop {
+            let id = try!(cursor.read_u8());
+            // TERMINATOR
+            if id == 0xFF {
+                break;
+            }
+            let len = try!(cursor.read_u32::<LittleEndian>()) as usize;
+            features.push((id, try!(read_bytes(cursor, len))));
+        }
+        Ok(TokenStreamFeatureExtAck { features: features })
+    }
+}
@@ -1,5 +1,5 @@
 use std::io::Cursor;
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
 use super::DecodeTokenStream;
 use protocol::util::ReadCharStream;
 use ::{TdsResult};
@@ -8,13 +8,14 @@ use ::{TdsResult};
 #[derive(Debug)]
 pub struct TokenStreamLoginAck {
     interface: u8,
-    tds_version: u32,
+    /// the negotiated TDS version, e.g. 0x730B0003 for TDS 7.3B
+    pub tds_version: u32,
     /// The name of the server
-    prog_name: String,
-    major_version: u8,
-    minor_version: u8,
-    build_num_high: u8,
-    build_num_low: u8
+    pub prog_name: String,
+    pub major_version: u8,
+    pub minor_version: u8,
+    pub build_num_high: u8,
+    pub build_num_low: u8
 }
 
 impl DecodeTokenStream for TokenStreamLoginAck {
@@ -23,7 +24,8 @@ impl DecodeTokenStream for TokenStreamLoginAck {
 
         Ok(TokenStreamLoginAck {
             interface: try!(cursor.read_u8()),
-            tds_version: try!(cursor.read_u32::<LittleEndian>()),
+            // unlike the LOGIN7 packet, this is sent in network byte order
+            tds_version: try!(cursor.read_u32::<BigEndian>()),
             prog_name: try!(cursor.read_b_varchar()),
             major_version: try!(cursor.read_u8()),
             minor_version: try!(cursor.read_u8()),
@@ -1,5 +1,6 @@
 mod err;
 mod loginack;
+mod featureextack;
 mod env_change;
 mod done;
 mod colmetadata;
@@ -15,6 +16,7 @@ use ::{TdsResult};
 
 pub use self::err::*;
 pub use self::loginack::*;
+pub use self::featureextack::*;
 pub use self::env_change::*;
 pub use self::done::*;
 pub use self::colmetadata::*;
@@ -31,14 +33,16 @@ pub enum MessageTypeToken
     DoneInProc = 0xFF,
     EnvChange = 0xE3,
     Error = 0xAA,
+    Info = 0xAB,
     LoginAck = 0xAD,
+    FeatureExtAck = 0xAE,
     ReturnStatus = 0x79,
     Colmetadata = 0x81,
     ReturnValue = 0xAC,
     Row = 0xD1,
     Order = 0xA9,
 }
-impl_from_primitive!(MessageTypeToken, Done, DoneProc, DoneInProc, EnvChange, Error, LoginAck, ReturnStatus, Colmetadata, ReturnValue, Row, Order);
+impl_from_primitive!(MessageTypeToken, Done, DoneProc, DoneInProc, EnvChange, Error, Info, LoginAck, FeatureExtAck, ReturnStatus, Colmetadata, ReturnValue, Row, Order);
 
 pub trait DecodeTokenStream {
     fn decode<T: AsRef<[u8]>>(cursor: &mut Cursor<T>) -> TdsResult<Self> where Self: Sized;
@@ -55,7 +59,10 @@ pub trait DecodeStmtTokenStream {
 #[derive(Debug)]
 pub enum TokenStream<'a> {
     Error(TokenStreamError),
+    /// an informational message (e.g. `PRINT`), shares the layout of ERROR
+    Info(TokenStreamError),
     LoginAck(TokenStreamLoginAck),
+    FeatureExtAck(TokenStreamFeatureExtAck),
     EnvChange(TokenStreamEnvChange),
     Done(TokenStreamDone),
     DoneProc(TokenStreamDone),
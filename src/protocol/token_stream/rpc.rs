@@ -32,6 +32,8 @@ pub struct RpcParamData<'a> {
     // fByRefValue[1b], fDefaultValue[1b], reserved[1b], fEncrypted[1b], reserved[4b]
     pub status_flags: u8,
     pub value: ColumnType<'a>,
+    /// send a NULL of the type of `value` instead
+    pub is_null: bool,
 }
 
 #[derive(Debug)]
@@ -2,20 +2,21 @@ use std::borrow::Cow;
 use std::io::prelude::*;
 use std::io::Cursor;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use chrono::{NaiveDateTime, NaiveDate, NaiveTime, Duration, FixedOffset, TimeZone};
-use encoding::{Encoding, DecoderTrap};
-use encoding::all::UTF_16LE;
+use chrono::{NaiveDateTime, NaiveDate, NaiveTime, Duration, FixedOffset, Offset, Timelike};
+use encoding::{DecoderTrap, Encoding, EncodingRef};
+use encoding::all::{UTF_8, UTF_16LE, WINDOWS_1252};
+use encoding::label::encoding_from_windows_code_page;
 use protocol::WriteTokenStream;
-use protocol::util::{FromPrimitive, ReadCharStream, WriteUtf16};
-use types::{ColumnValue, ColumnType, Guid};
+use protocol::util::{FromPrimitive, ReadCharStream, WriteCharStream, WriteUtf16, read_bytes, remaining_len};
+use types::{ColumnValue, ColumnType, Guid, Tvp};
 use super::{DecodeTokenStream};
 use ::{TdsResult, TdsError, TdsProtocolError};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Collation {
-    // lcid is first 20 bits (12 left), the next 8 bits are copied into flags, the next 4 into version
+    // lcid is the lower 20 bits, the next 8 bits are copied into flags, the last 4 into version
     lcid: u32,
-    /// ignoreCase[1b], ignoreAccent[1b], ignoreKana[1b], ignoreWidth[1b], binary[1b], binary2[1b], reserved[2b]
+    /// ignoreCase[1b], ignoreAccent[1b], ignoreWidth[1b], ignoreKana[1b], binary[1b], binary2[1b], utf8[1b], reserved[1b]
     flags: u8,
     /// 4 bits!
     version: u8,
@@ -30,13 +31,102 @@ impl DecodeTokenStream for Collation {
             version: 0,
             flags: 0
         };
-        collation.flags = (collation.lcid & 0x00000FF0) as u8;
-        collation.version = (collation.lcid & 0x0000000F) as u8;
-        collation.lcid = collation.lcid & 0xFFFFF000;
+        collation.flags = (collation.lcid >> 20) as u8;
+        collation.version = (collation.lcid >> 28) as u8;
+        collation.lcid = collation.lcid & 0x000FFFFF;
         Ok(collation)
     }
 }
 
+impl Collation {
+    /// the windows code page of non-unicode data, SQL collations are identified by their sort id
+    /// and windows collations by their locale
+    pub fn code_page(&self) -> u16 {
+        match self.sortid {
+            0 => lcid_code_page(self.lcid),
+            30...34 => 437,
+            40...49 | 55...61 => 850,
+            80...96 => 1250,
+            104...108 => 1251,
+            112...124 => 1253,
+            128...130 => 1254,
+            136...138 => 1255,
+            144...146 => 1256,
+            152...160 => 1257,
+            _ => 1252,
+        }
+    }
+
+    /// whether comparisons ignore the case (`_CI`)
+    pub fn ignore_case(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+
+    /// whether comparisons ignore accents (`_AI`)
+    pub fn ignore_accent(&self) -> bool {
+        self.flags & 0x02 != 0
+    }
+
+    /// whether comparisons ignore the kana type (not `_KS`)
+    pub fn ignore_kana(&self) -> bool {
+        self.flags & 0x08 != 0
+    }
+
+    /// whether comparisons ignore the width (not `_WS`)
+    pub fn ignore_width(&self) -> bool {
+        self.flags & 0x04 != 0
+    }
+
+    /// whether values are compared by their code points (`_BIN` or `_BIN2`)
+    pub fn binary(&self) -> bool {
+        self.flags & 0x30 != 0
+    }
+
+    /// whether non-unicode data is UTF-8 (a `_UTF8` collation, see `LoginFeature::Utf8Support`)
+    pub fn utf8(&self) -> bool {
+        self.flags & 0x40 != 0
+    }
+
+    /// the encoding of non-unicode data, windows-1252 if the code page is not supported
+    pub fn encoding(&self) -> EncodingRef {
+        if self.utf8() {
+            return UTF_8;
+        }
+        encoding_from_windows_code_page(self.code_page() as usize).unwrap_or(WINDOWS_1252)
+    }
+}
+
+/// the ANSI code page of a locale, by its primary language (and for chinese its sublanguage)
+fn lcid_code_page(lcid: u32) -> u16 {
+    match lcid & 0x3FF {
+        0x05 | 0x0E | 0x15 | 0x18 | 0x1A | 0x1B | 0x1C | 0x24 => 1250,
+        0x02 | 0x19 | 0x22 | 0x23 | 0x2F | 0x3F | 0x40 | 0x44 | 0x50 => 1251,
+        0x08 => 1253,
+        0x1F | 0x2C | 0x43 => 1254,
+        0x0D => 1255,
+        0x01 | 0x20 | 0x29 => 1256,
+        0x25 | 0x26 | 0x27 => 1257,
+        0x2A => 1258,
+        0x1E => 874,
+        0x11 => 932,
+        0x12 => 949,
+        0x04 => match lcid & 0xFFFF {
+            0x0804 | 0x1004 => 936,
+            _ => 950,
+        },
+        _ => 1252,
+    }
+}
+
+/// decode non-unicode data using the code page of its collation
+fn decode_varchar(buf: &[u8], collation: &Option<Collation>) -> TdsResult<String> {
+    let encoding = match *collation {
+        Some(ref collation) => collation.encoding(),
+        None => WINDOWS_1252,
+    };
+    Ok(try!(encoding.decode(buf, DecoderTrap::Strict)))
+}
+
 /// 2.2.5.4.1
 #[derive(PartialEq, Debug, Clone)]
 #[repr(u8)]
@@ -59,7 +149,7 @@ impl_from_primitive!(FixedLenType, Int1, Bit, Int2, Int4, DateTime4, Float4, Mon
 
 /// 2.2.5.4.2
 #[repr(u8)]
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum VarLenType {
     Guid = 0x24,
     Intn = 0x26,
@@ -85,12 +175,11 @@ pub enum VarLenType {
     NChar = 0xEF,
     // not supported yet
     Xml = 0xF1,
-    // not supported yet
+    /// a CLR type (e.g. hierarchyid or geography), read as its serialized bytes
     Udt = 0xF0,
     Text = 0x23,
     Image = 0x22,
     NText = 0x63,
-    // not supported yet
     SSVariant = 0x62
     // legacy types (not supported since post-7.2):
     // Char = 0x2F,
@@ -103,7 +192,7 @@ pub enum VarLenType {
 impl_from_primitive!(VarLenType, Guid, Intn, Bitn, Decimaln, Numericn, Floatn, Money, Datetimen, Daten, Timen, Datetime2, DatetimeOffsetn,
     BigVarBin, BigVarChar, BigBinary, BigChar, NVarchar, NChar, Xml, Udt, Text, Image, NText, SSVariant);
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum TypeInfo {
     FixedLenType(FixedLenType),
     /// VARLENTYPE TYPE_VARLEN [COLLATION]
@@ -143,7 +232,7 @@ impl DecodeTokenStream for TypeInfo {
                                 has_collation = true;
                                 try!(cursor.read_i32::<LittleEndian>()) as u32
                             },
-                            VarLenType::Image => try!(cursor.read_u32::<LittleEndian>()),
+                            VarLenType::Image | VarLenType::SSVariant => try!(cursor.read_u32::<LittleEndian>()),
                             VarLenType::Decimaln | VarLenType::Numericn => {
                                 has_precision = true;
                                 try!(cursor.read_u8()) as u32
@@ -153,6 +242,24 @@ impl DecodeTokenStream for TypeInfo {
                                 0
                             },
                             VarLenType::Daten => 0,
+                            VarLenType::Xml => {
+                                // XML_INFO: the schema collection is not used, but has to be skipped
+                                if try!(cursor.read_u8()) == 1 {
+                                    try!(cursor.read_b_varchar()); // dbname
+                                    try!(cursor.read_b_varchar()); // owning schema
+                                    try!(cursor.read_us_varchar()); // xml schema collection
+                                }
+                                0
+                            },
+                            VarLenType::Udt => {
+                                // UDT_INFO: the max length and the names of the type are not used
+                                let len = try!(cursor.read_u16::<LittleEndian>()) as u32;
+                                try!(cursor.read_b_varchar()); // dbname
+                                try!(cursor.read_b_varchar()); // owning schema
+                                try!(cursor.read_b_varchar()); // type name
+                                try!(cursor.read_us_varchar()); // assembly qualified name
+                                len
+                            },
                             _ => return Err(TdsError::Other(format!("variable length type {:?} not supported", var_len_type)))
                         };
                         match true {
@@ -170,7 +277,7 @@ impl DecodeTokenStream for TypeInfo {
 }
 
 /// 2.2.7.4
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ColumnData {
     pub user_type: u32,
     /// fNullable[1b], fCaseSen[1b], usUpdateable[2b], fIdentity[1b], fComputed[1b], usReservedODBC[2b]
@@ -233,57 +340,248 @@ impl ColumnData {
 
 impl<'a, W: Write> WriteTokenStream<&'a ColumnType<'a>> for W {
     fn write_token_stream(&mut self, data: &'a ColumnType<'a>) -> TdsResult<()> {
-        match *data {
-            ColumnType::I8(ref val) => {
-                try!(self.write_u8(VarLenType::Intn as u8));
-                try!(self.write_u8(1));
-                try!(self.write_u8(1));
-                try!(self.write_i8(*val))
-            },
-            ColumnType::I16(ref val) => {
-                try!(self.write_u8(VarLenType::Intn as u8));
-                try!(self.write_u8(2));
-                try!(self.write_u8(2));
-                try!(self.write_i16::<LittleEndian>(*val));
-            },
-            ColumnType::I32(ref val) => {
-                try!(self.write_u8(VarLenType::Intn as u8));
-                try!(self.write_u8(4));
-                try!(self.write_u8(4));
-                try!(self.write_i32::<LittleEndian>(*val));
-            },
-            ColumnType::I64(ref val) => {
-                try!(self.write_u8(VarLenType::Intn as u8));
-                try!(self.write_u8(8));
-                try!(self.write_u8(8));
-                try!(self.write_i64::<LittleEndian>(*val));
-            },
-            ColumnType::F32(ref val) => {
-                try!(self.write_u8(VarLenType::Floatn as u8));
-                try!(self.write_u8(4));
-                try!(self.write_u8(4));
-                try!(self.write_f32::<LittleEndian>(*val));
-            },
-            ColumnType::F64(ref val) => {
-                try!(self.write_u8(VarLenType::Floatn as u8));
-                try!(self.write_u8(8));
-                try!(self.write_u8(8));
-                try!(self.write_f64::<LittleEndian>(*val));
-            },
-            ColumnType::String(ref val) => {
-                let len = (val.len() as u32 * 2) as u16;
-                try!(self.write_u8(VarLenType::NVarchar as u8));
-                try!(self.write_u16::<LittleEndian>(len));
-                try!(self.write_all(&[0, 0, 0, 0, 0])); //todo use a non-hardcoded collation
-                try!(self.write_u16::<LittleEndian>(len));
-                try!(self.write_as_utf16(&val));
-            },
-            _ => panic!("rpc: encoding of ColumnType {:?} not supported", data)
+        write_rpc_value(self, data, false)
+    }
+}
+
+/// write the length (as byte) and the value, or a zero length if the value is NULL
+macro_rules! write_bytelen_value {
+    ($w:expr, $null:expr, $len:expr, $write:expr) => {
+        if $null {
+            try!($w.write_u8(0));
+        } else {
+            try!($w.write_u8($len));
+            try!($write);
         }
-        Ok(())
     }
 }
 
+/// write the TYPE_INFO and the value of a RPC parameter, if `null` is set a NULL of that type is written instead
+pub fn write_rpc_value<W: Write>(w: &mut W, data: &ColumnType, null: bool) -> TdsResult<()> {
+    match *data {
+        ColumnType::Tvp(ref tvp) => return write_tvp(w, tvp),
+        // bind the value of a sql_variant as its base type
+        ColumnType::Variant(ref x) => return write_rpc_value(w, x, null),
+        _ => ()
+    }
+    try!(write_type_info(w, data, null));
+    write_value(w, data, null)
+}
+
+fn write_type_info<W: Write>(w: &mut W, data: &ColumnType, null: bool) -> TdsResult<()> {
+    match *data {
+        ColumnType::Bool(_) => {
+            try!(w.write_u8(VarLenType::Bitn as u8));
+            try!(w.write_u8(1));
+        },
+        ColumnType::I8(_) | ColumnType::I16(_) | ColumnType::I32(_) | ColumnType::I64(_) => {
+            try!(w.write_u8(VarLenType::Intn as u8));
+            try!(w.write_u8(match *data { ColumnType::I8(_) => 1, ColumnType::I16(_) => 2, ColumnType::I32(_) => 4, _ => 8 }));
+        },
+        ColumnType::F32(_) | ColumnType::F64(_) => {
+            try!(w.write_u8(VarLenType::Floatn as u8));
+            try!(w.write_u8(match *data { ColumnType::F32(_) => 4, _ => 8 }));
+        },
+        ColumnType::String(ref val) => {
+            // nvarchar(max), whose value is sent as PLP, for more than 8000 bytes of UTF-16
+            let len = val.encode_utf16().count() * 2;
+            try!(w.write_u8(VarLenType::NVarchar as u8));
+            try!(w.write_u16::<LittleEndian>(if null { 2 } else if len > 8000 { 0xFFFF } else { len as u16 }));
+            try!(w.write_all(&[0, 0, 0, 0, 0])); //todo use a non-hardcoded collation
+        },
+        ColumnType::Guid(_) => {
+            try!(w.write_u8(VarLenType::Guid as u8));
+            try!(w.write_u8(0x10));
+        },
+        ColumnType::Binary(ref val) => {
+            try!(w.write_u8(VarLenType::BigVarBin as u8));
+            // varbinary(max), whose value is sent as PLP
+            try!(w.write_u16::<LittleEndian>(if !null && val.len() > 8000 { 0xFFFF } else { 8000 }));
+        },
+        ColumnType::Time(_) => {
+            try!(w.write_u8(VarLenType::Timen as u8));
+            try!(w.write_u8(7)); // scale
+        },
+        ColumnType::Date(_) => try!(w.write_u8(VarLenType::Daten as u8)),
+        ColumnType::Datetime(_) => {
+            try!(w.write_u8(VarLenType::Datetime2 as u8));
+            try!(w.write_u8(7)); // scale
+        },
+        ColumnType::DatetimeOffset(_, _) => {
+            try!(w.write_u8(VarLenType::DatetimeOffsetn as u8));
+            try!(w.write_u8(7)); // scale
+        },
+        ColumnType::Money(_) => {
+            try!(w.write_u8(VarLenType::Money as u8));
+            try!(w.write_u8(8));
+        },
+        _ => return Err(TdsError::Other(format!("rpc: encoding of ColumnType {} is not supported", data.variant_name())))
+    }
+    Ok(())
+}
+
+fn write_value<W: Write>(w: &mut W, data: &ColumnType, null: bool) -> TdsResult<()> {
+    match *data {
+        ColumnType::Bool(ref val) => write_bytelen_value!(w, null, 1, w.write_u8(*val as u8)),
+        ColumnType::I8(ref val) => write_bytelen_value!(w, null, 1, w.write_i8(*val)),
+        ColumnType::I16(ref val) => write_bytelen_value!(w, null, 2, w.write_i16::<LittleEndian>(*val)),
+        ColumnType::I32(ref val) => write_bytelen_value!(w, null, 4, w.write_i32::<LittleEndian>(*val)),
+        ColumnType::I64(ref val) => write_bytelen_value!(w, null, 8, w.write_i64::<LittleEndian>(*val)),
+        ColumnType::F32(ref val) => write_bytelen_value!(w, null, 4, w.write_f32::<LittleEndian>(*val)),
+        ColumnType::F64(ref val) => write_bytelen_value!(w, null, 8, w.write_f64::<LittleEndian>(*val)),
+        ColumnType::String(ref val) => {
+            if null {
+                try!(w.write_u16::<LittleEndian>(0xFFFF));
+            } else {
+                let len = val.encode_utf16().count() * 2;
+                if len > 8000 {
+                    let mut buf = Vec::with_capacity(len);
+                    try!(buf.write_as_utf16(&val));
+                    try!(write_plp(w, &buf));
+                } else {
+                    try!(w.write_u16::<LittleEndian>(len as u16));
+                    try!(w.write_as_utf16(&val));
+                }
+            }
+        },
+        ColumnType::Guid(ref val) => write_bytelen_value!(w, null, 0x10, w.write_all(val.as_bytes())),
+        ColumnType::Binary(ref val) => {
+            if null {
+                try!(w.write_u16::<LittleEndian>(0xFFFF));
+            } else if val.len() > 8000 {
+                try!(write_plp(w, val));
+            } else {
+                try!(w.write_u16::<LittleEndian>(val.len() as u16));
+                try!(w.write_all(val));
+            }
+        },
+        ColumnType::Time(ref val) => write_bytelen_value!(w, null, 5, write_time(w, val)),
+        ColumnType::Date(ref val) => {
+            if null {
+                try!(w.write_u8(0));
+            } else {
+                let days = try!(date_days(val));
+                try!(w.write_u8(3));
+                try!(write_days(w, days));
+            }
+        },
+        ColumnType::Datetime(ref val) => {
+            if null {
+                try!(w.write_u8(0));
+            } else {
+                // checked before anything is written
+                let days = try!(date_days(&val.date()));
+                try!(w.write_u8(8));
+                try!(write_time(w, &val.time()));
+                try!(write_days(w, days));
+            }
+        },
+        ColumnType::DatetimeOffset(ref val, ref offset) => {
+            if null {
+                try!(w.write_u8(0));
+            } else {
+                // the date and time in UTC, followed by the offset in minutes
+                let days = try!(date_days(&val.date()));
+                try!(w.write_u8(10));
+                try!(write_time(w, &val.time()));
+                try!(write_days(w, days));
+                try!(w.write_i16::<LittleEndian>(offset.local_minus_utc().num_minutes() as i16));
+            }
+        },
+        ColumnType::Money(ref val) => {
+            // the high dword comes first
+            write_bytelen_value!(w, null, 8, w.write_i32::<LittleEndian>((*val >> 32) as i32).and_then(|_| w.write_u32::<LittleEndian>(*val as u32)))
+        },
+        _ => return Err(TdsError::Other(format!("rpc: encoding of ColumnType {} is not supported", data.variant_name())))
+    }
+    Ok(())
+}
+
+/// write a time(7) value, 10^-7 second increments since 12 AM
+fn write_time<W: Write>(w: &mut W, val: &NaiveTime) -> TdsResult<()> {
+    let increments = val.num_seconds_from_midnight() as u64 * 10_000_000 + val.nanosecond() as u64 / 100;
+    try!(w.write_u32::<LittleEndian>(increments as u32));
+    try!(w.write_u8((increments >> 32) as u8));
+    Ok(())
+}
+
+/// the days since 0001-01-01 (proleptic gregorian) of a date, which has to be up to 9999-12-31
+fn date_days(val: &NaiveDate) -> TdsResult<u32> {
+    let days = (*val - NaiveDate::from_ymd(1, 1, 1)).num_days();
+    if days < 0 || days > 3652058 {
+        return Err(TdsError::Other(format!("rpc: the date {} is out of the range of date", val)))
+    }
+    Ok(days as u32)
+}
+
+/// write the days of a date in 3 bytes
+fn write_days<W: Write>(w: &mut W, days: u32) -> TdsResult<()> {
+    try!(w.write_u16::<LittleEndian>(days as u16));
+    try!(w.write_u8((days >> 16) as u8));
+    Ok(())
+}
+
+
+/// write a table-valued parameter (TVP_TYPE_INFO and the rows, 2.2.6.9)
+fn write_tvp<W: Write>(w: &mut W, tvp: &Tvp) -> TdsResult<()> {
+    const TVP_TYPE: u8 = 0xF3;
+    const TVP_NULL_TOKEN: u16 = 0xFFFF;
+    const TVP_ROW_TOKEN: u8 = 0x01;
+    const TVP_END_TOKEN: u8 = 0x00;
+
+    try!(w.write_u8(TVP_TYPE));
+    let mut name_parts = tvp.type_name.rsplitn(2, '.');
+    let type_name = name_parts.next().unwrap_or("");
+    let schema = name_parts.next().unwrap_or("");
+    try!(w.write_b_varchar("")); // the database has to be the current one
+    try!(w.write_b_varchar(schema.trim_matches(|c| c == '[' || c == ']')));
+    try!(w.write_b_varchar(type_name.trim_matches(|c| c == '[' || c == ']')));
+
+    // TVP_COLMETADATA
+    match tvp.rows.first() {
+        None => try!(w.write_u16::<LittleEndian>(TVP_NULL_TOKEN)),
+        Some(first) => {
+            try!(w.write_u16::<LittleEndian>(first.len() as u16));
+            for &(ref col, _) in first {
+                try!(w.write_u32::<LittleEndian>(0)); // user type
+                try!(w.write_u16::<LittleEndian>(0x01)); // fNullable
+                match *col {
+                    // the length is the same for all rows
+                    ColumnType::String(_) => {
+                        try!(w.write_u8(VarLenType::NVarchar as u8));
+                        try!(w.write_u16::<LittleEndian>(8000));
+                        try!(w.write_all(&[0, 0, 0, 0, 0]));
+                    },
+                    ColumnType::Tvp(_) => return Err(TdsError::Other("tvp: a column cannot be a table-valued parameter".to_owned())),
+                    _ => try!(write_type_info(w, col, false))
+                }
+                try!(w.write_u8(0)); // the column name has to be empty
+            }
+        }
+    }
+    try!(w.write_u8(TVP_END_TOKEN));
+
+    for row in &tvp.rows {
+        if row.len() != tvp.rows[0].len() {
+            return Err(TdsError::Other(format!("tvp: expected {} values per row, got {}", tvp.rows[0].len(), row.len())))
+        }
+        try!(w.write_u8(TVP_ROW_TOKEN));
+        for (&(ref val, null), &(ref col, _)) in row.iter().zip(tvp.rows[0].iter()) {
+            if val.variant_name() != col.variant_name() {
+                return Err(TdsError::Other(format!("tvp: expected a {} value, got {}", col.variant_name(), val.variant_name())))
+            }
+            if let ColumnType::String(ref x) = *val {
+                if x.encode_utf16().count() > 4000 {
+                    return Err(TdsError::Other(format!("tvp: a string of {} characters does not fit into nvarchar(4000)", x.chars().count())))
+                }
+            }
+            try!(write_value(w, val, null));
+        }
+    }
+    try!(w.write_u8(TVP_END_TOKEN));
+    Ok(())
+}
+
 #[inline]
 fn decode_datetime<T: AsRef<[u8]>>(ty: FixedLenType, cursor: &mut Cursor<T>) -> TdsResult<NaiveDateTime> {
     let days: i64;
@@ -296,16 +594,18 @@ fn decode_datetime<T: AsRef<[u8]>>(ty: FixedLenType, cursor: &mut Cursor<T>) ->
             Duration::minutes(mins as i64)
         },
         FixedLenType::DateTime => {
-            // days since 1.1.1900
-            days = try!(cursor.read_u32::<LittleEndian>()) as i64;
-            // number of 1/300 since 12am
-            let ticks = try!(cursor.read_u32::<LittleEndian>());
-            Duration::nanoseconds((1E9/300f64 * ticks as f64) as i64)
+            // days since 1.1.1900, negative for earlier dates
+            days = try!(cursor.read_i32::<LittleEndian>()) as i64;
+            // number of 1/300 since 12am, rounded to the nearest nanosecond (a float would truncate e.g. .997 to .996666666)
+            let ticks = try!(cursor.read_u32::<LittleEndian>()) as i64;
+            Duration::nanoseconds((ticks * 10_000_000 + 1) / 3)
         },
         _ => unreachable!()
     };
-    let date = NaiveDate::from_ymd(1900, 1, 1) + Duration::days(days as i64);
-    Ok(date.and_hms(0, 0, 0) + duration)
+    match NaiveDate::from_ymd(1900, 1, 1).and_hms(0, 0, 0).checked_add(Duration::days(days)).and_then(|x| x.checked_add(duration)) {
+        Some(datetime) => Ok(datetime),
+        None => Err(TdsError::ProtocolError(TdsProtocolError::InvalidValue(format!("datetime: {} days since 1900 are out of range", days), cursor.position())))
+    }
 }
 
 /// decode a TDS 7.3 date
@@ -325,18 +625,105 @@ fn decode_time<T: AsRef<[u8]>>(scale: u8, cursor: &mut Cursor<T>) -> TdsResult<N
         5...7 => try!(cursor.read_u32::<LittleEndian>()) as u64 | (try!(cursor.read_u8()) as u64) << 32,
         _ => return Err(TdsError::ProtocolError(TdsProtocolError::InvalidLength(format!("datetime2: scale of {} is invalid", scale))))
     };
-    let duration = Duration::nanoseconds((increments as f64/(10u64.pow(scale as u32) as f64)*1e9f64) as i64);
+    // use integer arithmetic to avoid rounding errors, the scale is at most 7
+    let duration = Duration::nanoseconds((increments * 10u64.pow(9 - scale as u32)) as i64);
     Ok(NaiveTime::from_hms(0, 0, 0) + duration)
 }
 
+/// write a value of known length as PLP, in a single chunk followed by the terminator
+fn write_plp<W: Write>(w: &mut W, data: &[u8]) -> TdsResult<()> {
+    if data.len() > u32::max_value() as usize {
+        return Err(TdsError::Other(format!("rpc: a value of {} bytes does not fit into a PLP chunk", data.len())))
+    }
+    try!(w.write_u64::<LittleEndian>(data.len() as u64));
+    try!(w.write_u32::<LittleEndian>(data.len() as u32));
+    try!(w.write_all(data));
+    try!(w.write_u32::<LittleEndian>(0));
+    Ok(())
+}
+
+/// read a PLP (partially length-prefixed) value as described by 2.2.5.2.3, which is None for NULL
+fn read_plp<T: AsRef<[u8]>>(cursor: &mut Cursor<T>) -> TdsResult<Option<Vec<u8>>> {
+    let len = try!(cursor.read_u64::<LittleEndian>());
+    if len == 0xFFFFFFFFFFFFFFFF {
+        return Ok(None)
+    }
+    // the total length might be unknown (0xFFFFFFFFFFFFFFFE), so rely on the chunks
+    // and do not trust it for the allocation either
+    let mut buf = Vec::with_capacity(::std::cmp::min(len, remaining_len(cursor) as u64) as usize);
+    loop {
+        let chunk_len = try!(cursor.read_u32::<LittleEndian>()) as usize;
+        if chunk_len == 0 {
+            break
+        }
+        buf.extend(try!(read_bytes(cursor, chunk_len)));
+    }
+    Ok(Some(buf))
+}
+
+/// decode the value of a sql_variant (2.2.5.5.4): the base type and its properties precede the value,
+/// which is decoded as a value of the base type (prefixed by its length)
+fn decode_variant<'a>(buf: &[u8]) -> TdsResult<ColumnType<'a>> {
+    let mut cursor = Cursor::new(buf);
+    let base_type = try!(cursor.read_u8());
+    let prop_len = try!(cursor.read_u8()) as usize;
+    let props = try!(read_bytes(&mut cursor, prop_len));
+    let data = &buf[cursor.position() as usize..];
+    let mut props = Cursor::new(props);
+    let mut value = vec![];
+    let fixed_type: Option<FixedLenType> = FromPrimitive::from(base_type);
+    let type_info = match fixed_type {
+        Some(fixed_type) => TypeInfo::FixedLenType(fixed_type),
+        None => {
+            let var_len_type: Option<VarLenType> = FromPrimitive::from(base_type);
+            match var_len_type {
+                Some(ty @ VarLenType::Guid) | Some(ty @ VarLenType::Daten) => {
+                    value.push(data.len() as u8);
+                    TypeInfo::VarLenType(ty, data.len() as u32, None)
+                },
+                Some(ty @ VarLenType::Decimaln) | Some(ty @ VarLenType::Numericn) => {
+                    value.push(data.len() as u8);
+                    TypeInfo::VarLenTypeP(ty, data.len() as u32, try!(props.read_u8()), try!(props.read_u8()))
+                },
+                Some(ty @ VarLenType::Timen) | Some(ty @ VarLenType::Datetime2) | Some(ty @ VarLenType::DatetimeOffsetn) => {
+                    value.push(data.len() as u8);
+                    TypeInfo::VarLenTypeS(ty, try!(props.read_u8()))
+                },
+                Some(ty @ VarLenType::BigBinary) | Some(ty @ VarLenType::BigVarBin) => {
+                    try!(value.write_u16::<LittleEndian>(data.len() as u16));
+                    TypeInfo::VarLenType(ty, try!(props.read_u16::<LittleEndian>()) as u32, None)
+                },
+                Some(ty @ VarLenType::BigChar) | Some(ty @ VarLenType::BigVarChar) | Some(ty @ VarLenType::NChar) | Some(ty @ VarLenType::NVarchar) => {
+                    try!(value.write_u16::<LittleEndian>(data.len() as u16));
+                    let collation = try!(Collation::decode(&mut props));
+                    TypeInfo::VarLenType(ty, try!(props.read_u16::<LittleEndian>()) as u32, Some(collation))
+                },
+                _ => return Err(TdsError::ProtocolError(TdsProtocolError::InvalidValue(format!("sql_variant: base type 0x{:x} is invalid", base_type), 0)))
+            }
+        }
+    };
+    value.extend_from_slice(data);
+    let mut value_cursor = Cursor::new(value);
+    match try!(ColumnValue::decode(&mut value_cursor, &type_info)) {
+        ColumnValue::Some(x) => {
+            if value_cursor.position() as usize != value_cursor.get_ref().len() {
+                return Err(TdsError::ProtocolError(TdsProtocolError::InvalidLength(format!("sql_variant: {} bytes exceed the value of type {:?}", value_cursor.get_ref().len() - value_cursor.position() as usize, type_info))))
+            }
+            Ok(x)
+        },
+        ColumnValue::None => Err(TdsError::ProtocolError(TdsProtocolError::InvalidValue("sql_variant: the base value is NULL".to_owned(), 0)))
+    }
+}
+
 #[inline]
 fn decode_money<'a, T: AsRef<[u8]>>(ty: FixedLenType, cursor: &mut Cursor<T>) -> TdsResult<ColumnType<'a>> {
     Ok(match ty {
-        FixedLenType::Money4 => ColumnType::F32(try!(cursor.read_i32::<LittleEndian>()) as f32 / (10u32.pow(4) as f32)),
+        FixedLenType::Money4 => ColumnType::Money(try!(cursor.read_i32::<LittleEndian>()) as i64),
         FixedLenType::Money8 => {
+            // the high dword comes first, the low dword must not be sign extended
             let mut val: i64 = (try!(cursor.read_i32::<LittleEndian>()) as i64) << 32;
-            val |= try!(cursor.read_i32::<LittleEndian>()) as i64;
-            ColumnType::F64(val as f64 / (10u32.pow(4) as f64))
+            val |= try!(cursor.read_u32::<LittleEndian>()) as i64;
+            ColumnType::Money(val)
         },
         _ => unreachable!()
     })
@@ -348,7 +735,7 @@ impl<'a> ColumnValue<'a> {
         Ok(match *tyinfo {
             TypeInfo::FixedLenType(ref f_type) => {
                 match *f_type {
-                    FixedLenType::Bit => ColumnValue::Some(ColumnType::Bool(try!(cursor.read_u8()) == 1)),
+                    FixedLenType::Bit => ColumnValue::Some(ColumnType::Bool(try!(cursor.read_u8()) != 0)),
                     FixedLenType::Int1 => ColumnValue::Some(ColumnType::I8(try!(cursor.read_i8()))),
                     FixedLenType::Int2 => ColumnValue::Some(ColumnType::I16(try!(cursor.read_i16::<LittleEndian>()))),
                     FixedLenType::Int4 => ColumnValue::Some(ColumnType::I32(try!(cursor.read_i32::<LittleEndian>()))),
@@ -362,19 +749,27 @@ impl<'a> ColumnValue<'a> {
                     }
                 }
             },
-            TypeInfo::VarLenType(ref v_type, _, ref collation) => {
+            TypeInfo::VarLenType(ref v_type, max_len, ref collation) => {
                 match *v_type {
+                    // varchar(max), nvarchar(max) and varbinary(max) are sent as PLP, the chunks are joined
+                    // before decoding since a chunk may end within a character
+                    VarLenType::BigVarChar | VarLenType::NVarchar | VarLenType::BigVarBin if max_len == 0xFFFF => {
+                        match try!(read_plp(cursor)) {
+                            None => ColumnValue::None,
+                            Some(buf) => ColumnValue::Some(match *v_type {
+                                VarLenType::BigVarChar => ColumnType::String(Cow::Owned(try!(decode_varchar(&buf, collation)))),
+                                VarLenType::NVarchar => ColumnType::String(Cow::Owned(try!(UTF_16LE.decode(&buf, DecoderTrap::Strict)))),
+                                _ => ColumnType::Binary(buf)
+                            })
+                        }
+                    },
                     VarLenType::BigChar | VarLenType::BigVarChar => {
                         let len = try!(cursor.read_u16::<LittleEndian>());
                         if len == 0xFFFF {
                             ColumnValue::None
                         } else {
-                            let mut buf = vec![0; len as usize];
-                            try!(cursor.read(&mut buf));
-                            match String::from_utf8(buf) {
-                                Err(x) => return Err(TdsError::Conversion(Box::new(x))),
-                                Ok(x) => ColumnValue::Some(ColumnType::String(Cow::Owned(x)))
-                            }
+                            let buf = try!(read_bytes(cursor, len as usize));
+                            ColumnValue::Some(ColumnType::String(Cow::Owned(try!(decode_varchar(&buf, collation)))))
                         }
                     },
                     VarLenType::NVarchar | VarLenType::NChar => {
@@ -382,8 +777,7 @@ impl<'a> ColumnValue<'a> {
                         if len == 0xFFFF {
                             ColumnValue::None
                         } else {
-                            let mut buf = vec![0; len as usize];
-                            try!(cursor.read(&mut buf));
+                            let buf = try!(read_bytes(cursor, len as usize));
                             ColumnValue::Some(ColumnType::String(Cow::Owned(try!(UTF_16LE.decode(&buf, DecoderTrap::Strict)))))
                         }
                     },
@@ -392,21 +786,19 @@ impl<'a> ColumnValue<'a> {
                         if len == 0xFFFF {
                             ColumnValue::None
                         } else {
-                            let mut buf = vec![0; len as usize];
-                            try!(cursor.read(&mut buf));
+                            let buf = try!(read_bytes(cursor, len as usize));
                             ColumnValue::Some(ColumnType::Binary(buf))
                         }
                     },
                     VarLenType::Text | VarLenType::NText | VarLenType::Image => {
-                        // TODO what is textptr dummy stuff...
+                        // the text pointer and its timestamp (only used by the deprecated READTEXT/WRITETEXT)
+                        // precede the value, a NULL value has no text pointer
                         match try!(cursor.read_u8()) {
                             0 => ColumnValue::None,
                             text_ptr_len => {
-                                let mut buf = vec![0; text_ptr_len as usize]; //text_ptr
-                                try!(cursor.read(&mut buf));
-                                // Timestamp TODO: what is this..
+                                try!(read_bytes(cursor, text_ptr_len as usize));
                                 let mut timestamp = [0; 8];
-                                try!(cursor.read(&mut timestamp));
+                                try!(cursor.read_exact(&mut timestamp));
                                 let len = try!(cursor.read_i32::<LittleEndian>());
 
                                 if len < -1 {
@@ -415,12 +807,10 @@ impl<'a> ColumnValue<'a> {
                                 if len < 0 {
                                     ColumnValue::None
                                 } else {
-                                    let mut buf = vec![0; len as usize];
-                                    try!(cursor.read(&mut buf));
+                                    let buf = try!(read_bytes(cursor, len as usize));
                                     match *v_type {
-                                        VarLenType::Text => match String::from_utf8(buf) {
-                                            Err(x) => return Err(TdsError::Conversion(Box::new(x))),
-                                            Ok(x) => ColumnValue::Some(ColumnType::String(Cow::Owned(x)))
+                                        VarLenType::Text => {
+                                            ColumnValue::Some(ColumnType::String(Cow::Owned(try!(decode_varchar(&buf, collation)))))
                                         },
                                         VarLenType::NText => {
                                             ColumnValue::Some(ColumnType::String(Cow::Owned(try!(UTF_16LE.decode(&buf, DecoderTrap::Strict)))))
@@ -489,6 +879,27 @@ impl<'a> ColumnValue<'a> {
                             _ => return Err(TdsError::ProtocolError(TdsProtocolError::InvalidLength(format!("bitn: length of {} is invalid", len))))
                         }
                     },
+                    VarLenType::Udt => {
+                        match try!(read_plp(cursor)) {
+                            None => ColumnValue::None,
+                            Some(buf) => ColumnValue::Some(ColumnType::Binary(buf))
+                        }
+                    },
+                    VarLenType::Xml => {
+                        match try!(read_plp(cursor)) {
+                            None => ColumnValue::None,
+                            Some(buf) => ColumnValue::Some(ColumnType::String(Cow::Owned(try!(UTF_16LE.decode(&buf, DecoderTrap::Strict)))))
+                        }
+                    },
+                    VarLenType::SSVariant => {
+                        let len = try!(cursor.read_u32::<LittleEndian>());
+                        if len == 0 {
+                            ColumnValue::None
+                        } else {
+                            let buf = try!(read_bytes(cursor, len as usize));
+                            ColumnValue::Some(ColumnType::Variant(Box::new(try!(decode_variant(&buf)))))
+                        }
+                    },
                     VarLenType::Daten => {
                         let len = try!(cursor.read_u8());
                         match len {
@@ -497,7 +908,7 @@ impl<'a> ColumnValue<'a> {
                             _ => return Err(TdsError::ProtocolError(TdsProtocolError::InvalidLength(format!("timen: length of {} is invalid", len))))
                         }
                     },
-                    _ => panic!("unsupported vtype {:?}", v_type)
+                    _ => return Err(TdsError::Other(format!("column data type {:?} not supported", v_type)))
                 }
             },
             TypeInfo::VarLenTypeP(ref v_type, _, ref precision, ref scale) => {
@@ -513,7 +924,7 @@ impl<'a> ColumnValue<'a> {
                             _ => return Err(TdsError::ProtocolError(TdsProtocolError::InvalidLength(format!("decimal: length of {} is unsupported", *precision))))
                         }
                     },
-                    _ => panic!("unsupported scaled vtype {:?}", v_type)
+                    _ => return Err(TdsError::Other(format!("column data type {:?} with precision not supported", v_type)))
                 }
             },
             TypeInfo::VarLenTypeS(ref v_type, ref scale) => {
@@ -553,12 +964,12 @@ impl<'a> ColumnValue<'a> {
                             let datetime = NaiveDateTime::new(date, time);
                             // number of minutes from UTC
                             let offset = try!(cursor.read_i16::<LittleEndian>());
-                            ColumnValue::Some(ColumnType::Datetime(FixedOffset::east(offset as i32 * 60).from_utc_datetime(&datetime).naive_utc()))
+                            ColumnValue::Some(ColumnType::DatetimeOffset(datetime, FixedOffset::east(offset as i32 * 60)))
                         } else {
                             return Err(TdsError::ProtocolError(TdsProtocolError::InvalidLength(format!("datetimeoffset: length of {} with scale {} is unsupported", len, scale))));
                         }
                     },
-                    _ => panic!("unsupported scale-only vtype {:?}", v_type)
+                    _ => return Err(TdsError::Other(format!("column data type {:?} with scale not supported", v_type)))
                 }
             },
         })
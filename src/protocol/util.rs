@@ -1,10 +1,49 @@
 use std::io;
 use std::io::prelude::*;
+use std::io::Cursor;
+use std::ptr;
+use std::sync::atomic;
 
 use encoding::{Encoding, EncoderTrap, DecoderTrap};
 use encoding::all::UTF_16LE;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use ::{TdsResult};
+use ::{TdsResult, TdsError, TdsProtocolError};
+
+/// read `len` bytes of a message, a length exceeding the rest of the message (e.g. a bogus length prefix)
+/// is rejected before anything is allocated
+pub fn read_bytes<T: AsRef<[u8]>>(cursor: &mut Cursor<T>, len: usize) -> TdsResult<Vec<u8>> {
+    let remaining = remaining_len(cursor);
+    if len > remaining {
+        return Err(TdsError::ProtocolError(TdsProtocolError::InvalidLength(format!("read: length of {} exceeds the remaining {} bytes", len, remaining))))
+    }
+    let mut buf = vec![0; len];
+    try!(cursor.read_exact(&mut buf));
+    Ok(buf)
+}
+
+/// overwrite a buffer which held a secret (e.g. the password) with zeroes,
+/// volatile so the writes to a buffer which is freed afterwards are not optimized away
+pub fn wipe(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    atomic::compiler_fence(atomic::Ordering::SeqCst);
+}
+
+/// like `wipe`, but for the whole allocation of the buffer, whose spare capacity may still hold a secret
+/// (e.g. after `truncate` or `split_off`)
+pub fn wipe_vec(buf: &mut Vec<u8>) {
+    let ptr = buf.as_mut_ptr();
+    for i in 0..buf.capacity() {
+        unsafe { ptr::write_volatile(ptr.offset(i as isize), 0) };
+    }
+    atomic::compiler_fence(atomic::Ordering::SeqCst);
+}
+
+/// the number of bytes left to read in a message
+pub fn remaining_len<T: AsRef<[u8]>>(cursor: &Cursor<T>) -> usize {
+    (cursor.get_ref().as_ref().len() as u64).saturating_sub(cursor.position()) as usize
+}
 
 #[doc(hidden)]
 pub trait WriteCStr {
@@ -50,7 +89,7 @@ impl<R: Read> ReadCharStream for R {
     fn read_varchar(&mut self, length: usize) -> TdsResult<String> {
         let length = length * 2;
         let mut bytes: Vec<u8> = vec![0; length];
-        assert_eq!(try!(self.read(&mut bytes[..])), length);
+        try!(self.read_exact(&mut bytes[..]));
         Ok(try!(UTF_16LE.decode(&bytes, DecoderTrap::Strict)))
     }
 
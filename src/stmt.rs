@@ -1,12 +1,15 @@
 use std::borrow::Cow;
 use std::convert::From;
-use std::cell::RefCell;
+use std::cell::{Ref, RefCell};
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::io::{Cursor, Write};
+use std::panic::{self, AssertUnwindSafe};
 use std::rc::Rc;
 use protocol::*;
-use conn::{Connection};
-use types::{ColumnType, ColumnValue, ToColumnType};
-use ::{TargetStream, TdsResult, TdsError};
+use conn::{Connection, InternalConnection};
+use types::{ColumnType, ColumnValue, ToColumnType, FromColumnValue};
+use ::{TargetStream, TdsResult, TdsError, ServerError, ServerMessage};
 
 #[derive(Debug)]
 #[doc(hidden)]
@@ -14,6 +17,8 @@ pub struct StatementInfo {
     pub column_infos: Vec<ColumnData>,
     /// The handle for e.g. prepared statements
     pub handle: Option<u32>,
+    /// The parameters a prepared statement was prepared with
+    pub params: Vec<ParamInfo>,
 }
 
 impl StatementInfo {
@@ -21,6 +26,7 @@ impl StatementInfo {
         StatementInfo {
             column_infos: vec![],
             handle: None,
+            params: vec![],
         }
     }
 }
@@ -43,16 +49,55 @@ impl RowIndex for usize {
     }
 }
 
+/// the name of a column compared case-insensitively (ASCII only), like identifiers under most collations
+/// if multiple columns only differ in case the first one is used, see `CaseSensitive`
 impl<'a> RowIndex for &'a str {
     fn get_index(&self, row: &Row) -> Option<usize> {
-        for (idx, column) in row.stmt.borrow().column_infos.iter().enumerate() {
-            match column.col_name {
-                Some(ref col_name) if col_name == *self => return Some(idx),
-                _ => ()
+        column_index(row, |col_name| col_name.eq_ignore_ascii_case(self))
+    }
+}
+
+/// the name of a column compared case-sensitively, e.g. `row.get(CaseSensitive("Name"))`
+#[derive(Clone, Copy, Debug)]
+pub struct CaseSensitive<'a>(pub &'a str);
+
+impl<'a> RowIndex for CaseSensitive<'a> {
+    fn get_index(&self, row: &Row) -> Option<usize> {
+        column_index(row, |col_name| col_name == self.0)
+    }
+}
+
+fn column_index<F: Fn(&str) -> bool>(row: &Row, matches: F) -> Option<usize> {
+    row.stmt.borrow().column_infos.iter().position(|column| match column.col_name {
+        Some(ref col_name) => matches(col_name),
+        None => false
+    })
+}
+
+/// the names of the columns, an unnamed column is named `column_{idx}`
+fn column_names(columns: &[ColumnData]) -> Vec<String> {
+    columns.iter().enumerate().map(|(idx, column)| match column.col_name {
+        Some(ref name) if !name.is_empty() => name.clone(),
+        _ => format!("column_{}", idx)
+    }).collect()
+}
+
+/// write a line of CSV fields, quoting fields containing a separator, quote or line break
+fn write_csv_line<'a, W: Write, I: Iterator<Item = Option<&'a str>>>(w: &mut W, fields: I) -> TdsResult<()> {
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            try!(w.write_all(b","));
+        }
+        if let Some(field) = field {
+            if field.contains(|c| c == ',' || c == '"' || c == '\r' || c == '\n') {
+                try!(write!(w, "\"{}\"", field.replace("\"", "\"\"")));
+            } else {
+                try!(w.write_all(field.as_bytes()));
             }
         }
-        None
     }
+    try!(w.write_all(b"\r\n"));
+    Ok(())
 }
 
 impl<'a> Row<'a> {
@@ -66,17 +111,147 @@ impl<'a> Row<'a> {
             None => panic!("type mismatch for: {}, got instead: {:?}", idx, self.values[idx])
         }
     }
+
+    /// like `get`, but return an error instead of panicking on an unknown index or a type mismatch
+    pub fn try_get<I: RowIndex + Debug, T: FromColumnValue<'a>>(&'a self, idx: I) -> TdsResult<T> {
+        match idx.get_index(self) {
+            Some(x) if x < self.values.len() => T::from_column_value(&self.values[x]),
+            _ => Err(TdsError::Other(format!("unknown index: {:?}", idx)))
+        }
+    }
+
+    /// append the bytes of a binary column to `buf`, which allows reusing one buffer for many rows
+    /// returns the number of appended bytes or `None` if the value is NULL
+    pub fn read_bytes_into<I: RowIndex + Debug>(&'a self, idx: I, buf: &mut Vec<u8>) -> TdsResult<Option<usize>> {
+        let bytes: Option<&[u8]> = try!(self.try_get(idx));
+        Ok(bytes.map(|bytes| {
+            buf.extend_from_slice(bytes);
+            bytes.len()
+        }))
+    }
+
+    /// the number of values (columns) of the row
+    pub fn column_count(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// the metadata (name and type) of the columns of the row
+    pub fn columns(&self) -> Ref<[ColumnData]> {
+        Ref::map(self.stmt.borrow(), |stmt| &stmt.column_infos[..])
+    }
+
+    /// consume the row and return its values ordered by column
+    pub fn into_values(self) -> Vec<ColumnValue<'a>> {
+        self.values
+    }
+
+    /// the values keyed by the names of their columns, unnamed columns (e.g. an expression without
+    /// an alias) are named `column_{idx}` and of columns sharing a name only the first is kept
+    pub fn as_map(&self) -> HashMap<String, &ColumnValue<'a>> {
+        let mut map = HashMap::with_capacity(self.values.len());
+        for (name, value) in column_names(&self.stmt.borrow().column_infos).into_iter().zip(self.values.iter()) {
+            map.entry(name).or_insert(value);
+        }
+        map
+    }
+
+    /// like `as_map`, but consumes the row
+    pub fn into_map(self) -> HashMap<String, ColumnValue<'a>> {
+        let mut map = HashMap::with_capacity(self.values.len());
+        for (name, value) in column_names(&self.stmt.borrow().column_infos).into_iter().zip(self.values.into_iter()) {
+            map.entry(name).or_insert(value);
+        }
+        map
+    }
+
+    /// copy borrowed values, e.g. to keep the row after the connection is gone
+    pub fn into_owned(self) -> Row<'static> {
+        Row {
+            stmt: self.stmt,
+            values: self.values.into_iter().map(|x| x.into_static()).collect(),
+        }
+    }
+
+    /// map the columns to the fields of `T` by their names, use `Option` for nullable columns
+    #[cfg(feature = "row")]
+    pub fn deserialize<T: ::serde::Deserialize<'a>>(&'a self) -> TdsResult<T> {
+        let stmt = self.stmt.borrow();
+        let columns = stmt.column_infos.iter().zip(self.values.iter())
+            .map(|(column, value)| (column.col_name.clone().unwrap_or_default(), value))
+            .collect();
+        T::deserialize(::de::RowDeserializer::new(columns))
+    }
+}
+
+/// Map a row to a value, e.g. a struct of owned values (see `Connection::query_as`)
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> TdsResult<Self>;
+}
+
+macro_rules! from_row_tuple {
+    ($($ty:ident: $idx:expr),+) => {
+        /// maps the columns by their position
+        impl<$($ty: for<'b> FromColumnValue<'b>),+> FromRow for ($($ty,)+) {
+            fn from_row(row: &Row) -> TdsResult<Self> {
+                Ok(($(try!(row.try_get::<_, $ty>($idx)),)+))
+            }
+        }
+    }
+}
+from_row_tuple!(A: 0);
+from_row_tuple!(A: 0, B: 1);
+from_row_tuple!(A: 0, B: 1, C: 2);
+from_row_tuple!(A: 0, B: 1, C: 2, D: 3);
+from_row_tuple!(A: 0, B: 1, C: 2, D: 3, E: 4);
+from_row_tuple!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+
+/// A column the server ordered the rows by (ORDER token), describing how its values compare
+/// NULL compares lower than any other value, so it comes first in ascending order
+#[derive(Clone, Debug)]
+pub struct SortColumn {
+    /// the index of the column within the row, starting at 0
+    pub index: usize,
+    pub name: Option<String>,
+    /// the collation of a (non-unicode or unicode) character column
+    pub collation: Option<Collation>,
+}
+
+/// The name and type of a column of a resultset, see `Connection::describe`
+#[derive(Clone, Debug)]
+pub struct ColumnInfo {
+    pub name: Option<String>,
+    pub type_info: TypeInfo,
+    pub nullable: bool,
+}
+
+impl<'a> From<&'a ColumnData> for ColumnInfo {
+    fn from(column: &'a ColumnData) -> ColumnInfo {
+        ColumnInfo {
+            name: column.col_name.clone(),
+            type_info: column.type_info.clone(),
+            // fNullable
+            nullable: column.flags & 1 != 0,
+        }
+    }
 }
 
 /// The resultset of a query (containing the resulting rows)
 #[derive(Debug)]
 pub struct QueryResult<'a> {
     rows: Option<Vec<Row<'a>>>,
-    //stmt: Rc<RefCell<StatementInfo>>
+    messages: Vec<ServerMessage>,
+    order: Vec<SortColumn>,
+    /// the error of a row which failed to decode, which ended the rows
+    row_error: Option<TdsError>,
+    stmt: Rc<RefCell<StatementInfo>>
 }
 
 impl<'a> QueryResult<'a> {
-    /// return the number of contained rows
+    /// return the number of contained rows, not including a malformed row and the rows following it
     pub fn len(&self) -> usize {
         match self.rows {
             None => 0,
@@ -96,13 +271,83 @@ impl<'a> QueryResult<'a> {
         }
         panic!("queryresult: get: idx out of bounds");
     }
+
+    /// the metadata (name and type) of the columns, also available if the query returned no rows
+    /// (e.g. `SELECT * FROM t WHERE 1 = 0` to discover the schema of a table)
+    pub fn columns(&self) -> Ref<[ColumnData]> {
+        Ref::map(self.stmt.borrow(), |stmt| &stmt.column_infos[..])
+    }
+
+    /// the informational messages (e.g. of `PRINT`) the server sent along with the rows
+    pub fn messages(&self) -> &[ServerMessage] {
+        &self.messages
+    }
+
+    /// the columns of an `ORDER BY` in the order of their precedence, empty if the rows are unordered
+    /// the server does not send the direction of the ordering
+    pub fn order(&self) -> &[SortColumn] {
+        &self.order
+    }
+
+    /// copy the borrowed values of all rows, e.g. to return the result from a function
+    pub fn into_owned(self) -> QueryResult<'static> {
+        QueryResult {
+            rows: self.rows.map(|rows| rows.into_iter().map(Row::into_owned).collect()),
+            messages: self.messages,
+            order: self.order,
+            row_error: self.row_error,
+            stmt: self.stmt,
+        }
+    }
+
+    /// iterate over the rows, yielding the error of a row which failed to decode (after the rows before it)
+    pub fn try_iter(self) -> impl Iterator<Item = TdsResult<Row<'a>>> {
+        let rows = self.rows.unwrap_or_default();
+        rows.into_iter().map(Ok).chain(self.row_error.map(Err))
+    }
+
+    /// write the rows as CSV (RFC 4180): a header of the column names and a line per row,
+    /// values are rendered by their `Display` and NULL is an empty field
+    ///
+    /// the error of a row which failed to decode is returned after the rows before it are written
+    pub fn write_csv<W: Write>(&self, w: &mut W) -> TdsResult<()> {
+        let names = column_names(&self.columns());
+        try!(write_csv_line(w, names.iter().map(|name| Some(&name[..]))));
+        if let Some(ref rows) = self.rows {
+            for row in rows {
+                let values: Vec<Option<String>> = row.values.iter().map(|value| match *value {
+                    ColumnValue::Some(ref x) => Some(x.to_string()),
+                    ColumnValue::None => None
+                }).collect();
+                try!(write_csv_line(w, values.iter().map(|value| value.as_ref().map(|x| &x[..]))));
+            }
+        }
+        match self.row_error {
+            Some(ref err) => Err(TdsError::Other(format!("write_csv: a row failed to decode: {}", err))),
+            None => Ok(())
+        }
+    }
+
+    /// convert the values of a column of all rows, failing on the first value which cannot be converted
+    pub fn collect_column<T, I>(self, idx: I) -> TdsResult<Vec<T>> where T: for<'r> FromColumnValue<'r>, I: RowIndex + Debug + Copy {
+        self.collect_rows(|row| row.try_get(idx))
+    }
+
+    /// map all rows using `f`, failing on the first error of `f` or a row which failed to decode
+    pub fn collect_rows<T, F>(self, f: F) -> TdsResult<Vec<T>> where F: Fn(&Row) -> TdsResult<T> {
+        self.try_iter().map(|row| row.and_then(|row| f(&row))).collect()
+    }
 }
 
+/// panics if a row failed to decode, use `try_iter` to handle the error instead
 impl<'a> IntoIterator for QueryResult<'a> {
     type Item = Row<'a>;
     type IntoIter = ::std::vec::IntoIter<Row<'a>>;
 
     fn into_iter(self) -> Self::IntoIter {
+        if let Some(err) = self.row_error {
+            panic!("queryresult: a row failed to decode: {:?}", err);
+        }
         match self.rows {
             Some(x) => x.into_iter(),
             None => vec![].into_iter()
@@ -110,6 +355,57 @@ impl<'a> IntoIterator for QueryResult<'a> {
     }
 }
 
+/// A parameter of a stored procedure call
+pub struct Param<'a> {
+    name: Cow<'a, str>,
+    value: &'a ToColumnType,
+    output: bool,
+}
+
+impl<'a> Param<'a> {
+    pub fn new<N: Into<Cow<'a, str>>>(name: N, value: &'a ToColumnType) -> Param<'a> {
+        Param {
+            name: name.into(),
+            value: value,
+            output: false,
+        }
+    }
+
+    /// mark the parameter as `OUTPUT`, the given value is only used as initial value
+    pub fn output(mut self) -> Param<'a> {
+        self.output = true;
+        self
+    }
+}
+
+/// The result of a stored procedure call (containing the return status and output parameters)
+#[derive(Debug)]
+pub struct ProcResult<'a> {
+    return_status: Option<i32>,
+    return_values: Vec<(String, ColumnValue<'a>)>,
+}
+
+impl<'a> ProcResult<'a> {
+    /// the value of the `RETURN` statement of the procedure
+    pub fn return_status(&self) -> Option<i32> {
+        self.return_status
+    }
+
+    /// return the value of an output parameter, the leading `@` of the name is optional
+    pub fn get<T>(&'a self, name: &str) -> T where Option<T>: From<&'a ColumnValue<'a>> {
+        let name = name.trim_start_matches('@');
+        for &(ref param_name, ref value) in &self.return_values {
+            if param_name.trim_start_matches('@') == name {
+                return match From::from(value) {
+                    Some(x) => x,
+                    None => panic!("type mismatch for: {}, got instead: {:?}", name, value)
+                }
+            }
+        }
+        panic!("unknown output parameter: {:?}", name)
+    }
+}
+
 #[doc(hidden)]
 pub struct StatementInternal<'a, S: 'a + TargetStream> {
     conn: Connection<'a, S>,
@@ -117,34 +413,134 @@ pub struct StatementInternal<'a, S: 'a + TargetStream> {
     stmt: Rc<RefCell<StatementInfo>>,
 }
 
-fn handle_execute_packet(packet: &Packet) -> TdsResult<usize> {
+/// The status of a single statement as reported by its DONE token
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DoneStatus {
+    /// a combination of the DONE status flags (2.2.7.5)
+    pub status: u16,
+    /// the number of affected rows, if the server sent it (not with `SET NOCOUNT ON`)
+    pub row_count: Option<u64>,
+}
+
+impl DoneStatus {
+    fn from_token(token: &TokenStreamDone) -> DoneStatus {
+        DoneStatus {
+            status: token.status,
+            row_count: if token.status & TokenStreamDoneStatus::Count as u16 != 0 { Some(token.done_row_count) } else { None },
+        }
+    }
+
+    /// more results (of further statements) follow
+    pub fn more(&self) -> bool {
+        self.status & TokenStreamDoneStatus::More as u16 != 0
+    }
+
+    /// the statement failed
+    pub fn error(&self) -> bool {
+        self.status & TokenStreamDoneStatus::Error as u16 != 0
+    }
+
+    /// a transaction is in progress
+    pub fn in_xact(&self) -> bool {
+        self.status & TokenStreamDoneStatus::Inxact as u16 != 0
+    }
+}
+
+/// collect the status of all statements until the final DONE token of the batch
+/// or the final DONEPROC token of a RPC request
+fn handle_done_statuses(packet: &Packet) -> TdsResult<Vec<DoneStatus>> {
     if let Packet::TokenStream(ref tokens) = *packet {
+            let mut statuses = vec![];
             for token in tokens {
                 match *token {
                     TokenStream::Error(ref err) => {
-                        return Err(TdsError::ServerError(err.clone()))
+                        return Err(TdsError::from(err.clone()))
                     },
-                    TokenStream::Done(ref done_token) => {
-                        assert_eq!(done_token.status, TokenStreamDoneStatus::Count as u16);
-                        return Ok(done_token.done_row_count as usize)
+                    TokenStream::Done(ref done_token) | TokenStream::DoneInProc(ref done_token) => {
+                        statuses.push(DoneStatus::from_token(done_token));
+                        if let TokenStream::Done(_) = *token {
+                            if done_token.status & TokenStreamDoneStatus::More as u16 == 0 {
+                                return Ok(statuses)
+                            }
+                        }
                     },
-                    _ => return Err(TdsError::Other(format!("exec: unexpected TOKEN {:?}", token)))
+                    // the statements executed within the procedure were already reported by their DONEINPROC tokens
+                    TokenStream::DoneProc(ref done_token) => {
+                        if done_token.status & TokenStreamDoneStatus::More as u16 == 0 {
+                            return Ok(statuses)
+                        }
+                    },
+                    _ => ()
                 }
             }
     }
-    Err(TdsError::Other(format!("exec: Unexpected packet {:?}", packet)))
+    Err(TdsError::Other(format!("exec: did not receive a final DONE token {:?}", packet)))
 }
 
-fn handle_query_packet(packet: Packet, stmt: Rc<RefCell<StatementInfo>>) -> TdsResult<QueryResult> {
+/// the affected rows of each statement of a batch until the first error, which is returned as well:
+/// a statement is terminated by its DONE, or by the DONEPROC of a procedure (counting the rows of the
+/// procedure's statements reported by their DONEINPROC tokens)
+fn handle_batch_counts(packet: &Packet) -> TdsResult<(Vec<usize>, Option<ServerError>)> {
+    if let Packet::TokenStream(ref tokens) = *packet {
+        let mut counts = vec![];
+        let mut proc_count = 0;
+        for token in tokens {
+            let done_token = match *token {
+                TokenStream::Error(ref err) => return Ok((counts, Some(err.clone()))),
+                TokenStream::DoneInProc(ref done_token) => {
+                    proc_count += DoneStatus::from_token(done_token).row_count.unwrap_or(0) as usize;
+                    continue
+                },
+                TokenStream::Done(ref done_token) | TokenStream::DoneProc(ref done_token) => done_token,
+                _ => continue
+            };
+            counts.push(proc_count + DoneStatus::from_token(done_token).row_count.unwrap_or(0) as usize);
+            proc_count = 0;
+            if done_token.status & TokenStreamDoneStatus::More as u16 == 0 {
+                return Ok((counts, None))
+            }
+        }
+    }
+    Err(TdsError::Other(format!("exec: did not receive a final DONE token {:?}", packet)))
+}
+
+/// sum up the affected rows of all statements, statements without a count (`SET NOCOUNT ON`) count as 0
+fn handle_execute_packet(packet: &Packet) -> TdsResult<usize> {
+    let statuses = try!(handle_done_statuses(packet));
+    Ok(statuses.iter().map(|x| x.row_count.unwrap_or(0) as usize).sum())
+}
+
+/// resolve the (1-based) column ordinals of an ORDER token using the column metadata
+fn sort_columns(ordinals: &[u16], stmt: &StatementInfo) -> TdsResult<Vec<SortColumn>> {
+    ordinals.iter().map(|&ordinal| {
+        let column = match stmt.column_infos.get((ordinal as usize).wrapping_sub(1)) {
+            Some(column) => column,
+            None => return Err(TdsError::Other(format!("order: unknown column ordinal {}", ordinal))),
+        };
+        let collation = match column.type_info {
+            TypeInfo::VarLenType(_, _, ref collation) => collation.clone(),
+            _ => None,
+        };
+        Ok(SortColumn { index: ordinal as usize - 1, name: column.col_name.clone(), collation: collation })
+    }).collect()
+}
+
+fn handle_query_packet(packet: Packet, row_error: Option<TdsError>, stmt: Rc<RefCell<StatementInfo>>) -> TdsResult<QueryResult> {
     let mut query_result = QueryResult {
         rows: None,
+        messages: vec![],
+        order: vec![],
+        row_error: row_error,
+        stmt: stmt.clone(),
     };
     if let Packet::TokenStream(tokens) = packet {
             let mut rows = Vec::with_capacity(tokens.len());
             for token in tokens {
                 match token {
-                    TokenStream::Error(x) => return Err(TdsError::ServerError(x)),
+                    TokenStream::Error(x) => return Err(TdsError::from(x)),
                     TokenStream::Row(row) => rows.push(Row { values: row.data, stmt: stmt.clone() }),
+                    TokenStream::Info(msg) => query_result.messages.push(msg),
+                    TokenStream::Order(ordinals) => query_result.order = try!(sort_columns(&ordinals, &stmt.borrow())),
                     _ => ()
                 }
             }
@@ -164,20 +560,315 @@ impl<'a, S: 'a + TargetStream> StatementInternal<'a, S> {
     }
 
     pub fn execute_into_query(self) -> TdsResult<QueryResult<'a>> {
-        let mut conn = self.conn.borrow_mut();
+        let mut conn = self.conn.lock().unwrap();
+        try!(conn.internal_exec(&self.query));
+        let (packet, row_error) = try!(conn.read_query_message(&mut *self.stmt.borrow_mut()));
+        handle_query_packet(packet, row_error, self.stmt)
+    }
+
+    /// like `execute_into_query`, but the statement can be executed again (e.g. to retry it),
+    /// each result keeps the columns of its own execution
+    pub fn execute_query(&mut self) -> TdsResult<QueryResult<'a>> {
+        self.reset();
+        let mut conn = self.conn.lock().unwrap();
+        try!(conn.internal_exec(&self.query));
+        let (packet, row_error) = try!(conn.read_query_message(&mut *self.stmt.borrow_mut()));
+        handle_query_packet(packet, row_error, self.stmt.clone())
+    }
+
+    /// forget the columns of the previous execution, results of it which are still alive are not affected
+    pub fn reset(&mut self) {
+        self.stmt = Rc::new(RefCell::new(StatementInfo::new()));
+    }
+
+    pub fn execute_into_query_cancellable(self, cancel: &Fn() -> bool) -> TdsResult<QueryResult<'a>> {
+        let mut conn = self.conn.lock().unwrap();
+        try!(conn.internal_exec(&self.query));
+        let (packet, row_error) = try!(try!(conn.read_message_cancellable(cancel)).into_query_token_stream(&mut *self.stmt.borrow_mut()));
+        conn.process_tokens(&packet);
+        handle_query_packet(packet, row_error, self.stmt)
+    }
+
+    /// call `f` for each row as it is read, cancelling the query if it fails
+    pub fn execute_each<F>(self, mut f: F) -> TdsResult<()> where F: FnMut(&Row) -> TdsResult<()> {
+        {
+            let mut conn = self.conn.lock().unwrap();
+            try!(conn.internal_exec(&self.query));
+            conn.reading_rows = true;
+        }
+        let result = self.read_each(&mut f);
+        self.conn.lock().unwrap().reading_rows = false;
+        result
+    }
+
+    /// read the response of `execute_each` packet by packet, calling `f` for the rows of a packet once
+    /// the lock of the connection is released (so a panic in `f` does not poison it)
+    fn read_each<F>(&self, f: &mut F) -> TdsResult<()> where F: FnMut(&Row) -> TdsResult<()> {
+        let mut buf = vec![];
+        let mut server_error = None;
+        loop {
+            let mut rows = vec![];
+            let last = {
+                let mut conn = self.conn.lock().unwrap();
+                let packet = try!(conn.opts.stream.read_packet());
+                let last = packet.header.status != PacketStatus::NormalMessage;
+                buf.extend(packet.data);
+                // a token which is split across packets is decoded again once the next packet arrived
+                let mut pos = 0;
+                while pos < buf.len() {
+                    let token = {
+                        let mut cursor = Cursor::new(&buf[pos..]);
+                        match decode_stmt_token(&mut cursor, &mut *self.stmt.borrow_mut()) {
+                            Ok(token) => {
+                                pos += cursor.position() as usize;
+                                token
+                            },
+                            Err(err) => if last { return Err(err) } else { break }
+                        }
+                    };
+                    conn.process_token(&token);
+                    match token {
+                        // like the other requests, the remaining response is read before the error is returned
+                        TokenStream::Error(err) => if server_error.is_none() {
+                            server_error = Some(err);
+                        },
+                        TokenStream::Row(row) => if server_error.is_none() {
+                            rows.push(Row { values: row.data, stmt: self.stmt.clone() });
+                        },
+                        _ => ()
+                    }
+                }
+                buf.drain(..pos);
+                last
+            };
+            for row in &rows {
+                let result = match panic::catch_unwind(AssertUnwindSafe(|| f(row))) {
+                    Ok(result) => result,
+                    Err(cause) => {
+                        let _ = self.stop_each(last, &buf);
+                        panic::resume_unwind(cause)
+                    }
+                };
+                if let Err(err) = result {
+                    try!(self.stop_each(last, &buf));
+                    return Err(err)
+                }
+            }
+            if last {
+                break
+            }
+        }
+        match server_error {
+            Some(err) => Err(TdsError::from(err)),
+            None => Ok(())
+        }
+    }
+
+    /// stop reading the rows of `execute_each`, cancelling the rest of the response unless it was read,
+    /// `pending` are the bytes of the packets read which were not decoded yet
+    fn stop_each(&self, last: bool, pending: &[u8]) -> TdsResult<()> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.reading_rows = false;
+        if !last {
+            try!(conn.cancel_request(pending, &mut *self.stmt.borrow_mut()));
+        }
+        Ok(())
+    }
+
+    pub fn execute_statuses(&mut self) -> TdsResult<Vec<DoneStatus>> {
+        let mut conn = self.conn.lock().unwrap();
         try!(conn.internal_exec(&self.query));
-        let packet = try!(try!(conn.opts.stream.read_message()).into_stmt_token_stream(&mut *self.stmt.borrow_mut()));
-        handle_query_packet(packet, self.stmt)
+        let packet = try!(conn.read_stmt_message(&mut *self.stmt.borrow_mut()));
+        handle_done_statuses(&packet)
+    }
+
+    /// execute a batch, returning the affected rows of each statement until the first error and that error
+    pub fn execute_batch(&mut self) -> TdsResult<(Vec<usize>, Option<ServerError>)> {
+        let mut conn = self.conn.lock().unwrap();
+        try!(conn.internal_exec(&self.query));
+        let packet = try!(conn.read_stmt_message(&mut *self.stmt.borrow_mut()));
+        handle_batch_counts(&packet)
     }
 
     pub fn execute(&mut self) -> TdsResult<usize> {
-        let mut conn = self.conn.borrow_mut();
+        let mut conn = self.conn.lock().unwrap();
         try!(conn.internal_exec(&self.query));
-        let packet = try!(conn.read_packet());
+        // the whole response (until EOM) has to be consumed, so we do not leave any tokens for the next request
+        let packet = try!(conn.read_stmt_message(&mut *self.stmt.borrow_mut()));
+        handle_execute_packet(&packet)
+    }
+
+    /// execute a statement producing both affected counts and rows, e.g. using an `OUTPUT` clause
+    pub fn execute_returning(self) -> TdsResult<(usize, QueryResult<'a>)> {
+        let mut conn = self.conn.lock().unwrap();
+        try!(conn.internal_exec(&self.query));
+        let packet = try!(conn.read_stmt_message(&mut *self.stmt.borrow_mut()));
+        let count = try!(handle_execute_packet(&packet));
+        Ok((count, try!(handle_query_packet(packet, None, self.stmt))))
+    }
+
+    /// Call the stored procedure named by the query (RPC) and collect its output parameters
+    pub fn execute_proc(&self, params: &[Param]) -> TdsResult<ProcResult<'a>> {
+        let empty_as_null = self.conn.lock().unwrap().opts.empty_strings_as_null;
+        let mut params_meta = Vec::with_capacity(params.len());
+        for param in params {
+            let value = param.value.to_column_type();
+            params_meta.push(RpcParamData {
+                name: param.name.clone(),
+                status_flags: if param.output { rpc::fByRefValue } else { 0 },
+                is_null: bind_as_null(param.value, &value, empty_as_null),
+                value: value,
+            });
+        }
+        let rpc_req = RpcRequestData {
+            proc_id: RpcProcIdValue::Name(self.query.clone()),
+            flags: 0,
+            params: params_meta,
+        };
+        let mut conn = self.conn.lock().unwrap();
+        try!(conn.send_packet(&Packet::RpcRequest(&rpc_req)));
+        let packet = try!(conn.read_stmt_message(&mut *self.stmt.borrow_mut()));
+
+        let mut result = ProcResult {
+            return_status: None,
+            return_values: vec![],
+        };
+        if let Packet::TokenStream(tokens) = packet {
+            for token in tokens {
+                match token {
+                    TokenStream::Error(x) => return Err(TdsError::from(x)),
+                    TokenStream::ReturnStatus(status) => result.return_status = Some(status),
+                    TokenStream::ReturnValue(retval) => {
+                        if let Some(data) = retval.data {
+                            result.return_values.push((retval.name, data));
+                        }
+                    },
+                    _ => ()
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// declare the parameters `@P1, @P2, ...` with the types of the given values, e.g. `@P1 int,@P2 nvarchar(4000)`
+pub fn param_declarations(params: &[&ToColumnType]) -> String {
+    let mut param_str = String::new();
+    for (i, param) in params.iter().enumerate() {
+        if i > 0 {
+            param_str.push(',')
+        }
+        param_str.push_str(&format!("@P{} ", i + 1));
+        param_str.push_str(&param.declared_type());
+    }
+    param_str
+}
+
+/// bind the values of the parameters `@P1, @P2, ...`
+fn param_values<'p>(params: &[&'p ToColumnType], empty_as_null: bool) -> Vec<RpcParamData<'p>> {
+    params.iter().enumerate().map(|(i, param)| {
+        let value = param.to_column_type();
+        RpcParamData {
+            name: Cow::Owned(format!("@P{}", i + 1)),
+            status_flags: 0,
+            is_null: bind_as_null(*param, &value, empty_as_null),
+            value: value,
+        }
+    }).collect()
+}
+
+/// whether a parameter is sent as NULL, which empty strings are with `empty_strings_as_null`
+fn bind_as_null(param: &ToColumnType, value: &ColumnType, empty_as_null: bool) -> bool {
+    param.is_null() || empty_as_null && match *value {
+        ColumnType::String(ref x) => x.is_empty(),
+        _ => false
+    }
+}
+
+/// A type whose fields are bound as the parameters `@P1, @P2, ...` in their order,
+/// e.g. to insert a struct (`stmt.exec(&user.bind())`), the counterpart of `FromRow`
+pub trait Parameters {
+    fn bind(&self) -> Vec<&ToColumnType>;
+
+    /// declare the parameters with the types of the bound values (see `param_declarations`)
+    fn declarations(&self) -> String {
+        param_declarations(&self.bind())
+    }
+}
+
+/// A statement executed once with `sp_executesql`, the parameter declarations are derived from the bound values
+pub struct ParameterizedStatement<'a, S: 'a + TargetStream> {
+    conn: Connection<'a, S>,
+    stmt: Rc<RefCell<StatementInfo>>,
+    sql: Cow<'a, str>,
+}
+
+impl<'a, S: 'a + TargetStream> ParameterizedStatement<'a, S> {
+    pub fn new(conn: Connection<'a, S>, sql: Cow<'a, str>) -> TdsResult<ParameterizedStatement<'a, S>> {
+        Ok(ParameterizedStatement {
+            conn: conn,
+            sql: sql,
+            stmt: Rc::new(RefCell::new(StatementInfo::new())),
+        })
+    }
+
+    fn send<'b>(&self, params: &[&ToColumnType]) -> TdsResult<Packet<'b>> {
+        let mut params_meta = vec![
+            RpcParamData {
+                name: Cow::Borrowed(""),
+                status_flags: 0,
+                value: ColumnType::String(self.sql.clone()),
+                is_null: false,
+            },
+            RpcParamData {
+                name: Cow::Borrowed(""),
+                status_flags: 0,
+                value: ColumnType::String(Cow::Owned(param_declarations(params))),
+                is_null: false,
+            },
+        ];
+        let empty_as_null = self.conn.lock().unwrap().opts.empty_strings_as_null;
+        params_meta.extend(param_values(params, empty_as_null));
+        let rpc_req = RpcRequestData {
+            proc_id: RpcProcIdValue::Name(Cow::Borrowed("sp_executesql")),
+            flags: 0,
+            params: params_meta,
+        };
+        let mut conn = self.conn.lock().unwrap();
+        try!(conn.send_packet(&Packet::RpcRequest(&rpc_req)));
+        conn.read_stmt_message(&mut *self.stmt.borrow_mut())
+    }
+
+    /// Execute the statement and return the resulting rows
+    pub fn query<'b>(&self, params: &[&ToColumnType]) -> TdsResult<QueryResult<'b>> {
+        let packet = try!(self.send(params));
+        handle_query_packet(packet, None, self.stmt.clone())
+    }
+
+    /// Execute the statement and return the number of affected rows
+    pub fn exec(&self, params: &[&ToColumnType]) -> TdsResult<usize> {
+        let packet = try!(self.send(params));
         handle_execute_packet(&packet)
     }
 }
 
+/// A parameter of a prepared statement
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParamInfo {
+    /// the position of the parameter starting at 1, it is named `@P{ordinal}`
+    pub ordinal: usize,
+    /// the declared type of the parameter, e.g. `nvarchar(4000)`
+    pub type_name: String,
+}
+
+/// the parameters as declared by `param_declarations`
+fn param_infos(params: &[&ToColumnType]) -> Vec<ParamInfo> {
+    params.iter().enumerate().map(|(i, param)| ParamInfo {
+        ordinal: i + 1,
+        type_name: param.declared_type().into_owned(),
+    }).collect()
+}
+
 pub struct PreparedStatement<'a, S: 'a + TargetStream> {
     conn: Connection<'a, S>,
     stmt: Rc<RefCell<StatementInfo>>,
@@ -193,17 +884,55 @@ impl<'a, S: 'a + TargetStream> PreparedStatement<'a, S> {
         })
     }
 
-    /// Prepares the actual statement (sp_prepare)
-    fn do_prepare(&self, stmt: &mut StatementInfo, params: &[&ToColumnType]) -> TdsResult<()> {
-        let mut param_str = String::new();
-        // determine the types from the given params
-        for (i, param) in params.iter().enumerate() {
-            if i > 0 {
-                param_str.push(',')
+    /// The parameters with the types the statement was prepared with,
+    /// empty until the statement is prepared by its first execution
+    pub fn parameters(&self) -> Ref<[ParamInfo]> {
+        Ref::map(self.stmt.borrow(), |stmt| &stmt.params[..])
+    }
+
+    /// The parameters with the types the server infers from their usage (`sp_describe_undeclared_parameters`)
+    fn describe_parameters(&self, conn: &mut InternalConnection<'a, S>) -> TdsResult<Vec<ParamInfo>> {
+        let sql = format!("EXEC sp_describe_undeclared_parameters N'{}';", self.sql.replace("'", "''"));
+        let stmt = Rc::new(RefCell::new(StatementInfo::new()));
+        try!(conn.internal_exec(&sql));
+        let (packet, row_error) = try!(conn.read_query_message(&mut *stmt.borrow_mut()));
+        let mut infos = vec![];
+        for row in try!(handle_query_packet(packet, row_error, stmt)).try_iter() {
+            let row = try!(row);
+            let ordinal: i32 = try!(row.try_get("parameter_ordinal"));
+            let type_name: &str = try!(row.try_get("suggested_system_type_name"));
+            infos.push(ParamInfo {
+                ordinal: ordinal as usize,
+                type_name: type_name.to_owned(),
+            });
+        }
+        Ok(infos)
+    }
+
+    /// Compare the bound types with the types the server infers for the parameters
+    /// and fail on unicode strings compared to non-unicode columns
+    fn check_strict_binding(&self, conn: &mut InternalConnection<'a, S>, params: &[&ToColumnType]) -> TdsResult<()> {
+        for info in try!(self.describe_parameters(conn)) {
+            let param = match params.get(info.ordinal - 1) {
+                Some(param) => param,
+                None => continue
+            };
+            let bound = param.column_type();
+            let suggested = &info.type_name;
+            if bound.starts_with("nvarchar") && (suggested.starts_with("varchar") || suggested.starts_with("char")) {
+                return Err(TdsError::Other(format!("strict binding: parameter @P{} is bound as {} but used as {}, the implicit conversion prevents using an index",
+                    info.ordinal, bound, suggested)))
             }
-            param_str.push_str(&format!("@P{} ", i + 1));
-            param_str.push_str(param.column_type());
         }
+        Ok(())
+    }
+
+    /// Prepares the actual statement (sp_prepare)
+    fn do_prepare(&self, conn: &mut InternalConnection<'a, S>, stmt: &mut StatementInfo, params: &[&ToColumnType]) -> TdsResult<()> {
+        if conn.opts.strict_binding {
+            try!(self.check_strict_binding(conn, params));
+        }
+        let param_str = param_declarations(params);
         // for some reason mssql fails when we pass "handle" as int4 (fixed len) insteadof intn (varlen)
         // because it does not know the type (0x38) - probably since int4 was "deprecated" ages ago?
         let params_meta = vec![
@@ -211,16 +940,19 @@ impl<'a, S: 'a + TargetStream> PreparedStatement<'a, S> {
                 name: Cow::Borrowed("handle"),
                 status_flags: rpc::fByRefValue,
                 value: ColumnType::I32(0),
+                is_null: false,
             },
             RpcParamData {
                 name: Cow::Borrowed("params"),
                 status_flags: 0,
-                value: ColumnType::String(Cow::Owned(param_str))
+                value: ColumnType::String(Cow::Owned(param_str)),
+                is_null: false,
             },
             RpcParamData {
                 name: Cow::Borrowed("stmt"),
                 status_flags: 0,
                 value: ColumnType::String(self.sql.clone()),
+                is_null: false,
             }
         ];
         let rpc_req = RpcRequestData {
@@ -229,10 +961,9 @@ impl<'a, S: 'a + TargetStream> PreparedStatement<'a, S> {
             params: params_meta,
         };
         let rpc_packet = Packet::RpcRequest(&rpc_req);
-        let mut conn = self.conn.borrow_mut();
         try!(conn.send_packet(&rpc_packet));
         {
-            let packet = try!(try!(conn.opts.stream.read_message()).into_stmt_token_stream(stmt));
+            let packet = try!(conn.read_stmt_message(stmt));
             try!(packet.catch_error());
             match packet {
                 Packet::TokenStream(ref tokens) => {
@@ -255,35 +986,60 @@ impl<'a, S: 'a + TargetStream> PreparedStatement<'a, S> {
                 return Err(TdsError::Other(format!("prepare: did not receive a handle id {:?}", packet)))
             }
         }
+        stmt.params = param_infos(params);
+        Ok(())
+    }
+
+    /// Prepare the statement unless it is already, using the statement cache of the connection if enabled
+    ///
+    /// the lookup, prepare and insert happen under the lock of the caller, so another owner of the
+    /// connection cannot prepare the same statement or evict (and unprepare) the handle in between
+    fn ensure_prepared(&self, conn: &mut InternalConnection<'a, S>, stmt: &mut StatementInfo, params: &[&ToColumnType]) -> TdsResult<()> {
+        if conn.opts.statement_cache_size == 0 {
+            if stmt.handle.is_none() {
+                try!(self.do_prepare(conn, stmt, params));
+            }
+            return Ok(())
+        }
+        // the cache owns the handle (which is unprepared when evicted), so it is looked up for every execution
+        // the handle is only valid for the parameter types it was prepared with
+        let key = format!("{}\n{}", param_declarations(params), self.sql);
+        match conn.cached_statement(&key) {
+            Some((handle, column_infos)) => {
+                stmt.handle = Some(handle);
+                stmt.column_infos = column_infos;
+                stmt.params = param_infos(params);
+            },
+            None => {
+                stmt.handle = None;
+                try!(self.do_prepare(conn, stmt, params));
+                try!(conn.cache_statement(key, stmt.handle.unwrap(), stmt.column_infos.clone()));
+            }
+        }
         Ok(())
     }
 
     /// Execute the statement (sp_execute)
     #[inline]
-    fn do_internal_exec(&self, stmt: &mut StatementInfo, params: &[&ToColumnType]) -> TdsResult<()> {
+    fn do_internal_exec(&self, conn: &mut InternalConnection<'a, S>, stmt: &mut StatementInfo, params: &[&ToColumnType]) -> TdsResult<()> {
         let mut params_meta = vec![
             RpcParamData {
                 name: Cow::Borrowed("handle"),
                 status_flags: rpc::fByRefValue,
                 value: ColumnType::I32(stmt.handle.unwrap() as i32),
+                is_null: false,
             },
         ];
-        for (i, param) in params.iter().enumerate() {
-            params_meta.push(RpcParamData {
-                name: Cow::Owned(format!("@P{}", i+1)),
-                status_flags: 0,
-                value: param.to_column_type(),
-            });
-        }
+        params_meta.extend(param_values(params, conn.opts.empty_strings_as_null));
 
         let rpc_req = RpcRequestData {
             // as freeTDS, use sp_execute since SpPrepare (as int) seems broken, even microsofts odbc driver seems to use this
             proc_id: RpcProcIdValue::Name(Cow::Borrowed("sp_execute")),
+            // the rows are decoded using the metadata of the sp_prepare response (or the statement cache), kept in `stmt`
             flags: rpc::fNoMetaData,
             params: params_meta,
         };
         let rpc_packet = Packet::RpcRequest(&rpc_req);
-        let mut conn = self.conn.borrow_mut();
         try!(conn.send_packet(&rpc_packet));
         Ok(())
     }
@@ -291,16 +1047,42 @@ impl<'a, S: 'a + TargetStream> PreparedStatement<'a, S> {
     /// Makes sure the statement is prepared, since we lazily prepare statements
     /// and then executes the statement, handling it as a query and therefore returning the results as rows
     pub fn query<'b>(&self, params: &[&ToColumnType]) -> TdsResult<QueryResult<'b>> {
-        let packet;
-        {
-            let mut stmt = &mut * self.stmt.borrow_mut();
-            if stmt.handle.is_none() {
-                try!(self.do_prepare(stmt, params));
-            }
-            try!(self.do_internal_exec(stmt, params));
-            let mut conn = self.conn.borrow_mut();
-            packet = try!(try!(conn.opts.stream.read_message()).into_stmt_token_stream(stmt));
-        }
-        handle_query_packet(packet, self.stmt.clone())
+        let (packet, row_error) = {
+            let stmt = &mut *self.stmt.borrow_mut();
+            // one lock from preparing to reading the response, another owner of the connection cannot interleave
+            let mut conn = self.conn.lock().unwrap();
+            try!(self.ensure_prepared(&mut conn, stmt, params));
+            try!(self.do_internal_exec(&mut conn, stmt, params));
+            try!(conn.read_query_message(stmt))
+        };
+        handle_query_packet(packet, row_error, self.stmt.clone())
+    }
+
+    /// Like `query`, but also returns the number of affected rows, e.g. for `UPDATE ... OUTPUT`
+    pub fn query_with_count<'b>(&self, params: &[&ToColumnType]) -> TdsResult<(usize, QueryResult<'b>)> {
+        let (packet, row_error) = {
+            let stmt = &mut *self.stmt.borrow_mut();
+            let mut conn = self.conn.lock().unwrap();
+            try!(self.ensure_prepared(&mut conn, stmt, params));
+            try!(self.do_internal_exec(&mut conn, stmt, params));
+            try!(conn.read_query_message(stmt))
+        };
+        let count = try!(handle_execute_packet(&packet));
+        Ok((count, try!(handle_query_packet(packet, row_error, self.stmt.clone()))))
+    }
+
+    /// Makes sure the statement is prepared and executes it, returning the number of affected rows
+    pub fn execute(&self, params: &[&ToColumnType]) -> TdsResult<usize> {
+        let stmt = &mut *self.stmt.borrow_mut();
+        let mut conn = self.conn.lock().unwrap();
+        try!(self.ensure_prepared(&mut conn, stmt, params));
+        try!(self.do_internal_exec(&mut conn, stmt, params));
+        let packet = try!(conn.read_stmt_message(stmt));
+        handle_execute_packet(&packet)
+    }
+
+    #[deprecated(since = "0.1.0", note = "renamed to `execute`")]
+    pub fn exec(&self, params: &[&ToColumnType]) -> TdsResult<usize> {
+        self.execute(params)
     }
 }
@@ -0,0 +1,153 @@
+use std::cell::Cell;
+use std::ops::Deref;
+use std::time::Duration;
+use conn::{Connection, TargetStream};
+use stmt::StatementInfo;
+use ::{TdsResult, TdsError};
+
+/// The importance of a session when the server chooses the victim of a deadlock,
+/// the session with the lowest priority is rolled back (`SET DEADLOCK_PRIORITY`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeadlockPriority {
+    Low,
+    Normal,
+    High,
+    /// a priority from -10 to 10
+    Numeric(i8),
+}
+
+impl DeadlockPriority {
+    fn value(&self) -> i8 {
+        match *self {
+            DeadlockPriority::Low => -5,
+            DeadlockPriority::Normal => 0,
+            DeadlockPriority::High => 5,
+            DeadlockPriority::Numeric(x) => x,
+        }
+    }
+}
+
+/// A transaction started by `Connection::begin_transaction`
+///
+/// Statements are executed through the underlying connection (which it derefs to),
+/// if the transaction is neither committed nor rolled back it is rolled back when dropped
+pub struct Transaction<'a, S: 'a + TargetStream> {
+    conn: Connection<'a, S>,
+    finished: Cell<bool>,
+    /// the settings of the session before they were changed for the transaction
+    prev_deadlock_priority: Cell<Option<i32>>,
+    prev_lock_timeout: Cell<Option<i32>>,
+}
+
+impl<'a, S: 'a + TargetStream> Transaction<'a, S> {
+    #[doc(hidden)]
+    pub fn new(conn: Connection<'a, S>) -> TdsResult<Transaction<'a, S>> {
+        try!(conn.exec("BEGIN TRANSACTION;"));
+        Ok(Transaction {
+            conn: conn,
+            finished: Cell::new(false),
+            prev_deadlock_priority: Cell::new(None),
+            prev_lock_timeout: Cell::new(None),
+        })
+    }
+
+    /// Set the deadlock priority of the session until the transaction ends
+    pub fn deadlock_priority(&self, priority: DeadlockPriority) -> TdsResult<()> {
+        let value = priority.value();
+        if value < -10 || value > 10 {
+            return Err(TdsError::Other(format!("deadlock_priority: {} is not within -10 and 10", value)))
+        }
+        let prev = try!(self.conn.query_scalar::<i32, _>(format!("SELECT deadlock_priority FROM sys.dm_exec_sessions WHERE session_id = @@SPID; \
+            SET DEADLOCK_PRIORITY {};", value)));
+        if self.prev_deadlock_priority.get().is_none() {
+            self.prev_deadlock_priority.set(Some(prev.unwrap_or(0)));
+        }
+        Ok(())
+    }
+
+    /// Set how long a statement waits for a lock until the transaction ends,
+    /// `None` waits forever (`SET LOCK_TIMEOUT`)
+    pub fn lock_timeout(&self, timeout: Option<Duration>) -> TdsResult<()> {
+        let millis = match timeout {
+            Some(timeout) => timeout.as_secs() * 1000 + (timeout.subsec_nanos() / 1_000_000) as u64,
+            None => return self.swap_lock_timeout(-1),
+        };
+        if millis > i32::max_value() as u64 {
+            return Err(TdsError::Other(format!("lock_timeout: {}ms is too long", millis)))
+        }
+        self.swap_lock_timeout(millis as i32)
+    }
+
+    fn swap_lock_timeout(&self, millis: i32) -> TdsResult<()> {
+        let prev = try!(self.conn.query_scalar::<i32, _>(format!("SELECT @@LOCK_TIMEOUT; SET LOCK_TIMEOUT {};", millis)));
+        if self.prev_lock_timeout.get().is_none() {
+            self.prev_lock_timeout.set(Some(prev.unwrap_or(-1)));
+        }
+        Ok(())
+    }
+
+    /// restore the settings which were changed for the transaction
+    fn restore_settings(&self) -> TdsResult<()> {
+        let mut sql = String::new();
+        if let Some(prev) = self.prev_deadlock_priority.take() {
+            sql.push_str(&format!("SET DEADLOCK_PRIORITY {};", prev));
+        }
+        if let Some(prev) = self.prev_lock_timeout.take() {
+            sql.push_str(&format!("SET LOCK_TIMEOUT {};", prev));
+        }
+        if !sql.is_empty() {
+            try!(self.exec_unpoisoned(&sql));
+        }
+        Ok(())
+    }
+
+    /// execute a statement unless a panic poisoned the connection, which may have left a response half read:
+    /// such a connection is given up (the server rolls the transaction back when it is closed)
+    fn exec_unpoisoned(&self, sql: &str) -> TdsResult<()> {
+        let mut conn = match self.conn.lock() {
+            Ok(conn) => conn,
+            Err(_) => return Err(TdsError::Other("transaction: the connection is poisoned by a panic".to_owned())),
+        };
+        try!(conn.internal_exec(sql));
+        let packet = try!(conn.read_stmt_message(&mut StatementInfo::new()));
+        packet.catch_error()
+    }
+
+    /// Commit the transaction
+    pub fn commit(self) -> TdsResult<()> {
+        self.finished.set(true);
+        try!(self.conn.exec("COMMIT TRANSACTION;"));
+        self.restore_settings()
+    }
+
+    /// Roll the transaction back
+    pub fn rollback(self) -> TdsResult<()> {
+        self.finished.set(true);
+        self.do_rollback()
+    }
+
+    fn do_rollback(&self) -> TdsResult<()> {
+        // the server may already have rolled back, e.g. with XACT_ABORT
+        // (also called while panicking, so it must not panic itself)
+        let result = self.exec_unpoisoned("IF @@TRANCOUNT > 0 ROLLBACK TRANSACTION;");
+        let restored = self.restore_settings();
+        try!(result);
+        restored
+    }
+}
+
+impl<'a, S: 'a + TargetStream> Deref for Transaction<'a, S> {
+    type Target = Connection<'a, S>;
+
+    fn deref(&self) -> &Connection<'a, S> {
+        &self.conn
+    }
+}
+
+impl<'a, S: 'a + TargetStream> Drop for Transaction<'a, S> {
+    fn drop(&mut self) {
+        if !self.finished.get() {
+            let _ = self.do_rollback();
+        }
+    }
+}
@@ -1,10 +1,14 @@
 ///! The SQL type mapping to rust
 use std::borrow::Cow;
+use std::cmp;
+use std::fmt::{self, Debug};
+use std::{i16, i32};
 use std::io::Cursor;
+use std::time::Duration as StdDuration;
 use byteorder::{ReadBytesExt};
-use chrono::{NaiveDateTime, NaiveDate, NaiveTime, DateTime, TimeZone, UTC, Local};
+use chrono::{NaiveDateTime, NaiveDate, NaiveTime, DateTime, FixedOffset, TimeZone, Timelike, UTC, Local};
 use protocol::{DecodeTokenStream};
-use ::{TdsResult};
+use ::{TdsResult, TdsError};
 
 /// The converted SQL value of a column
 #[derive(Debug)]
@@ -19,9 +23,53 @@ pub enum ColumnType<'a> {
     String(Cow<'a, str>),
     Guid(Guid),
     Datetime(NaiveDateTime),
+    /// a datetimeoffset: the datetime in UTC and the offset of its time zone
+    DatetimeOffset(NaiveDateTime, FixedOffset),
     Date(NaiveDate),
     Time(NaiveTime),
     Binary(Vec<u8>),
+    /// money and smallmoney, in ten-thousandths of the currency unit
+    Money(i64),
+    /// a table-valued parameter, only used to bind values
+    Tvp(Tvp<'a>),
+    /// a sql_variant, holding a value of its base type (converted like the base type)
+    Variant(Box<ColumnType<'a>>),
+}
+
+/// renders the value like the server would (e.g. for `CAST(x AS nvarchar)`): a bit as `1` or `0`,
+/// binary data as `0x` followed by hex digits and money with 4 decimals
+impl<'a> fmt::Display for ColumnType<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ColumnType::Bool(x) => write!(f, "{}", x as u8),
+            // tinyint is unsigned
+            ColumnType::I8(x) => write!(f, "{}", x as u8),
+            ColumnType::I16(x) => write!(f, "{}", x),
+            ColumnType::I32(x) => write!(f, "{}", x),
+            ColumnType::I64(x) => write!(f, "{}", x),
+            ColumnType::F32(x) => write!(f, "{}", x),
+            ColumnType::F64(x) => write!(f, "{}", x),
+            ColumnType::String(ref x) => write!(f, "{}", x),
+            ColumnType::Guid(ref x) => write!(f, "{}", x.as_str()),
+            ColumnType::Datetime(ref x) => write!(f, "{}", x),
+            ColumnType::DatetimeOffset(ref x, ref offset) => write!(f, "{}", offset.from_utc_datetime(x)),
+            ColumnType::Date(ref x) => write!(f, "{}", x),
+            ColumnType::Time(ref x) => write!(f, "{}", x),
+            ColumnType::Binary(ref x) => {
+                try!(write!(f, "0x"));
+                for byte in x {
+                    try!(write!(f, "{:02X}", byte));
+                }
+                Ok(())
+            },
+            ColumnType::Money(x) => {
+                let abs = x.wrapping_abs() as u64;
+                write!(f, "{}{}.{:04}", if x < 0 { "-" } else { "" }, abs / 10_000, abs % 10_000)
+            },
+            ColumnType::Tvp(ref x) => write!(f, "{}", x.type_name),
+            ColumnType::Variant(ref x) => write!(f, "{}", x),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -32,7 +80,20 @@ pub enum ColumnValue<'a> {
 
 pub trait ToColumnType {
     fn to_column_type(&self) -> ColumnType;
-    fn column_type<'a>(&self) -> &'a str;
+    fn column_type(&self) -> &'static str;
+    /// the type used to declare the parameter, if it depends on the value (e.g. the length of a `SqlString`)
+    fn declared_type(&self) -> Cow<'static, str> {
+        Cow::Borrowed(self.column_type())
+    }
+    /// whether to bind a NULL (of the type of `to_column_type`) instead of the value
+    fn is_null(&self) -> bool {
+        false
+    }
+    /// the value and the declared type to bind a NULL of this type as, e.g. for `None` of an `Option`
+    /// (a NULL nvarchar unless overridden)
+    fn null_column_type() -> (ColumnType<'static>, &'static str) where Self: Sized {
+        (ColumnType::String(Cow::Borrowed("")), "nvarchar(4000)")
+    }
 }
 
 macro_rules! column_sql {
@@ -46,9 +107,14 @@ macro_rules! column_sql {
             fn column_type(&self) -> &'static str {
                 $name
             }
+
+            fn null_column_type() -> (ColumnType<'static>, &'static str) {
+                (ColumnType::$cty(Default::default()), $name)
+            }
         }
     }
 }
+column_sql!(bool, Bool, "bit");
 column_sql!(i8, I8, "tinyint");
 column_sql!(i16, I16, "smallint");
 column_sql!(i32, I32, "int");
@@ -61,90 +127,546 @@ column_sql!(u64, I64, "bigint", i64);
 column_sql!(f32, F32, "float(24)");
 column_sql!(f64, F64, "float(53)");
 
+/// A duration since midnight bound as `time(7)`, the counterpart of reading a `time` as `Duration`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeOfDay(NaiveTime);
+
+impl TimeOfDay {
+    /// fails for a duration of 24 hours or more, which is not a time of day
+    pub fn new(since_midnight: StdDuration) -> TdsResult<TimeOfDay> {
+        let secs = since_midnight.as_secs();
+        if secs >= 24 * 3600 {
+            return Err(TdsError::Other(format!("time: a duration of {}s since midnight is not less than 24 hours", secs)))
+        }
+        Ok(TimeOfDay(NaiveTime::from_num_seconds_from_midnight(secs as u32, since_midnight.subsec_nanos())))
+    }
+}
+
+impl ToColumnType for TimeOfDay {
+    fn to_column_type(&self) -> ColumnType {
+        ColumnType::Time(self.0)
+    }
+
+    fn column_type(&self) -> &'static str {
+        "time(7)"
+    }
+
+    fn null_column_type() -> (ColumnType<'static>, &'static str) {
+        (ColumnType::Time(NaiveTime::from_hms(0, 0, 0)), "time(7)")
+    }
+}
+
+impl ToColumnType for NaiveDate {
+    fn to_column_type(&self) -> ColumnType {
+        ColumnType::Date(*self)
+    }
+
+    fn column_type(&self) -> &'static str {
+        "date"
+    }
+
+    fn null_column_type() -> (ColumnType<'static>, &'static str) {
+        (ColumnType::Date(NaiveDate::from_ymd(1, 1, 1)), "date")
+    }
+}
+
+/// `nvarchar(4000)`, or `nvarchar(max)` for more than 4000 UTF-16 code units
+fn nvarchar_type(val: &str) -> &'static str {
+    if val.encode_utf16().count() > 4000 { "nvarchar(max)" } else { "nvarchar(4000)" }
+}
+
+/// binds the string as `nvarchar(4000)`, or as `nvarchar(max)` if it is longer
 impl<'a> ToColumnType for &'a str {
     fn to_column_type(&self) -> ColumnType {
         ColumnType::String(Cow::Borrowed(self))
     }
 
     fn column_type(&self) -> &'static str {
-        "nvarchar"
+        nvarchar_type(self)
     }
 }
 
-macro_rules! column_conv_unpack {
-    (pack, true, $val:expr) => (Some($val));
-    (pack, false, $val:expr) => ($val);
-    ($val:expr, true, $id:ident, $is_nullable:ident) => {
-        match $val {
-            ColumnValue::Some(ColumnType::$id(ref val)) => column_conv_unpack!(pack, $is_nullable, Some(val)),
-            ColumnValue::None => column_conv_unpack!(pack, $is_nullable, None),
-            _ => None
+impl ToColumnType for String {
+    fn to_column_type(&self) -> ColumnType {
+        ColumnType::String(Cow::Borrowed(self))
+    }
+
+    fn column_type(&self) -> &'static str {
+        nvarchar_type(self)
+    }
+}
+
+/// A string parameter declared as `varchar` or `nvarchar` of a specific length,
+/// e.g. `varchar` to avoid converting a non-unicode column when comparing it, which prevents using an index
+///
+/// without a length the string is declared with the maximum length which is not `max` (4000 resp. 8000)
+/// or as `max` if it is longer, a longer string than the length is truncated by the server
+///
+/// `None` of an `Option<SqlString>` is declared as `nvarchar(4000)`, since the declared type of a `None`
+/// cannot depend on a value, use `SqlString::null` to bind a NULL declared like the values
+/// (a different declaration also prepares a cached statement again)
+#[derive(Clone, Debug)]
+pub struct SqlString<'a> {
+    pub value: Cow<'a, str>,
+    /// `nvarchar` instead of `varchar`
+    pub unicode: bool,
+    pub max_len: Option<usize>,
+    /// bind a NULL instead of the value
+    pub null: bool,
+}
+
+impl<'a> SqlString<'a> {
+    pub fn new<V: Into<Cow<'a, str>>>(value: V) -> SqlString<'a> {
+        SqlString {
+            value: value.into(),
+            unicode: true,
+            max_len: None,
+            null: false,
         }
-    };
-    ($val:expr, false, $id:ident, $is_nullable:ident) => {
-        match $val {
-            ColumnValue::Some(ColumnType::$id(val)) => column_conv_unpack!(pack, $is_nullable, Some(val)),
-            ColumnValue::None => column_conv_unpack!(pack, $is_nullable, None),
-            _ => None
+    }
+
+    /// a NULL declared with the type given by `unicode` and `max_len`, e.g. `SqlString::null().unicode(false).max_len(50)`
+    pub fn null() -> SqlString<'a> {
+        SqlString {
+            null: true,
+            ..SqlString::new("")
         }
     }
-}
 
-macro_rules! column_conv_nullable {
-    ($ty:ty, $id:ident, $by_ref:ident) => {
-        impl <'a> From<&'a ColumnValue<'a>> for Option<Option<$ty>> {
-            fn from(val: &'a ColumnValue) -> Option<Option<$ty>> {
-                column_conv_unpack!(*val, $by_ref, $id, true)
+    pub fn unicode(mut self, unicode: bool) -> SqlString<'a> {
+        self.unicode = unicode;
+        self
+    }
+
+    pub fn max_len(mut self, max_len: usize) -> SqlString<'a> {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// the declared length, None for `max`
+    fn declared_len(&self) -> Option<usize> {
+        let limit = if self.unicode { 4000 } else { 8000 };
+        match self.max_len {
+            Some(len) if len <= limit => Some(cmp::max(len, 1)),
+            Some(_) => None,
+            None => {
+                let len = if self.unicode { self.value.encode_utf16().count() } else { self.value.chars().count() };
+                if len <= limit { Some(limit) } else { None }
             }
         }
-    };
+    }
 }
 
-macro_rules! column_conv {
-    ($ty:ty, $id:ident) => { column_conv!($ty, $id, false); };
-    ($ty:ty, $id:ident, $by_ref:ident) => {
-        impl <'a> From<&'a ColumnValue<'a>> for Option<$ty> {
-            fn from(val: &'a ColumnValue) -> Option<$ty> {
-                column_conv_unpack!(*val, $by_ref, $id, false)
-            }
+/// the value is sent as unicode, the server converts it to the declared type
+impl<'a> ToColumnType for SqlString<'a> {
+    fn to_column_type(&self) -> ColumnType {
+        ColumnType::String(Cow::Borrowed(&self.value))
+    }
+
+    fn column_type(&self) -> &'static str {
+        match (self.unicode, self.declared_len()) {
+            (true, Some(_)) => "nvarchar(4000)",
+            (true, None) => "nvarchar(max)",
+            (false, Some(_)) => "varchar(8000)",
+            (false, None) => "varchar(max)",
+        }
+    }
+
+    fn declared_type(&self) -> Cow<'static, str> {
+        let name = if self.unicode { "nvarchar" } else { "varchar" };
+        match self.declared_len() {
+            Some(len) => Cow::Owned(format!("{}({})", name, len)),
+            None => Cow::Owned(format!("{}(max)", name)),
         }
+    }
 
-        column_conv_nullable!($ty, $id, $by_ref);
+    fn is_null(&self) -> bool {
+        self.null
+    }
+
+    fn null_column_type() -> (ColumnType<'static>, &'static str) {
+        (ColumnType::String(Cow::Borrowed("")), "nvarchar(4000)")
     }
 }
 
-column_conv!(bool, Bool);
-column_conv!(i32, I32);
-column_conv!(f32, F32);
-column_conv!(f64, F64);
-column_conv!(&'a str, String, true);
-column_conv!(&'a Guid, Guid, true);
-column_conv!(&'a [u8], Binary, true);
-column_conv!(&'a NaiveDateTime, Datetime, true);
-column_conv!(&'a NaiveDate, Date, true);
-column_conv!(&'a NaiveTime, Time, true);
+/// binds the bytes as `varbinary(8000)`, or as `varbinary(max)` if they are longer
+impl<'a> ToColumnType for &'a [u8] {
+    fn to_column_type(&self) -> ColumnType {
+        ColumnType::Binary(self.to_vec())
+    }
 
-impl <'a> From<&'a ColumnValue<'a>> for Option<DateTime<Local>> {
-    fn from(val: &'a ColumnValue) -> Option<DateTime<Local>> {
-        match *val {
-            ColumnValue::Some(ColumnType::Datetime(ref dt)) => Some(UTC.from_utc_datetime(dt).with_timezone(&Local)),
-            _ => None
+    fn column_type(&self) -> &'static str {
+        if self.len() > 8000 { "varbinary(max)" } else { "varbinary(8000)" }
+    }
+
+    fn null_column_type() -> (ColumnType<'static>, &'static str) {
+        (ColumnType::Binary(vec![]), "varbinary(8000)")
+    }
+}
+
+impl ToColumnType for Vec<u8> {
+    fn to_column_type(&self) -> ColumnType {
+        ColumnType::Binary(self.clone())
+    }
+
+    fn column_type(&self) -> &'static str {
+        if self.len() > 8000 { "varbinary(max)" } else { "varbinary(8000)" }
+    }
+
+    fn null_column_type() -> (ColumnType<'static>, &'static str) {
+        (ColumnType::Binary(vec![]), "varbinary(8000)")
+    }
+}
+
+/// binds a dynamically typed value
+impl<'a> ToColumnType for ColumnType<'a> {
+    fn to_column_type(&self) -> ColumnType {
+        match *self {
+            ColumnType::Bool(x) => ColumnType::Bool(x),
+            ColumnType::I8(x) => ColumnType::I8(x),
+            ColumnType::I16(x) => ColumnType::I16(x),
+            ColumnType::I32(x) => ColumnType::I32(x),
+            ColumnType::I64(x) => ColumnType::I64(x),
+            ColumnType::F32(x) => ColumnType::F32(x),
+            ColumnType::F64(x) => ColumnType::F64(x),
+            ColumnType::String(ref x) => ColumnType::String(Cow::Borrowed(x)),
+            ColumnType::Guid(ref x) => ColumnType::Guid(Guid(x.0, None)),
+            ColumnType::Datetime(x) => ColumnType::Datetime(x),
+            ColumnType::DatetimeOffset(x, offset) => ColumnType::DatetimeOffset(x, offset),
+            ColumnType::Date(x) => ColumnType::Date(x),
+            ColumnType::Time(x) => ColumnType::Time(x),
+            ColumnType::Binary(ref x) => ColumnType::Binary(x.clone()),
+            ColumnType::Money(x) => ColumnType::Money(x),
+            ColumnType::Tvp(ref x) => x.to_column_type(),
+            ColumnType::Variant(ref x) => ColumnType::Variant(Box::new(x.to_column_type())),
+        }
+    }
+
+    fn column_type(&self) -> &'static str {
+        match *self {
+            ColumnType::Bool(_) => "bit",
+            ColumnType::I8(_) => "tinyint",
+            ColumnType::I16(_) => "smallint",
+            ColumnType::I32(_) => "int",
+            ColumnType::I64(_) => "bigint",
+            ColumnType::F32(_) => "float(24)",
+            ColumnType::F64(_) => "float(53)",
+            ColumnType::String(ref x) => nvarchar_type(x),
+            ColumnType::Guid(_) => "uniqueidentifier",
+            ColumnType::Datetime(_) => "datetime2",
+            ColumnType::DatetimeOffset(_, _) => "datetimeoffset",
+            ColumnType::Date(_) => "date",
+            ColumnType::Time(_) => "time(7)",
+            ColumnType::Binary(ref x) if x.len() > 8000 => "varbinary(max)",
+            ColumnType::Binary(_) => "varbinary(8000)",
+            ColumnType::Money(_) => "money",
+            ColumnType::Tvp(ref x) => x.column_type(),
+            ColumnType::Variant(_) => "sql_variant",
+        }
+    }
+}
+
+/// binds a dynamically typed value, `ColumnValue::None` is bound as a NULL nvarchar
+impl<'a> ToColumnType for ColumnValue<'a> {
+    fn to_column_type(&self) -> ColumnType {
+        match *self {
+            ColumnValue::Some(ref x) => x.to_column_type(),
+            ColumnValue::None => ColumnType::String(Cow::Borrowed("")),
+        }
+    }
+
+    fn column_type(&self) -> &'static str {
+        match *self {
+            ColumnValue::Some(ref x) => x.column_type(),
+            ColumnValue::None => "nvarchar(4000)",
+        }
+    }
+
+    fn is_null(&self) -> bool {
+        match *self {
+            ColumnValue::Some(_) => false,
+            ColumnValue::None => true,
+        }
+    }
+}
+
+/// binds `None` as a NULL of the type of `T`, see `ToColumnType::null_column_type`
+impl<T: ToColumnType> ToColumnType for Option<T> {
+    fn to_column_type(&self) -> ColumnType {
+        match *self {
+            Some(ref x) => x.to_column_type(),
+            None => T::null_column_type().0,
+        }
+    }
+
+    fn column_type(&self) -> &'static str {
+        match *self {
+            Some(ref x) => x.column_type(),
+            None => T::null_column_type().1,
+        }
+    }
+
+    fn declared_type(&self) -> Cow<'static, str> {
+        match *self {
+            Some(ref x) => x.declared_type(),
+            None => Cow::Borrowed(T::null_column_type().1),
+        }
+    }
+
+    fn null_column_type() -> (ColumnType<'static>, &'static str) {
+        T::null_column_type()
+    }
+
+    fn is_null(&self) -> bool {
+        match *self {
+            Some(ref x) => x.is_null(),
+            None => true,
+        }
+    }
+}
+
+impl<'a> ColumnValue<'a> {
+    /// detach the value from borrowed data
+    pub fn into_static(self) -> ColumnValue<'static> {
+        match self {
+            ColumnValue::Some(x) => ColumnValue::Some(x.into_static()),
+            ColumnValue::None => ColumnValue::None,
         }
     }
 }
 
-impl <'a> From<&'a ColumnValue<'a>> for Option<Option<DateTime<Local>>> {
-    fn from(val: &'a ColumnValue) -> Option<Option<DateTime<Local>>> {
+impl<'a> ColumnType<'a> {
+    /// detach the value from borrowed data
+    pub fn into_static(self) -> ColumnType<'static> {
+        match self {
+            ColumnType::Bool(x) => ColumnType::Bool(x),
+            ColumnType::I8(x) => ColumnType::I8(x),
+            ColumnType::I16(x) => ColumnType::I16(x),
+            ColumnType::I32(x) => ColumnType::I32(x),
+            ColumnType::I64(x) => ColumnType::I64(x),
+            ColumnType::F32(x) => ColumnType::F32(x),
+            ColumnType::F64(x) => ColumnType::F64(x),
+            ColumnType::String(x) => ColumnType::String(Cow::Owned(x.into_owned())),
+            ColumnType::Guid(x) => ColumnType::Guid(x),
+            ColumnType::Datetime(x) => ColumnType::Datetime(x),
+            ColumnType::DatetimeOffset(x, offset) => ColumnType::DatetimeOffset(x, offset),
+            ColumnType::Date(x) => ColumnType::Date(x),
+            ColumnType::Time(x) => ColumnType::Time(x),
+            ColumnType::Binary(x) => ColumnType::Binary(x),
+            ColumnType::Money(x) => ColumnType::Money(x),
+            ColumnType::Tvp(x) => ColumnType::Tvp(Tvp {
+                type_name: Cow::Owned(x.type_name.into_owned()),
+                rows: x.rows.into_iter().map(|row| row.into_iter().map(|(val, null)| (val.into_static(), null)).collect()).collect(),
+            }),
+            ColumnType::Variant(x) => ColumnType::Variant(Box::new(x.into_static())),
+        }
+    }
+
+    /// the name of the variant, used for conversion errors
+    pub fn variant_name(&self) -> &'static str {
+        match *self {
+            ColumnType::Bool(_) => "Bool",
+            ColumnType::I8(_) => "I8",
+            ColumnType::I16(_) => "I16",
+            ColumnType::I32(_) => "I32",
+            ColumnType::I64(_) => "I64",
+            ColumnType::F32(_) => "F32",
+            ColumnType::F64(_) => "F64",
+            ColumnType::String(_) => "String",
+            ColumnType::Guid(_) => "Guid",
+            ColumnType::Datetime(_) => "Datetime",
+            ColumnType::DatetimeOffset(_, _) => "DatetimeOffset",
+            ColumnType::Date(_) => "Date",
+            ColumnType::Time(_) => "Time",
+            ColumnType::Binary(_) => "Binary",
+            ColumnType::Money(_) => "Money",
+            ColumnType::Tvp(_) => "Tvp",
+            ColumnType::Variant(ref x) => x.variant_name(),
+        }
+    }
+
+    /// the value of a sql_variant, any other value itself
+    pub fn base_value(&self) -> &ColumnType<'a> {
+        match *self {
+            ColumnType::Variant(ref x) => x.base_value(),
+            _ => self
+        }
+    }
+}
+
+/// A table-valued parameter: the rows passed for a user-defined table type, e.g.
+/// `Tvp::new("dbo.IntPairs").row(&[&1, &2]).row(&[&3, &4])`
+///
+/// the column types are taken from the values of the first row (strings are sent as nvarchar(4000)),
+/// table-valued parameters can only be passed to stored procedures (`Connection::call_proc`)
+#[derive(Debug)]
+pub struct Tvp<'a> {
+    /// the name of the table type, optionally qualified by its schema
+    pub type_name: Cow<'a, str>,
+    /// the values of each row, with whether they are NULL
+    pub rows: Vec<Vec<(ColumnType<'a>, bool)>>,
+}
+
+impl<'a> Tvp<'a> {
+    pub fn new<N: Into<Cow<'a, str>>>(type_name: N) -> Tvp<'a> {
+        Tvp {
+            type_name: type_name.into(),
+            rows: vec![],
+        }
+    }
+
+    /// append a row, the values have to be ordered as the columns of the table type
+    pub fn row(mut self, values: &[&'a ToColumnType]) -> Tvp<'a> {
+        self.rows.push(values.iter().map(|x| (x.to_column_type(), x.is_null())).collect());
+        self
+    }
+}
+
+impl<'a> ToColumnType for Tvp<'a> {
+    fn to_column_type(&self) -> ColumnType {
+        ColumnType::Tvp(Tvp {
+            type_name: Cow::Borrowed(&self.type_name),
+            rows: self.rows.iter().map(|row| row.iter().map(|&(ref x, null)| (x.to_column_type(), null)).collect()).collect(),
+        })
+    }
+
+    fn column_type(&self) -> &'static str {
+        // the declaration would need the name of the table type
+        "table"
+    }
+}
+
+/// Conversion of a column value into a rust type, which fails on a type mismatch
+/// e.g. `Option<T>` is used to read nullable columns
+pub trait FromColumnValue<'a>: Sized {
+    fn from_column_value(val: &'a ColumnValue<'a>) -> TdsResult<Self>;
+}
+
+impl<'a, T: FromColumnValue<'a>> FromColumnValue<'a> for Option<T> {
+    fn from_column_value(val: &'a ColumnValue<'a>) -> TdsResult<Option<T>> {
         match *val {
-            ColumnValue::None => Some(None),
-            ColumnValue::Some(ColumnType::Datetime(ref dt)) => Some(Some(UTC.from_utc_datetime(dt).with_timezone(&Local))),
-            _ => None
+            ColumnValue::None => Ok(None),
+            _ => Ok(Some(try!(T::from_column_value(val))))
+        }
+    }
+}
+
+#[inline]
+fn conversion_error(expected: &str, val: &ColumnValue) -> TdsError {
+    match *val {
+        ColumnValue::Some(ref x) => TdsError::Other(format!("conversion: expected {} got {}", expected, x.variant_name())),
+        ColumnValue::None => TdsError::Other(format!("conversion: expected {} got NULL", expected)),
+    }
+}
+
+#[inline]
+fn conversion_overflow<T: Debug>(expected: &str, val: T) -> TdsError {
+    TdsError::Other(format!("conversion: value {:?} does not fit into {}", val, expected))
+}
+
+/// the `From` conversions, which are used by `Row::get` and yield `None` on failure
+macro_rules! column_conv_from {
+    ($ty:ty) => {
+        impl<'a> From<&'a ColumnValue<'a>> for Option<$ty> {
+            fn from(val: &'a ColumnValue) -> Option<$ty> {
+                FromColumnValue::from_column_value(val).ok()
+            }
+        }
+
+        impl<'a> From<&'a ColumnValue<'a>> for Option<Option<$ty>> {
+            fn from(val: &'a ColumnValue) -> Option<Option<$ty>> {
+                FromColumnValue::from_column_value(val).ok()
+            }
+        }
+    }
+}
+
+macro_rules! column_conv {
+    ($ty:ty, $expected:expr, $($id:ident($($val:ident),+) => $conv:expr),+) => {
+        impl<'a> FromColumnValue<'a> for $ty {
+            fn from_column_value(val: &'a ColumnValue<'a>) -> TdsResult<$ty> {
+                let value = match *val {
+                    ColumnValue::Some(ref x) => x.base_value(),
+                    ColumnValue::None => return Err(conversion_error($expected, val))
+                };
+                match *value {
+                    $( ColumnType::$id($(ref $val),+) => $conv, )+
+                    _ => Err(conversion_error($expected, val))
+                }
+            }
         }
+
+        column_conv_from!($ty);
     }
 }
 
+// tinyint is unsigned on the wire, bit and integer columns are interchangeable (for legacy schemas)
+column_conv!(bool, "Bool",
+    Bool(x) => Ok(*x),
+    I8(x) => Ok(*x != 0),
+    I16(x) => Ok(*x != 0),
+    I32(x) => Ok(*x != 0),
+    I64(x) => Ok(*x != 0)
+);
+column_conv!(u8, "I8",
+    Bool(x) => Ok(*x as u8),
+    I8(x) => Ok(*x as u8),
+    I16(x) => if *x >= 0 && *x <= 0xFF { Ok(*x as u8) } else { Err(conversion_overflow("I8", x)) },
+    I32(x) => if *x >= 0 && *x <= 0xFF { Ok(*x as u8) } else { Err(conversion_overflow("I8", x)) },
+    I64(x) => if *x >= 0 && *x <= 0xFF { Ok(*x as u8) } else { Err(conversion_overflow("I8", x)) }
+);
+column_conv!(i16, "I16",
+    Bool(x) => Ok(*x as i16),
+    I8(x) => Ok(*x as u8 as i16),
+    I16(x) => Ok(*x),
+    I32(x) => if *x >= i16::MIN as i32 && *x <= i16::MAX as i32 { Ok(*x as i16) } else { Err(conversion_overflow("I16", x)) },
+    I64(x) => if *x >= i16::MIN as i64 && *x <= i16::MAX as i64 { Ok(*x as i16) } else { Err(conversion_overflow("I16", x)) }
+);
+column_conv!(i32, "I32",
+    Bool(x) => Ok(*x as i32),
+    I8(x) => Ok(*x as u8 as i32),
+    I16(x) => Ok(*x as i32),
+    I32(x) => Ok(*x),
+    I64(x) => if *x >= i32::MIN as i64 && *x <= i32::MAX as i64 { Ok(*x as i32) } else { Err(conversion_overflow("I32", x)) }
+);
+column_conv!(i64, "I64",
+    Bool(x) => Ok(*x as i64),
+    I8(x) => Ok(*x as u8 as i64),
+    I16(x) => Ok(*x as i64),
+    I32(x) => Ok(*x as i64),
+    I64(x) => Ok(*x)
+);
+column_conv!(f32, "F32", F32(x) => Ok(*x), Money(x) => Ok((*x as f64 / 10_000f64) as f32));
+column_conv!(f64, "F64", F32(x) => Ok(*x as f64), F64(x) => Ok(*x), Money(x) => Ok(*x as f64 / 10_000f64));
+column_conv!(&'a str, "String", String(x) => Ok(&x[..]));
+column_conv!(String, "String", String(x) => Ok(x.clone().into_owned()));
+column_conv!(&'a Guid, "Guid", Guid(x) => Ok(x));
+column_conv!(&'a [u8], "Binary", Binary(x) => Ok(&x[..]));
+column_conv!(Vec<u8>, "Binary", Binary(x) => Ok(x.clone()));
+// the datetime of a datetimeoffset is in UTC
+column_conv!(&'a NaiveDateTime, "Datetime", Datetime(x) => Ok(x), DatetimeOffset(x, _offset) => Ok(x));
+column_conv!(NaiveDateTime, "Datetime", Datetime(x) => Ok(*x), DatetimeOffset(x, _offset) => Ok(*x));
+column_conv!(&'a NaiveDate, "Date", Date(x) => Ok(x));
+column_conv!(NaiveDate, "Date", Date(x) => Ok(*x));
+column_conv!(&'a NaiveTime, "Time", Time(x) => Ok(x));
+// the time since midnight, without having to use chrono
+column_conv!(StdDuration, "Time", Time(x) => Ok(StdDuration::new(x.num_seconds_from_midnight() as u64, x.nanosecond())));
+column_conv!(DateTime<UTC>, "Datetime",
+    Datetime(x) => Ok(UTC.from_utc_datetime(x)),
+    DatetimeOffset(x, _offset) => Ok(UTC.from_utc_datetime(x))
+);
+column_conv!(DateTime<Local>, "Datetime",
+    Datetime(x) => Ok(UTC.from_utc_datetime(x).with_timezone(&Local)),
+    DatetimeOffset(x, _offset) => Ok(UTC.from_utc_datetime(x).with_timezone(&Local))
+);
+// keeps the offset of a datetimeoffset, a datetime is taken as UTC
+column_conv!(DateTime<FixedOffset>, "DatetimeOffset",
+    Datetime(x) => Ok(FixedOffset::east(0).from_utc_datetime(x)),
+    DatetimeOffset(x, offset) => Ok(offset.from_utc_datetime(x))
+);
+
 /// A TSQL uniqueidentifier/GUID
-#[derive(Debug)]
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Guid([u8; 16], Option<String>);
 impl DecodeTokenStream for Guid {
     fn decode<T: AsRef<[u8]>>(cursor: &mut Cursor<T>) -> TdsResult<Guid> {
@@ -157,6 +679,42 @@ impl DecodeTokenStream for Guid {
 }
 
 impl<'a> Guid {
+    /// create a GUID from the bytes as stored by mssql (the first 3 groups are little endian)
+    pub fn from_bytes(bytes: [u8; 16]) -> Guid {
+        Guid(bytes, None)
+    }
+
+    /// parse the hyphenated form (e.g. `6f9619ff-8b86-d011-b42d-00c04fc964ff`)
+    pub fn parse(s: &str) -> TdsResult<Guid> {
+        let invalid = || TdsError::Other(format!("guid: invalid GUID {}", s));
+        let groups: Vec<&str> = s.split('-').collect();
+        if groups.len() != 5 || groups.iter().zip([8, 4, 4, 4, 12].iter()).any(|(g, l)| g.len() != *l) {
+            return Err(invalid())
+        }
+        let hex = groups.concat();
+        if !hex.chars().all(|c| c.is_digit(16)) {
+            return Err(invalid())
+        }
+        let mut bytes = [0; 16];
+        for i in 0..16 {
+            bytes[i] = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        Ok(Guid(bytes, None))
+    }
+
+    /// the bytes as stored by mssql
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    /// bind the GUID in its hyphenated form, for GUIDs stored in `char(36)` columns
+    pub fn as_string(&self) -> GuidString {
+        GuidString(self.as_str())
+    }
+
     pub fn as_str(&'a self) -> String {
         format!(
             "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
@@ -166,3 +724,35 @@ impl<'a> Guid {
         )
     }
 }
+
+impl ToColumnType for Guid {
+    fn to_column_type(&self) -> ColumnType {
+        ColumnType::Guid(Guid(self.0, None))
+    }
+
+    fn column_type(&self) -> &'static str {
+        "uniqueidentifier"
+    }
+
+    fn null_column_type() -> (ColumnType<'static>, &'static str) {
+        (ColumnType::Guid(Guid([0; 16], None)), "uniqueidentifier")
+    }
+}
+
+/// A GUID bound as `char(36)` instead of `uniqueidentifier`, see `Guid::as_string`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GuidString(String);
+
+impl ToColumnType for GuidString {
+    fn to_column_type(&self) -> ColumnType {
+        ColumnType::String(Cow::Borrowed(&self.0))
+    }
+
+    fn column_type(&self) -> &'static str {
+        "char(36)"
+    }
+
+    fn null_column_type() -> (ColumnType<'static>, &'static str) {
+        (ColumnType::String(Cow::Borrowed("")), "char(36)")
+    }
+}
@@ -0,0 +1,194 @@
+//! A scripted in-memory stream standing in for a SQL server
+#![allow(dead_code)]
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use tiberius::{AuthenticationMethod, Connection, ConnectionOptBuilder};
+
+/// replays the queued chunks, a single read never crosses a chunk boundary
+/// so splitting a packet into multiple chunks simulates short reads
+#[derive(Debug)]
+pub struct MockStream {
+    reads: VecDeque<Vec<u8>>,
+    pub written: Arc<Mutex<Vec<u8>>>,
+    /// the number of bytes read so far
+    pub read: Arc<Mutex<usize>>,
+}
+
+impl MockStream {
+    pub fn new() -> MockStream {
+        MockStream::with_login_response(done(0, 0))
+    }
+
+    /// answer the login with the given tokens
+    pub fn with_login_response(login_response: Vec<u8>) -> MockStream {
+        let mut stream = MockStream::empty();
+        // prelogin response: only the terminator
+        stream.push(packet(1, &[0xFF]));
+        stream.push(packet(1, &login_response));
+        stream
+    }
+
+    /// a stream which does not answer the prelogin and login yet
+    pub fn empty() -> MockStream {
+        MockStream {
+            reads: VecDeque::new(),
+            written: Arc::new(Mutex::new(vec![])),
+            read: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    pub fn push(&mut self, chunk: Vec<u8>) {
+        self.reads.push_back(chunk);
+    }
+}
+
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (len, empty) = match self.reads.front_mut() {
+            None => return Ok(0),
+            Some(chunk) => {
+                let len = ::std::cmp::min(buf.len(), chunk.len());
+                buf[..len].copy_from_slice(&chunk[..len]);
+                chunk.drain(..len);
+                (len, chunk.is_empty())
+            }
+        };
+        if empty {
+            self.reads.pop_front();
+        }
+        *self.read.lock().unwrap() += len;
+        Ok(len)
+    }
+}
+
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// a tabular result packet with the given status (1 = EOM)
+pub fn packet(status: u8, data: &[u8]) -> Vec<u8> {
+    let len = data.len() + 8;
+    let mut buf = vec![4, status, (len >> 8) as u8, len as u8, 0, 0, 1, 0];
+    buf.extend_from_slice(data);
+    buf
+}
+
+/// split the tokens into packets of at most `size` bytes of data, tokens may span packets
+pub fn packets(data: &[u8], size: usize) -> Vec<Vec<u8>> {
+    let chunks: Vec<&[u8]> = data.chunks(size).collect();
+    chunks.iter().enumerate().map(|(i, chunk)| packet(if i + 1 == chunks.len() { 1 } else { 0 }, chunk)).collect()
+}
+
+/// a DONE token
+pub fn done(status: u16, rows: u64) -> Vec<u8> {
+    let mut buf = vec![0xFD, status as u8, (status >> 8) as u8, 0, 0];
+    buf.extend((0..8).map(|i| (rows >> (i * 8)) as u8));
+    buf
+}
+
+/// a LOGINACK token acknowledging the given TDS version
+pub fn login_ack(tds_version: u32) -> Vec<u8> {
+    let mut data = vec![1, (tds_version >> 24) as u8, (tds_version >> 16) as u8, (tds_version >> 8) as u8, tds_version as u8];
+    data.extend(b_varchar("Microsoft SQL Server"));
+    data.extend_from_slice(&[10, 0, 0, 0]);
+    let mut buf = vec![0xAD, data.len() as u8, (data.len() >> 8) as u8];
+    buf.extend(data);
+    buf
+}
+
+/// an ENVCHANGE token announcing a new packet size
+pub fn env_packet_size(new: u16, old: u16) -> Vec<u8> {
+    let mut data = vec![4];
+    data.extend(b_varchar(&new.to_string()));
+    data.extend(b_varchar(&old.to_string()));
+    let mut buf = vec![0xE3, data.len() as u8, (data.len() >> 8) as u8];
+    buf.extend(data);
+    buf
+}
+
+/// an ENVCHANGE token of the given type, its new and old value are already encoded
+pub fn env_change(ty: u8, new: &[u8], old: &[u8]) -> Vec<u8> {
+    let len = 1 + new.len() + old.len();
+    let mut buf = vec![0xE3, len as u8, (len >> 8) as u8, ty];
+    buf.extend_from_slice(new);
+    buf.extend_from_slice(old);
+    buf
+}
+
+/// a B_VARCHAR (length in characters, UTF-16LE)
+pub fn b_varchar(s: &str) -> Vec<u8> {
+    let mut buf = vec![s.len() as u8];
+    for c in s.encode_utf16() {
+        buf.push(c as u8);
+        buf.push((c >> 8) as u8);
+    }
+    buf
+}
+
+/// a US_VARCHAR value of a nvarchar column
+pub fn nvarchar(s: &str) -> Vec<u8> {
+    let chars = b_varchar(s);
+    let len = chars.len() - 1;
+    let mut buf = vec![len as u8, (len >> 8) as u8];
+    buf.extend_from_slice(&chars[1..]);
+    buf
+}
+
+/// an INFO token, e.g. for the output of `PRINT`
+pub fn info(number: u32, class: u8, message: &str) -> Vec<u8> {
+    server_message(0xAB, number, class, message, "", 1)
+}
+
+/// an ERROR token, raised at the given line of the batch
+pub fn error(number: u32, class: u8, message: &str, line: u32) -> Vec<u8> {
+    server_message(0xAA, number, class, message, "", line)
+}
+
+/// an ERROR token, raised at the given line of a procedure
+pub fn proc_error(number: u32, class: u8, message: &str, proc_name: &str, line: u32) -> Vec<u8> {
+    server_message(0xAA, number, class, message, proc_name, line)
+}
+
+fn server_message(token: u8, number: u32, class: u8, message: &str, proc_name: &str, line: u32) -> Vec<u8> {
+    let mut data = vec![number as u8, (number >> 8) as u8, (number >> 16) as u8, (number >> 24) as u8, 1, class];
+    // US_VARCHAR: the length in characters
+    let chars = b_varchar(message);
+    data.extend_from_slice(&[chars[0], 0]);
+    data.extend_from_slice(&chars[1..]);
+    data.extend(b_varchar("server"));
+    data.extend(b_varchar(proc_name));
+    data.extend_from_slice(&[line as u8, (line >> 8) as u8, (line >> 16) as u8, (line >> 24) as u8]);
+    let mut buf = vec![token, data.len() as u8, (data.len() >> 8) as u8];
+    buf.extend(data);
+    buf
+}
+
+/// split the written bytes into the sent packets
+pub fn sent_packets(written: &[u8]) -> Vec<Vec<u8>> {
+    let mut packets = vec![];
+    let mut pos = 0;
+    while pos < written.len() {
+        let len = (written[pos + 2] as usize) << 8 | written[pos + 3] as usize;
+        packets.push(written[pos..pos + len].to_vec());
+        pos += len;
+    }
+    packets
+}
+
+pub fn builder<'a>(stream: MockStream) -> ConnectionOptBuilder<'a, MockStream> {
+    ConnectionOptBuilder::new(stream)
+        .auth(AuthenticationMethod::internal("test", "test"))
+        .db("test")
+}
+
+pub fn connect<'a>(stream: MockStream) -> Connection<'a, MockStream> {
+    Connection::connect(builder(stream).build()).unwrap()
+}
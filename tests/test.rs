@@ -1,7 +1,8 @@
 extern crate tiberius;
 extern crate chrono;
+use std::time::Duration;
 use self::chrono::{NaiveDateTime, NaiveDate, NaiveTime, DateTime, Local};
-use tiberius::{TargetStream, Guid, Connection};
+use tiberius::{TargetStream, Guid, Connection, ColumnValue, ColumnType, Param};
 
 pub fn get_connection<'a>() -> Connection<'a, Box<TargetStream>> {
     let opts = "server=localhost:1433;UID=test;PWD=test;Database=test";
@@ -135,3 +136,669 @@ fn test_v73_datatypes() {
     let tzdtime_optional: Option<DateTime<Local>> = rows.get(0).get("col_datetime_offset");
     assert_eq!(format!("{:?}", tzdtime_optional), "Some(2016-04-08T12:47:37.413+02:00)");
 }
+
+#[test]
+fn test_row_into_values() {
+    let cl = get_connection();
+    let rows = cl.query("SELECT 1 AS a, N'two' AS b, NULL AS c;").unwrap();
+    assert_eq!(rows.len(), 1);
+    let values = rows.into_iter().next().unwrap().into_values();
+    assert_eq!(values.len(), 3);
+    match values[0] {
+        ColumnValue::Some(ColumnType::I32(1)) => (),
+        ref x => panic!("unexpected value for column 0: {:?}", x)
+    }
+    match values[1] {
+        ColumnValue::Some(ColumnType::String(ref x)) if x == "two" => (),
+        ref x => panic!("unexpected value for column 1: {:?}", x)
+    }
+    match values[2] {
+        ColumnValue::None => (),
+        ref x => panic!("unexpected value for column 2: {:?}", x)
+    }
+}
+
+/// expects: CREATE PROCEDURE [dbo].[test_proc_output] @in int, @out int OUTPUT AS SET @out = @in * 2
+#[test]
+fn test_proc_output_param() {
+    let cl = get_connection();
+    let input = 21i32;
+    let output = 0i32;
+    let result = cl.call_proc("[test].[dbo].[test_proc_output]", &[Param::new("@in", &input), Param::new("@out", &output).output()]).unwrap();
+    let out: i32 = result.get("@out");
+    assert_eq!(out, 42);
+    assert_eq!(result.return_status(), Some(0));
+}
+
+#[test]
+fn test_exec_multiple_statements() {
+    let cl = get_connection();
+    let count = cl.exec("UPDATE [test].[dbo].[test] SET col_int = col_int WHERE id = 1; UPDATE [test].[dbo].[test] SET col_int = col_int WHERE id = 2;").unwrap();
+    assert_eq!(count, 2);
+    // the next request must not see any leftover tokens of the batch
+    let rows = cl.query("SELECT 42 AS a;").unwrap();
+    assert_eq!(rows.len(), 1);
+    let a: i32 = rows.get(0).get("a");
+    assert_eq!(a, 42);
+}
+
+#[test]
+fn test_time_as_duration() {
+    let cl = get_connection();
+    let rows = cl.query("SELECT CAST('13:45:30.1234567' AS time(7)) AS t;").unwrap();
+    let t: Duration = rows.get(0).get("t");
+    assert_eq!(t, Duration::new(13 * 3600 + 45 * 60 + 30, 123456700));
+}
+
+#[test]
+fn test_floats() {
+    let cl = get_connection();
+    let rows = cl.query("SELECT CAST(3.14 AS float) AS f, CAST(1.5 AS real) AS r, CAST(NULL AS float) AS n;").unwrap();
+    let f: f64 = rows.get(0).get("f");
+    assert_eq!(f, 3.14f64);
+    let r: f32 = rows.get(0).get("r");
+    assert_eq!(r, 1.5f32);
+    let n: Option<f64> = rows.get(0).get("n");
+    assert_eq!(n, None);
+}
+
+#[test]
+fn test_bit() {
+    let cl = get_connection();
+    let rows = cl.query("SELECT CAST(1 AS bit) AS t, CAST(0 AS bit) AS f, CAST(NULL AS bit) AS n;").unwrap();
+    let t: bool = rows.get(0).get("t");
+    assert_eq!(t, true);
+    let f: bool = rows.get(0).get("f");
+    assert_eq!(f, false);
+    let n: Option<bool> = rows.get(0).get("n");
+    assert_eq!(n, None);
+}
+
+#[test]
+fn test_database_with_space() {
+    let cl = get_connection();
+    cl.exec("IF DB_ID('test db') IS NULL CREATE DATABASE [test db];").unwrap();
+    for db in &["test db", "[test db]"] {
+        let opts = format!("server=localhost:1433;UID=test;PWD=test;Database={}", db);
+        let conn: Connection<Box<TargetStream>> = Connection::connect(&opts[..]).unwrap();
+        let rows = conn.query("SELECT DB_NAME() AS db;").unwrap();
+        let name: &str = rows.get(0).get("db");
+        assert_eq!(name, "test db");
+    }
+    assert_eq!(cl.current_database(), Some("test".to_owned()));
+    cl.use_database("test db").unwrap();
+    assert_eq!(cl.current_database(), Some("test db".to_owned()));
+    let rows = cl.query("SELECT DB_NAME() AS db;").unwrap();
+    let name: &str = rows.get(0).get("db");
+    assert_eq!(name, "test db");
+}
+
+#[test]
+fn test_connection_moved_to_thread() {
+    let cl = get_connection();
+    let handle = std::thread::spawn(move || {
+        let rows = cl.query("SELECT 42 AS answer;").unwrap();
+        let answer: i32 = rows.get(0).get("answer");
+        answer
+    });
+    assert_eq!(handle.join().unwrap(), 42);
+}
+
+#[test]
+fn test_money() {
+    let cl = get_connection();
+    let rows = cl.query("SELECT CAST(1234.5678 AS money) AS m, CAST(-12.3456 AS smallmoney) AS sm;").unwrap();
+    let m: f64 = rows.get(0).get("m");
+    assert_eq!(m, 1234.5678);
+    let sm: f64 = rows.get(0).get("sm");
+    assert_eq!(sm, -12.3456);
+}
+
+#[test]
+fn test_query_row_and_scalar() {
+    let cl = get_connection();
+    assert!(cl.query_row("SELECT 1 AS x WHERE 1 = 0;").unwrap().is_none());
+    let row = cl.query_row("SELECT 1 AS x;").unwrap().unwrap();
+    let x: i32 = row.get("x");
+    assert_eq!(x, 1);
+    assert_eq!(cl.query_scalar::<i32, _>("SELECT 1 AS x WHERE 1 = 0;").unwrap(), None);
+    assert_eq!(cl.query_scalar::<i32, _>("SELECT COUNT(*) FROM (VALUES (1), (2)) AS t(x);").unwrap(), Some(2));
+    assert_eq!(cl.query_scalar::<Option<i32>, _>("SELECT CAST(NULL AS int);").unwrap(), Some(None));
+}
+
+#[test]
+fn test_query_cancellable() {
+    use std::time::Instant;
+    use tiberius::TdsError;
+    let cl = get_connection();
+    let start = Instant::now();
+    match cl.query_cancellable("WAITFOR DELAY '00:00:05'; SELECT 1 AS x;", || start.elapsed() > Duration::from_millis(500)) {
+        Err(TdsError::Cancelled) => (),
+        x => panic!("expected the query to be cancelled, got {:?}", x)
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+    // the connection is still usable after the attention was acknowledged
+    let rows = cl.query("SELECT 1 AS x;").unwrap();
+    let x: i32 = rows.get(0).get("x");
+    assert_eq!(x, 1);
+}
+
+#[test]
+fn test_server_utc_now() {
+    use self::chrono::UTC;
+    let cl = get_connection();
+    let server_now = cl.server_utc_now().unwrap();
+    let skew = server_now - UTC::now();
+    assert!(skew.num_seconds().abs() < 60, "clock skew of {}", skew);
+}
+
+#[test]
+fn test_xml() {
+    let cl = get_connection();
+    let rows = cl.query("SELECT CAST('<a b=\"1\"/>' AS xml) AS x, CAST(NULL AS xml) AS n;").unwrap();
+    let x: String = rows.get(0).get("x");
+    assert_eq!(x, "<a b=\"1\"/>");
+    let n: Option<String> = rows.get(0).get("n");
+    assert_eq!(n, None);
+}
+
+#[test]
+fn test_merge_output() {
+    let cl = get_connection();
+    cl.exec("CREATE TABLE #merge_target (id int PRIMARY KEY, val varchar(10)); INSERT INTO #merge_target VALUES (1, 'old');").unwrap();
+    let (count, rows) = cl.exec_returning("MERGE #merge_target AS t USING (VALUES (1, 'new'), (2, 'ins')) AS s (id, val) ON t.id = s.id \
+        WHEN MATCHED THEN UPDATE SET val = s.val WHEN NOT MATCHED THEN INSERT (id, val) VALUES (s.id, s.val) \
+        OUTPUT $action, inserted.id, deleted.val AS old_val;").unwrap();
+    assert_eq!(count, 2);
+    let mut actions: Vec<(String, i32, Option<String>)> = rows.into_iter()
+        .map(|row| (row.get("$action"), row.get("id"), row.get("old_val")))
+        .collect();
+    actions.sort();
+    assert_eq!(actions, vec![("INSERT".to_owned(), 2, None), ("UPDATE".to_owned(), 1, Some("old".to_owned()))]);
+}
+
+#[test]
+fn test_print_messages() {
+    let cl = get_connection();
+    let rows = cl.query("PRINT 'hello'; SELECT 1").unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows.messages().len(), 1);
+    assert_eq!(rows.messages()[0].message, "hello");
+    assert_eq!(rows.messages()[0].class, 0);
+}
+
+#[test]
+fn test_query_metadata_only() {
+    let cl = get_connection();
+    cl.exec("CREATE TABLE #schema (a int, b nvarchar(10));").unwrap();
+    let rows = cl.query("SELECT a, b FROM #schema WHERE 1 = 0").unwrap();
+    assert_eq!(rows.len(), 0);
+    let names: Vec<Option<String>> = rows.columns().iter().map(|x| x.col_name.clone()).collect();
+    assert_eq!(names, vec![Some("a".to_owned()), Some("b".to_owned())]);
+}
+
+#[test]
+fn test_ping() {
+    let cl = get_connection();
+    cl.ping().unwrap();
+    assert_eq!(cl.query_scalar::<i32, _>("SELECT 2").unwrap(), Some(2));
+}
+
+#[test]
+fn test_query_order() {
+    let cl = get_connection();
+    cl.exec("CREATE TABLE #order_test (id int, name varchar(10) COLLATE Latin1_General_CI_AS); \
+        INSERT INTO #order_test VALUES (1, 'b'), (2, NULL), (3, 'A');").unwrap();
+    let rows = cl.query("SELECT id, name FROM #order_test ORDER BY name").unwrap();
+    assert_eq!(rows.order().len(), 1);
+    assert_eq!(rows.order()[0].index, 1);
+    assert!(rows.order()[0].collation.as_ref().unwrap().ignore_case());
+    let ids: Vec<i32> = rows.into_iter().map(|row| row.get("id")).collect();
+    assert_eq!(ids, vec![2, 3, 1]);
+}
+
+#[test]
+fn test_connection_string_mars_disabled() {
+    let cl = Connection::connect("server=localhost:1433;UID=test;PWD=test;Database=test;MultipleActiveResultSets=false").unwrap();
+    assert_eq!(cl.query_scalar::<i32, _>("SELECT 1").unwrap(), Some(1));
+}
+
+#[test]
+fn test_parameterized_auto() {
+    let cl = get_connection();
+    let stmt = cl.parameterized_auto("SELECT @P1 + 1 AS n, @P2 + N'!' AS s").unwrap();
+    let rows = stmt.query(&[&41i32, &"hello"]).unwrap();
+    let n: i32 = rows.get(0).get("n");
+    let s: &str = rows.get(0).get("s");
+    assert_eq!((n, s), (42, "hello!"));
+}
+
+#[test]
+fn test_bind_parameters() {
+    use tiberius::{Parameters, ToColumnType};
+    struct NewUser {
+        id: i32,
+        name: String,
+    }
+    impl Parameters for NewUser {
+        fn bind(&self) -> Vec<&ToColumnType> {
+            vec![&self.id, &self.name]
+        }
+    }
+    let cl = get_connection();
+    cl.exec("CREATE TABLE #users (id int, name nvarchar(50));").unwrap();
+    let stmt = cl.parameterized_auto("INSERT INTO #users (id, name) VALUES (@P1, @P2)").unwrap();
+    let user = NewUser { id: 1, name: "alice".to_owned() };
+    assert_eq!(stmt.exec(&user.bind()).unwrap(), 1);
+    let row = cl.query_row("SELECT id, name FROM #users").unwrap().unwrap();
+    assert_eq!((row.get::<_, i32>("id"), row.get::<_, &str>("name")), (1, "alice"));
+}
+
+#[test]
+fn test_bind_varbinary_max() {
+    let cl = get_connection();
+    cl.exec("CREATE TABLE #blobs (data varbinary(max));").unwrap();
+    let blob: Vec<u8> = (0..100000).map(|i| i as u8).collect();
+    let stmt = cl.parameterized_auto("INSERT INTO #blobs (data) VALUES (@P1)").unwrap();
+    assert_eq!(stmt.exec(&[&blob]).unwrap(), 1);
+    assert_eq!(cl.query_scalar::<i64, _>("SELECT CAST(DATALENGTH(data) AS bigint) FROM #blobs").unwrap(), Some(100000));
+}
+
+#[test]
+fn test_narrow_integers() {
+    let cl = get_connection();
+    let stmt = cl.parameterized_auto("SELECT @P1 AS a, @P2 AS b, CAST(@P3 AS tinyint) AS c").unwrap();
+    let row = stmt.query(&[&255u8, &-1234i16, &7i32]).unwrap().into_iter().next().unwrap();
+    assert_eq!((row.get::<_, u8>("a"), row.get::<_, i16>("b"), row.get::<_, u8>("c")), (255, -1234, 7));
+}
+
+#[test]
+fn test_connect_without_database() {
+    let cl = Connection::connect("server=localhost:1433;UID=test;PWD=test").unwrap();
+    let db: String = cl.query_scalar("SELECT DB_NAME();").unwrap().unwrap();
+    assert!(!db.is_empty());
+}
+
+#[test]
+fn test_empty_strings_as_null() {
+    use tiberius::{AuthenticationMethod, TcpConnectionBuilder};
+    for &empty_as_null in &[false, true] {
+        let opts = TcpConnectionBuilder::new_connect("localhost:1433").unwrap()
+            .auth(AuthenticationMethod::internal("test", "test"))
+            .db("test")
+            .empty_strings_as_null(empty_as_null);
+        let cl = Connection::connect(opts.build()).unwrap();
+        cl.exec("CREATE TABLE #empty (name nvarchar(50) NULL);").unwrap();
+        let stmt = cl.parameterized_auto("INSERT INTO #empty (name) VALUES (@P1)").unwrap();
+        assert_eq!(stmt.exec(&[&""]).unwrap(), 1);
+        let nulls: i32 = cl.query_scalar("SELECT COUNT(*) FROM #empty WHERE name IS NULL").unwrap().unwrap();
+        assert_eq!(nulls, empty_as_null as i32);
+    }
+}
+
+#[test]
+fn test_sql_variant() {
+    let cl = get_connection();
+    let row = cl.query_row("SELECT CAST(42 AS sql_variant) AS i, CAST(N'hello' AS sql_variant) AS s, CAST(1.5 AS sql_variant) AS d").unwrap().unwrap();
+    assert_eq!(row.get::<_, i32>("i"), 42);
+    assert_eq!(row.get::<_, &str>("s"), "hello");
+    assert_eq!(row.get::<_, f64>("d"), 1.5);
+}
+
+#[test]
+fn test_language() {
+    use tiberius::{AuthenticationMethod, TcpConnectionBuilder};
+    let opts = TcpConnectionBuilder::new_connect("localhost:1433").unwrap()
+        .auth(AuthenticationMethod::internal("test", "test"))
+        .db("test")
+        .language("Deutsch");
+    let cl = Connection::connect(opts.build()).unwrap();
+    let month = "SELECT DATENAME(month, CAST('2017-03-01' AS date));";
+    assert_eq!(cl.query_scalar::<String, _>(month).unwrap(), Some("März".to_owned()));
+    cl.set_option("LANGUAGE", "us_english").unwrap();
+    assert_eq!(cl.query_scalar::<String, _>(month).unwrap(), Some("March".to_owned()));
+    cl.set_option("DATEFORMAT", "dmy").unwrap();
+    let date: String = cl.query_scalar("SELECT CONVERT(nvarchar(10), CAST('13/03/2017' AS datetime), 120);").unwrap().unwrap();
+    assert_eq!(date, "2017-03-13");
+}
+
+#[test]
+fn test_server_version() {
+    let cl = get_connection();
+    let (major, _, _) = cl.server_version();
+    let product_version: String = cl.query_scalar("SELECT CAST(SERVERPROPERTY('ProductVersion') AS nvarchar(128));").unwrap().unwrap();
+    assert!(product_version.starts_with(&format!("{}.", major)));
+    assert_eq!(cl.server_name(), "Microsoft SQL Server");
+}
+
+#[test]
+fn test_session_options() {
+    let cl = get_connection();
+    cl.exec("SET ARITHABORT ON; SET LOCK_TIMEOUT 1000;").unwrap();
+    let opts = cl.session_options().unwrap();
+    assert!(opts.arithabort);
+    assert_eq!(opts.lock_timeout, 1000);
+    cl.exec("SET ARITHABORT OFF;").unwrap();
+    assert!(!cl.session_options().unwrap().arithabort);
+}
+
+#[test]
+fn test_transaction_rollback_batch() {
+    let cl = get_connection();
+    cl.exec("CREATE TABLE #tx_batch (id int);").unwrap();
+    let tx = cl.begin_transaction().unwrap();
+    assert_eq!(tx.exec("INSERT INTO #tx_batch VALUES (1);").unwrap(), 1);
+    tx.rollback().unwrap();
+    assert_eq!(cl.query_scalar::<i32, _>("SELECT COUNT(*) FROM #tx_batch;").unwrap(), Some(0));
+}
+
+#[test]
+fn test_transaction_descriptor() {
+    let cl = get_connection();
+    assert_eq!(cl.current_transaction_descriptor(), None);
+    let tx = cl.begin_transaction().unwrap();
+    assert!(cl.current_transaction_descriptor().is_some());
+    tx.commit().unwrap();
+    assert_eq!(cl.current_transaction_descriptor(), None);
+}
+
+#[test]
+fn test_transaction_deadlock_priority() {
+    use std::thread;
+    use tiberius::{DeadlockPriority, TdsError};
+    let cl = get_connection();
+    cl.exec("IF OBJECT_ID('tempdb..##deadlock_a') IS NOT NULL DROP TABLE ##deadlock_a; \
+        IF OBJECT_ID('tempdb..##deadlock_b') IS NOT NULL DROP TABLE ##deadlock_b; \
+        CREATE TABLE ##deadlock_a (id int); CREATE TABLE ##deadlock_b (id int); \
+        INSERT INTO ##deadlock_a VALUES (1); INSERT INTO ##deadlock_b VALUES (1);").unwrap();
+    // both transactions lock one table and then wait for the table the other one locked
+    let run = |first: &'static str, second: &'static str, priority: DeadlockPriority| thread::spawn(move || {
+        let cl = get_connection();
+        let tx = cl.begin_transaction().unwrap();
+        tx.deadlock_priority(priority).unwrap();
+        tx.lock_timeout(Some(Duration::from_secs(30))).unwrap();
+        tx.exec(format!("UPDATE {} SET id = 2;", first)).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        let result = tx.exec(format!("UPDATE {} SET id = 2;", second)).map(|_| ());
+        let _ = tx.rollback();
+        // the settings are restored once the transaction ended
+        assert_eq!(cl.query_scalar::<i32, _>("SELECT @@LOCK_TIMEOUT;").unwrap(), Some(-1));
+        result
+    });
+    let low = run("##deadlock_a", "##deadlock_b", DeadlockPriority::Low);
+    let normal = run("##deadlock_b", "##deadlock_a", DeadlockPriority::Normal);
+    match low.join().unwrap() {
+        Err(TdsError::ServerError(ref err)) if err.code == 1205 => (),
+        x => panic!("expected the low priority transaction to be the deadlock victim, got {:?}", x)
+    }
+    normal.join().unwrap().unwrap();
+    cl.exec("DROP TABLE ##deadlock_a; DROP TABLE ##deadlock_b;").unwrap();
+}
+
+#[test]
+fn test_transaction() {
+    use std::panic::{self, AssertUnwindSafe};
+    let cl = get_connection();
+    cl.exec("CREATE TABLE #tx (id int);").unwrap();
+    cl.transaction(|tx| tx.exec("INSERT INTO #tx VALUES (1);")).unwrap();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        cl.transaction(|tx| -> Result<(), tiberius::TdsError> {
+            tx.exec("INSERT INTO #tx VALUES (2);").unwrap();
+            panic!("failure within the transaction")
+        })
+    }));
+    assert!(result.is_err());
+    // the insert of the panicking transaction was rolled back
+    assert_eq!(cl.query_scalar::<i32, _>("SELECT COUNT(*) FROM #tx;").unwrap(), Some(1));
+    assert_eq!(cl.query_scalar::<i32, _>("SELECT @@TRANCOUNT;").unwrap(), Some(0));
+}
+
+#[test]
+fn test_query_each() {
+    let cl = get_connection();
+    let sql = "SELECT TOP 100000 ROW_NUMBER() OVER (ORDER BY a.object_id) AS n FROM sys.all_columns a CROSS JOIN sys.all_columns b;";
+    let mut count = 0i64;
+    cl.query_each(sql, |row| {
+        let n: i64 = row.get("n");
+        count += 1;
+        assert_eq!(n, count);
+        Ok(())
+    }).unwrap();
+    assert_eq!(count, 100000);
+
+    // an error of the callback cancels the query
+    count = 0;
+    assert!(cl.query_each(sql, |_| {
+        count += 1;
+        if count == 10 { Err(tiberius::TdsError::Other("stop".to_owned())) } else { Ok(()) }
+    }).is_err());
+    assert_eq!(count, 10);
+    assert_eq!(cl.query_scalar::<i32, _>("SELECT 1").unwrap(), Some(1));
+}
+
+#[test]
+fn test_tvp() {
+    use tiberius::Tvp;
+    let cl = get_connection();
+    cl.exec("IF OBJECT_ID('dbo.sum_pairs') IS NOT NULL DROP PROCEDURE dbo.sum_pairs;").unwrap();
+    cl.exec("IF TYPE_ID('dbo.IntPairs') IS NOT NULL DROP TYPE dbo.IntPairs;").unwrap();
+    cl.exec("CREATE TYPE dbo.IntPairs AS TABLE (a int, b int);").unwrap();
+    cl.exec("CREATE PROCEDURE dbo.sum_pairs @pairs dbo.IntPairs READONLY, @sum int OUTPUT AS SELECT @sum = SUM(a * b) FROM @pairs;").unwrap();
+    let tvp = Tvp::new("dbo.IntPairs").row(&[&1i32, &2i32]).row(&[&3i32, &4i32]);
+    let sum = 0i32;
+    let result = cl.call_proc("dbo.sum_pairs", &[Param::new("@pairs", &tvp), Param::new("@sum", &sum).output()]).unwrap();
+    let sum: i32 = result.get("@sum");
+    assert_eq!(sum, 14);
+}
+
+#[test]
+fn test_exec_proc() {
+    let cl = get_connection();
+    cl.exec("CREATE TABLE #proc_rows (id int);").unwrap();
+    cl.exec("IF OBJECT_ID('dbo.multi_result') IS NOT NULL DROP PROCEDURE dbo.multi_result;").unwrap();
+    cl.exec("CREATE PROCEDURE dbo.multi_result AS INSERT INTO #proc_rows VALUES (1), (2); SELECT 1; SELECT 2; RETURN 3;").unwrap();
+    assert_eq!(cl.exec("EXEC dbo.multi_result").unwrap(), 4);
+    let rows = cl.query("EXEC dbo.multi_result").unwrap();
+    assert_eq!(rows.len(), 2);
+    let result = cl.call_proc("dbo.multi_result", &[]).unwrap();
+    assert_eq!(result.return_status(), Some(3));
+}
+
+#[test]
+fn test_exec_nocount() {
+    let cl = get_connection();
+    cl.exec("CREATE TABLE #nocount (id int); INSERT INTO #nocount VALUES (1), (2);").unwrap();
+    assert_eq!(cl.exec("SET NOCOUNT ON; UPDATE #nocount SET id = id + 1; SET NOCOUNT OFF;").unwrap(), 0);
+    let statuses = cl.exec_statuses("UPDATE #nocount SET id = id + 1; DELETE FROM #nocount WHERE id = 3;").unwrap();
+    assert_eq!(statuses.iter().map(|x| x.row_count).collect::<Vec<_>>(), vec![Some(2), Some(1)]);
+    assert!(statuses[0].more());
+    assert!(!statuses[1].more());
+}
+
+#[test]
+fn test_bind_guid() {
+    use tiberius::Guid;
+    let cl = get_connection();
+    cl.exec("CREATE TABLE #guids (id uniqueidentifier, id_str char(36));").unwrap();
+    cl.exec("INSERT INTO #guids VALUES ('6f9619ff-8b86-d011-b42d-00c04fc964ff', '6f9619ff-8b86-d011-b42d-00c04fc964ff'); INSERT INTO #guids VALUES (NEWID(), 'other');").unwrap();
+    let guid = Guid::parse("6f9619ff-8b86-d011-b42d-00c04fc964ff").unwrap();
+    let stmt = cl.parameterized_auto("SELECT id FROM #guids WHERE id = @P1").unwrap();
+    let rows = stmt.query(&[&guid]).unwrap();
+    assert_eq!(rows.len(), 1);
+    let id: &Guid = rows.get(0).get(0);
+    assert_eq!(id, &guid);
+    let stmt = cl.parameterized_auto("SELECT id FROM #guids WHERE id_str = @P1").unwrap();
+    let rows = stmt.query(&[&guid.as_string()]).unwrap();
+    assert_eq!(rows.len(), 1);
+}
+
+#[test]
+fn test_text_null() {
+    let cl = get_connection();
+    cl.exec("CREATE TABLE #texts (id int, t text, nt ntext); INSERT INTO #texts VALUES (1, REPLICATE(CAST('x' AS varchar(max)), 10000), N'莊子'), (2, NULL, NULL);").unwrap();
+    let rows = cl.query("SELECT t, nt FROM #texts ORDER BY id;").unwrap();
+    let text: &str = rows.get(0).get(0);
+    assert_eq!(text.len(), 10000);
+    let ntext: &str = rows.get(0).get(1);
+    assert_eq!(ntext, "莊子");
+    let text: Option<&str> = rows.get(1).get(0);
+    assert_eq!(text, None);
+    let ntext: Option<&str> = rows.get(1).get(1);
+    assert_eq!(ntext, None);
+}
+
+#[test]
+fn test_exec_truncate() {
+    let cl = get_connection();
+    cl.exec("CREATE TABLE #truncate (id int); INSERT INTO #truncate VALUES (1), (2);").unwrap();
+    assert_eq!(cl.exec("TRUNCATE TABLE #truncate").unwrap(), 0);
+    assert_eq!(cl.exec_count("TRUNCATE TABLE #truncate").unwrap(), None);
+    assert_eq!(cl.exec_count("DELETE FROM #truncate").unwrap(), Some(0));
+}
+
+#[test]
+fn test_exec_batch() {
+    use tiberius::TdsError;
+    let cl = get_connection();
+    cl.exec("CREATE TABLE #batch (id int);").unwrap();
+    let counts = cl.exec_batch(&["INSERT INTO #batch VALUES (1)", "INSERT INTO #batch VALUES (2), (3)", "INSERT INTO #batch SELECT id FROM #batch"]).unwrap();
+    assert_eq!(counts, vec![1, 2, 3]);
+    match cl.exec_batch(&["INSERT INTO #batch VALUES (1)", "INSERT INTO #batch_missing VALUES (1)"]) {
+        Err(TdsError::BatchError(idx, counts, _)) => {
+            assert_eq!(idx, 1);
+            assert_eq!(counts, vec![1]);
+        },
+        x => panic!("expected a batch error, got {:?}", x)
+    }
+}
+
+#[test]
+fn test_query_cancelable_token() {
+    use std::thread;
+    use std::time::Instant;
+    use tiberius::{CancellationToken, TdsError};
+    let cl = get_connection();
+    let token = CancellationToken::new();
+    let canceller = token.clone();
+    let start = Instant::now();
+    let handle = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(500));
+        canceller.cancel();
+    });
+    match cl.query_cancelable("WAITFOR DELAY '00:00:05'; SELECT 1 AS x;", &token) {
+        Err(TdsError::Cancelled) => (),
+        x => panic!("expected the query to be cancelled, got {:?}", x)
+    }
+    handle.join().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    let rows = cl.query("SELECT 1 AS x;").unwrap();
+    let x: i32 = rows.get(0).get("x");
+    assert_eq!(x, 1);
+}
+
+#[test]
+fn test_row_columns() {
+    let cl = get_connection();
+    let rows = cl.query("SELECT 1 AS a, N'b' AS b, CAST(1 AS bit) AS c;").unwrap();
+    let row = rows.get(0);
+    assert_eq!(row.column_count(), 3);
+    let names: Vec<String> = row.columns().iter().map(|x| x.col_name.clone().unwrap()).collect();
+    assert_eq!(names, vec!["a", "b", "c"]);
+}
+
+#[test]
+fn test_bit_as_integer() {
+    let cl = get_connection();
+    cl.exec("CREATE TABLE #bits (id int, flag bit);").unwrap();
+    let stmt = cl.parameterized_auto("INSERT INTO #bits VALUES (@P1, @P2)").unwrap();
+    stmt.exec(&[&1i32, &true]).unwrap();
+    stmt.exec(&[&2i32, &0i32]).unwrap();
+    let rows = cl.query("SELECT flag FROM #bits ORDER BY id;").unwrap();
+    let flag: bool = rows.get(0).get(0);
+    assert_eq!(flag, true);
+    let flag: i32 = rows.get(0).get(0);
+    assert_eq!(flag, 1);
+    let flag: bool = rows.get(1).get(0);
+    assert_eq!(flag, false);
+    let flag: i32 = rows.get(1).get(0);
+    assert_eq!(flag, 0);
+}
+
+#[test]
+fn test_varchar_collation() {
+    let cl = get_connection();
+    let rows = cl.query("SELECT CAST('café' COLLATE Latin1_General_CI_AS AS varchar(10)) AS a, CAST(N'Ми' COLLATE Cyrillic_General_CI_AS AS varchar(10)) AS b;").unwrap();
+    let a: &str = rows.get(0).get("a");
+    assert_eq!(a, "café");
+    let b: &str = rows.get(0).get("b");
+    assert_eq!(b, "Ми");
+}
+
+#[test]
+fn test_query_as() {
+    use tiberius::{FromRow, Row, TdsResult};
+    #[derive(Debug, PartialEq)]
+    struct User {
+        id: i32,
+        name: String,
+    }
+    impl FromRow for User {
+        fn from_row(row: &Row) -> TdsResult<User> {
+            Ok(User { id: try!(row.try_get("id")), name: try!(row.try_get("name")) })
+        }
+    }
+    let cl = get_connection();
+    let users: Vec<User> = cl.query_as("SELECT 1 AS id, N'alice' AS name UNION ALL SELECT 2, N'bob' ORDER BY id;").unwrap();
+    assert_eq!(users, vec![User { id: 1, name: "alice".to_owned() }, User { id: 2, name: "bob".to_owned() }]);
+    let users: Vec<User> = cl.query_as_params("SELECT @P1 AS id, @P2 AS name;", &[&3i32, &"carol"]).unwrap();
+    assert_eq!(users, vec![User { id: 3, name: "carol".to_owned() }]);
+}
+
+#[test]
+fn test_describe() {
+    let cl = get_connection();
+    let columns = cl.describe("SELECT 1 AS a, 'x' AS b").unwrap();
+    assert_eq!(columns.len(), 2);
+    assert_eq!(columns[0].name.as_ref().unwrap(), "a");
+    assert_eq!(columns[1].name.as_ref().unwrap(), "b");
+    // FMTONLY is reset afterwards
+    assert_eq!(cl.query("SELECT 1 AS a").unwrap().len(), 1);
+}
+
+#[test]
+fn test_lock_timeout() {
+    let cl = get_connection();
+    cl.exec("IF OBJECT_ID('tempdb..##lock_timeout') IS NULL CREATE TABLE ##lock_timeout (id INT PRIMARY KEY, x INT); \
+        DELETE FROM ##lock_timeout; INSERT INTO ##lock_timeout VALUES (1, 0);").unwrap();
+    let trans = cl.begin_transaction().unwrap();
+    trans.exec("UPDATE ##lock_timeout SET x = 1 WHERE id = 1;").unwrap();
+
+    let other = get_connection();
+    other.set_lock_timeout(100).unwrap();
+    match other.exec("UPDATE ##lock_timeout SET x = 2 WHERE id = 1;") {
+        Err(tiberius::TdsError::LockTimeout(_)) => (),
+        x => panic!("expected a lock timeout, got {:?}", x)
+    }
+    trans.rollback().unwrap();
+}
+
+#[test]
+fn test_nvarchar_max() {
+    let cl = get_connection();
+    let rows = cl.query("SELECT REPLICATE(CAST(N'a' AS NVARCHAR(MAX)), 2000) + NCHAR(0xD83D) + NCHAR(0xDE00) + REPLICATE(CAST(N'b' AS NVARCHAR(MAX)), 2100) AS s, \
+        CAST(NULL AS NVARCHAR(MAX)) AS n;").unwrap();
+    let s: &str = rows.get(0).get("s");
+    assert_eq!(s, format!("{}\u{1F600}{}", "a".repeat(2000), "b".repeat(2100)));
+    let n: Option<&str> = rows.get(0).get("n");
+    assert_eq!(n, None);
+}
+
+#[test]
+fn test_udt_bytes() {
+    let cl = get_connection();
+    let rows = cl.query("SELECT geography::Point(47.65, -122.34, 4326) AS g, hierarchyid::Parse('/1/2/') AS h;").unwrap();
+    let g: &[u8] = rows.get(0).get("g");
+    assert!(!g.is_empty());
+    let h: &[u8] = rows.get(0).get("h");
+    assert!(!h.is_empty());
+}
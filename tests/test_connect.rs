@@ -0,0 +1,299 @@
+extern crate tiberius;
+mod mock;
+use std::net::TcpListener;
+use std::thread;
+use std::time::{Duration, Instant};
+use mock::{MockStream, builder, sent_packets, packet, done, env_packet_size, login_ack, b_varchar, connect};
+use tiberius::{AuthenticationMethod, Connection, LoginFeature, LoginFlag, Param, TcpConnectionBuilder, TdsError, TimeOfDay, quote_identifier};
+
+#[test]
+fn test_handshake_timeout() {
+    // accepts the connection but never answers the prelogin
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        thread::sleep(Duration::from_secs(2));
+        drop(stream);
+    });
+
+    let start = Instant::now();
+    let opts = TcpConnectionBuilder::new_connect_timeout(addr, Duration::from_millis(200)).unwrap()
+        .auth(AuthenticationMethod::internal("test", "test"))
+        .db("test")
+        .build();
+    match Connection::connect(opts) {
+        Err(TdsError::Timeout) => (),
+        Err(x) => panic!("expected a timeout, got {:?}", x),
+        Ok(_) => panic!("expected a timeout"),
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+    server.join().unwrap();
+}
+
+#[test]
+fn test_login_timeout() {
+    // accepts the connection but never answers the prelogin
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        thread::sleep(Duration::from_secs(3));
+        drop(stream);
+    });
+
+    let start = Instant::now();
+    let conn_str = format!("Server={};Login Timeout=1;UID=test;PWD=test", addr);
+    match Connection::connect(&conn_str[..]) {
+        Err(TdsError::Timeout) => (),
+        Err(x) => panic!("expected a timeout, got {:?}", x),
+        Ok(_) => panic!("expected a timeout"),
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(3));
+    server.join().unwrap();
+
+    match Connection::connect("Login Timeout=soon;Server=localhost:1433") {
+        Err(TdsError::Other(ref msg)) => assert_eq!(msg, "invalid value soon for Login Timeout"),
+        _ => panic!("expected an invalid value"),
+    }
+}
+
+#[test]
+fn test_failover_partner() {
+    use std::io::{Read, Write};
+    // the primary refuses the connection
+    let primary = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    // the partner answers the prelogin and the login
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let partner = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        for response in vec![packet(1, &[0xFF]), packet(1, &done(0, 0))] {
+            let mut header = [0; 8];
+            stream.read_exact(&mut header).unwrap();
+            let mut data = vec![0; ((header[2] as usize) << 8 | header[3] as usize) - 8];
+            stream.read_exact(&mut data).unwrap();
+            stream.write_all(&response).unwrap();
+        }
+    });
+
+    let conn_str = format!("Server=tcp:{},{};Failover Partner={};UID=test;PWD=test", primary.ip(), primary.port(), partner);
+    Connection::connect(&conn_str[..]).unwrap();
+    server.join().unwrap();
+
+    // without a partner the error of the primary is returned
+    let conn_str = format!("Server={};UID=test;PWD=test", primary);
+    match Connection::connect(&conn_str[..]) {
+        Err(TdsError::IoError(_)) => (),
+        Err(x) => panic!("expected the connection to be refused, got {:?}", x),
+        Ok(_) => panic!("expected the connection to be refused"),
+    }
+}
+
+#[test]
+fn test_quote_identifier() {
+    assert_eq!(quote_identifier("test"), "[test]");
+    assert_eq!(quote_identifier("My DB"), "[My DB]");
+    assert_eq!(quote_identifier("a]b"), "[a]]b]");
+}
+
+#[test]
+fn test_login_flags() {
+    let login_flags = |flags: &[LoginFlag]| {
+        let stream = MockStream::new();
+        let written = stream.written.clone();
+        let mut opts = builder(stream);
+        for flag in flags {
+            opts = opts.login_flag(*flag);
+        }
+        Connection::connect(opts.build()).unwrap();
+        // OptionFlags1 follows the 8 byte header and 24 bytes of fixed fields, return OptionFlags2, TypeFlags and OptionFlags3
+        let packets = sent_packets(&written.lock().unwrap());
+        assert_eq!(packets[1][0], 16);
+        (packets[1][33], packets[1][34], packets[1][35])
+    };
+    assert_eq!(login_flags(&[]), (0, 0, 0));
+    assert_eq!(login_flags(&[LoginFlag::ReadOnlyIntent]), (0, 0x20, 0));
+    assert_eq!(login_flags(&[LoginFlag::CacheConnect, LoginFlag::UserInstance]), (0x08, 0, 0x04));
+    assert_eq!(login_flags(&[LoginFlag::UnknownCollationHandling, LoginFlag::UnknownCollationHandling]), (0, 0, 0x08));
+}
+
+#[test]
+fn test_login_features() {
+    let mut login_response = login_ack(0x74000004);
+    // FEATUREEXTACK: UTF8_SUPPORT with the supported bit set, the terminator
+    login_response.extend_from_slice(&[0xAE, 0x0A, 1, 0, 0, 0, 0x01, 0xFF]);
+    login_response.extend(done(0, 0));
+    let stream = MockStream::with_login_response(login_response);
+    let written = stream.written.clone();
+    let conn = Connection::connect(builder(stream).login_feature(LoginFeature::Utf8Support).build()).unwrap();
+    assert!(conn.utf8_support());
+    assert_eq!(conn.feature_ack(LoginFeature::Utf8Support), Some(vec![0x01]));
+
+    let packets = sent_packets(&written.lock().unwrap());
+    let login = &packets[1][8..];
+    let read_u16 = |pos: usize| login[pos] as usize | (login[pos + 1] as usize) << 8;
+    // TDS 7.4 and fExtension
+    assert_eq!(&login[4..8], &[0x04, 0, 0, 0x74]);
+    assert_eq!(login[27] & 0x10, 0x10);
+    // ibExtension points to the offset of the block, which follows all other data (the database is the last)
+    let (ib_extension, cb_extension) = (read_u16(56), read_u16(58));
+    assert_eq!(cb_extension, 4);
+    let feature_ext = login[ib_extension] as usize | (login[ib_extension + 1] as usize) << 8;
+    assert_eq!(feature_ext, read_u16(68) + read_u16(70) * 2);
+    assert_eq!(&login[feature_ext..], &[0x0A, 0, 0, 0, 0, 0xFF]);
+    assert_eq!(login.len(), feature_ext + 6);
+
+    // without features TDS 7.3 is requested and nothing is acknowledged
+    let stream = MockStream::new();
+    let written = stream.written.clone();
+    let conn = connect(stream);
+    assert!(!conn.utf8_support());
+    let packets = sent_packets(&written.lock().unwrap());
+    assert_eq!(&packets[1][8 + 4..8 + 8], &[0x03, 0, 0x0A, 0x73]);
+    assert_eq!(packets[1][8 + 27] & 0x10, 0);
+}
+
+#[test]
+fn test_packet_size() {
+    let login_packet_size = |size: u16| {
+        let stream = MockStream::new();
+        let written = stream.written.clone();
+        Connection::connect(builder(stream).packet_size(size).build()).unwrap();
+        // the packet size follows the 8 byte header, the length and the TDS version
+        let packets = sent_packets(&written.lock().unwrap());
+        let login = &packets[1];
+        login[16] as u32 | (login[17] as u32) << 8 | (login[18] as u32) << 16 | (login[19] as u32) << 24
+    };
+    assert_eq!(login_packet_size(8192), 8192);
+    assert_eq!(login_packet_size(100), 512);
+    assert_eq!(login_packet_size(40000), 32767);
+
+    // the server forces a smaller packet size which is used for sending
+    let mut login_response = env_packet_size(512, 8192);
+    login_response.extend(done(0, 0));
+    let mut stream = MockStream::with_login_response(login_response);
+    let written = stream.written.clone();
+    stream.push(packet(1, &done(0, 0)));
+    let conn = Connection::connect(builder(stream).packet_size(8192).build()).unwrap();
+    let sql = format!("SELECT '{}'", (0..1000).map(|_| "x").collect::<String>());
+    conn.exec(&sql[..]).unwrap();
+    let packets = sent_packets(&written.lock().unwrap());
+    let batch = &packets[2..];
+    // 22 bytes of the transaction header and the statement as UTF-16
+    assert_eq!(batch.len(), 5);
+    assert!(batch.iter().all(|p| p[0] == 1 && p.len() <= 512));
+    assert!(batch[..4].iter().all(|p| p[1] == 0 && p.len() == 512));
+    assert_eq!(batch[4][1], 1);
+}
+
+#[test]
+fn test_tds_version() {
+    let mut login_response = login_ack(0x730B0003);
+    login_response.extend(done(0, 0));
+    let conn = connect(MockStream::with_login_response(login_response));
+    assert_eq!(conn.tds_version(), 0x730B0003);
+
+    // TDS 7.2 does not know the time type
+    let mut login_response = login_ack(0x72090002);
+    login_response.extend(done(0, 0));
+    let conn = connect(MockStream::with_login_response(login_response));
+    assert_eq!(conn.tds_version(), 0x72090002);
+    let time = TimeOfDay::new(Duration::from_secs(60)).unwrap();
+    match conn.call_proc("p", &[Param::new("@t", &time)]) {
+        Err(TdsError::Other(ref msg)) if msg.contains("requires TDS 7.3") => (),
+        x => panic!("expected an error, got {:?}", x.map(|_| ()))
+    }
+}
+
+#[test]
+fn test_connection_string_mars() {
+    // the keyword is parsed before the server is connected to
+    match Connection::connect("MultipleActiveResultSets=true;server=localhost:1433;UID=test;PWD=test;Database=test") {
+        Err(TdsError::Other(ref msg)) => assert_eq!(msg, "MultipleActiveResultSets is not supported"),
+        _ => panic!("expected MARS to be rejected"),
+    }
+    match Connection::connect("MultipleActiveResultSets=maybe;server=localhost:1433") {
+        Err(TdsError::Other(ref msg)) => assert_eq!(msg, "invalid value maybe for MultipleActiveResultSets"),
+        _ => panic!("expected an invalid value"),
+    }
+}
+
+#[test]
+fn test_server_version() {
+    // SQL Server 2016 SP1: 13.0.4001
+    let mut data = vec![1, 0x74, 0x00, 0x00, 0x04];
+    data.extend(b_varchar("Microsoft SQL Server"));
+    data.extend_from_slice(&[13, 0, 0x0F, 0xA1]);
+    let mut login_response = vec![0xAD, data.len() as u8, (data.len() >> 8) as u8];
+    login_response.extend(data);
+    login_response.extend(done(0, 0));
+    let conn = connect(MockStream::with_login_response(login_response));
+    assert_eq!(conn.server_name(), "Microsoft SQL Server");
+    assert_eq!(conn.server_version(), (13, 0, 4001));
+    assert_eq!(conn.tds_version(), 0x74000004);
+}
+
+#[test]
+fn test_connect_refused() {
+    // a port nobody listens on anymore
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    match TcpConnectionBuilder::new_connect(addr) {
+        Err(TdsError::IoError(ref err)) => assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused),
+        Err(x) => panic!("expected an io error, got {:?}", x),
+        Ok(_) => panic!("expected the connection to be refused")
+    }
+}
+
+#[test]
+fn test_connect_retries() {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    // the first stream is closed right away and the first reconnect is refused
+    let attempts = Arc::new(Mutex::new(0));
+    let reconnects = attempts.clone();
+    let opts = builder(MockStream::empty())
+        .connect_retries(2)
+        .connect_backoff(Duration::from_millis(1))
+        .on_reconnect(move || {
+            let mut attempts = reconnects.lock().unwrap();
+            *attempts += 1;
+            match *attempts {
+                1 => Err(TdsError::from(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"))),
+                _ => Ok(MockStream::new())
+            }
+        });
+    Connection::connect(opts.build()).unwrap();
+    assert_eq!(*attempts.lock().unwrap(), 2);
+
+    // the retries are exhausted
+    let opts = builder(MockStream::empty())
+        .connect_retries(1)
+        .connect_backoff(Duration::from_millis(1))
+        .on_reconnect(|| Err(TdsError::from(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"))));
+    match Connection::connect(opts.build()) {
+        Err(TdsError::IoError(ref err)) => assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused),
+        Err(x) => panic!("expected the connection to be refused, got {:?}", x),
+        Ok(_) => panic!("expected the connection to be refused")
+    }
+
+    // a failed login is not retried
+    let reconnected = Arc::new(Mutex::new(false));
+    let flag = reconnected.clone();
+    let mut login = vec![];
+    login.extend(mock::error(18456, 14, "Login failed for user 'test'.", 1));
+    login.extend(done(0x02, 0));
+    let opts = builder(MockStream::with_login_response(login))
+        .connect_retries(3)
+        .on_reconnect(move || {
+            *flag.lock().unwrap() = true;
+            Ok(MockStream::new())
+        });
+    match Connection::connect(opts.build()) {
+        Err(TdsError::ServerError(ref err)) => assert_eq!(err.code, 18456),
+        Err(x) => panic!("expected the login to fail, got {:?}", x),
+        Ok(_) => panic!("expected the login to fail")
+    }
+    assert!(!*reconnected.lock().unwrap());
+}
@@ -0,0 +1,2393 @@
+extern crate tiberius;
+extern crate chrono;
+#[cfg(feature = "row")]
+#[macro_use]
+extern crate serde_derive;
+mod mock;
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use chrono::{DateTime, FixedOffset, NaiveDate, UTC, TimeZone};
+use mock::{MockStream, packet, packets, done, env_change, error, proc_error, info, b_varchar, nvarchar, builder, connect, sent_packets};
+use tiberius::Connection;
+use tiberius::{CancellationToken, CaseSensitive, ColumnType, ColumnValue, DeadlockPriority, FixedLenType, FromRow, Guid, GuidString, Param, Parameters, Row, SqlString, StatementInternal, TdsError, TimeOfDay, ToColumnType, Tvp, TypeInfo, VarLenType, param_declarations};
+
+#[test]
+fn test_row_split_across_packets() {
+    let value: Vec<u8> = (0..200).collect();
+    let mut msg = vec![];
+    // COLMETADATA: 1 column varbinary(200) NULL named "bin"
+    msg.extend_from_slice(&[0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0xA5, 200, 0]);
+    msg.extend(b_varchar("bin"));
+    // ROW
+    msg.extend_from_slice(&[0xD1, 200, 0]);
+    msg.extend_from_slice(&value);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    let (first, second) = msg.split_at(100);
+    stream.push(packet(0, first));
+    // the last packet arrives in two reads
+    let second = packet(1, second);
+    let (a, b) = second.split_at(50);
+    stream.push(a.to_vec());
+    stream.push(b.to_vec());
+
+    let conn = connect(stream);
+    let rows = conn.query("SELECT bin FROM test").unwrap();
+    assert_eq!(rows.len(), 1);
+    let bin: &[u8] = rows.get(0).get("bin");
+    assert_eq!(bin, &value[..]);
+}
+
+#[test]
+fn test_floatn() {
+    let mut msg = vec![];
+    // COLMETADATA: float NULL, real NULL, float NULL
+    msg.extend_from_slice(&[0x81, 3, 0]);
+    for &(len, name) in &[(8, "f"), (4, "r"), (8, "n")] {
+        msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0x6D, len]);
+        msg.extend(b_varchar(name));
+    }
+    // ROW: 3.14, 1.5, NULL
+    msg.extend_from_slice(&[0xD1, 8, 0x1F, 0x85, 0xEB, 0x51, 0xB8, 0x1E, 0x09, 0x40]);
+    msg.extend_from_slice(&[4, 0x00, 0x00, 0xC0, 0x3F]);
+    msg.push(0);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT f, r, n FROM test").unwrap();
+    let f: f64 = rows.get(0).get("f");
+    assert_eq!(f, 3.14f64);
+    let r: f32 = rows.get(0).get("r");
+    assert_eq!(r, 1.5f32);
+    let n: Option<f64> = rows.get(0).get("n");
+    assert_eq!(n, None);
+}
+
+#[test]
+fn test_bitn() {
+    let mut msg = vec![];
+    // COLMETADATA: bit NULL x3
+    msg.extend_from_slice(&[0x81, 3, 0]);
+    for name in &["t", "f", "n"] {
+        msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0x68, 1]);
+        msg.extend(b_varchar(name));
+    }
+    // ROW: any non-zero byte is true, 0, NULL
+    msg.extend_from_slice(&[0xD1, 1, 2, 1, 0, 0]);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT t, f, n FROM test").unwrap();
+    let t: bool = rows.get(0).get("t");
+    assert_eq!(t, true);
+    let f: bool = rows.get(0).get("f");
+    assert_eq!(f, false);
+    let n: Option<bool> = rows.get(0).get("n");
+    assert_eq!(n, None);
+}
+
+#[test]
+fn test_connection_moved_to_thread() {
+    let mut msg = vec![];
+    // COLMETADATA: int NULL
+    msg.extend_from_slice(&[0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0x26, 4]);
+    msg.extend(b_varchar("answer"));
+    msg.extend_from_slice(&[0xD1, 4, 42, 0, 0, 0]);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let handle = std::thread::spawn(move || {
+        let rows = conn.query("SELECT 42 AS answer").unwrap();
+        let answer: i32 = rows.get(0).get("answer");
+        answer
+    });
+    assert_eq!(handle.join().unwrap(), 42);
+}
+
+#[test]
+fn test_read_bytes_into_reused_buffer() {
+    let mut msg = vec![];
+    // COLMETADATA: 1 column varbinary(100) NULL named "bin"
+    msg.extend_from_slice(&[0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0xA5, 100, 0]);
+    msg.extend(b_varchar("bin"));
+    for i in 0..3u8 {
+        msg.extend_from_slice(&[0xD1, 100, 0]);
+        msg.extend((0..100).map(|x| x as u8 ^ i));
+    }
+    // and a NULL
+    msg.extend_from_slice(&[0xD1, 0xFF, 0xFF]);
+    msg.extend(done(0x10, 4));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT bin FROM test").unwrap();
+    assert_eq!(rows.len(), 4);
+
+    let mut buf = Vec::with_capacity(100);
+    let ptr = buf.as_ptr();
+    for i in 0..3 {
+        buf.clear();
+        assert_eq!(rows.get(i).read_bytes_into("bin", &mut buf).unwrap(), Some(100));
+        assert!(buf.iter().enumerate().all(|(x, &b)| b == x as u8 ^ i as u8));
+        // the buffer was neither grown nor reallocated
+        assert_eq!(buf.capacity(), 100);
+        assert_eq!(buf.as_ptr(), ptr);
+    }
+    buf.clear();
+    assert_eq!(rows.get(3).read_bytes_into("bin", &mut buf).unwrap(), None);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_moneyn() {
+    let mut msg = vec![];
+    // COLMETADATA: money NULL x3, smallmoney NULL
+    msg.extend_from_slice(&[0x81, 4, 0]);
+    for &(len, name) in &[(8, "a"), (8, "b"), (8, "c"), (4, "d")] {
+        msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0x6E, len]);
+        msg.extend(b_varchar(name));
+    }
+    msg.push(0xD1);
+    // 1234.5678: high dword first, then the low dword
+    msg.extend_from_slice(&[8, 0, 0, 0, 0, 0x4E, 0x61, 0xBC, 0x00]);
+    // 214748.3648: the low dword has its highest bit set
+    msg.extend_from_slice(&[8, 0, 0, 0, 0, 0, 0, 0, 0x80]);
+    // -0.0001
+    msg.extend_from_slice(&[8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    // -12.3456 as smallmoney
+    msg.extend_from_slice(&[4, 0xC0, 0x1D, 0xFE, 0xFF]);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT a, b, c, d FROM test").unwrap();
+    let a: f64 = rows.get(0).get("a");
+    assert_eq!(a, 1234.5678);
+    let b: f64 = rows.get(0).get("b");
+    assert_eq!(b, 214748.3648);
+    let c: f64 = rows.get(0).get("c");
+    assert_eq!(c, -0.0001);
+    let d: f64 = rows.get(0).get("d");
+    assert_eq!(d, -12.3456);
+}
+
+#[test]
+fn test_query_scalar() {
+    let mut msg = vec![];
+    // COLMETADATA: int NULL, without a column name
+    msg.extend_from_slice(&[0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0x26, 4, 0]);
+    msg.extend_from_slice(&[0xD1, 4, 2, 0, 0, 0]);
+    msg.extend(done(0x10, 1));
+    let mut empty = vec![0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0x26, 4, 0];
+    empty.extend(done(0x10, 0));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    stream.push(packet(1, &empty));
+    let conn = connect(stream);
+    assert_eq!(conn.query_scalar::<i32, _>("SELECT COUNT(*) FROM test").unwrap(), Some(2));
+    assert_eq!(conn.query_scalar::<i32, _>("SELECT x FROM test WHERE 1 = 0").unwrap(), None);
+}
+
+#[test]
+fn test_query_cancellable() {
+    let mut msg = vec![];
+    // COLMETADATA: int NULL
+    msg.extend_from_slice(&[0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0x26, 4]);
+    msg.extend(b_varchar("x"));
+    msg.extend_from_slice(&[0xD1, 4, 1, 0, 0, 0]);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    // the first packet of the response, then the rest of it and the attention acknowledgement
+    let (first, second) = msg.split_at(10);
+    stream.push(packet(0, first));
+    stream.push(packet(1, second));
+    stream.push(packet(1, &done(0x20, 0)));
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+
+    let polls = Cell::new(0);
+    let ret = conn.query_cancellable("SELECT x FROM test", || {
+        polls.set(polls.get() + 1);
+        polls.get() > 1
+    });
+    match ret {
+        Err(TdsError::Cancelled) => (),
+        x => panic!("expected the query to be cancelled, got {:?}", x)
+    }
+    // prelogin, login, the query and the attention
+    let packets = sent_packets(&written.lock().unwrap());
+    assert_eq!(packets.len(), 4);
+    assert_eq!(packets[3], vec![6, 1, 0, 8, 0, 0, packets[3][6], 0]);
+
+    let rows = conn.query("SELECT x FROM test").unwrap();
+    let x: i32 = rows.get(0).get("x");
+    assert_eq!(x, 1);
+}
+
+#[test]
+fn test_narrow_integers() {
+    // tinyint, smallint and int columns
+    let mut msg = vec![0x81, 3, 0];
+    for ty in &[0x30, 0x34, 0x38] {
+        msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, *ty, 0]);
+    }
+    msg.extend_from_slice(&[0xD1, 0xFF, 0x2E, 0xFB, 0xFF, 0xFF, 0xFF, 0x7F]);
+    msg.extend(done(0x10, 1));
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+
+    let stmt = conn.parameterized_auto("SELECT @P1, @P2, @P3").unwrap();
+    let rows = stmt.query(&[&255u8, &-1234i16, &i32::max_value()]).unwrap();
+    let row = rows.get(0);
+    assert_eq!((row.get::<_, u8>(0), row.get::<_, i16>(1), row.get::<_, i32>(2)), (255, -1234, i32::max_value()));
+
+    let packets = sent_packets(&written.lock().unwrap());
+    let utf16 = |s: &str| s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect::<Vec<u8>>();
+    let declarations = utf16("@P1 tinyint,@P2 smallint,@P3 int");
+    assert!(packets[2].windows(declarations.len()).any(|x| x == &declarations[..]));
+    let mut params = vec![];
+    for (i, value) in [&[1, 1, 0xFF][..], &[2, 2, 0x2E, 0xFB], &[4, 4, 0xFF, 0xFF, 0xFF, 0x7F]].iter().enumerate() {
+        params.extend(b_varchar(&format!("@P{}", i + 1)));
+        params.extend_from_slice(&[0, 0x26]);
+        params.extend_from_slice(value);
+    }
+    assert!(packets[2].ends_with(&params));
+}
+
+#[test]
+fn test_sql_variant() {
+    // three sql_variant columns
+    let mut msg = vec![0x81, 3, 0];
+    for _ in 0..3 {
+        msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x62, 0x10, 0x1F, 0, 0, 0]);
+    }
+    msg.push(0xD1);
+    // an int, an nvarchar(8000) with its collation and NULL
+    msg.extend_from_slice(&[6, 0, 0, 0, 0x38, 0, 42, 0, 0, 0]);
+    msg.extend_from_slice(&[13, 0, 0, 0, 0xE7, 7, 0x09, 0x04, 0xD0, 0x00, 0x34, 0x40, 0x1F, b'a', 0, b'b', 0]);
+    msg.extend_from_slice(&[0, 0, 0, 0]);
+    msg.extend(done(0x10, 1));
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+
+    let sql = "SELECT CAST(42 AS sql_variant), CAST(N'ab' AS sql_variant), CAST(NULL AS sql_variant)";
+    let rows = conn.query(sql).unwrap();
+    let row = rows.get(0);
+    assert_eq!(row.get::<_, i32>(0), 42);
+    assert_eq!(row.get::<_, &str>(1), "ab");
+    assert_eq!(row.get::<_, Option<i32>>(2), None);
+    let values = conn.query_row(sql).unwrap().unwrap().into_values();
+    match values[0] {
+        ColumnValue::Some(ColumnType::Variant(ref x)) => assert_eq!(x.variant_name(), "I32"),
+        ref x => panic!("expected a sql_variant, got {:?}", x)
+    }
+}
+
+#[test]
+fn test_bind_dynamic_values() {
+    let mut msg = vec![0x79, 0, 0, 0, 0];
+    msg.extend_from_slice(&[0xFE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+
+    let i = ColumnValue::Some(ColumnType::I32(42));
+    let result = conn.call_proc("p", &[Param::new("@i", &i), Param::new("@n", &ColumnValue::None)]).unwrap();
+    assert_eq!(result.return_status(), Some(0));
+
+    let packets = sent_packets(&written.lock().unwrap());
+    let mut params = b_varchar("@i");
+    params.extend_from_slice(&[0, 0x26, 4, 4, 42, 0, 0, 0]);
+    // a NULL nvarchar
+    params.extend(b_varchar("@n"));
+    params.extend_from_slice(&[0, 0xE7, 2, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF]);
+    assert!(packets[2].ends_with(&params));
+}
+
+#[test]
+fn test_empty_strings_as_null() {
+    let sent_param = |empty_as_null: bool| {
+        let mut stream = MockStream::new();
+        let written = stream.written.clone();
+        stream.push(packet(1, &done(0x10, 1)));
+        let conn = Connection::connect(builder(stream).empty_strings_as_null(empty_as_null).build()).unwrap();
+        let stmt = conn.parameterized_auto("INSERT INTO t (name) VALUES (@P1)").unwrap();
+        assert_eq!(stmt.exec(&[&""]).unwrap(), 1);
+        let packets = sent_packets(&written.lock().unwrap());
+        packets[2].clone()
+    };
+    let mut param = b_varchar("@P1");
+    param.extend_from_slice(&[0, 0xE7, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert!(sent_param(false).ends_with(&param));
+    let mut param = b_varchar("@P1");
+    param.extend_from_slice(&[0, 0xE7, 2, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF]);
+    assert!(sent_param(true).ends_with(&param));
+}
+
+#[test]
+fn test_upload_progress() {
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(packet(1, &[0xFE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+    stream.push(packet(1, &done(0, 0)));
+    let progress = Arc::new(Mutex::new(vec![]));
+    let reported = progress.clone();
+    let conn = Connection::connect(builder(stream).packet_size(512).on_upload_progress(move |sent, total| {
+        reported.lock().unwrap().push((sent, total));
+    }).build()).unwrap();
+
+    let blob: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+    conn.call_proc("p", &[Param::new("@blob", &blob)]).unwrap();
+    // requests without parameters do not report their progress
+    conn.exec("SELECT 1").unwrap();
+
+    // the value is sent as varbinary(max) in a single PLP chunk
+    let packets = sent_packets(&written.lock().unwrap());
+    let data: Vec<u8> = packets[2..packets.len() - 1].iter().flat_map(|x| x[8..].to_vec()).collect();
+    let mut param = b_varchar("@blob");
+    param.extend_from_slice(&[0, 0xA5, 0xFF, 0xFF, 0x20, 0x4E, 0, 0, 0, 0, 0, 0, 0x20, 0x4E, 0, 0]);
+    param.extend(blob);
+    param.extend_from_slice(&[0, 0, 0, 0]);
+    assert!(data.ends_with(&param));
+
+    let progress = progress.lock().unwrap();
+    assert_eq!(progress.len(), packets.len() - 3);
+    assert!(progress.windows(2).all(|x| x[0].0 < x[1].0));
+    assert_eq!(*progress.last().unwrap(), (data.len(), data.len()));
+}
+
+#[test]
+fn test_bind_strings() {
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(packet(1, &[0xFE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+    let conn = connect(stream);
+
+    // the length is the one of the UTF-16 encoding, not twice the UTF-8 one
+    let short = "\u{fc}\u{20ac}\u{1f600}";
+    // 10000 bytes as UTF-16, sent as nvarchar(max) in a single PLP chunk
+    let long = "\u{e9}".repeat(5000);
+    conn.call_proc("p", &[Param::new("@short", &short), Param::new("@long", &long)]).unwrap();
+
+    let packets = sent_packets(&written.lock().unwrap());
+    let data: Vec<u8> = packets[2..].iter().flat_map(|x| x[8..].to_vec()).collect();
+    let mut params = b_varchar("@short");
+    params.extend_from_slice(&[0, 0xE7, 8, 0, 0, 0, 0, 0, 0, 8, 0, 0xFC, 0, 0xAC, 0x20, 0x3D, 0xD8, 0x00, 0xDE]);
+    params.extend(b_varchar("@long"));
+    params.extend_from_slice(&[0, 0xE7, 0xFF, 0xFF, 0, 0, 0, 0, 0, 0x10, 0x27, 0, 0, 0, 0, 0, 0, 0x10, 0x27, 0, 0]);
+    for _ in 0..5000 {
+        params.extend_from_slice(&[0xE9, 0]);
+    }
+    params.extend_from_slice(&[0, 0, 0, 0]);
+    assert!(data.ends_with(&params));
+}
+
+#[test]
+fn test_server_utc_now() {
+    let mut msg = vec![];
+    // COLMETADATA: datetime2(7) NULL without a name
+    msg.extend_from_slice(&[0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0x2A, 7, 0]);
+    // 2016-05-04 13:45:30.1234567
+    msg.extend_from_slice(&[0xD1, 8, 0x87, 0x0F, 0x41, 0x52, 0x73, 0x57, 0x3B, 0x0B]);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let now: DateTime<UTC> = conn.server_utc_now().unwrap();
+    assert_eq!(now, UTC.from_utc_datetime(&NaiveDate::from_ymd(2016, 5, 4).and_hms_nano(13, 45, 30, 123456700)));
+}
+
+#[test]
+fn test_xml() {
+    let utf16 = |s: &str| b_varchar(s)[1..].to_vec();
+    let mut msg = vec![];
+    // COLMETADATA: xml with a schema collection, xml without one and an int
+    msg.extend_from_slice(&[0x81, 3, 0]);
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xF1, 1]);
+    msg.extend(b_varchar("db"));
+    msg.extend(b_varchar("dbo"));
+    msg.extend_from_slice(&[4, 0]);
+    msg.extend(utf16("coll"));
+    msg.extend(b_varchar("x"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xF1, 0]);
+    msg.extend(b_varchar("n"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0x26, 4]);
+    msg.extend(b_varchar("i"));
+    // ROW: the xml in two PLP chunks of unknown total length, a NULL and 7
+    msg.push(0xD1);
+    msg.extend_from_slice(&[0xFE, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    for chunk in &["<a b=", "\"1\"/>"] {
+        let bytes = utf16(chunk);
+        msg.extend_from_slice(&[bytes.len() as u8, 0, 0, 0]);
+        msg.extend(bytes);
+    }
+    msg.extend_from_slice(&[0, 0, 0, 0]);
+    msg.extend_from_slice(&[0xFF; 8]);
+    msg.extend_from_slice(&[4, 7, 0, 0, 0]);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT x, n, i FROM test").unwrap();
+    let x: String = rows.get(0).get("x");
+    assert_eq!(x, "<a b=\"1\"/>");
+    let n: Option<String> = rows.get(0).get("n");
+    assert_eq!(n, None);
+    let i: i32 = rows.get(0).get("i");
+    assert_eq!(i, 7);
+}
+
+#[test]
+fn test_strict_binding() {
+    let mut msg = vec![];
+    // COLMETADATA: the relevant columns of sp_describe_undeclared_parameters
+    msg.extend_from_slice(&[0x81, 2, 0]);
+    msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x26, 4]);
+    msg.extend(b_varchar("parameter_ordinal"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xE7, 0x00, 0x02, 0x09, 0x04, 0xD0, 0x00, 0x34]);
+    msg.extend(b_varchar("suggested_system_type_name"));
+    msg.extend_from_slice(&[0xD1, 4, 1, 0, 0, 0]);
+    msg.extend(nvarchar("varchar(50)"));
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(packet(1, &msg));
+    let conn = Connection::connect(builder(stream).strict_binding(true).build()).unwrap();
+    let stmt = conn.prepare("SELECT * FROM test WHERE col_varchar = @P1").unwrap();
+    match stmt.query(&[&"abc"]) {
+        Err(TdsError::Other(ref msg)) if msg.starts_with("strict binding: parameter @P1 is bound as nvarchar(4000) but used as varchar(50)") => (),
+        x => panic!("expected a strict binding error, got {:?}", x)
+    }
+    // nothing but the sp_describe_undeclared_parameters batch was sent
+    let packets = sent_packets(&written.lock().unwrap());
+    assert_eq!(packets.len(), 3);
+    assert_eq!(packets[2][0], 1);
+}
+
+#[test]
+fn test_merge_output() {
+    let mut msg = vec![];
+    // COLMETADATA: $action nvarchar(10), id int
+    msg.extend_from_slice(&[0x81, 2, 0]);
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xE7, 20, 0, 0x09, 0x04, 0xD0, 0x00, 0x34]);
+    msg.extend(b_varchar("$action"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x26, 4]);
+    msg.extend(b_varchar("id"));
+    msg.push(0xD1);
+    msg.extend(nvarchar("INSERT"));
+    msg.extend_from_slice(&[4, 2, 0, 0, 0]);
+    msg.push(0xD1);
+    msg.extend(nvarchar("UPDATE"));
+    msg.extend_from_slice(&[4, 1, 0, 0, 0]);
+    msg.extend(done(0x10, 2));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let (count, rows) = conn.exec_returning("MERGE ...").unwrap();
+    assert_eq!(count, 2);
+    assert_eq!(rows.len(), 2);
+    let action: &str = rows.get(0).get("$action");
+    assert_eq!(action, "INSERT");
+    let id: i32 = rows.get(0).get("id");
+    assert_eq!(id, 2);
+    let action: &str = rows.get(1).get("$action");
+    assert_eq!(action, "UPDATE");
+}
+
+#[test]
+fn test_info_messages() {
+    let mut msg = info(0, 0, "hello");
+    msg.extend(done(0x01, 0));
+    msg.extend_from_slice(&[0x81, 1, 0, 0, 0, 0, 0, 0, 0, 0x26, 4, 0]);
+    msg.extend_from_slice(&[0xD1, 4, 1, 0, 0, 0]);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let received = Arc::new(Mutex::new(vec![]));
+    let handler_received = received.clone();
+    let opts = builder(stream)
+        .on_message(move |msg| handler_received.lock().unwrap().push(msg.message.clone()))
+        .build();
+    let conn = Connection::connect(opts).unwrap();
+    let rows = conn.query("PRINT 'hello'; SELECT 1").unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows.messages().len(), 1);
+    assert_eq!(rows.messages()[0].code, 0);
+    assert_eq!(rows.messages()[0].class, 0);
+    assert_eq!(rows.messages()[0].message, "hello");
+    assert_eq!(*received.lock().unwrap(), vec!["hello".to_owned()]);
+}
+
+#[test]
+fn test_parameterized_auto() {
+    assert_eq!(param_declarations(&[&1i32, &"abc"]), "@P1 int,@P2 nvarchar(4000)");
+
+    let mut msg = vec![];
+    msg.extend_from_slice(&[0x81, 1, 0, 0, 0, 0, 0, 0, 0, 0x26, 4, 0]);
+    msg.extend_from_slice(&[0xD1, 4, 42, 0, 0, 0]);
+    msg.extend(done(0x10, 1));
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let stmt = conn.parameterized_auto("SELECT @P1 WHERE @P2 = 'abc'").unwrap();
+    let rows = stmt.query(&[&42i32, &"abc"]).unwrap();
+    let value: i32 = rows.get(0).get(0);
+    assert_eq!(value, 42);
+
+    // the RPC request calls sp_executesql, declaring the parameters
+    let packets = sent_packets(&written.lock().unwrap());
+    let rpc = &packets[2];
+    assert_eq!(rpc[0], 3);
+    let utf16 = |s: &str| s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect::<Vec<u8>>();
+    let contains = |needle: &[u8]| rpc.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(&utf16("sp_executesql")));
+    assert!(contains(&utf16("@P1 int,@P2 nvarchar(4000)")));
+}
+
+struct NewUser {
+    id: i32,
+    name: String,
+}
+
+impl Parameters for NewUser {
+    fn bind(&self) -> Vec<&ToColumnType> {
+        vec![&self.id, &self.name]
+    }
+}
+
+#[test]
+fn test_bind_parameters() {
+    let user = NewUser { id: 1, name: "alice".to_owned() };
+    assert_eq!(user.declarations(), "@P1 int,@P2 nvarchar(4000)");
+
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(packet(1, &done(0x10, 1)));
+    let conn = connect(stream);
+    let stmt = conn.parameterized_auto("INSERT INTO users (id, name) VALUES (@P1, @P2)").unwrap();
+    assert_eq!(stmt.exec(&user.bind()).unwrap(), 1);
+
+    let packets = sent_packets(&written.lock().unwrap());
+    let utf16 = |s: &str| s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect::<Vec<u8>>();
+    let contains = |needle: &[u8]| packets[2].windows(needle.len()).any(|w| w == needle);
+    assert!(contains(&utf16("@P1 int,@P2 nvarchar(4000)")));
+    assert!(packets[2].ends_with(&utf16("alice")));
+}
+
+#[test]
+fn test_malformed_lengths() {
+    let respond = |msg: Vec<u8>| {
+        let mut stream = MockStream::new();
+        stream.push(packet(1, &msg));
+        // a malformed row ends the rows with its error
+        connect(stream).query("SELECT x FROM t").and_then(|rows| rows.try_iter().collect::<Result<Vec<_>, _>>())
+    };
+    // an ENVCHANGE exceeding the message
+    let mut msg = vec![0xE3, 0xFF, 0x7F, 1];
+    msg.extend(done(0, 0));
+    assert!(respond(msg).is_err());
+    // a transaction token which claims 4 GB
+    let mut msg = env_change(15, &[0xFF, 0xFF, 0xFF, 0xFF], &[0]);
+    msg.extend(done(0, 0));
+    assert!(respond(msg).is_err());
+    // varchar(10) value with a length exceeding the row
+    let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0xA7, 10, 0, 0x09, 0x04, 0xD0, 0x00, 0x34];
+    msg.extend(b_varchar("x"));
+    msg.extend_from_slice(&[0xD1, 0xFE, 0xFF, b'a']);
+    assert!(respond(msg).is_err());
+    // xml (PLP) value with a bogus total and chunk length
+    let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0xF1, 0];
+    msg.extend(b_varchar("x"));
+    msg.extend_from_slice(&[0xD1, 0xFD, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F, 0xFF, 0xFF, 0xFF, 0xFF, b'a']);
+    assert!(respond(msg).is_err());
+    // a datetime too far in the future
+    let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0x3D];
+    msg.extend(b_varchar("x"));
+    msg.extend_from_slice(&[0xD1, 0xFF, 0xFF, 0xFF, 0x7F, 0, 0, 0, 0]);
+    match respond(msg) {
+        Err(TdsError::ProtocolError(_)) => (),
+        x => panic!("expected a protocol error, got {:?}", x)
+    }
+}
+
+#[test]
+fn test_query_try_iter() {
+    let mut stream = MockStream::new();
+    // two int rows followed by a row whose varchar exceeds the message
+    let mut msg = vec![0x81, 2, 0, 0, 0, 0, 0, 0, 0, 0x26, 4, 0, 0, 0, 0, 0, 1, 0, 0xA7, 10, 0, 0x09, 0x04, 0xD0, 0x00, 0x34, 0];
+    for value in 1..3 {
+        msg.extend_from_slice(&[0xD1, 4, value, 0, 0, 0, 0xFF, 0xFF]);
+    }
+    msg.extend_from_slice(&[0xD1, 4, 3, 0, 0, 0, 0xFE, 0xFF, b'a']);
+    stream.push(packet(1, &msg));
+    stream.push(packet(1, &msg));
+    stream.push(packet(1, &done(0, 0)));
+    let conn = connect(stream);
+
+    let mut rows = conn.query("SELECT x, y FROM t").unwrap().try_iter();
+    for value in 1..3 {
+        let x: i32 = rows.next().unwrap().unwrap().get(0);
+        assert_eq!(x, value);
+    }
+    match rows.next() {
+        Some(Err(TdsError::ProtocolError(_))) => (),
+        x => panic!("expected a protocol error, got {:?}", x)
+    }
+    assert!(rows.next().is_none());
+    // the whole response was consumed
+    assert!(conn.query_as::<(i32, Option<String>), _>("SELECT x, y FROM t").is_err());
+    assert_eq!(conn.exec("SELECT 1").unwrap(), 0);
+}
+
+#[test]
+fn test_connect_without_database() {
+    use tiberius::{AuthenticationMethod, ConnectionOptBuilder};
+    // the length of the database name within the login packet
+    let database_len = |opts: ConnectionOptBuilder<'static, MockStream>, written: Arc<Mutex<Vec<u8>>>| {
+        Connection::connect(opts.build()).unwrap();
+        let packets = sent_packets(&written.lock().unwrap());
+        packets[1][78] as u16 | (packets[1][79] as u16) << 8
+    };
+    let stream = MockStream::new();
+    let written = stream.written.clone();
+    assert_eq!(database_len(builder(stream), written), 4);
+    let stream = MockStream::new();
+    let written = stream.written.clone();
+    assert_eq!(database_len(ConnectionOptBuilder::new(stream).auth(AuthenticationMethod::internal("test", "test")), written), 0);
+}
+
+#[test]
+fn test_language_and_set_option() {
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    for _ in 0..2 {
+        stream.push(packet(1, &done(0, 0)));
+    }
+    let conn = Connection::connect(builder(stream).language("Deutsch").build()).unwrap();
+    conn.set_option("DATEFORMAT", "dmy").unwrap();
+    conn.set_option("LANGUAGE", "x'; DROP TABLE t; --").unwrap();
+    assert!(conn.set_option("NOCOUNT ON; DROP TABLE t; --", "ON").is_err());
+
+    let utf16 = |s: &str| s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect::<Vec<u8>>();
+    let packets = sent_packets(&written.lock().unwrap());
+    assert_eq!(packets.len(), 4);
+    // the offset and length (in characters) of the language within the login
+    let login = &packets[1][8..];
+    let (offset, len) = (login[64] as usize | (login[65] as usize) << 8, login[66] as usize | (login[67] as usize) << 8);
+    assert_eq!(len, 7);
+    assert_eq!(&login[offset..offset + 14], &utf16("Deutsch")[..]);
+    assert!(packets[2].ends_with(&utf16("SET DATEFORMAT dmy;")));
+    assert!(packets[3].ends_with(&utf16("SET LANGUAGE N'x''; DROP TABLE t; --';")));
+}
+
+#[test]
+fn test_use_database() {
+    // the login announces the default database, USE the new one
+    let mut login_response = env_change(1, &b_varchar("test"), &b_varchar("master"));
+    login_response.extend(done(0, 0));
+    let mut stream = MockStream::with_login_response(login_response);
+    let written = stream.written.clone();
+    for (new, old) in vec![("a]b", "test"), ("other", "a]b")] {
+        let mut msg = env_change(1, &b_varchar(new), &b_varchar(old));
+        msg.extend(done(0, 0));
+        stream.push(packet(1, &msg));
+    }
+    let conn = connect(stream);
+    assert_eq!(conn.current_database(), Some("test".to_owned()));
+
+    conn.use_database("a]b").unwrap();
+    assert_eq!(conn.current_database(), Some("a]b".to_owned()));
+    // a name which is not a single quoted identifier is quoted as a whole
+    conn.use_database("[x]; DROP TABLE t; --]").unwrap();
+    assert_eq!(conn.current_database(), Some("other".to_owned()));
+
+    let utf16 = |s: &str| s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect::<Vec<u8>>();
+    let packets = sent_packets(&written.lock().unwrap());
+    assert!(packets[2].ends_with(&utf16("USE [a]]b];")));
+    assert!(packets[3].ends_with(&utf16("USE [[x]]; DROP TABLE t; --]]];")));
+}
+
+#[test]
+fn test_prelogin_unexpected_packet() {
+    // the response to the prelogin has to end the message (instead of e.g. being ignored)
+    let mut stream = MockStream::empty();
+    stream.push(packet(3, &[0xFF]));
+    assert!(Connection::connect(builder(stream).build()).is_err());
+}
+
+#[test]
+fn test_prelogin_truncated_option() {
+    // the instance name, TraceId and nonce options are shorter than their declared length
+    for &(token, len, data) in &[(2u8, 10u16, &b"abc"[..]), (5, 36, &[1; 20][..]), (7, 32, &[1; 8][..])] {
+        let mut response = vec![token, 0, 6, (len >> 8) as u8, len as u8, 0xFF];
+        response.extend_from_slice(data);
+        let mut stream = MockStream::empty();
+        stream.push(packet(1, &response));
+        stream.push(packet(1, &done(0, 0)));
+        assert!(Connection::connect(builder(stream).build()).is_err(), "option {}", token);
+    }
+}
+
+#[test]
+fn test_envchange_promote_transaction() {
+    let mut msg = vec![];
+    // a collation change (not interpreted) is skipped
+    msg.extend(env_change(7, &[5, 0x09, 0x04, 0xD0, 0x00, 0x34], &[0]));
+    msg.extend(env_change(15, &[4, 0, 0, 0, 0xDE, 0xAD, 0xBE, 0xEF], &[0]));
+    msg.extend(env_change(1, &b_varchar("other"), &b_varchar("test")));
+    msg.extend(done(0, 0));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    assert_eq!(conn.promoted_transaction(), None);
+    conn.exec("EXEC sp_promote_something").unwrap();
+    assert_eq!(conn.promoted_transaction(), Some(vec![0xDE, 0xAD, 0xBE, 0xEF]));
+}
+
+#[cfg(feature = "row")]
+#[test]
+fn test_row_deserialize() {
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct User<'a> {
+        id: i32,
+        name: Option<&'a str>,
+    }
+    #[derive(Debug, Deserialize)]
+    struct Renamed {
+        #[allow(dead_code)]
+        user_id: i32,
+    }
+
+    let mut msg = vec![];
+    msg.extend_from_slice(&[0x81, 2, 0]);
+    msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x26, 4]);
+    msg.extend(b_varchar("id"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xE7, 100, 0, 0x09, 0x04, 0xD0, 0x00, 0x34]);
+    msg.extend(b_varchar("name"));
+    msg.extend_from_slice(&[0xD1, 4, 1, 0, 0, 0]);
+    msg.extend(nvarchar("alice"));
+    msg.extend_from_slice(&[0xD1, 4, 2, 0, 0, 0, 0xFF, 0xFF]);
+    msg.extend(done(0x10, 2));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT id, name FROM users").unwrap();
+    let user: User = rows.get(0).deserialize().unwrap();
+    assert_eq!(user, User { id: 1, name: Some("alice") });
+    let user: User = rows.get(1).deserialize().unwrap();
+    assert_eq!(user, User { id: 2, name: None });
+    // a field without a column
+    match rows.get(0).deserialize::<Renamed>() {
+        Err(TdsError::Other(ref msg)) => assert_eq!(msg, "deserialize: missing field `user_id`"),
+        x => panic!("expected an error, got {:?}", x)
+    }
+    // a NULL for a field which is not an Option
+    #[derive(Debug, Deserialize)]
+    struct Strict {
+        #[allow(dead_code)]
+        name: String,
+    }
+    assert!(rows.get(1).deserialize::<Strict>().is_err());
+}
+
+#[test]
+fn test_session_options() {
+    let columns = ["quoted_identifier", "arithabort", "ansi_nulls", "ansi_null_dflt_on", "ansi_padding", "ansi_warnings", "concat_null_yields_null"];
+    let mut msg = vec![0x81, 9, 0];
+    for name in &columns {
+        msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x32]);
+        msg.extend(b_varchar(name));
+    }
+    msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x34]);
+    msg.extend(b_varchar("transaction_isolation_level"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x38]);
+    msg.extend(b_varchar("lock_timeout"));
+    msg.extend_from_slice(&[0xD1, 1, 0, 1, 1, 1, 1, 1, 2, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let opts = conn.session_options().unwrap();
+    assert!(opts.quoted_identifier);
+    assert!(!opts.arithabort);
+    assert!(opts.ansi_nulls && opts.ansi_null_dflt_on && opts.ansi_padding && opts.ansi_warnings && opts.concat_null_yields_null);
+    assert_eq!(opts.transaction_isolation_level, 2);
+    assert_eq!(opts.lock_timeout, -1);
+}
+
+#[test]
+fn test_transaction_rollback_on_panic() {
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    // BEGIN TRANSACTION, ROLLBACK, SELECT 1
+    stream.push(packet(1, &done(0, 0)));
+    stream.push(packet(1, &done(0, 0)));
+    let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 0, 0, 0x26, 4, 0];
+    msg.extend_from_slice(&[0xD1, 4, 1, 0, 0, 0]);
+    msg.extend(done(0x10, 1));
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        conn.transaction(|_| -> Result<(), TdsError> { panic!("failure within the transaction") })
+    }));
+    assert!(result.is_err());
+    assert_eq!(conn.query_scalar::<i32, _>("SELECT 1").unwrap(), Some(1));
+
+    let utf16 = |s: &str| s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect::<Vec<u8>>();
+    let packets = sent_packets(&written.lock().unwrap());
+    assert_eq!(packets.len(), 5);
+    assert!(packets[2].ends_with(&utf16("BEGIN TRANSACTION;")));
+    assert!(packets[3].ends_with(&utf16("IF @@TRANCOUNT > 0 ROLLBACK TRANSACTION;")));
+}
+
+#[test]
+fn test_transaction_poisoned() {
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    // BEGIN TRANSACTION, a statement with a message the handler panics on
+    stream.push(packet(1, &done(0, 0)));
+    let mut msg = info(0, 0, "hello");
+    msg.extend(done(0, 0));
+    stream.push(packet(1, &msg));
+    let conn = Connection::connect(builder(stream).on_message(|_| panic!("failure in the handler")).build()).unwrap();
+
+    // the panic is resumed (instead of one in the rollback or an abort) and the rollback is skipped
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        conn.transaction(|tx| tx.exec("PRINT 'hello';"))
+    }));
+    assert_eq!(result.unwrap_err().downcast_ref::<&str>(), Some(&"failure in the handler"));
+    assert_eq!(sent_packets(&written.lock().unwrap()).len(), 4);
+}
+
+#[test]
+fn test_transaction_descriptor() {
+    let descriptor = [1, 2, 3, 4, 5, 6, 7, 8];
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    // BEGIN TRANSACTION announces the descriptor, ROLLBACK ends the transaction
+    let mut begin = env_change(8, &[&[8][..], &descriptor[..]].concat(), &[0]);
+    begin.extend(done(0, 0));
+    stream.push(packet(1, &begin));
+    stream.push(packet(1, &done(0x10, 1)));
+    let mut rollback = env_change(10, &[0], &[&[8][..], &descriptor[..]].concat());
+    rollback.extend(done(0, 0));
+    stream.push(packet(1, &rollback));
+    stream.push(packet(1, &done(0x10, 1)));
+    let conn = connect(stream);
+
+    assert_eq!(conn.current_transaction_descriptor(), None);
+    let tx = conn.begin_transaction().unwrap();
+    assert_eq!(conn.current_transaction_descriptor(), Some(0x0807060504030201));
+    assert_eq!(tx.exec("INSERT INTO t VALUES (1)").unwrap(), 1);
+    tx.rollback().unwrap();
+    assert_eq!(conn.current_transaction_descriptor(), None);
+    assert_eq!(conn.exec("INSERT INTO t VALUES (1)").unwrap(), 1);
+
+    // ALL_HEADERS: total length, header length, type 2 (transaction descriptor), the descriptor and outstanding requests
+    let all_headers = |descriptor: &[u8]| [&[22, 0, 0, 0, 18, 0, 0, 0, 2, 0][..], descriptor, &[1, 0, 0, 0][..]].concat();
+    let packets = sent_packets(&written.lock().unwrap());
+    assert_eq!(packets.len(), 6);
+    assert!(packets[2][8..].starts_with(&all_headers(&[0; 8])));
+    assert!(packets[3][8..].starts_with(&all_headers(&descriptor)));
+    assert!(packets[4][8..].starts_with(&all_headers(&descriptor)));
+    assert!(packets[5][8..].starts_with(&all_headers(&[0; 8])));
+}
+
+#[test]
+fn test_transaction_deadlock_priority() {
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    // BEGIN TRANSACTION, the previous deadlock priority and lock timeout, COMMIT and the restore
+    stream.push(packet(1, &done(0, 0)));
+    for prev in &[0u8, 0xFF] {
+        let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 0, 0, 0x26, 4, 0];
+        msg.extend_from_slice(&[0xD1, 4, *prev, *prev, *prev, *prev]);
+        msg.extend(done(0x11, 1));
+        msg.extend(done(0, 0));
+        stream.push(packet(1, &msg));
+    }
+    stream.push(packet(1, &done(0, 0)));
+    stream.push(packet(1, &done(0, 0)));
+    let conn = connect(stream);
+
+    let tx = conn.begin_transaction().unwrap();
+    tx.deadlock_priority(DeadlockPriority::Low).unwrap();
+    tx.lock_timeout(Some(Duration::from_millis(1500))).unwrap();
+    assert!(tx.deadlock_priority(DeadlockPriority::Numeric(11)).is_err());
+    tx.commit().unwrap();
+
+    let utf16 = |s: &str| s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect::<Vec<u8>>();
+    let packets = sent_packets(&written.lock().unwrap());
+    assert_eq!(packets.len(), 7);
+    assert!(packets[3].ends_with(&utf16("SET DEADLOCK_PRIORITY -5;")));
+    assert!(packets[4].ends_with(&utf16("SELECT @@LOCK_TIMEOUT; SET LOCK_TIMEOUT 1500;")));
+    assert!(packets[5].ends_with(&utf16("COMMIT TRANSACTION;")));
+    assert!(packets[6].ends_with(&utf16("SET DEADLOCK_PRIORITY 0;SET LOCK_TIMEOUT -1;")));
+}
+
+#[test]
+fn test_statement_cache() {
+    // the response to sp_prepare: the metadata of the result and the handle
+    let prepared = |handle: u8| {
+        let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 0, 0, 0x26, 4, 0];
+        msg.extend_from_slice(&[0xAC, 0, 0]);
+        msg.extend(b_varchar("handle"));
+        msg.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0x26, 4, 4, handle, 0, 0, 0]);
+        msg.extend_from_slice(&[0xFE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        packet(1, &msg)
+    };
+    let executed = |value: u8| {
+        let mut msg = vec![0xD1, 4, value, 0, 0, 0];
+        msg.extend_from_slice(&[0xFE, 0x10, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        packet(1, &msg)
+    };
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(prepared(1));
+    stream.push(executed(1));
+    stream.push(executed(2));
+    stream.push(prepared(2));
+    stream.push(packet(1, &[0xFE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+    stream.push(executed(3));
+    let conn = Connection::connect(builder(stream).statement_cache_size(1).build()).unwrap();
+
+    for (sql, value) in vec![("SELECT @P1", 1), ("SELECT @P1", 2), ("SELECT 1 + @P1", 3)] {
+        let stmt = conn.prepare(sql).unwrap();
+        let rows = stmt.query(&[&value]).unwrap();
+        let x: i32 = rows.get(0).get(0);
+        assert_eq!(x, value);
+    }
+
+    // the second statement reuses the cached handle, the third one evicts it
+    let sp_execute: Vec<u8> = "sp_execute".encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect();
+    let calls: Vec<&str> = sent_packets(&written.lock().unwrap())[2..].iter().map(|p| {
+        if p.windows(sp_execute.len()).any(|w| w == &sp_execute[..]) {
+            "execute"
+        } else if p.windows(4).any(|w| w == [0xFF, 0xFF, 11, 0]) {
+            "prepare"
+        } else if p.windows(4).any(|w| w == [0xFF, 0xFF, 15, 0]) {
+            "unprepare"
+        } else {
+            "other"
+        }
+    }).collect();
+    assert_eq!(calls, vec!["prepare", "execute", "execute", "prepare", "unprepare", "execute"]);
+}
+
+/// a result of `count` rows of a single int column, with the id as value
+fn int_rows(count: i32) -> Vec<u8> {
+    let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 0, 0, 0x26, 4];
+    msg.extend(b_varchar("id"));
+    for i in 0..count {
+        msg.extend_from_slice(&[0xD1, 4, i as u8, (i >> 8) as u8, (i >> 16) as u8, (i >> 24) as u8]);
+    }
+    msg.extend(done(0x10, count as u64));
+    msg
+}
+
+#[test]
+fn test_query_each() {
+    let msg = int_rows(10000);
+    let mut stream = MockStream::new();
+    let read = stream.read.clone();
+    // 59 bytes do not fit a whole number of rows, so rows are split across packets
+    for p in packets(&msg, 59) {
+        stream.push(p);
+    }
+    let conn = connect(stream);
+    let login_read = *read.lock().unwrap();
+
+    let mut count = 0;
+    let mut max_ahead = 0;
+    conn.query_each("SELECT id FROM large", |row| {
+        let id: i32 = row.get("id");
+        assert_eq!(id, count);
+        count += 1;
+        // the bytes read beyond the end of this row (the metadata takes 16 bytes, a row 6 bytes)
+        let data_end = 16 + count as usize * 6;
+        let wire_end = data_end + 8 * ((data_end + 58) / 59);
+        max_ahead = ::std::cmp::max(*read.lock().unwrap() - login_read - wire_end, max_ahead);
+        Ok(())
+    }).unwrap();
+    assert_eq!(count, 10000);
+    // never more than the packet containing the row
+    assert!(max_ahead < 59 + 8, "read {} bytes ahead", max_ahead);
+}
+
+#[test]
+fn test_query_each_cancel() {
+    let msg = int_rows(100);
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    let mut response = packets(&msg, 59);
+    // the server stops sending rows once it received the attention,
+    // it ends the message with the rest of the split row and the acknowledgement
+    response.truncate(3);
+    for p in response {
+        stream.push(p);
+    }
+    stream.push(packet(1, &[&msg[3 * 59..3 * 59 + 1], &done(0x20, 0)[..]].concat()));
+    let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 0, 0, 0x26, 4, 0];
+    msg.extend_from_slice(&[0xD1, 4, 1, 0, 0, 0]);
+    msg.extend(done(0x10, 1));
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+
+    let mut count = 0;
+    let result = conn.query_each("SELECT id FROM large", |_| {
+        count += 1;
+        if count == 3 {
+            return Err(TdsError::Other("enough".to_owned()))
+        }
+        Ok(())
+    });
+    match result {
+        Err(TdsError::Other(ref msg)) => assert_eq!(msg, "enough"),
+        x => panic!("expected the error of the callback, got {:?}", x)
+    }
+    assert_eq!(count, 3);
+    let packets = sent_packets(&written.lock().unwrap());
+    assert_eq!(packets.last().unwrap()[0], 6);
+    // the connection is usable again
+    assert_eq!(conn.query_scalar::<i32, _>("SELECT 1").unwrap(), Some(1));
+}
+
+#[test]
+fn test_query_each_unlocked() {
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &int_rows(2)));
+    let rows = int_rows(100);
+    let mut response = packets(&rows, 59);
+    response.truncate(3);
+    for p in response {
+        stream.push(p);
+    }
+    stream.push(packet(1, &[&rows[3 * 59..3 * 59 + 1], &done(0x20, 0)[..]].concat()));
+    let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 0, 0, 0x26, 4, 0];
+    msg.extend_from_slice(&[0xD1, 4, 1, 0, 0, 0]);
+    msg.extend(done(0x10, 1));
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+
+    // the callback runs without the lock, but another request has to wait until the rows are read
+    let mut count = 0;
+    conn.query_each("SELECT id FROM t", |_| {
+        count += 1;
+        match conn.exec("SELECT 2") {
+            Err(TdsError::Other(ref msg)) => assert_eq!(msg, "request: the connection is still reading the rows of query_each"),
+            x => panic!("expected an error, got {:?}", x)
+        }
+        Ok(())
+    }).unwrap();
+    assert_eq!(count, 2);
+
+    // a panic in the callback cancels the query and does not poison the connection
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        conn.query_each("SELECT id FROM large", |_| -> Result<(), TdsError> { panic!("failure in the callback") })
+    }));
+    assert_eq!(result.unwrap_err().downcast_ref::<&str>(), Some(&"failure in the callback"));
+    assert_eq!(conn.query_scalar::<i32, _>("SELECT 1").unwrap(), Some(1));
+}
+
+#[test]
+fn test_bind_tvp() {
+    let mut msg = vec![0x79, 0, 0, 0, 0];
+    msg.extend_from_slice(&[0xFE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+
+    let tvp = Tvp::new("dbo.IntPairs").row(&[&1i32, &2i32]).row(&[&3i32, &ColumnValue::Some(ColumnType::I32(4))]);
+    conn.call_proc("p", &[Param::new("@pairs", &tvp)]).unwrap();
+
+    let mut param = b_varchar("@pairs");
+    param.extend_from_slice(&[0, 0xF3, 0]);
+    param.extend(b_varchar("dbo"));
+    param.extend(b_varchar("IntPairs"));
+    // TVP_COLMETADATA: 2 nullable int columns without names, TVP_END_TOKEN
+    param.extend_from_slice(&[2, 0, 0, 0, 0, 0, 1, 0, 0x26, 4, 0, 0, 0, 0, 0, 1, 0, 0x26, 4, 0, 0]);
+    // the rows, TVP_END_TOKEN
+    param.extend_from_slice(&[1, 4, 1, 0, 0, 0, 4, 2, 0, 0, 0, 1, 4, 3, 0, 0, 0, 4, 4, 0, 0, 0, 0]);
+    let packets = sent_packets(&written.lock().unwrap());
+    assert!(packets[2].ends_with(&param));
+
+    // a row has to match the columns of the first one
+    let tvp = Tvp::new("dbo.IntPairs").row(&[&1i32, &2i32]).row(&[&3i32, &"4"]);
+    match conn.call_proc("p", &[Param::new("@pairs", &tvp)]) {
+        Err(TdsError::Other(ref msg)) => assert_eq!(msg, "tvp: expected a I32 value, got String"),
+        x => panic!("expected an error, got {:?}", x.map(|_| ()))
+    }
+}
+
+#[test]
+fn test_done_statuses() {
+    let mut stream = MockStream::new();
+    // SET NOCOUNT ON; UPDATE ...: no count
+    stream.push(packet(1, &done(0x01, 0).into_iter().chain(done(0x00, 0)).collect::<Vec<u8>>()));
+    let mut msg = done(0x11, 2);
+    msg.extend(done(0x15, 3));
+    msg.extend(done(0x14, 1));
+    stream.push(packet(1, &msg.clone()));
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+
+    assert_eq!(conn.exec("SET NOCOUNT ON; UPDATE t SET x = 1;").unwrap(), 0);
+    assert_eq!(conn.exec("UPDATE a ...; BEGIN TRAN; UPDATE b ...; UPDATE c ...;").unwrap(), 6);
+    let statuses = conn.exec_statuses("UPDATE a ...; BEGIN TRAN; UPDATE b ...; UPDATE c ...;").unwrap();
+    assert_eq!(statuses.iter().map(|x| x.row_count).collect::<Vec<_>>(), vec![Some(2), Some(3), Some(1)]);
+    assert_eq!(statuses.iter().map(|x| x.more()).collect::<Vec<_>>(), vec![true, true, false]);
+    assert_eq!(statuses.iter().map(|x| x.in_xact()).collect::<Vec<_>>(), vec![false, true, true]);
+    assert!(!statuses.iter().any(|x| x.error()));
+}
+
+#[test]
+fn test_bind_guid() {
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(packet(1, &done(0x10, 1)));
+    stream.push(packet(1, &done(0x10, 1)));
+    let conn = connect(stream);
+    let guid = Guid::parse("6f9619ff-8b86-d011-b42d-00c04fc964ff").unwrap();
+    let stmt = conn.parameterized_auto("DELETE FROM t WHERE id = @P1").unwrap();
+    assert_eq!(stmt.exec(&[&guid]).unwrap(), 1);
+    assert_eq!(stmt.exec(&[&guid.as_string()]).unwrap(), 1);
+
+    let packets = sent_packets(&written.lock().unwrap());
+    let utf16 = |s: &str| s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect::<Vec<u8>>();
+    let contains = |rpc: &[u8], needle: &[u8]| rpc.windows(needle.len()).any(|w| w == needle);
+    // as uniqueidentifier: GUIDTYPE with a length of 16 and the bytes as stored by mssql
+    let mut value = vec![0x24, 0x10, 0x10];
+    value.extend_from_slice(guid.as_bytes());
+    assert!(contains(&packets[2], &utf16("@P1 uniqueidentifier")));
+    assert!(packets[2].ends_with(&value));
+    // as string, in the hyphenated form
+    assert!(contains(&packets[3], &utf16("@P1 char(36)")));
+    assert!(packets[3].ends_with(&utf16("6f9619ff-8b86-d011-b42d-00c04fc964ff")));
+}
+
+#[test]
+fn test_bind_datetime_money() {
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(packet(1, &done(0, 0)));
+    let conn = connect(stream);
+    let datetime = NaiveDate::from_ymd(2020, 1, 2).and_hms_nano(3, 4, 5, 123456700);
+    let with_offset = NaiveDate::from_ymd(2020, 1, 2).and_hms_nano(1, 4, 5, 123456700);
+    let (dt, dto, money) = (ColumnType::Datetime(datetime), ColumnType::DatetimeOffset(with_offset, FixedOffset::east(7200)), ColumnType::Money(-12345678901));
+    conn.call_proc("p", &[Param::new("@dt", &dt), Param::new("@dto", &dto), Param::new("@m", &money)]).unwrap();
+
+    let packets = sent_packets(&written.lock().unwrap());
+    // DATETIME2N and DATETIMEOFFSETN (in UTC) with a scale of 7: 5 bytes of time, 3 bytes of days, the offset in minutes
+    let mut param = b_varchar("@dt");
+    param.extend_from_slice(&[0, 0x2A, 7, 8, 7, 23, 104, 183, 25, 145, 64, 11]);
+    param.extend(b_varchar("@dto"));
+    param.extend_from_slice(&[0, 0x2B, 7, 10, 7, 71, 223, 243, 8, 145, 64, 11, 120, 0]);
+    // MONEYN: the high dword is sent first
+    param.extend(b_varchar("@m"));
+    param.extend_from_slice(&[0, 0x6E, 8, 8, 253, 255, 255, 255, 203, 227, 35, 32]);
+    assert!(packets[2].ends_with(&param));
+
+    // a variant cannot be encoded as a tvp column
+    let variant = ColumnType::Variant(Box::new(ColumnType::I32(1)));
+    let tvp = Tvp::new("dbo.Variants").row(&[&variant]);
+    match conn.call_proc("p", &[Param::new("@v", &tvp)]) {
+        Err(TdsError::Other(ref msg)) => assert!(msg.starts_with("rpc: encoding of ColumnType"), "{}", msg),
+        x => panic!("expected an error, got {:?}", x.map(|_| ()))
+    }
+}
+
+#[test]
+fn test_bind_option() {
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(packet(1, &done(0x10, 1)));
+    stream.push(packet(1, &done(0x10, 1)));
+    let conn = connect(stream);
+    let stmt = conn.parameterized_auto("INSERT INTO t VALUES (@P1, @P2)").unwrap();
+    assert_eq!(stmt.exec(&[&Some(1i32), &None::<&str>]).unwrap(), 1);
+    assert_eq!(stmt.exec(&[&None::<i32>, &Some("a")]).unwrap(), 1);
+
+    // a NULL is declared and sent with the type of the value
+    let packets = sent_packets(&written.lock().unwrap());
+    let utf16 = |s: &str| s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect::<Vec<u8>>();
+    let contains = |rpc: &[u8], needle: &[u8]| rpc.windows(needle.len()).any(|w| w == needle);
+    for rpc in &packets[2..4] {
+        assert!(contains(rpc, &utf16("@P1 int,@P2 nvarchar(4000)")));
+    }
+    let mut values = vec![0x26, 4, 4, 1, 0, 0, 0];
+    values.extend(b_varchar("@P2"));
+    values.extend_from_slice(&[0, 0xE7, 2, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF]);
+    assert!(packets[2].ends_with(&values));
+    let mut values = vec![0x26, 4, 0];
+    values.extend(b_varchar("@P2"));
+    values.extend_from_slice(&[0, 0xE7, 2, 0, 0, 0, 0, 0, 0, 2, 0, b'a', 0]);
+    assert!(packets[3].ends_with(&values));
+}
+
+#[test]
+fn test_bind_option_null_type() {
+    // a NULL is bound with the static null type of `T`, which does not need to implement `Default`
+    let date: Option<NaiveDate> = None;
+    let time: Option<TimeOfDay> = None;
+    let string: Option<SqlString> = None;
+    let limited = Some(SqlString::new("a").max_len(10));
+    let guid: Option<Guid> = None;
+    let guid_string: Option<GuidString> = None;
+    assert_eq!(param_declarations(&[&date, &time, &string, &limited, &guid, &guid_string]),
+        "@P1 date,@P2 time(7),@P3 nvarchar(4000),@P4 nvarchar(10),@P5 uniqueidentifier,@P6 char(36)");
+    // a NULL string declared like its values
+    let null = SqlString::null().unicode(false).max_len(50);
+    assert_eq!(param_declarations(&[&null]), param_declarations(&[&SqlString::new("a").unicode(false).max_len(50)]));
+    assert!(null.is_null());
+    assert!(Some(null).is_null());
+    match time.to_column_type() {
+        ColumnType::Time(_) => assert!(time.is_null()),
+        x => panic!("expected a time, got {:?}", x)
+    }
+    match None::<Option<i32>>.to_column_type() {
+        ColumnType::I32(_) => (),
+        x => panic!("expected an int, got {:?}", x)
+    }
+}
+
+#[test]
+fn test_text_columns() {
+    // a nullable text and ntext column, each with the table name
+    let mut msg = vec![0x81, 2, 0];
+    for &ty in &[0x23, 0x63] {
+        msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, ty, 0xFF, 0xFF, 0xFF, 0x7F, 0x09, 0x04, 0xD0, 0x00, 0x34]);
+        msg.extend_from_slice(&[1, 1, 0, b't', 0]);
+        msg.extend(b_varchar("c"));
+    }
+    let text_value = |data: &[u8]| {
+        // the text pointer, its timestamp and the length
+        let mut buf = vec![16];
+        buf.extend_from_slice(&[0xAA; 16]);
+        buf.extend_from_slice(&[0xBB; 8]);
+        buf.extend_from_slice(&[data.len() as u8, (data.len() >> 8) as u8, 0, 0]);
+        buf.extend_from_slice(data);
+        buf
+    };
+    let text = vec![b't'; 5000];
+    let ntext: Vec<u8> = "莊子".encode_utf16().cycle().take(3000).flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect();
+    msg.push(0xD1);
+    msg.extend(text_value(&text));
+    msg.extend(text_value(&ntext));
+    // NULL values have no text pointer
+    msg.extend_from_slice(&[0xD1, 0, 0]);
+    msg.extend(done(0x10, 2));
+
+    let mut stream = MockStream::new();
+    for p in packets(&msg, 1000) {
+        stream.push(p);
+    }
+    let conn = connect(stream);
+    let rows = conn.query("SELECT c, c FROM t").unwrap();
+    assert_eq!(rows.len(), 2);
+    let value: &str = rows.get(0).get(0);
+    assert_eq!(value.len(), 5000);
+    assert!(value.chars().all(|c| c == 't'));
+    let value: &str = rows.get(0).get(1);
+    assert_eq!(value.chars().count(), 3000);
+    assert!(value.starts_with("莊子莊子"));
+    let value: Option<&str> = rows.get(1).get(0);
+    assert_eq!(value, None);
+    let value: Option<&str> = rows.get(1).get(1);
+    assert_eq!(value, None);
+}
+
+#[test]
+fn test_exec_without_count() {
+    let mut stream = MockStream::new();
+    // TRUNCATE TABLE: a DONE token without the count bit
+    for _ in 0..2 {
+        stream.push(packet(1, &done(0x00, 0)));
+    }
+    // a DELETE affecting no rows
+    stream.push(packet(1, &done(0x10, 0)));
+    let conn = connect(stream);
+    assert_eq!(conn.exec("TRUNCATE TABLE t").unwrap(), 0);
+    assert_eq!(conn.exec_count("TRUNCATE TABLE t").unwrap(), None);
+    assert_eq!(conn.exec_count("DELETE FROM t").unwrap(), Some(0));
+}
+
+#[test]
+fn test_exec_batch() {
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    let mut msg = done(0x11, 1);
+    msg.extend(done(0x11, 2));
+    msg.extend(done(0x10, 3));
+    stream.push(packet(1, &msg));
+    // the third statement (starting at line 3) fails
+    let mut msg = done(0x11, 1);
+    msg.extend(done(0x11, 1));
+    msg.extend(error(208, 16, "Invalid object name 'u'.", 4));
+    msg.extend(done(0x02, 0));
+    stream.push(packet(1, &msg));
+    // a procedure with two statements (DONEINPROC, return status, DONEPROC) and a SELECT
+    let token = |ty: u8, status: u16, rows: u64| {
+        let mut token = done(status, rows);
+        token[0] = ty;
+        token
+    };
+    let mut msg = token(0xFF, 0x11, 2);
+    msg.extend(token(0xFF, 0x11, 3));
+    msg.extend_from_slice(&[0x79, 0, 0, 0, 0]);
+    msg.extend(token(0xFE, 0x01, 0));
+    msg.extend_from_slice(&[0x81, 1, 0, 0, 0, 0, 0, 0, 0, 0x26, 4, 0]);
+    msg.extend_from_slice(&[0xD1, 4, 1, 0, 0, 0]);
+    msg.extend(done(0x11, 1));
+    msg.extend(done(0x10, 4));
+    stream.push(packet(1, &msg));
+    // the lock timeout of the second statement
+    let mut msg = done(0x11, 1);
+    msg.extend(error(1222, 16, "Lock request time out period exceeded.", 2));
+    msg.extend(done(0x02, 0));
+    stream.push(packet(1, &msg));
+    // an error at the first line of the procedure called by the second statement
+    let mut msg = done(0x11, 1);
+    msg.extend(proc_error(2627, 14, "Violation of PRIMARY KEY constraint.", "p", 1));
+    msg.extend(token(0xFE, 0x02, 0));
+    msg.extend(done(0x02, 0));
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+
+    let counts = conn.exec_batch(&["INSERT INTO t VALUES (1)", "INSERT INTO t VALUES (1), (2);", "INSERT INTO t\nVALUES (1), (2), (3)"]).unwrap();
+    assert_eq!(counts, vec![1, 2, 3]);
+    let utf16 = |s: &str| s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect::<Vec<u8>>();
+    let sql = utf16("INSERT INTO t VALUES (1);\nINSERT INTO t VALUES (1), (2);\nINSERT INTO t\nVALUES (1), (2), (3)");
+    assert!(sent_packets(&written.lock().unwrap())[2].ends_with(&sql));
+
+    match conn.exec_batch(&["INSERT INTO t VALUES (1)", "INSERT INTO t VALUES (1)", "INSERT INTO u\nVALUES (1)", "INSERT INTO t VALUES (1)"]) {
+        Err(TdsError::BatchError(idx, ref counts, ref err)) => {
+            assert_eq!(idx, 2);
+            assert_eq!(*counts, vec![1, 1]);
+            assert_eq!(err.code, 208);
+        },
+        x => panic!("expected a batch error, got {:?}", x)
+    }
+
+    // one count per statement
+    assert_eq!(conn.exec_batch(&["EXEC p", "SELECT 1", "INSERT INTO t VALUES (1), (2), (3), (4)"]).unwrap(), vec![5, 1, 4]);
+
+    match conn.exec_batch(&["INSERT INTO t VALUES (1)", "UPDATE t SET x = 1"]) {
+        Err(TdsError::BatchError(idx, ref counts, ref err)) => {
+            assert_eq!(idx, 1);
+            assert_eq!(*counts, vec![1]);
+            assert_eq!(err.code, 1222);
+        },
+        x => panic!("expected a batch error, got {:?}", x)
+    }
+
+    match conn.exec_batch(&["INSERT INTO t VALUES (1)", "EXEC p", "INSERT INTO t VALUES (2)"]) {
+        Err(TdsError::BatchError(idx, ref counts, ref err)) => {
+            assert_eq!(idx, 1);
+            assert_eq!(*counts, vec![1]);
+            assert_eq!((err.code, &err.proc_name[..], err.line_number), (2627, "p", 1));
+        },
+        x => panic!("expected a batch error, got {:?}", x)
+    }
+}
+
+#[test]
+fn test_exec_proc_batch() {
+    // the response to `EXEC p`: DONEINPROC for each statement, the return status, DONEPROC and the DONE of the batch
+    let token = |ty: u8, status: u16, rows: u64| {
+        let mut token = done(status, rows);
+        token[0] = ty;
+        token
+    };
+    let mut stream = MockStream::new();
+    let mut msg = token(0xFF, 0x11, 2);
+    msg.extend(token(0xFF, 0x11, 3));
+    msg.extend_from_slice(&[0x79, 0, 0, 0, 0]);
+    msg.extend(token(0xFE, 0x01, 0));
+    msg.extend(done(0x00, 0));
+    stream.push(packet(1, &msg));
+    // a procedure returning two result sets
+    let mut msg = vec![];
+    for value in 1..3 {
+        msg.extend_from_slice(&[0x81, 1, 0, 0, 0, 0, 0, 0, 0, 0x26, 4, 0]);
+        msg.extend_from_slice(&[0xD1, 4, value, 0, 0, 0]);
+        msg.extend(token(0xFF, 0x11, 1));
+    }
+    msg.extend_from_slice(&[0x79, 0, 0, 0, 0]);
+    msg.extend(token(0xFE, 0x01, 0));
+    msg.extend(done(0x00, 0));
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+
+    assert_eq!(conn.exec("EXEC p").unwrap(), 5);
+    let rows = conn.query("EXEC p").unwrap();
+    assert_eq!(rows.len(), 2);
+    let x: i32 = rows.get(1).get(0);
+    assert_eq!(x, 2);
+}
+
+#[test]
+fn test_statement_cache_shared() {
+    let mut prepared = vec![0x81, 1, 0, 0, 0, 0, 0, 0, 0, 0x26, 4, 0];
+    prepared.extend_from_slice(&[0xAC, 0, 0]);
+    prepared.extend(b_varchar("handle"));
+    prepared.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0x26, 4, 4, 1, 0, 0, 0]);
+    prepared.extend_from_slice(&[0xFE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut executed = vec![0xD1, 4, 1, 0, 0, 0];
+    executed.extend_from_slice(&[0xFE, 0x10, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(packet(1, &prepared));
+    stream.push(packet(1, &executed));
+    stream.push(packet(1, &executed));
+    let conn = Connection::connect(builder(stream).statement_cache_size(4).build()).unwrap();
+
+    // e.g. checked out of a pool twice: the second checkout reuses the handle prepared by the first one
+    for _ in 0..2 {
+        let checkout = conn.clone();
+        let stmt = checkout.prepare("SELECT @P1").unwrap();
+        let rows = stmt.query(&[&1i32]).unwrap();
+        let x: i32 = rows.get(0).get(0);
+        assert_eq!(x, 1);
+    }
+    let packets = sent_packets(&written.lock().unwrap());
+    assert_eq!(packets.len(), 5);
+    assert!(packets[2].windows(4).any(|w| w == [0xFF, 0xFF, 11, 0]));
+    let sp_execute: Vec<u8> = "sp_execute".encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect();
+    for p in &packets[3..] {
+        assert!(p.windows(sp_execute.len()).any(|w| w == &sp_execute[..]));
+    }
+}
+
+#[test]
+fn test_cancel_split_acknowledgement() {
+    let mut msg = vec![];
+    // COLMETADATA: int NULL
+    msg.extend_from_slice(&[0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0x26, 4]);
+    msg.extend(b_varchar("x"));
+    msg.extend_from_slice(&[0xD1, 4, 1, 0, 0, 0]);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    // the rest of the response, then the acknowledgement split across two packets
+    let (first, second) = msg.split_at(10);
+    stream.push(packet(0, first));
+    stream.push(packet(1, second));
+    let ack = done(0x20, 0);
+    stream.push(packet(0, &ack[..5]));
+    stream.push(packet(1, &ack[5..]));
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+
+    let polls = Cell::new(0);
+    match conn.query_cancellable("SELECT x FROM test", || {
+        polls.set(polls.get() + 1);
+        polls.get() > 1
+    }) {
+        Err(TdsError::Cancelled) => (),
+        x => panic!("expected the query to be cancelled, got {:?}", x)
+    }
+    assert_eq!(conn.query_scalar::<i32, _>("SELECT x FROM test").unwrap(), Some(1));
+}
+
+#[test]
+fn test_query_cancelable_token() {
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    // the server acknowledges the attention, then answers the next query
+    stream.push(packet(1, &done(0x20, 0)));
+    stream.push(packet(1, &done(0x10, 1)));
+    let conn = connect(stream);
+
+    let token = CancellationToken::new();
+    token.clone().cancel();
+    match conn.query_cancelable("WAITFOR DELAY '00:00:05'", &token) {
+        Err(TdsError::Cancelled) => (),
+        x => panic!("expected the query to be cancelled, got {:?}", x)
+    }
+    let packets = sent_packets(&written.lock().unwrap());
+    assert_eq!(packets[3], vec![6, 1, 0, 8, 0, 0, packets[3][6], 0]);
+    assert_eq!(conn.exec("DELETE FROM t").unwrap(), 1);
+}
+
+#[test]
+fn test_prepared_parameters() {
+    // the response to sp_prepare: only the handle
+    let mut prepared = vec![0xAC, 0, 0];
+    prepared.extend(b_varchar("handle"));
+    prepared.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0x26, 4, 4, 7, 0, 0, 0]);
+    prepared.extend_from_slice(&[0xFE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(packet(1, &prepared));
+    stream.push(packet(1, &done(0x10, 0)));
+    let conn = connect(stream);
+    let stmt = conn.prepare("SELECT * FROM test WHERE id = @P1 AND col_varchar = @P2").unwrap();
+    assert!(stmt.parameters().is_empty());
+    stmt.execute(&[&1i32, &"abc"]).unwrap();
+    let params = stmt.parameters();
+    assert_eq!(params.len(), 2);
+    assert_eq!((params[0].ordinal, &params[0].type_name[..]), (1, "int"));
+    assert_eq!((params[1].ordinal, &params[1].type_name[..]), (2, "nvarchar(4000)"));
+    // only sp_prepare and sp_execute were sent
+    assert_eq!(sent_packets(&written.lock().unwrap()).len(), 4);
+}
+
+#[test]
+fn test_row_columns() {
+    let mut msg = vec![];
+    // COLMETADATA: id int, name nvarchar(10), flag bit
+    msg.extend_from_slice(&[0x81, 3, 0]);
+    msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x26, 4]);
+    msg.extend(b_varchar("id"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xE7, 20, 0, 0x09, 0x04, 0xD0, 0x00, 0x34]);
+    msg.extend(b_varchar("name"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x32]);
+    msg.extend(b_varchar("flag"));
+    msg.extend_from_slice(&[0xD1, 4, 1, 0, 0, 0]);
+    msg.extend(nvarchar("a"));
+    msg.push(1);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT id, name, flag FROM t").unwrap();
+    let row = rows.get(0);
+    assert_eq!(row.column_count(), 3);
+    assert!(!row.is_empty());
+    let columns = row.columns();
+    assert_eq!(columns.len(), 3);
+    let names: Vec<&str> = columns.iter().map(|x| x.col_name.as_ref().unwrap().as_str()).collect();
+    assert_eq!(names, vec!["id", "name", "flag"]);
+    assert!(columns[1].is_nullable());
+    match columns[2].type_info {
+        TypeInfo::FixedLenType(FixedLenType::Bit) => (),
+        ref x => panic!("unexpected type {:?}", x)
+    }
+}
+
+#[test]
+fn test_bind_bool() {
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(packet(1, &done(0x10, 1)));
+    let conn = connect(stream);
+    let stmt = conn.parameterized_auto("UPDATE t SET flag = @P1").unwrap();
+    assert_eq!(stmt.exec(&[&true]).unwrap(), 1);
+    // declared as bit, sent as BITN with a length of 1
+    let packets = sent_packets(&written.lock().unwrap());
+    let utf16 = |s: &str| s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect::<Vec<u8>>();
+    assert!(packets[2].windows(14).any(|w| w == &utf16("@P1 bit")[..]));
+    assert!(packets[2].ends_with(&[0x68, 1, 1, 1]));
+}
+
+#[test]
+fn test_prepared_no_metadata() {
+    // the response to sp_prepare: the metadata of the result (a int, b int) and the handle
+    let mut prepared = vec![0x81, 2, 0];
+    prepared.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x26, 4]);
+    prepared.extend(b_varchar("a"));
+    prepared.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x26, 4]);
+    prepared.extend(b_varchar("b"));
+    prepared.extend_from_slice(&[0xAC, 0, 0]);
+    prepared.extend(b_varchar("handle"));
+    prepared.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0x26, 4, 4, 1, 0, 0, 0]);
+    prepared.extend_from_slice(&[0xFE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    // sp_execute with fNoMetaData: only the rows
+    let mut executed = vec![0xD1, 4, 1, 0, 0, 0, 4, 2, 0, 0, 0];
+    executed.extend_from_slice(&[0xFE, 0x10, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+    for &cache_size in &[0, 1] {
+        let mut stream = MockStream::new();
+        let written = stream.written.clone();
+        stream.push(packet(1, &prepared));
+        stream.push(packet(1, &executed));
+        // without the statement cache, the second statement is prepared again
+        if cache_size == 0 {
+            stream.push(packet(1, &prepared));
+        }
+        stream.push(packet(1, &executed));
+        let conn = Connection::connect(builder(stream).statement_cache_size(cache_size).build()).unwrap();
+        for _ in 0..2 {
+            let stmt = conn.prepare("SELECT a, b FROM t WHERE a = @P1").unwrap();
+            let rows = stmt.query(&[&1i32]).unwrap();
+            let (a, b): (i32, i32) = (rows.get(0).get("a"), rows.get(0).get("b"));
+            assert_eq!((a, b), (1, 2));
+        }
+        // the executions were sent with fNoMetaData (0x02)
+        let sp_execute: Vec<u8> = "sp_execute".encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect();
+        let packets = sent_packets(&written.lock().unwrap());
+        for p in packets[2..].iter().filter(|p| p.windows(sp_execute.len()).any(|w| w == &sp_execute[..])) {
+            let pos = p.windows(sp_execute.len()).position(|w| w == &sp_execute[..]).unwrap() + sp_execute.len();
+            assert_eq!(&p[pos..pos + 2], &[0x02, 0x00]);
+        }
+    }
+}
+
+#[test]
+fn test_login_routing() {
+    // ROUTING: TCP to node.example.com:11000, the old value is empty
+    let server = b_varchar("node.example.com");
+    let mut routing = vec![0, 0, 0, 0xF8, 0x2A, server[0], 0];
+    routing.extend_from_slice(&server[1..]);
+    let len = routing.len() - 2;
+    routing[0] = len as u8;
+    routing[1] = (len >> 8) as u8;
+    let mut login_response = env_change(20, &routing, &[0, 0]);
+    login_response.extend(done(0, 0));
+    let stream = MockStream::with_login_response(login_response);
+    let written = stream.written.clone();
+
+    let mut routed = MockStream::new();
+    let routed_written = routed.written.clone();
+    routed.push(packet(1, &done(0x10, 1)));
+    let routed = Mutex::new(Some(routed));
+    let addresses = Arc::new(Mutex::new(vec![]));
+    let connected_to = addresses.clone();
+    let conn = Connection::connect(builder(stream).on_redirect(move |server, port| {
+        connected_to.lock().unwrap().push((server.to_owned(), port));
+        Ok(routed.lock().unwrap().take().unwrap())
+    }).build()).unwrap();
+
+    assert!(conn.was_redirected());
+    assert_eq!(*addresses.lock().unwrap(), vec![("node.example.com".to_owned(), 11000)]);
+    // the login is repeated on the new stream, which is used from then on
+    assert_eq!(sent_packets(&written.lock().unwrap()).len(), 2);
+    assert_eq!(conn.exec("DELETE FROM t").unwrap(), 1);
+    let packets = sent_packets(&routed_written.lock().unwrap());
+    assert_eq!(packets.iter().map(|p| p[0]).collect::<Vec<_>>(), vec![0x12, 0x10, 0x01]);
+
+    // without a way to reconnect, the connection fails
+    let mut login_response = env_change(20, &routing, &[0, 0]);
+    login_response.extend(done(0, 0));
+    match Connection::connect(builder(MockStream::with_login_response(login_response)).build()) {
+        Err(TdsError::Other(ref msg)) => assert_eq!(msg, "login: the server redirects to node.example.com:11000, but the connection cannot reconnect"),
+        x => panic!("expected an error, got {:?}", x.map(|_| ()))
+    }
+    assert!(!connect(MockStream::new()).was_redirected());
+}
+
+#[test]
+fn test_varchar_code_page() {
+    let mut msg = vec![];
+    // COLMETADATA: varchar(10) with SQL_Latin1_General_CP1_CI_AS (sort id 52), Cyrillic_General_CI_AS (LCID 0x419)
+    // and Greek_CI_AS (LCID 0x408)
+    msg.extend_from_slice(&[0x81, 3, 0]);
+    for collation in &[[0x09, 0x04, 0xD0, 0x00, 0x34], [0x19, 0x04, 0xD0, 0x00, 0x00], [0x08, 0x04, 0xD0, 0x00, 0x00]] {
+        msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xA7, 10, 0]);
+        msg.extend_from_slice(collation);
+        msg.extend(b_varchar("c"));
+    }
+    msg.extend_from_slice(&[0xD1, 5, 0, b'c', b'a', b'f', 0xE9, b's', 2, 0, 0xCC, 0xE8, 2, 0, 0xE1, 0xE3]);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT c, c, c FROM t").unwrap();
+    let values: Vec<&str> = (0..3).map(|i| rows.get(0).get(i)).collect();
+    assert_eq!(values, vec!["cafés", "Ми", "αγ"]);
+}
+
+#[test]
+fn test_query_metadata_only() {
+    let mut msg = vec![];
+    // COLMETADATA: a int, b nvarchar(10) and no rows
+    msg.extend_from_slice(&[0x81, 2, 0]);
+    msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x38]);
+    msg.extend(b_varchar("a"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xE7, 20, 0, 0x09, 0x04, 0xD0, 0x00, 0x34]);
+    msg.extend(b_varchar("b"));
+    msg.extend(done(0x10, 0));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT a, b FROM t WHERE 1 = 0").unwrap();
+    assert_eq!(rows.len(), 0);
+    let names: Vec<Option<String>> = rows.columns().iter().map(|x| x.col_name.clone()).collect();
+    assert_eq!(names, vec![Some("a".to_owned()), Some("b".to_owned())]);
+}
+
+#[test]
+fn test_ping() {
+    let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 0, 0, 0x26, 4, 0];
+    msg.extend_from_slice(&[0xD1, 4, 1, 0, 0, 0]);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    conn.ping().unwrap();
+    // the result is consumed fully, the connection is ready for the next statement
+    assert_eq!(conn.query_scalar::<i32, _>("SELECT 1").unwrap(), Some(1));
+}
+
+#[test]
+fn test_ping_closed() {
+    // the stream is at its end after the login
+    let conn = connect(MockStream::new());
+    assert!(conn.ping().is_err());
+}
+
+#[test]
+fn test_collation_flags() {
+    let mut msg = vec![];
+    // COLMETADATA: varchar(10) columns with Japanese_CI_AS_KS_WS, Japanese_CI_AS_KS and Japanese_CI_AS_WS
+    msg.extend_from_slice(&[0x81, 3, 0]);
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xA7, 10, 0, 0x11, 0x04, 0x10, 0x00, 0x00]);
+    msg.extend(b_varchar("ks_ws"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xA7, 10, 0, 0x11, 0x04, 0x50, 0x00, 0x00]);
+    msg.extend(b_varchar("ks"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xA7, 10, 0, 0x11, 0x04, 0x90, 0x00, 0x00]);
+    msg.extend(b_varchar("ws"));
+    msg.extend(done(0x10, 0));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT ks_ws, ks, ws FROM t").unwrap();
+    let flags: Vec<(bool, bool, bool, bool)> = rows.columns().iter().map(|col| match col.type_info {
+        TypeInfo::VarLenType(_, _, Some(ref collation)) => (collation.ignore_case(), collation.ignore_accent(), collation.ignore_kana(), collation.ignore_width()),
+        ref x => panic!("expected a collation, got {:?}", x)
+    }).collect();
+    assert_eq!(flags, vec![(true, false, false, false), (true, false, false, true), (true, false, true, false)]);
+}
+
+#[test]
+fn test_query_order() {
+    let mut msg = vec![];
+    // COLMETADATA: id int, name varchar(10) with SQL_Latin1_General_CP1_CI_AS, code varchar(10) with Latin1_General_BIN
+    msg.extend_from_slice(&[0x81, 3, 0]);
+    msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x38]);
+    msg.extend(b_varchar("id"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xA7, 10, 0, 0x09, 0x04, 0xD0, 0x00, 0x34]);
+    msg.extend(b_varchar("name"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xA7, 10, 0, 0x09, 0x04, 0x00, 0x01, 0x00]);
+    msg.extend(b_varchar("code"));
+    // ORDER BY name, code
+    msg.extend_from_slice(&[0xA9, 4, 0, 2, 0, 3, 0]);
+    msg.extend_from_slice(&[0xD1, 2, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+    msg.extend_from_slice(&[0xD1, 1, 0, 0, 0, 1, 0, b'a', 1, 0, b'B']);
+    msg.extend(done(0x10, 2));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT id, name, code FROM t ORDER BY name, code").unwrap();
+    let order = rows.order();
+    assert_eq!(order.len(), 2);
+    assert_eq!((order[0].index, order[0].name.as_ref().unwrap().as_str()), (1, "name"));
+    let collation = order[0].collation.as_ref().unwrap();
+    assert!(collation.ignore_case() && !collation.ignore_accent() && !collation.binary());
+    assert_eq!((order[1].index, order[1].name.as_ref().unwrap().as_str()), (2, "code"));
+    let collation = order[1].collation.as_ref().unwrap();
+    assert!(collation.binary() && !collation.ignore_case());
+    assert_eq!(rows.len(), 2);
+
+    // an unordered result
+    let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 0, 0, 0x38];
+    msg.extend(b_varchar("id"));
+    msg.extend(done(0x10, 0));
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    assert!(conn.query("SELECT id FROM t").unwrap().order().is_empty());
+}
+
+#[derive(Debug, PartialEq)]
+struct User {
+    id: i32,
+    name: String,
+}
+
+impl FromRow for User {
+    fn from_row(row: &Row) -> Result<User, TdsError> {
+        Ok(User {
+            id: try!(row.try_get("id")),
+            name: try!(row.try_get("name")),
+        })
+    }
+}
+
+#[test]
+fn test_query_as() {
+    let result = || {
+        let mut msg = vec![];
+        // COLMETADATA: id int, name nvarchar(10)
+        msg.extend_from_slice(&[0x81, 2, 0]);
+        msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x26, 4]);
+        msg.extend(b_varchar("id"));
+        msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xE7, 20, 0, 0x09, 0x04, 0xD0, 0x00, 0x34]);
+        msg.extend(b_varchar("name"));
+        for &(id, name) in &[(1u8, "alice"), (2, "bob")] {
+            msg.extend_from_slice(&[0xD1, 4, id, 0, 0, 0]);
+            msg.extend(nvarchar(name));
+        }
+        msg.extend(done(0x10, 2));
+        packet(1, &msg)
+    };
+    let mut stream = MockStream::new();
+    for _ in 0..4 {
+        stream.push(result());
+    }
+    let conn = connect(stream);
+
+    let users: Vec<User> = conn.query_as("SELECT id, name FROM users").unwrap();
+    assert_eq!(users, vec![User { id: 1, name: "alice".to_owned() }, User { id: 2, name: "bob".to_owned() }]);
+    let users: Vec<User> = conn.query_as_params("SELECT id, name FROM users WHERE id < @P1", &[&3i32]).unwrap();
+    assert_eq!(users.len(), 2);
+    let tuples: Vec<(i32, String)> = conn.query_as("SELECT id, name FROM users").unwrap();
+    assert_eq!(tuples[1], (2, "bob".to_owned()));
+    // a conversion error of any row fails the whole query
+    match conn.query_as::<(String,), _>("SELECT id, name FROM users") {
+        Err(TdsError::Other(ref msg)) => assert_eq!(msg, "conversion: expected String got I32"),
+        x => panic!("expected a conversion error, got {:?}", x)
+    }
+}
+
+#[test]
+fn test_prepared_exec() {
+    // the response to sp_prepare: only the handle
+    let mut prepared = vec![0xAC, 0, 0];
+    prepared.extend(b_varchar("handle"));
+    prepared.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0x26, 4, 4, 7, 0, 0, 0]);
+    prepared.extend_from_slice(&[0xFE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    // the UPDATE within sp_execute reports its count in a DONEINPROC token
+    let mut executed = vec![0xFF, 0x11, 0, 0xC5, 0, 3, 0, 0, 0, 0, 0, 0, 0];
+    executed.extend_from_slice(&[0xFE, 0, 0, 0xE0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &prepared));
+    stream.push(packet(1, &executed));
+    stream.push(packet(1, &executed));
+    let conn = connect(stream);
+    let stmt = conn.prepare("UPDATE t SET x = x + 1 WHERE y = @P1").unwrap();
+    assert_eq!(stmt.execute(&[&1i32]).unwrap(), 3);
+    assert_eq!(stmt.execute(&[&2i32]).unwrap(), 3);
+}
+
+#[test]
+fn test_describe() {
+    let mut msg = vec![];
+    // COLMETADATA: a int, b varchar(1) NULL
+    msg.extend_from_slice(&[0x81, 2, 0]);
+    msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x38]);
+    msg.extend(b_varchar("a"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xA7, 1, 0, 0x09, 0x04, 0xD0, 0x00, 0x34]);
+    msg.extend(b_varchar("b"));
+    msg.extend(done(0x01, 0));
+    msg.extend(done(0x00, 0));
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let written = stream.written.clone();
+    let conn = connect(stream);
+    let columns = conn.describe("SELECT 1 AS a, 'x' AS b").unwrap();
+    assert_eq!(columns.len(), 2);
+    assert_eq!(columns[0].name.as_ref().unwrap(), "a");
+    assert!(!columns[0].nullable);
+    match columns[0].type_info {
+        TypeInfo::FixedLenType(FixedLenType::Int4) => (),
+        ref x => panic!("unexpected type {:?}", x)
+    }
+    assert_eq!(columns[1].name.as_ref().unwrap(), "b");
+    assert!(columns[1].nullable);
+    match columns[1].type_info {
+        TypeInfo::VarLenType(VarLenType::BigVarChar, 1, Some(_)) => (),
+        ref x => panic!("unexpected type {:?}", x)
+    }
+    let utf16 = |s: &str| s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect::<Vec<u8>>();
+    let packets = sent_packets(&written.lock().unwrap());
+    assert!(packets[2].ends_with(&utf16("SET FMTONLY ON; SELECT 1 AS a, 'x' AS b; SET FMTONLY OFF;")));
+}
+
+#[test]
+fn test_lock_timeout() {
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    stream.push(packet(1, &done(0, 0)));
+    let mut msg = error(1222, 16, "Lock request time out period exceeded.", 1);
+    msg.extend(done(0x02, 0));
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+
+    conn.set_lock_timeout(100).unwrap();
+    let utf16 = |s: &str| s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect::<Vec<u8>>();
+    assert!(sent_packets(&written.lock().unwrap())[2].ends_with(&utf16("SET LOCK_TIMEOUT 100;")));
+    match conn.exec("UPDATE t SET x = 1 WHERE id = 1") {
+        Err(TdsError::LockTimeout(ref err)) => assert_eq!(err.code, 1222),
+        x => panic!("expected a lock timeout, got {:?}", x)
+    }
+}
+
+#[test]
+fn test_image_column() {
+    // a nullable image column with the table name
+    let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0x22, 0xFF, 0xFF, 0xFF, 0x7F];
+    msg.extend_from_slice(&[1, 1, 0, b't', 0]);
+    msg.extend(b_varchar("img"));
+    let value: Vec<u8> = (0..4000).map(|x| x as u8).collect();
+    // the text pointer, its timestamp and the length
+    msg.extend_from_slice(&[0xD1, 16]);
+    msg.extend_from_slice(&[0xAA; 16]);
+    msg.extend_from_slice(&[0xBB; 8]);
+    msg.extend_from_slice(&[value.len() as u8, (value.len() >> 8) as u8, 0, 0]);
+    msg.extend_from_slice(&value);
+    // NULL values have no text pointer
+    msg.extend_from_slice(&[0xD1, 0]);
+    msg.extend(done(0x10, 2));
+
+    let mut stream = MockStream::new();
+    for p in packets(&msg, 1000) {
+        stream.push(p);
+    }
+    let conn = connect(stream);
+    let rows = conn.query("SELECT img FROM t").unwrap();
+    assert_eq!(rows.len(), 2);
+    let img: Vec<u8> = rows.get(0).get("img");
+    assert_eq!(img, value);
+    let img: Option<&[u8]> = rows.get(1).get("img");
+    assert_eq!(img, None);
+}
+
+#[cfg(feature = "raw")]
+#[test]
+fn test_raw_packets() {
+    use tiberius::{OptionTokenPair, Packet, TokenStream};
+    let mut stream = MockStream::new();
+    let written = stream.written.clone();
+    // prelogin response: the version of the server
+    stream.push(packet(1, &[0, 0, 6, 0, 6, 0xFF, 0x0E, 0, 0, 0, 0, 0]));
+    stream.push(packet(1, &done(0x10, 0)));
+    let conn = connect(stream);
+
+    conn.send_raw(Packet::PreLogin(vec![OptionTokenPair::Version(0x09000000, 0)])).unwrap();
+    assert_eq!(sent_packets(&written.lock().unwrap())[2][0], 0x12);
+    match conn.recv_raw().unwrap() {
+        Packet::PreLogin(ref options) => match options[..] {
+            [OptionTokenPair::Version(0x0E000000, 0)] => (),
+            ref x => panic!("unexpected prelogin options {:?}", x)
+        },
+        x => panic!("expected a prelogin response, got {:?}", x)
+    }
+    conn.send_raw(Packet::SqlBatch("SELECT 1")).unwrap();
+    match conn.recv_raw().unwrap() {
+        Packet::TokenStream(ref tokens) => match tokens[..] {
+            [TokenStream::Done(_)] => (),
+            ref x => panic!("unexpected tokens {:?}", x)
+        },
+        x => panic!("expected a token stream, got {:?}", x)
+    }
+}
+
+#[test]
+fn test_nvarchar_max() {
+    // COLMETADATA: nvarchar(max) NULL
+    let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0xE7, 0xFF, 0xFF, 0x09, 0x04, 0xD0, 0x00, 0x34];
+    msg.extend(b_varchar("s"));
+    // a surrogate pair straddles the two chunks
+    let value = format!("{}\u{1F600}{}", "a".repeat(2000), "b".repeat(2100));
+    let bytes: Vec<u8> = value.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect();
+    assert!(bytes.len() > 8000);
+    let le32 = |x: usize| vec![x as u8, (x >> 8) as u8, (x >> 16) as u8, (x >> 24) as u8];
+    msg.push(0xD1);
+    msg.extend(le32(bytes.len()));
+    msg.extend_from_slice(&[0, 0, 0, 0]);
+    for chunk in &[&bytes[..4002], &bytes[4002..]] {
+        msg.extend(le32(chunk.len()));
+        msg.extend_from_slice(chunk);
+    }
+    msg.extend(le32(0));
+    // PLP NULL
+    msg.extend_from_slice(&[0xD1, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    msg.extend(done(0x10, 2));
+
+    let mut stream = MockStream::new();
+    for p in packets(&msg, 1000) {
+        stream.push(p);
+    }
+    let conn = connect(stream);
+    let rows = conn.query("SELECT s FROM t").unwrap();
+    assert_eq!(rows.len(), 2);
+    let s: &str = rows.get(0).get("s");
+    assert_eq!(s, value);
+    let s: Option<&str> = rows.get(1).get("s");
+    assert_eq!(s, None);
+}
+
+#[test]
+fn test_udt_column() {
+    let utf16 = |s: &str| s.encode_utf16().flat_map(|c| vec![c as u8, (c >> 8) as u8]).collect::<Vec<u8>>();
+    // COLMETADATA: geography NULL (UDT_INFO), int NULL
+    let mut msg = vec![0x81, 2, 0, 0, 0, 0, 0, 1, 0, 0xF0, 0xFF, 0xFF];
+    msg.extend(b_varchar("test"));
+    msg.extend(b_varchar("sys"));
+    msg.extend(b_varchar("geography"));
+    let assembly = "Microsoft.SqlServer.Types.SqlGeography, Microsoft.SqlServer.Types";
+    msg.extend_from_slice(&[assembly.len() as u8, 0]);
+    msg.extend(utf16(assembly));
+    msg.extend(b_varchar("g"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0x26, 4]);
+    msg.extend(b_varchar("i"));
+    // ROW: a serialized point in a single PLP chunk and 7, then NULL and NULL
+    let point = [0xE6, 0x10, 0, 0, 1, 0x0C, 0, 0, 0, 0, 0, 0, 0x24, 0x40, 0, 0, 0, 0, 0, 0, 0x34, 0x40];
+    msg.push(0xD1);
+    msg.extend_from_slice(&[point.len() as u8, 0, 0, 0, 0, 0, 0, 0]);
+    msg.extend_from_slice(&[point.len() as u8, 0, 0, 0]);
+    msg.extend_from_slice(&point);
+    msg.extend_from_slice(&[0, 0, 0, 0]);
+    msg.extend_from_slice(&[4, 7, 0, 0, 0]);
+    msg.push(0xD1);
+    msg.extend_from_slice(&[0xFF; 8]);
+    msg.push(0);
+    msg.extend(done(0x10, 2));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT g, i FROM test").unwrap();
+    let g: &[u8] = rows.get(0).get("g");
+    assert_eq!(g, &point[..]);
+    let i: i32 = rows.get(0).get("i");
+    assert_eq!(i, 7);
+    let g: Option<&[u8]> = rows.get(1).get("g");
+    assert_eq!(g, None);
+}
+
+#[test]
+fn test_sql_string_declarations() {
+    let long = "x".repeat(5000);
+    let declarations = |s: SqlString| param_declarations(&[&s]);
+    assert_eq!(declarations(SqlString::new("abc")), "@P1 nvarchar(4000)");
+    assert_eq!(declarations(SqlString::new("abc").unicode(false)), "@P1 varchar(8000)");
+    assert_eq!(declarations(SqlString::new("abc").max_len(50)), "@P1 nvarchar(50)");
+    assert_eq!(declarations(SqlString::new("abc").unicode(false).max_len(50)), "@P1 varchar(50)");
+    // longer than the maximum length which is not `max`
+    assert_eq!(declarations(SqlString::new(&long[..])), "@P1 nvarchar(max)");
+    assert_eq!(declarations(SqlString::new(&long[..]).unicode(false)), "@P1 varchar(8000)");
+    assert_eq!(declarations(SqlString::new("abc").max_len(4001)), "@P1 nvarchar(max)");
+    assert_eq!(declarations(SqlString::new("abc").unicode(false).max_len(8001)), "@P1 varchar(max)");
+    assert_eq!(declarations(SqlString::new("").max_len(0)), "@P1 nvarchar(1)");
+    assert_eq!(SqlString::new("abc").unicode(false).column_type(), "varchar(8000)");
+}
+
+#[test]
+fn test_string_declarations() {
+    let long = "x".repeat(4001);
+    assert_eq!(param_declarations(&[&"abc", &"x".repeat(4000)]), "@P1 nvarchar(4000),@P2 nvarchar(4000)");
+    assert_eq!(param_declarations(&[&&long[..], &long]), "@P1 nvarchar(max),@P2 nvarchar(max)");
+    // counted in UTF-16 code units, a character outside of the BMP takes two
+    assert_eq!(param_declarations(&[&"\u{1f600}".repeat(2000)]), "@P1 nvarchar(4000)");
+    assert_eq!(param_declarations(&[&"\u{1f600}".repeat(2001)]), "@P1 nvarchar(max)");
+    assert_eq!(ColumnType::String(long.clone().into()).column_type(), "nvarchar(max)");
+}
+
+#[test]
+fn test_collect_rows() {
+    let result = || {
+        let mut msg = vec![];
+        // COLMETADATA: id int, name nvarchar(10)
+        msg.extend_from_slice(&[0x81, 2, 0]);
+        msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x26, 4]);
+        msg.extend(b_varchar("id"));
+        msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xE7, 20, 0, 0x09, 0x04, 0xD0, 0x00, 0x34]);
+        msg.extend(b_varchar("name"));
+        for &(id, name) in &[(1u8, "alice"), (2, "bob")] {
+            msg.extend_from_slice(&[0xD1, 4, id, 0, 0, 0]);
+            msg.extend(nvarchar(name));
+        }
+        msg.extend(done(0x10, 2));
+        packet(1, &msg)
+    };
+    let mut stream = MockStream::new();
+    for _ in 0..4 {
+        stream.push(result());
+    }
+    let conn = connect(stream);
+
+    let ids: Vec<i32> = conn.query("SELECT id, name FROM users").unwrap().collect_column(0).unwrap();
+    assert_eq!(ids, vec![1, 2]);
+    let names: Vec<String> = conn.query("SELECT id, name FROM users").unwrap().collect_column("name").unwrap();
+    assert_eq!(names, vec!["alice".to_owned(), "bob".to_owned()]);
+
+    #[derive(Debug, PartialEq)]
+    struct User(i32, String);
+    let users = conn.query("SELECT id, name FROM users").unwrap().collect_rows(|row| {
+        Ok(User(try!(row.try_get(0)), try!(row.try_get(1))))
+    }).unwrap();
+    assert_eq!(users, vec![User(1, "alice".to_owned()), User(2, "bob".to_owned())]);
+    // a conversion error fails the whole collection
+    match conn.query("SELECT id, name FROM users").unwrap().collect_column::<String, _>("id") {
+        Err(TdsError::Other(ref msg)) => assert_eq!(msg, "conversion: expected String got I32"),
+        x => panic!("expected a conversion error, got {:?}", x)
+    }
+}
+
+#[test]
+fn test_datetime_offset_column() {
+    use chrono::{Datelike, FixedOffset};
+    // COLMETADATA: datetimeoffset(7) NULL
+    let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0x2B, 7];
+    msg.extend(b_varchar("d"));
+    // ROW: 2017-03-01 12:00:00 UTC at -05:30
+    let ticks: u64 = 12 * 3600 * 10_000_000;
+    let days = NaiveDate::from_ymd(2017, 3, 1).num_days_from_ce() - 1;
+    msg.extend_from_slice(&[0xD1, 10, ticks as u8, (ticks >> 8) as u8, (ticks >> 16) as u8, (ticks >> 24) as u8, (ticks >> 32) as u8]);
+    msg.extend_from_slice(&[days as u8, (days >> 8) as u8, (days >> 16) as u8]);
+    let offset = -330i16;
+    msg.extend_from_slice(&[offset as u8, (offset >> 8) as u8]);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT d FROM t").unwrap();
+    let d: DateTime<FixedOffset> = rows.get(0).get("d");
+    assert_eq!(*d.offset(), FixedOffset::west(330 * 60));
+    assert_eq!(d.naive_local(), NaiveDate::from_ymd(2017, 3, 1).and_hms(6, 30, 0));
+    let utc: DateTime<UTC> = rows.get(0).get("d");
+    assert_eq!(utc, UTC.ymd(2017, 3, 1).and_hms(12, 0, 0));
+}
+
+#[test]
+fn test_query_owned() {
+    fn load_names() -> tiberius::QueryResult<'static> {
+        let mut msg = vec![];
+        // COLMETADATA: name nvarchar(10)
+        msg.extend_from_slice(&[0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0xE7, 20, 0, 0x09, 0x04, 0xD0, 0x00, 0x34]);
+        msg.extend(b_varchar("name"));
+        for name in &["alice", "bob"] {
+            msg.push(0xD1);
+            msg.extend(nvarchar(name));
+        }
+        msg.extend(done(0x10, 2));
+        let mut stream = MockStream::new();
+        stream.push(packet(1, &msg));
+        let conn = connect(stream);
+        let sql = String::from("SELECT name FROM users");
+        conn.query_owned(sql).unwrap()
+    }
+    // the connection is gone
+    let rows = load_names();
+    assert_eq!(rows.len(), 2);
+    let name: &str = rows.get(1).get("name");
+    assert_eq!(name, "bob");
+}
+
+#[test]
+fn test_column_name_case() {
+    let mut msg = vec![];
+    // COLMETADATA: name int, Name int, NAME2 int
+    msg.extend_from_slice(&[0x81, 3, 0]);
+    for name in &["name", "Name", "NAME2"] {
+        msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x26, 4]);
+        msg.extend(b_varchar(name));
+    }
+    msg.extend_from_slice(&[0xD1, 4, 1, 0, 0, 0, 4, 2, 0, 0, 0, 4, 3, 0, 0, 0]);
+    msg.extend(done(0x10, 1));
+
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT name, Name, NAME2 FROM t").unwrap();
+    let row = rows.get(0);
+    // the first of the columns differing only in case
+    let x: i32 = row.get("NAME");
+    assert_eq!(x, 1);
+    let x: i32 = row.get("name2");
+    assert_eq!(x, 3);
+    let x: i32 = row.get(CaseSensitive("Name"));
+    assert_eq!(x, 2);
+    assert!(row.try_get::<_, i32>(CaseSensitive("NAME")).is_err());
+}
+
+#[test]
+fn test_prepared_query_with_count() {
+    // the response to sp_prepare: the metadata of the OUTPUT and the handle
+    let mut prepared = vec![0x81, 1, 0, 0, 0, 0, 0, 0, 0, 0x26, 4];
+    prepared.extend(b_varchar("id"));
+    prepared.extend_from_slice(&[0xAC, 0, 0]);
+    prepared.extend(b_varchar("handle"));
+    prepared.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0x26, 4, 4, 7, 0, 0, 0]);
+    prepared.extend_from_slice(&[0xFE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    // the rows of the OUTPUT clause and the count of the UPDATE
+    let mut executed = vec![0xD1, 4, 3, 0, 0, 0, 0xD1, 4, 5, 0, 0, 0];
+    executed.extend_from_slice(&[0xFF, 0x11, 0, 0xC5, 0, 2, 0, 0, 0, 0, 0, 0, 0]);
+    executed.extend_from_slice(&[0xFE, 0, 0, 0xE0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &prepared));
+    stream.push(packet(1, &executed));
+    let conn = connect(stream);
+    let stmt = conn.prepare("UPDATE t SET x = x + 1 OUTPUT inserted.id WHERE y = @P1").unwrap();
+    let (count, rows) = stmt.query_with_count(&[&1i32]).unwrap();
+    assert_eq!(count, 2);
+    let ids: Vec<i32> = rows.collect_column("id").unwrap();
+    assert_eq!(ids, vec![3, 5]);
+}
+
+#[test]
+fn test_statement_executed_twice() {
+    let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 0, 0, 0x26, 4];
+    msg.extend(b_varchar("id"));
+    msg.extend_from_slice(&[0xD1, 4, 7, 0, 0, 0, 0xD1, 4, 8, 0, 0, 0]);
+    msg.extend(done(0x10, 2));
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    stream.push(packet(1, &msg));
+    let written = stream.written.clone();
+    let conn = connect(stream);
+    let mut stmt = StatementInternal::new(conn.clone(), "SELECT id FROM t".into());
+    let first = stmt.execute_query().unwrap();
+    let second = stmt.execute_query().unwrap();
+    for rows in vec![first, second] {
+        let ids: Vec<i32> = rows.collect_column("id").unwrap();
+        assert_eq!(ids, vec![7, 8]);
+    }
+    // the batch was sent twice
+    assert_eq!(sent_packets(&written.lock().unwrap()).len(), 4);
+}
+
+#[test]
+fn test_legacy_datetime() {
+    // COLMETADATA: dt datetime NULL, sdt smalldatetime NULL (both DATETIMN)
+    let mut msg = vec![0x81, 2, 0, 0, 0, 0, 0, 1, 0, 0x6F, 8];
+    msg.extend(b_varchar("dt"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0x6F, 4]);
+    msg.extend(b_varchar("sdt"));
+    // 2017-03-01 12:34:56.997 (42793 days since 1900, 299 ticks of 1/300s past 12:34:56) and 2017-03-01 12:35
+    msg.extend_from_slice(&[0xD1, 8, 41, 167, 0, 0, 107, 90, 207, 0, 4, 41, 167, 243, 2]);
+    msg.extend_from_slice(&[0xD1, 0, 0]);
+    msg.extend(done(0x10, 2));
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT dt, sdt FROM t").unwrap();
+    let datetime: Option<chrono::NaiveDateTime> = rows.get(0).get("dt");
+    assert_eq!(datetime, Some(NaiveDate::from_ymd(2017, 3, 1).and_hms_nano(12, 34, 56, 996666667)));
+    let datetime: Option<chrono::NaiveDateTime> = rows.get(0).get("sdt");
+    assert_eq!(datetime, Some(NaiveDate::from_ymd(2017, 3, 1).and_hms(12, 35, 0)));
+    let datetime: Option<chrono::NaiveDateTime> = rows.get(1).get("dt");
+    assert_eq!(datetime, None);
+    let datetime: Option<chrono::NaiveDateTime> = rows.get(1).get("sdt");
+    assert_eq!(datetime, None);
+}
+
+#[test]
+fn test_row_as_map() {
+    // COLMETADATA: id int, name nvarchar(10), an unnamed int
+    let mut msg = vec![0x81, 3, 0, 0, 0, 0, 0, 0, 0, 0x26, 4];
+    msg.extend(b_varchar("id"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xE7, 20, 0, 0x09, 0x04, 0xD0, 0x00, 0x34]);
+    msg.extend(b_varchar("name"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x26, 4, 0]);
+    msg.extend_from_slice(&[0xD1, 4, 1, 0, 0, 0]);
+    msg.extend(nvarchar("abc"));
+    msg.extend_from_slice(&[4, 42, 0, 0, 0]);
+    msg.extend(done(0x10, 1));
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT id, name, 42 FROM t").unwrap();
+    {
+        let map = rows.get(0).as_map();
+        let mut keys: Vec<&String> = map.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["column_2", "id", "name"]);
+        match *map["id"] {
+            ColumnValue::Some(ColumnType::I32(1)) => (),
+            ref x => panic!("unexpected value {:?}", x)
+        }
+    }
+    let map = rows.into_iter().next().unwrap().into_map();
+    match map["name"] {
+        ColumnValue::Some(ColumnType::String(ref x)) => assert_eq!(x, "abc"),
+        ref x => panic!("unexpected value {:?}", x)
+    }
+    match map["column_2"] {
+        ColumnValue::Some(ColumnType::I32(42)) => (),
+        ref x => panic!("unexpected value {:?}", x)
+    }
+}
+
+#[test]
+fn test_date_column() {
+    // COLMETADATA: d date NULL, rows of 0001-01-01, 2017-03-01, 9999-12-31 and NULL
+    let mut msg = vec![0x81, 1, 0, 0, 0, 0, 0, 1, 0, 0x28];
+    msg.extend(b_varchar("d"));
+    for days in &[[0, 0, 0], [132, 60, 11], [218, 185, 55]] {
+        msg.extend_from_slice(&[0xD1, 3]);
+        msg.extend_from_slice(days);
+    }
+    msg.extend_from_slice(&[0xD1, 0]);
+    msg.extend(done(0x10, 4));
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    stream.push(packet(1, &done(0, 0)));
+    let written = stream.written.clone();
+    let conn = connect(stream);
+    let dates: Vec<Option<NaiveDate>> = conn.query("SELECT d FROM t").unwrap().collect_column("d").unwrap();
+    assert_eq!(dates, vec![Some(NaiveDate::from_ymd(1, 1, 1)), Some(NaiveDate::from_ymd(2017, 3, 1)), Some(NaiveDate::from_ymd(9999, 12, 31)), None]);
+
+    // bound as DATEN
+    let date = NaiveDate::from_ymd(9999, 12, 31);
+    conn.call_proc("p", &[Param::new("@d", &date)]).unwrap();
+    let packets = sent_packets(&written.lock().unwrap());
+    let mut param = b_varchar("@d");
+    param.extend_from_slice(&[0, 0x28, 3, 218, 185, 55]);
+    assert!(packets[3].ends_with(&param));
+}
+
+#[test]
+fn test_write_csv() {
+    // COLMETADATA: id int, name nvarchar(20) NULL, an unnamed bit
+    let mut msg = vec![0x81, 3, 0, 0, 0, 0, 0, 0, 0, 0x26, 4];
+    msg.extend(b_varchar("id"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0xE7, 40, 0, 0x09, 0x04, 0xD0, 0x00, 0x34]);
+    msg.extend(b_varchar("name"));
+    msg.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0x68, 1, 0]);
+    for &(id, name, flag) in &[(1, Some("plain"), 1), (2, Some("a, \"quoted\"\nname"), 0), (3, None, 1)] {
+        msg.extend_from_slice(&[0xD1, 4, id, 0, 0, 0]);
+        match name {
+            Some(name) => msg.extend(nvarchar(name)),
+            None => msg.extend_from_slice(&[0xFF, 0xFF]),
+        }
+        msg.extend_from_slice(&[1, flag]);
+    }
+    msg.extend(done(0x10, 3));
+    let mut stream = MockStream::new();
+    stream.push(packet(1, &msg));
+    let conn = connect(stream);
+    let rows = conn.query("SELECT id, name, CAST(1 AS bit) FROM t").unwrap();
+    let mut csv = vec![];
+    rows.write_csv(&mut csv).unwrap();
+    assert_eq!(String::from_utf8(csv).unwrap(), "id,name,column_2\r\n1,plain,1\r\n2,\"a, \"\"quoted\"\"\nname\",0\r\n3,,1\r\n");
+}
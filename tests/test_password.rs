@@ -0,0 +1,85 @@
+//! checks with an allocator inspecting every freed block, that the password does not linger in freed memory
+extern crate tiberius;
+mod mock;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use mock::{MockStream, builder};
+use tiberius::{AuthenticationMethod, Connection};
+
+const PASSWORD: &'static [u8] = b"Tr0ub4dor&3";
+
+static ARMED: AtomicBool = AtomicBool::new(false);
+static LEAKED: AtomicBool = AtomicBool::new(false);
+
+/// whether the block contains the password with each byte encoded by `encode`, as UTF-8 or UTF-16
+fn contains(block: &[u8], encode: fn(u8) -> u8, utf16: bool) -> bool {
+    let width = if utf16 { 2 } else { 1 };
+    let len = PASSWORD.len() * width;
+    block.len() >= len && (0..block.len() - len + 1).any(|start| {
+        PASSWORD.iter().enumerate().all(|(i, &c)| {
+            let pos = start + i * width;
+            block[pos] == encode(c) && (!utf16 || block[pos + 1] == encode(0))
+        })
+    })
+}
+
+fn plain(byte: u8) -> u8 {
+    byte
+}
+
+/// the scrambling of the password in the login packet
+fn scrambled(byte: u8) -> u8 {
+    ((byte >> 4) | ((byte & 0x0f) << 4)) ^ 0xa5
+}
+
+struct CheckingAlloc;
+
+unsafe impl GlobalAlloc for CheckingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if ARMED.load(Ordering::SeqCst) {
+            let block = slice::from_raw_parts(ptr, layout.size());
+            if contains(block, plain, false) || contains(block, plain, true) || contains(block, scrambled, true) {
+                LEAKED.store(true, Ordering::SeqCst);
+            }
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CheckingAlloc = CheckingAlloc;
+
+/// connect, returning whether a freed block contained the password
+fn connect_leaks(packet_size: u16, user: &str) -> bool {
+    LEAKED.store(false, Ordering::SeqCst);
+    let stream = MockStream::new();
+    // what the mock stream records is the test's, not the connection's: keep it from being reallocated
+    let written = stream.written.clone();
+    written.lock().unwrap().reserve(64 * 1024);
+    let password = String::from_utf8(PASSWORD.to_vec()).unwrap();
+    ARMED.store(true, Ordering::SeqCst);
+    {
+        let opts = builder(stream).auth(AuthenticationMethod::internal(user.to_owned(), password)).packet_size(packet_size).build();
+        Connection::connect(opts).unwrap();
+    }
+    for byte in written.lock().unwrap().iter_mut() {
+        *byte = 0;
+    }
+    drop(written);
+    ARMED.store(false, Ordering::SeqCst);
+    LEAKED.load(Ordering::SeqCst)
+}
+
+// a single test, since the allocator is shared by the threads running the tests
+#[test]
+fn test_password_wiped() {
+    assert!(!connect_leaks(4096, "sa"));
+    // the login is split into several packets, the password (following the user name) into the second one:
+    // the spare capacity of the buffers has to be wiped as well
+    assert!(!connect_leaks(512, &"u".repeat(250)));
+}
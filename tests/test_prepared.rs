@@ -1,4 +1,7 @@
 extern crate tiberius;
+use std::borrow::Cow;
+use std::time::Duration;
+use tiberius::{ColumnValue, ColumnType, SqlString, TimeOfDay};
 mod test;
 use test::get_connection;
 
@@ -24,3 +27,122 @@ fn test_common_prepare_types() {
     stmt.query(&[&12.12f32, &23.23f64, &0f64, &0f64]).unwrap();
     stmt.query(&[&"12", &0f64, &0f64, &0f64]).unwrap();
 }
+
+#[test]
+fn test_bind_duration() {
+    let cl = get_connection();
+    let stmt = cl.prepare("SELECT @P1 AS t;").unwrap();
+    let rows = stmt.query(&[&TimeOfDay::new(Duration::new(13 * 3600 + 45 * 60 + 30, 123456700)).unwrap()]).unwrap();
+    let t: Duration = rows.get(0).get("t");
+    assert_eq!(t, Duration::new(13 * 3600 + 45 * 60 + 30, 123456700));
+}
+
+#[test]
+fn test_bind_floats() {
+    let cl = get_connection();
+    let stmt = cl.prepare("SELECT @P1 AS f, @P2 AS r;").unwrap();
+    let rows = stmt.query(&[&3.14f64, &1.5f32]).unwrap();
+    let f: f64 = rows.get(0).get("f");
+    assert_eq!(f, 3.14f64);
+    let r: f32 = rows.get(0).get("r");
+    assert_eq!(r, 1.5f32);
+}
+
+#[test]
+fn test_bind_bool() {
+    let cl = get_connection();
+    let stmt = cl.prepare("SELECT @P1 AS t, @P2 AS f;").unwrap();
+    let rows = stmt.query(&[&true, &false]).unwrap();
+    let t: bool = rows.get(0).get("t");
+    assert_eq!(t, true);
+    let f: bool = rows.get(0).get("f");
+    assert_eq!(f, false);
+}
+
+#[test]
+fn test_prepared_exec_delete() {
+    let cl = get_connection();
+    cl.exec("IF OBJECT_ID('tempdb..#tmp_delete') IS NOT NULL DROP TABLE #tmp_delete; CREATE TABLE #tmp_delete (id int);").unwrap();
+    cl.exec("INSERT INTO #tmp_delete (id) VALUES (1), (2), (3);").unwrap();
+    let stmt = cl.prepare("DELETE FROM #tmp_delete WHERE id >= @P1;").unwrap();
+    assert_eq!(stmt.execute(&[&2]).unwrap(), 2);
+    assert_eq!(stmt.execute(&[&1]).unwrap(), 1);
+}
+
+#[test]
+fn test_bind_dynamic_values() {
+    let cl = get_connection();
+    let stmt = cl.prepare("SELECT @P1 AS i, @P2 AS t, @P3 AS n;").unwrap();
+    let i = ColumnValue::Some(ColumnType::I32(42));
+    let t = ColumnValue::Some(ColumnType::String(Cow::Borrowed("hello")));
+    let rows = stmt.query(&[&i, &t, &ColumnValue::None]).unwrap();
+    let i: i32 = rows.get(0).get("i");
+    assert_eq!(i, 42);
+    let t: &str = rows.get(0).get("t");
+    assert_eq!(t, "hello");
+    let n: Option<&str> = rows.get(0).get("n");
+    assert_eq!(n, None);
+}
+
+#[test]
+fn test_strict_binding() {
+    use tiberius::{AuthenticationMethod, Connection, TcpConnectionBuilder};
+    let opts = TcpConnectionBuilder::new_connect("localhost:1433").unwrap()
+        .auth(AuthenticationMethod::internal("test", "test"))
+        .db("test")
+        .strict_binding(true)
+        .build();
+    let cl = Connection::connect(opts).unwrap();
+    // col_varchar_50 is varchar(50), comparing it to nvarchar converts the column
+    let stmt = cl.prepare("SELECT * FROM [test].[dbo].[test] WHERE col_varchar_50 = @P1;").unwrap();
+    assert!(stmt.query(&[&"HelloWorld"]).is_err());
+    let stmt = cl.prepare("SELECT * FROM [test].[dbo].[test] WHERE id = @P1;").unwrap();
+    assert!(stmt.query(&[&1i32]).is_ok());
+}
+
+#[test]
+fn test_bind_null() {
+    let cl = get_connection();
+    cl.exec("IF OBJECT_ID('tempdb..#tmp_null') IS NOT NULL DROP TABLE #tmp_null; CREATE TABLE #tmp_null (id int, name nvarchar(10));").unwrap();
+    let stmt = cl.prepare("INSERT INTO #tmp_null VALUES (@P1, @P2);").unwrap();
+    assert_eq!(stmt.execute(&[&None::<i32>, &Some("a")]).unwrap(), 1);
+    let rows = cl.query("SELECT id, name FROM #tmp_null WHERE id IS NULL;").unwrap();
+    assert_eq!(rows.len(), 1);
+    let id: Option<i32> = rows.get(0).get(0);
+    assert_eq!(id, None);
+}
+
+#[test]
+fn test_parameters() {
+    let cl = get_connection();
+    let stmt = cl.prepare("SELECT * FROM [test].[dbo].[test] WHERE id = @P1 AND col_varchar_50 = @P2;").unwrap();
+    stmt.query(&[&1i32, &SqlString::new("a").unicode(false).max_len(50)]).unwrap();
+    let params = stmt.parameters();
+    assert_eq!(params.len(), 2);
+    assert_eq!(params[0].ordinal, 1);
+    assert_eq!(params[0].type_name, "int");
+    assert_eq!(params[1].ordinal, 2);
+    assert_eq!(params[1].type_name, "varchar(50)");
+}
+
+#[test]
+fn test_column_names() {
+    let cl = get_connection();
+    let stmt = cl.prepare("SELECT @P1 AS a, @P1 + 1 AS b;").unwrap();
+    for i in 0..2 {
+        let rows = stmt.query(&[&i]).unwrap();
+        let a: i32 = rows.get(0).get("a");
+        let b: i32 = rows.get(0).get("b");
+        assert_eq!((a, b), (i, i + 1));
+    }
+}
+
+#[test]
+fn test_prepared_exec_update() {
+    let cl = get_connection();
+    cl.exec("IF OBJECT_ID('tempdb..#tmp_update') IS NOT NULL DROP TABLE #tmp_update; CREATE TABLE #tmp_update (id int, x int);").unwrap();
+    cl.exec("INSERT INTO #tmp_update (id, x) VALUES (1, 0), (2, 0), (3, 0);").unwrap();
+    let stmt = cl.prepare("UPDATE #tmp_update SET x = x + 1 WHERE id <= @P1;").unwrap();
+    assert_eq!(stmt.execute(&[&2]).unwrap(), 2);
+    assert_eq!(stmt.execute(&[&3]).unwrap(), 3);
+}
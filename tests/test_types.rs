@@ -0,0 +1,188 @@
+extern crate tiberius;
+extern crate chrono;
+use std::borrow::Cow;
+use std::time::Duration;
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveTime, TimeZone, UTC};
+use tiberius::{ColumnValue, ColumnType, FromColumnValue, Guid, TdsError, TimeOfDay, ToColumnType};
+
+fn convert<'a, T: FromColumnValue<'a>>(val: &'a ColumnValue<'a>) -> Result<T, TdsError> {
+    T::from_column_value(val)
+}
+
+#[test]
+fn test_convert_primitives() {
+    let val = ColumnValue::Some(ColumnType::Bool(true));
+    assert_eq!(convert::<bool>(&val).unwrap(), true);
+    let val = ColumnValue::Some(ColumnType::I8(-1));
+    assert_eq!(convert::<i32>(&val).unwrap(), 255);
+    let val = ColumnValue::Some(ColumnType::I16(-1234));
+    assert_eq!(convert::<i32>(&val).unwrap(), -1234);
+    let val = ColumnValue::Some(ColumnType::I32(42));
+    assert_eq!(convert::<i32>(&val).unwrap(), 42);
+    assert_eq!(convert::<i64>(&val).unwrap(), 42);
+    let val = ColumnValue::Some(ColumnType::I64(1 << 40));
+    assert_eq!(convert::<i64>(&val).unwrap(), 1 << 40);
+    let val = ColumnValue::Some(ColumnType::F32(1.5));
+    assert_eq!(convert::<f32>(&val).unwrap(), 1.5);
+    assert_eq!(convert::<f64>(&val).unwrap(), 1.5);
+    let val = ColumnValue::Some(ColumnType::F64(42.42));
+    assert_eq!(convert::<f64>(&val).unwrap(), 42.42);
+    let val = ColumnValue::Some(ColumnType::String(Cow::Borrowed("hello")));
+    assert_eq!(convert::<&str>(&val).unwrap(), "hello");
+    assert_eq!(convert::<String>(&val).unwrap(), "hello".to_owned());
+    let val = ColumnValue::Some(ColumnType::Binary(vec![1, 2, 3]));
+    assert_eq!(convert::<&[u8]>(&val).unwrap(), &[1, 2, 3]);
+    assert_eq!(convert::<Vec<u8>>(&val).unwrap(), vec![1, 2, 3]);
+}
+
+#[test]
+fn test_convert_nullable() {
+    let val = ColumnValue::None;
+    assert_eq!(convert::<Option<i32>>(&val).unwrap(), None);
+    assert_eq!(convert::<Option<&str>>(&val).unwrap(), None);
+    assert!(convert::<i32>(&val).is_err());
+    let val = ColumnValue::Some(ColumnType::I32(1));
+    assert_eq!(convert::<Option<i32>>(&val).unwrap(), Some(1));
+}
+
+#[test]
+fn test_convert_mismatch() {
+    let val = ColumnValue::Some(ColumnType::String(Cow::Borrowed("1")));
+    match convert::<i32>(&val) {
+        Err(TdsError::Other(ref msg)) => assert_eq!(msg, "conversion: expected I32 got String"),
+        x => panic!("unexpected result {:?}", x)
+    }
+    // the From conversions used by `Row::get` yield None instead
+    let x: Option<i32> = From::from(&val);
+    assert_eq!(x, None);
+}
+
+#[test]
+fn test_convert_overflow() {
+    let val = ColumnValue::Some(ColumnType::I64(i32::max_value() as i64 + 1));
+    assert!(convert::<i32>(&val).is_err());
+    let val = ColumnValue::Some(ColumnType::I64(i32::min_value() as i64));
+    assert_eq!(convert::<i32>(&val).unwrap(), i32::min_value());
+}
+
+#[test]
+fn test_convert_narrow_integers() {
+    // tinyint is unsigned
+    let val = ColumnValue::Some(ColumnType::I8(-1));
+    assert_eq!(convert::<u8>(&val).unwrap(), 255);
+    assert_eq!(convert::<i16>(&val).unwrap(), 255);
+    let val = ColumnValue::Some(ColumnType::I16(-1234));
+    assert_eq!(convert::<i16>(&val).unwrap(), -1234);
+    assert!(convert::<u8>(&val).is_err());
+    let val = ColumnValue::Some(ColumnType::I32(200));
+    assert_eq!(convert::<u8>(&val).unwrap(), 200);
+    assert_eq!(convert::<i16>(&val).unwrap(), 200);
+    let val = ColumnValue::Some(ColumnType::I32(40000));
+    assert!(convert::<i16>(&val).is_err());
+    let val = ColumnValue::Some(ColumnType::I64(-1));
+    assert!(convert::<u8>(&val).is_err());
+    let x: Option<u8> = From::from(&ColumnValue::Some(ColumnType::I8(42)));
+    assert_eq!(x, Some(42));
+}
+
+#[test]
+fn test_convert_time_duration() {
+    let val = ColumnValue::Some(ColumnType::Time(NaiveTime::from_hms_nano(13, 45, 30, 123456700)));
+    let nanos = (13 * 3600 + 45 * 60 + 30) * 1_000_000_000u64 + 123456700;
+    let duration = convert::<Duration>(&val).unwrap();
+    assert_eq!(duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64, nanos);
+}
+
+#[test]
+fn test_bind_time_of_day() {
+    let time = TimeOfDay::new(Duration::new(24 * 3600 - 1, 999999999)).unwrap();
+    match time.to_column_type() {
+        ColumnType::Time(x) => assert_eq!(x, NaiveTime::from_hms_nano(23, 59, 59, 999999999)),
+        x => panic!("expected a time, got {:?}", x)
+    }
+    match TimeOfDay::new(Duration::from_secs(24 * 3600)) {
+        Err(TdsError::Other(ref msg)) => assert_eq!(msg, "time: a duration of 86400s since midnight is not less than 24 hours"),
+        x => panic!("expected an error, got {:?}", x)
+    }
+}
+
+#[test]
+fn test_guid_parse() {
+    let guid = Guid::parse("6F9619FF-8B86-D011-B42D-00C04FC964FF").unwrap();
+    assert_eq!(guid.as_bytes(), &[0xFF, 0x19, 0x96, 0x6F, 0x86, 0x8B, 0x11, 0xD0, 0xB4, 0x2D, 0x00, 0xC0, 0x4F, 0xC9, 0x64, 0xFF]);
+    assert_eq!(guid.as_str(), "6f9619ff-8b86-d011-b42d-00c04fc964ff");
+    assert_eq!(Guid::from_bytes(*guid.as_bytes()), guid);
+    assert!(Guid::parse("6f9619ff8b86d011b42d00c04fc964ff").is_err());
+    assert!(Guid::parse("6f9619ff-8b86-d011-b42d-00c04fc964fg").is_err());
+}
+
+#[test]
+fn test_convert_bit_integer() {
+    let val = ColumnValue::Some(ColumnType::Bool(true));
+    assert_eq!(convert::<i32>(&val).unwrap(), 1);
+    assert_eq!(convert::<i64>(&val).unwrap(), 1);
+    let val = ColumnValue::Some(ColumnType::Bool(false));
+    assert_eq!(convert::<i32>(&val).unwrap(), 0);
+    let val = ColumnValue::Some(ColumnType::I32(1));
+    assert_eq!(convert::<bool>(&val).unwrap(), true);
+    let val = ColumnValue::Some(ColumnType::I8(0));
+    assert_eq!(convert::<bool>(&val).unwrap(), false);
+    // the From conversions used by `Row::get` as well
+    let x: Option<i32> = From::from(&ColumnValue::Some(ColumnType::Bool(true)));
+    assert_eq!(x, Some(1));
+}
+
+#[test]
+fn test_error_display_and_source() {
+    use std::error::Error;
+    use std::io;
+    assert_eq!(TdsError::Timeout.to_string(), "the operation timed out");
+    assert_eq!(TdsError::Other("oops".to_owned()).to_string(), "oops");
+    assert!(TdsError::Timeout.source().is_none());
+    let err = TdsError::from(io::Error::new(io::ErrorKind::Other, "boom"));
+    assert_eq!(err.to_string(), "boom");
+    assert_eq!(err.source().unwrap().to_string(), "boom");
+}
+
+#[test]
+fn test_convert_datetime_offset() {
+    // 2017-03-01 12:00 UTC
+    let utc = NaiveDate::from_ymd(2017, 3, 1).and_hms(12, 0, 0);
+    for &(minutes, local_hour, local_minute) in &[(120, 14, 0), (-300, 7, 0), (330, 17, 30)] {
+        let val = ColumnValue::Some(ColumnType::DatetimeOffset(utc, FixedOffset::east(minutes * 60)));
+        let datetime = convert::<DateTime<FixedOffset>>(&val).unwrap();
+        assert_eq!(*datetime.offset(), FixedOffset::east(minutes * 60));
+        assert_eq!(datetime.naive_local(), NaiveDate::from_ymd(2017, 3, 1).and_hms(local_hour, local_minute, 0));
+        assert_eq!(convert::<DateTime<UTC>>(&val).unwrap(), UTC.from_utc_datetime(&utc));
+        assert_eq!(*convert::<&chrono::NaiveDateTime>(&val).unwrap(), utc);
+    }
+    // a datetime without an offset is taken as UTC
+    let val = ColumnValue::Some(ColumnType::Datetime(utc));
+    assert_eq!(*convert::<DateTime<FixedOffset>>(&val).unwrap().offset(), FixedOffset::east(0));
+}
+
+#[test]
+fn test_convert_date() {
+    for date in &[NaiveDate::from_ymd(1, 1, 1), NaiveDate::from_ymd(2017, 3, 1), NaiveDate::from_ymd(9999, 12, 31)] {
+        let val = ColumnValue::Some(ColumnType::Date(*date));
+        assert_eq!(convert::<NaiveDate>(&val).unwrap(), *date);
+        let x: Option<NaiveDate> = From::from(&val);
+        assert_eq!(x, Some(*date));
+    }
+    assert_eq!(convert::<Option<NaiveDate>>(&ColumnValue::None).unwrap(), None);
+    // a date does not have a time
+    let val = ColumnValue::Some(ColumnType::Datetime(NaiveDate::from_ymd(2017, 3, 1).and_hms(0, 0, 0)));
+    assert!(convert::<NaiveDate>(&val).is_err());
+}
+
+#[test]
+fn test_display_values() {
+    assert_eq!(ColumnType::Bool(true).to_string(), "1");
+    assert_eq!(ColumnType::I8(-1).to_string(), "255");
+    assert_eq!(ColumnType::Money(-12345).to_string(), "-1.2345");
+    assert_eq!(ColumnType::Money(10_000 * 42 + 5).to_string(), "42.0005");
+    assert_eq!(ColumnType::Binary(vec![0x01, 0xAB]).to_string(), "0x01AB");
+    let utc = NaiveDate::from_ymd(2017, 3, 1).and_hms(12, 0, 0);
+    assert_eq!(ColumnType::Datetime(utc).to_string(), "2017-03-01 12:00:00");
+    assert_eq!(ColumnType::DatetimeOffset(utc, FixedOffset::east(7200)).to_string(), "2017-03-01 14:00:00 +02:00");
+}